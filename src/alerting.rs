@@ -0,0 +1,238 @@
+use actix::prelude::*;
+use chrono::prelude::*;
+use prometheus::IntCounterVec;
+use std::time::Duration;
+use crate::api::APIError;
+use crate::models::*;
+use crate::store::Store;
+
+/// How often each team's metrics are checked against its alert rules.
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The hour (UTC) in which the rules of teams which use differential privacy are evaluated,
+/// since each evaluation spends some of their privacy budget and so is only done once a day.
+const PRIVATE_EVALUATION_HOUR: u32 = 0;
+
+lazy_static! {
+    static ref ALERTS_RAISED: IntCounterVec = register_int_counter_vec!(
+        "rex_alerts_raised_total",
+        "The number of alerts which have been raised for teams' metrics, by the kind of alert.",
+        &["kind"]
+    ).unwrap();
+
+    static ref ALERT_EVALUATIONS: IntCounterVec = register_int_counter_vec!(
+        "rex_alert_evaluations_total",
+        "The number of times teams' metrics have been checked against their alert rules, by outcome.",
+        &["outcome"]
+    ).unwrap();
+}
+
+/// Periodically checks the recent reports of every team which has alert rules, raising an
+/// alert whenever one of them is met.
+pub struct AlertActor {
+    store: Store,
+}
+
+impl AlertActor {
+    pub fn new(store: Store) -> Self {
+        Self { store }
+    }
+
+    fn schedule_evaluation(&self, ctx: &mut Context<Self>) {
+        let store = self.store.clone();
+
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = evaluate(&store, Utc::now()).await {
+                error!("Unable to evaluate alert rules: {}", err);
+            }
+        }));
+    }
+}
+
+impl Actor for AlertActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.schedule_evaluation(ctx);
+        ctx.run_interval(EVALUATION_INTERVAL, |actor, ctx| actor.schedule_evaluation(ctx));
+    }
+}
+
+/// Evaluates every team's alert rules as of `now`, returning the number of alerts raised.
+///
+/// An alert is not raised again while an earlier alert of the same kind for the same metric
+/// is still waiting to be acknowledged, or was raised within the rule's window. Teams which use
+/// differential privacy are only evaluated during the [PRIVATE_EVALUATION_HOUR], spending
+/// `epsilon` for each of their rules, and are skipped once their budget has run out.
+pub async fn evaluate(store: &Store, now: DateTime<Utc>) -> Result<usize, APIError> {
+    let result = evaluate_teams(store, now).await;
+    ALERT_EVALUATIONS.with_label_values(&[if result.is_ok() { "success" } else { "failure" }]).inc();
+
+    result
+}
+
+async fn evaluate_teams(store: &Store, now: DateTime<Utc>) -> Result<usize, APIError> {
+    let mut raised = 0;
+
+    for team in store.get_all_teams().await?.into_iter().filter(|team| !team.alert_rules.is_empty()) {
+        let privacy = team.privacy.differential_privacy.as_ref();
+        if let Some(dp) = privacy {
+            if now.hour() != PRIVATE_EVALUATION_HOUR {
+                continue;
+            }
+
+            // Rules on the same metric may cover the same reports, so each one is paid for separately
+            let spent = store.send(SpendPrivacyBudget {
+                team_id: team.team_id,
+                epsilon: dp.epsilon * team.alert_rules.len() as f64,
+                budget: dp.budget,
+                since: Some(dp.window_start(now)),
+            }).await?;
+
+            match spent {
+                Err(err) if err.code == 429 => {
+                    info!("Skipping the alert rules of team {:0>32x}, which has used up its privacy budget", team.team_id);
+                    continue;
+                },
+                other => other?,
+            };
+        }
+
+        let start = team.alert_rules.iter().map(|rule| rule.start(now)).min().unwrap_or(now);
+        let reports = store.send(GetReports { team: team.team_id, after: Some(start), ..Default::default() }).await??.items;
+
+        let existing = store.send(GetAlerts { team_id: team.team_id }).await??;
+
+        let min_reports = team.privacy.min_group_size().max(MIN_ALERT_REPORTS);
+        for rule in team.alert_rules.iter() {
+            let window_start = now - chrono::Duration::days(rule.window_days as i64);
+
+            for alert in rule.evaluate(team.team_id, &reports, now, min_reports, privacy) {
+                let duplicate = existing.iter().any(|e| e.metric == alert.metric && e.kind == alert.kind
+                    && (e.acknowledged_at.is_none() || e.raised_at >= window_start));
+                if duplicate {
+                    continue;
+                }
+
+                info!("Raising a '{}' alert for the {} metric of team {:0>32x}", alert.kind.as_str(), alert.metric, alert.team_id);
+                ALERTS_RAISED.with_label_values(&[alert.kind.as_str()]).inc();
+
+                store.send(StoreAlert { alert }).await??;
+                raised += 1;
+            }
+        }
+    }
+
+    Ok(raised)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::test::get_test_state;
+
+    #[actix_rt::test]
+    async fn raises_alerts() {
+        let state = get_test_state();
+        let now = Utc::now();
+
+        state.store.send(StoreUser { email_hash: 1, principal_id: 1, first_name: "Testy".into() })
+            .await.expect("the actor should run").expect("the user should be stored");
+        state.store.send(StoreTeam {
+            team_id: 7,
+            principal_id: 1,
+            name: "Test Team".into(),
+            alert_rules: vec![AlertRule { metric: "happy_sad".into(), window_days: 7, baseline_days: 28, max_drop: None, below: Some(0.0), above: None }],
+            ..Default::default()
+        }).await.expect("the actor should run").expect("the team should be stored");
+        state.store.send(StoreTeamAssignment { team_id: 7, principal_id: 1, role: Role::Manager, ..Default::default() })
+            .await.expect("the actor should run").expect("the assignment should be stored");
+        state.store.send(StoreReports { reports: (0..3).map(|i| Report {
+            id: i,
+            team_id: 7,
+            metric: "happy_sad".into(),
+            timestamp: now - chrono::Duration::hours(1 + i as i64),
+            value: -1.0,
+            response_id: None,
+            receipt_hash: None,
+            options: vec![],
+            staged_at: None,
+        }).collect() }).await.expect("the actor should run").expect("the reports should be stored");
+
+        assert_eq!(evaluate(&state.store, now).await.expect("the evaluation should succeed"), 1);
+        assert_eq!(evaluate(&state.store, now).await.expect("the evaluation should succeed"), 0, "an open alert should not be raised again");
+
+        let alerts = state.store.send(GetAlerts { team_id: 7 }).await.expect("the actor should run").expect("the alerts should be listed");
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, AlertKind::Below);
+        assert_eq!(alerts[0].window_mean, -1.0);
+
+        state.store.send(AcknowledgeAlert { team_id: 7, id: alerts[0].id, principal_id: 1 })
+            .await.expect("the actor should run").expect("the alert should be acknowledged");
+        assert_eq!(evaluate(&state.store, now).await.expect("the evaluation should succeed"), 0, "an acknowledged alert should not be raised again within its window");
+
+        let later = now + chrono::Duration::days(8);
+        state.store.send(StoreReports { reports: (3..6).map(|i| Report {
+            id: i,
+            team_id: 7,
+            metric: "happy_sad".into(),
+            timestamp: later - chrono::Duration::hours(i as i64),
+            value: -1.0,
+            response_id: None,
+            receipt_hash: None,
+            options: vec![],
+            staged_at: None,
+        }).collect() }).await.expect("the actor should run").expect("the reports should be stored");
+        assert_eq!(evaluate(&state.store, later).await.expect("the evaluation should succeed"), 1, "the alert should be raised again once its window has passed");
+    }
+
+    #[actix_rt::test]
+    async fn raises_private_alerts() {
+        let state = get_test_state();
+        let now = Utc::now().date().and_hms(PRIVATE_EVALUATION_HOUR, 30, 0);
+
+        state.store.send(StoreUser { email_hash: 1, principal_id: 1, first_name: "Testy".into() })
+            .await.expect("the actor should run").expect("the user should be stored");
+        state.store.send(StoreTeam {
+            team_id: 7,
+            principal_id: 1,
+            name: "Test Team".into(),
+            privacy: TeamPrivacy { differential_privacy: Some(DifferentialPrivacy { epsilon: 100.0, budget: 100.0, ..Default::default() }), ..Default::default() },
+            alert_rules: vec![AlertRule { metric: "happy_sad".into(), window_days: 7, baseline_days: 28, max_drop: None, below: Some(0.0), above: None }],
+            ..Default::default()
+        }).await.expect("the actor should run").expect("the team should be stored");
+        state.store.send(StoreTeamAssignment { team_id: 7, principal_id: 1, role: Role::Manager, ..Default::default() })
+            .await.expect("the actor should run").expect("the assignment should be stored");
+        state.store.send(StoreReports { reports: (0..10).map(|i| Report {
+            id: i,
+            team_id: 7,
+            metric: "happy_sad".into(),
+            timestamp: now - chrono::Duration::hours(1 + i as i64),
+            value: -0.5,
+            response_id: None,
+            receipt_hash: None,
+            options: vec![],
+            staged_at: None,
+        }).collect() }).await.expect("the actor should run").expect("the reports should be stored");
+
+        assert_eq!(evaluate(&state.store, now + chrono::Duration::hours(1)).await.expect("the evaluation should succeed"), 0,
+            "private teams should only be evaluated once a day");
+        assert_eq!(evaluate(&state.store, now).await.expect("the evaluation should succeed"), 1);
+
+        let alerts = state.store.send(GetAlerts { team_id: 7 }).await.expect("the actor should run").expect("the alerts should be listed");
+        assert_ne!(alerts[0].window_mean, -0.5, "the exact mean should not be recorded");
+
+        state.store.send(SpendPrivacyBudget { team_id: 7, epsilon: 100.0, budget: 100.0, since: None })
+            .await.expect("the actor should run").expect_err("the evaluation should have spent from the team's budget");
+
+        state.store.send(StoreTeam {
+            team_id: 7,
+            principal_id: 1,
+            name: "Test Team".into(),
+            privacy: TeamPrivacy { differential_privacy: Some(DifferentialPrivacy { epsilon: 100.0, budget: 100.0, ..Default::default() }), ..Default::default() },
+            alert_rules: vec![AlertRule { metric: "happy_sad".into(), window_days: 7, baseline_days: 28, max_drop: None, below: None, above: Some(-2.0) }],
+            ..Default::default()
+        }).await.expect("the actor should run").expect("the team should be stored");
+        assert_eq!(evaluate(&state.store, now).await.expect("the evaluation should succeed"), 0, "a team which has used up its budget should be skipped");
+    }
+}
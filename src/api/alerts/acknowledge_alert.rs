@@ -0,0 +1,75 @@
+use actix_web::{post, web};
+use super::{AuthToken, APIError};
+use crate::models::*;
+use super::TeamIdFilter;
+
+#[post("/api/v1/team/{team}/alert/{id}/acknowledge")]
+async fn acknowledge_team_alert_v1(
+    (info, state, token): (web::Path<TeamIdFilter>, web::Data<GlobalState>, AuthToken),
+) -> Result<AlertV1, APIError> {
+    require_role!(token, "Administrator", "User");
+    require_scope!(token, "Teams.Write");
+
+    let cid = parse_uuid!(info.team, team ID);
+    let id = parse_uuid!(info.id, alert ID);
+    let uid = parse_uuid!(token.oid, auth token oid);
+
+    let role = state.store.send(GetTeamAssignment { team_id: cid, principal_id: uid }).await??;
+    if role.role != Role::Manager {
+        return Err(APIError::new(403, "Forbidden", "Only the managers of a team may acknowledge its alerts."));
+    }
+
+    state.store.send(AcknowledgeAlert { team_id: cid, id, principal_id: uid }).await?.map(|alert| alert.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::api::test::*;
+    use chrono::prelude::*;
+
+    #[actix_rt::test]
+    async fn acknowledge_team_alert_v1() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Manager,
+                ..Default::default()
+            },
+            StoreAlert {
+                alert: Alert { id: 1, team_id: 7, metric: "happy_sad".into(), raised_at: Utc::now(), ..Default::default() }
+            }
+        ]);
+
+        let content: AlertV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/alert/00000000000000000000000000000001/acknowledge" => OK with content | state = state);
+        assert_eq!(content.acknowledged_by, Some("00000000000000000000000000000000".into()));
+        let acknowledged_at = content.acknowledged_at.expect("the alert should be acknowledged");
+
+        let content: AlertV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/alert/00000000000000000000000000000001/acknowledge" => OK with content | state = state);
+        assert_eq!(content.acknowledged_at, Some(acknowledged_at), "acknowledging an alert again should keep the original acknowledgement");
+
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/alert/00000000000000000000000000000002/acknowledge" => NOT_FOUND | state = state);
+    }
+
+    #[actix_rt::test]
+    async fn acknowledge_team_alert_v1_member() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            },
+            StoreAlert {
+                alert: Alert { id: 1, team_id: 7, metric: "happy_sad".into(), raised_at: Utc::now(), ..Default::default() }
+            }
+        ]);
+
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/alert/00000000000000000000000000000001/acknowledge" => FORBIDDEN | state = state);
+    }
+}
@@ -0,0 +1,69 @@
+use actix_web::{get, web};
+use super::{AuthToken, APIError};
+use crate::models::*;
+use super::{AlertFilter, TeamFilter};
+
+#[get("/api/v1/team/{team}/alerts")]
+async fn get_team_alerts_v1(
+    (info, query, state, token): (web::Path<TeamFilter>, web::Query<AlertFilter>, web::Data<GlobalState>, AuthToken),
+) -> Result<web::Json<Vec<AlertV1>>, APIError> {
+    require_role!(token, "Administrator", "User");
+    require_scope!(token, "Reports.Read");
+
+    let cid = parse_uuid!(info.team, team ID);
+    let uid = parse_uuid!(token.oid, auth token oid);
+
+    state.store.send(GetTeamAssignment { team_id: cid, principal_id: uid }).await??;
+
+    let alerts = state.store.send(GetAlerts { team_id: cid }).await??;
+
+    Ok(web::Json(alerts.into_iter()
+        .filter(|alert| query.acknowledged.map(|acknowledged| alert.acknowledged_at.is_some() == acknowledged).unwrap_or(true))
+        .map(|alert| alert.into())
+        .collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::api::test::*;
+    use chrono::prelude::*;
+
+    #[actix_rt::test]
+    async fn get_team_alerts_v1() {
+        test_log_init();
+
+        let now = Utc::now();
+
+        test_state!(state = [
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            },
+            StoreAlert {
+                alert: Alert { id: 1, team_id: 7, metric: "happy_sad".into(), kind: AlertKind::Below, raised_at: now - chrono::Duration::days(1), window_mean: -0.5, threshold: Some(0.0), ..Default::default() }
+            },
+            StoreAlert {
+                alert: Alert { id: 2, team_id: 7, metric: "happy_sad".into(), kind: AlertKind::Drop, raised_at: now, window_mean: -0.5, baseline_mean: Some(0.5), threshold: Some(2.0), acknowledged_by: Some(1), acknowledged_at: Some(now) }
+            },
+            StoreAlert {
+                alert: Alert { id: 3, team_id: 8, metric: "happy_sad".into(), raised_at: now, ..Default::default() }
+            }
+        ]);
+
+        let content: Vec<AlertV1> = test_request!(GET "/api/v1/team/00000000000000000000000000000007/alerts" => OK with content | state = state);
+        assert_eq!(content.len(), 2);
+        assert_eq!(content[0].id, "00000000000000000000000000000002");
+        assert_eq!(content[0].acknowledged_by, Some("00000000000000000000000000000001".into()));
+        assert_eq!(content[1].kind, AlertKind::Below);
+        assert_eq!(content[1].threshold, Some(0.0));
+
+        let content: Vec<AlertV1> = test_request!(GET "/api/v1/team/00000000000000000000000000000007/alerts?acknowledged=false" => OK with content | state = state);
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[0].id, "00000000000000000000000000000001");
+
+        test_request!(GET "/api/v1/team/00000000000000000000000000000008/alerts" => FORBIDDEN | state = state);
+    }
+}
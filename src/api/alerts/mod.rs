@@ -0,0 +1,26 @@
+mod get_alerts;
+mod acknowledge_alert;
+
+use actix_web::web;
+use super::{AuthToken, APIError};
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_alerts::get_team_alerts_v1)
+        .service(acknowledge_alert::acknowledge_team_alert_v1);
+}
+
+#[derive(Deserialize, Serialize)]
+struct TeamFilter {
+    team: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct TeamIdFilter {
+    team: String,
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct AlertFilter {
+    acknowledged: Option<bool>,
+}
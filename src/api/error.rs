@@ -13,6 +13,10 @@ impl APIError {
         Self::new(401, "Unauthorized", "You have not provided a valid authentication token. Please authenticate and try again.")
     }
 
+    pub fn precondition_failed() -> Self {
+        Self::new(412, "Precondition Failed", "The resource has been modified since you last retrieved it. Please fetch the latest version and try again.")
+    }
+
     pub fn new(code: u16, error: &str, message: &str) -> Self {
         Self {
             code: code,
@@ -56,6 +60,23 @@ impl From<azure_sdk_core::errors::AzureError> for APIError {
     }
 }
 
+#[cfg(feature = "sqlite_storage")]
+impl From<rusqlite::Error> for APIError {
+    fn from(err: rusqlite::Error) -> Self {
+        error!("We were unable to call SQLite: {}", err);
+
+        let event = sentry::integrations::failure::event_from_error(&err.into());
+
+        sentry::capture_event(sentry::protocol::Event {
+            message: Some("Failed to interact with SQLite".into()),
+            level: sentry::protocol::Level::Error,
+            ..event
+        });
+
+        Self::new(500, "Internal Server Error", "We ran into a problem, this has been reported and will be looked at.")
+    }
+}
+
 impl From<actix::MailboxError> for APIError {
     fn from(err: actix::MailboxError) -> Self {
         error!("We were unable to call an actor: {}", err);
@@ -2,7 +2,7 @@
 macro_rules! parse_uuid {
     ($from:expr, $($desc:tt)+) => {
         u128::from_str_radix($from.replace("-", "").as_str(), 16)
-            .or(Err(APIError::new(400, "Bad Request", "The $($desc)+ you provided could not be parsed. Please check it and try again.")))?;
+            .or(Err(APIError::new(400, "Bad Request", "The $($desc)+ you provided could not be parsed. Please check it and try again.")))?
     };
 }
 
@@ -46,7 +46,7 @@ macro_rules! test_state {
     };
 
     ($state:ident = [ $($init:expr),* ]) => {
-        let $state = crate::models::GlobalState::new();
+        let $state = crate::api::test::get_test_state();
 
         test_state!(:: $state = [ $($init),* ]);
     }
@@ -120,7 +120,7 @@ macro_rules! test_request {
     
     ($method:ident $path:expr => $status:ident) => {
         {
-            let state = crate::models::GlobalState::new();
+            let state = crate::api::test::get_test_state();
             
             test_request!($method $path => $status | state = state)
         }
@@ -128,7 +128,7 @@ macro_rules! test_request {
 
     ($method:ident $path:expr, $body:expr => $status:ident) => {
         {
-            let state = crate::models::GlobalState::new();
+            let state = crate::api::test::get_test_state();
             
             test_request!($method $path, $body => $status | state = state)
         }
@@ -1,5 +1,6 @@
 #[macro_use] mod macros;
 
+mod alerts;
 mod auth;
 mod error;
 mod teams;
@@ -15,12 +16,13 @@ use actix_web::web;
 
 pub use error::APIError;
 pub use auth::AuthToken;
-pub use utils::ensure_user_team;
+pub use utils::{ensure_user_team, idempotency_key, if_match};
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     health::configure(cfg);
     teams::configure(cfg);
     team_assignments::configure(cfg);
     reports::configure(cfg);
+    alerts::configure(cfg);
     users::configure(cfg);
 }
\ No newline at end of file
@@ -0,0 +1,130 @@
+use actix_web::{post, web};
+use super::{AuthToken, APIError, receipt_teams};
+use crate::models::*;
+use chrono::prelude::*;
+
+#[post("/api/v1/reports/amend")]
+async fn amend_reports_v1(
+    (request, state, token): (web::Json<ReceiptV1>, web::Data<GlobalState>, AuthToken),
+) -> Result<web::Json<Vec<ReportV1>>, APIError> {
+    require_role!(token, "Administrator", "User");
+    require_scope!(token, "Reports.Write");
+
+    let receipt = request.parse(Utc::now())?;
+
+    // As with new reports, the options are kept in a consistent order and counted as the value.
+    let mut options = request.options.clone();
+    options.sort();
+    let value = if options.is_empty() {
+        request.value
+            .ok_or_else(|| APIError::new(400, "Bad Request", "You did not provide a new value or options for your report. Please provide one and try again."))?
+    } else {
+        options.len() as f32
+    };
+
+    // Every copy is checked against its team's metrics before any of them are changed, so that an
+    // amendment is either applied to all of the copies or to none of them.
+    let mut covered: Vec<(Team, String)> = vec![];
+    for team in receipt_teams(&state, &token).await? {
+        let receipt_hash = receipt.hash_for(team.team_id);
+        let reports = state.store.send(GetReceiptReports { team_id: team.team_id, receipt_hash: receipt_hash.clone() }).await??;
+
+        let mut matched = false;
+        for report in reports.iter().filter(|r| request.metric.as_ref().map(|m| &r.metric == m).unwrap_or(true)) {
+            validate_report(&team.metrics, &report.metric, value, &options)?;
+            matched = true;
+        }
+
+        if matched {
+            covered.push((team, receipt_hash));
+        }
+    }
+
+    if covered.is_empty() {
+        return Err(APIError::new(404, "Not Found", "No reports could be found for the receipt you provided. They may have been retracted."));
+    }
+
+    let mut amended: Vec<Report> = vec![];
+    for (team, receipt_hash) in covered {
+        amended.extend(state.store.send(AmendReports {
+            team_id: team.team_id,
+            receipt_hash,
+            metric: request.metric.clone(),
+            value,
+            options: options.clone(),
+        }).await??);
+    }
+
+    Ok(web::Json(amended.into_iter().map(|report| report.into()).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::api::test::*;
+    use std::collections::BTreeMap;
+
+    #[actix_rt::test]
+    async fn amend_reports_v1() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                metrics: vec![
+                    MetricDefinition { name: "happy_sad".into(), label: "Happiness".into(), kind: MetricKind::Binary, description: String::new() },
+                    MetricDefinition { name: "workload".into(), label: "Workload".into(), kind: MetricKind::Scale { min: 1, max: 5 }, description: String::new() },
+                    MetricDefinition { name: "focus".into(), label: "Focus".into(), kind: MetricKind::Categorical { options: vec!["deep".into(), "shallow".into()] }, description: String::new() },
+                ],
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let mut answers = BTreeMap::new();
+        answers.insert("happy_sad".to_string(), AnswerV1::Value(1.0));
+        answers.insert("workload".to_string(), AnswerV1::Value(2.0));
+        answers.insert("focus".to_string(), AnswerV1::Options(vec!["deep".into()]));
+
+        let response: ResponseV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/responses", ResponseV1 { id: None, team: None, timestamp: None, answers, receipt: None } => OK with content | state = state);
+        let receipt = response.receipt.expect("a receipt should be returned");
+
+        let amend = |metric: Option<&str>, value: Option<f32>| ReceiptV1 { receipt: receipt.clone(), metric: metric.map(|m| m.into()), value, options: vec![] };
+        let choose = |metric: &str, options: &[&str]| ReceiptV1 { receipt: receipt.clone(), metric: Some(metric.into()), value: None, options: options.iter().map(|o| o.to_string()).collect() };
+
+        test_request!(POST "/api/v1/reports/amend", amend(Some("workload"), None) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/reports/amend", amend(Some("workload"), Some(7.0)) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/reports/amend", amend(None, Some(4.0)) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/reports/amend", amend(Some("hours"), Some(4.0)) => NOT_FOUND | state = state);
+
+        let amended: Vec<ReportV1> = test_request!(POST "/api/v1/reports/amend", amend(Some("workload"), Some(4.0)) => OK with content | state = state);
+        assert_eq!(amended.len(), 1);
+        assert_eq!((amended[0].metric.as_str(), amended[0].value), ("workload", 4.0));
+
+        let reports = state.store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        let values: BTreeMap<String, f32> = reports.into_iter().map(|r| (r.metric, r.value)).collect();
+        assert_eq!(values.get("workload"), Some(&4.0));
+        assert_eq!(values.get("happy_sad"), Some(&1.0), "other answers in the response should be left alone");
+
+        test_request!(POST "/api/v1/reports/amend", amend(Some("focus"), Some(1.0)) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/reports/amend", choose("focus", &["deep", "shallow"]) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/reports/amend", choose("workload", &["deep"]) => BAD_REQUEST | state = state);
+
+        let amended: Vec<ReportV1> = test_request!(POST "/api/v1/reports/amend", choose("focus", &["shallow"]) => OK with content | state = state);
+        assert_eq!(amended.len(), 1);
+        assert_eq!(amended[0].options, vec!["shallow".to_string()]);
+
+        let reports = state.store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        let focus = reports.iter().find(|r| r.metric == "focus").expect("the amended report should exist");
+        assert_eq!((focus.value, focus.options.clone()), (1.0, vec!["shallow".to_string()]));
+    }
+}
@@ -0,0 +1,149 @@
+use actix_web::{get, web};
+use super::{AuthToken, APIError, ensure_user_team};
+use crate::models::*;
+use super::{HistoryFilter, TeamFilter, aggregate_privacy, parse_after};
+
+#[get("/api/v1/reports/history")]
+async fn get_history_v1(
+    (query, state, token): (web::Query<HistoryFilter>, web::Data<GlobalState>, AuthToken),
+) -> Result<web::Json<Vec<ReportHistoryV1>>, APIError> {
+    require_role!(token, "Administrator", "User");
+    require_scope!(token, "Reports.Read");
+
+    let uid = parse_uuid!(token.oid, auth token oid);
+
+    ensure_user_team(&state, &token).await?;
+    state.store.send(GetTeamAssignment { principal_id: uid, team_id: uid }).await??;
+    let privacy = aggregate_privacy(&state, uid, uid).await?;
+    require_exact_history(&privacy)?;
+
+    get_history(&state, uid, &query, &privacy).await
+}
+
+#[get("/api/v1/team/{team}/reports/history")]
+async fn get_team_history_v1(
+    (info, query, state, token): (web::Path<TeamFilter>, web::Query<HistoryFilter>, web::Data<GlobalState>, AuthToken),
+) -> Result<web::Json<Vec<ReportHistoryV1>>, APIError> {
+    require_role!(token, "Administrator", "User");
+    require_scope!(token, "Reports.Read");
+
+    let cid = parse_uuid!(info.team, team ID);
+    let uid = parse_uuid!(token.oid, auth token oid);
+
+    ensure_user_team(&state, &token).await?;
+    state.store.send(GetTeamAssignment { principal_id: uid, team_id: cid }).await??;
+    let privacy = aggregate_privacy(&state, cid, uid).await?;
+    require_exact_history(&privacy)?;
+
+    get_history(&state, cid, &query, &privacy).await
+}
+
+/// Ensures that a team's exact history can be shared, which isn't the case once it has asked for
+/// noise to be added to its aggregates.
+fn require_exact_history(privacy: &TeamPrivacy) -> Result<(), APIError> {
+    if privacy.differential_privacy.is_some() {
+        return Err(APIError::new(403, "Forbidden", "This team adds noise to its aggregates, so its exact history cannot be retrieved. Please use the team's report summary instead."));
+    }
+
+    Ok(())
+}
+
+/// Combines a team's daily rollups with the reports which have not been rolled up yet,
+/// so that the history looks the same regardless of how much of it has been compacted. Days
+/// with fewer reports than the team's minimum group size are merged with their neighbours.
+async fn get_history(state: &GlobalState, team: u128, query: &HistoryFilter, privacy: &TeamPrivacy) -> Result<web::Json<Vec<ReportHistoryV1>>, APIError> {
+    let after = parse_after(&query.after, privacy);
+
+    let rollups = state.store.send(GetReportRollups {
+        team,
+        metric: query.metric.clone(),
+        after,
+    }).await??;
+
+    let reports = state.store.send(GetReports {
+        team,
+        metric: query.metric.clone(),
+        after: after.map(ReportRollup::day_of),
+        ..Default::default()
+    }).await??.items;
+
+    let history = coalesce(
+        ReportRollup::combine(&rollups, &reports, |_| None),
+        privacy.min_group_size(),
+        |a, b| a.metric == b.metric,
+        |rollup| rollup.count,
+        |into, rollup| into.merge(&rollup));
+
+    Ok(web::Json(history.into_iter().map(|rollup| rollup.into()).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::api::test::*;
+    use chrono::prelude::*;
+
+    #[actix_rt::test]
+    async fn get_team_history_v1() {
+        test_log_init();
+
+        let day = Utc.ymd(2020, 3, 1);
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Manager,
+                ..Default::default()
+            },
+            StoreReportRollups {
+                rollups: vec![ReportRollup {
+                    team_id: 7,
+                    metric: "happy_sad".into(),
+                    day: day.and_hms(0, 0, 0),
+                    count: 2,
+                    sum: 3.0,
+                    min: 1.0,
+                    max: 2.0,
+                    sum_squares: 5.0,
+                    option_counts: Default::default(),
+                }]
+            },
+            StoreReport {
+                id: 1,
+                team: 7,
+                metric: "happy_sad".into(),
+                timestamp: Some(day.and_hms(18, 0, 0)),
+                value: 4.0,
+                options: vec![],
+            },
+            StoreReport {
+                id: 2,
+                team: 7,
+                metric: "happy_sad".into(),
+                timestamp: Some(day.succ().and_hms(9, 0, 0)),
+                value: -1.0,
+                options: vec![],
+            }
+        ]);
+
+        let content: Vec<ReportHistoryV1> = test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/history?metric=happy_sad" => OK with content | state = state);
+        assert_eq!(content.len(), 2);
+        assert_eq!(content[0].day, "2020-03-01");
+        assert_eq!(content[0].count, 3);
+        assert_eq!(content[0].mean, 7.0 / 3.0);
+        assert_eq!(content[0].max, 4.0);
+        assert_eq!(content[1].day, "2020-03-02");
+        assert_eq!(content[1].count, 1);
+
+        let content: Vec<ReportHistoryV1> = test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/history?after=2020-03-02T12:00:00Z" => OK with content | state = state);
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[0].day, "2020-03-02");
+    }
+}
@@ -1,7 +1,7 @@
 use actix_web::{get, web};
 use super::{AuthToken, APIError, ensure_user_team};
 use crate::models::*;
-use super::{IdFilter, TeamIdFilter};
+use super::{IdFilter, TeamIdFilter, require_raw_reports};
 
 
 #[get("/api/v1/report/{id}")]
@@ -33,6 +33,7 @@ async fn get_team_report_v1(
     ensure_user_team(&state, &token).await?;
 
     state.store.send(GetTeamAssignment { principal_id: uid, team_id: cid }).await??;
+    require_raw_reports(&state, cid, uid).await?;
 
     state.store.send(GetReport { team: cid, id: id }).await?.map(|report| report.clone().into())
 }
@@ -72,12 +73,14 @@ mod tests {
                 team_id: 7,
                 principal_id: 0,
                 name: "Test Team".into(),
+                privacy: TeamPrivacy { raw_reports: true, ..Default::default() },
                 ..Default::default()
             },
             StoreTeamAssignment {
                 team_id: 7,
                 principal_id: 0,
                 role: Role::Manager,
+                ..Default::default()
             },
             StoreReport {
                 id: 1,
@@ -1,13 +1,12 @@
 use actix_web::{get, web};
 use super::{AuthToken, APIError, ensure_user_team};
 use crate::models::*;
-use super::{QueryFilter, TeamFilter};
-use chrono::prelude::*;
+use super::{QueryFilter, TeamFilter, parse_after, reports_page_response, require_raw_reports};
 
 #[get("/api/v1/reports")]
 async fn get_reports_v1(
     (query, state, token): (web::Query<QueryFilter>, web::Data<GlobalState>, AuthToken),
-) -> Result<web::Json<Vec<ReportV1>>, APIError> {
+) -> Result<web::HttpResponse, APIError> {
     require_role!(token, "Administrator", "User");
     require_scope!(token, "Reports.Read");
     
@@ -15,18 +14,21 @@ async fn get_reports_v1(
 
     ensure_user_team(&state, &token).await?;
     state.store.send(GetTeamAssignment { principal_id: uid, team_id: uid }).await??;
+    let team = state.store.send(GetTeam { id: uid, principal_id: uid }).await??;
 
     state.store.send(GetReports {
         team: uid,
         metric: query.metric.clone(), 
-        after: query.after.clone().and_then(|after| DateTime::parse_from_rfc3339(after.as_str()).ok()).map(|dt| dt.with_timezone(&Utc)).clone()
-    }).await?.map(|reports| web::Json(reports.iter().map(|i| i.clone().into()).collect()))
+        after: parse_after(&query.after, &team.privacy),
+        limit: query.limit()?,
+        cursor: query.cursor.clone(),
+    }).await?.map(reports_page_response)
 }
 
 #[get("/api/v1/team/{team}/reports")]
 async fn get_team_reports_v1(
     (info, query, state, token): (web::Path<TeamFilter>, web::Query<QueryFilter>, web::Data<GlobalState>, AuthToken),
-) -> Result<web::Json<Vec<ReportV1>>, APIError> {
+) -> Result<web::HttpResponse, APIError> {
     require_role!(token, "Administrator", "User");
     require_scope!(token, "Reports.Read");
     
@@ -35,12 +37,15 @@ async fn get_team_reports_v1(
         
     ensure_user_team(&state, &token).await?;
     state.store.send(GetTeamAssignment { principal_id: uid, team_id: cid }).await??;
+    let privacy = require_raw_reports(&state, cid, uid).await?;
 
     state.store.send(GetReports {
         team: cid,
         metric: query.metric.clone(), 
-        after: query.after.clone().and_then(|after| DateTime::parse_from_rfc3339(after.as_str()).ok()).map(|dt| dt.with_timezone(&Utc)).clone()
-    }).await?.map(|reports| web::Json(reports.iter().map(|i| i.clone().into()).collect()))
+        after: parse_after(&query.after, &privacy),
+        limit: query.limit()?,
+        cursor: query.cursor.clone(),
+    }).await?.map(reports_page_response)
 }
 
 #[cfg(test)]
@@ -70,6 +75,29 @@ mod tests {
         assert_eq!(content[0].value, 2.5);
     }
 
+    #[actix_rt::test]
+    async fn get_reports_v1_keeps_personal_team_etag() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreReport {
+                id: 1,
+                team: 0,
+                metric: "test".into(),
+                value: 2.5,
+                ..Default::default()
+            }
+        ]);
+
+        // Every request makes sure the user has a personal team, which shouldn't change its assignment's ETag
+        test_request!(GET "/api/v1/reports" => OK | state = state);
+        let assignment = state.store.send(GetTeamAssignment { team_id: 0, principal_id: 0 }).await.expect("the actor should run").expect("the personal team should exist");
+
+        test_request!(GET "/api/v1/reports" => OK | state = state);
+        let unchanged = state.store.send(GetTeamAssignment { team_id: 0, principal_id: 0 }).await.expect("the actor should run").expect("the personal team should exist");
+        assert_eq!(unchanged.etag, assignment.etag);
+    }
+
     #[actix_rt::test]
     async fn get_team_reports_v1() {
         test_log_init();
@@ -79,12 +107,14 @@ mod tests {
                 team_id: 7,
                 principal_id: 0,
                 name: "Test Team".into(),
+                privacy: TeamPrivacy { raw_reports: true, ..Default::default() },
                 ..Default::default()
             },
             StoreTeamAssignment {
                 team_id: 7,
                 principal_id: 0,
                 role: Role::Manager,
+                ..Default::default()
             },
             StoreReport {
                 id: 1,
@@ -102,4 +132,92 @@ mod tests {
         assert_eq!(content[0].metric, "test".to_string());
         assert_eq!(content[0].value, 2.5);
     }
-}
\ No newline at end of file
+
+    #[actix_rt::test]
+    async fn get_team_reports_v1_paged() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                privacy: TeamPrivacy { raw_reports: true, ..Default::default() },
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Manager,
+                ..Default::default()
+            },
+            StoreReport {
+                id: 1,
+                team: 7,
+                metric: "test".into(),
+                value: 1.0,
+                ..Default::default()
+            },
+            StoreReport {
+                id: 2,
+                team: 7,
+                metric: "test".into(),
+                value: 2.0,
+                ..Default::default()
+            },
+            StoreReport {
+                id: 3,
+                team: 7,
+                metric: "test".into(),
+                value: 3.0,
+                ..Default::default()
+            }
+        ]);
+
+        let mut response = test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports?limit=2" => OK | state = state);
+        let cursor = response.headers().get("X-Next-Cursor").expect("a next cursor header").to_str().expect("a valid cursor").to_string();
+        let content: Vec<ReportV1> = get_content(&mut response).await;
+        assert_eq!(content.len(), 2);
+        assert_eq!(content[0].id, Some("00000000000000000000000000000001".into()));
+        assert_eq!(content[1].id, Some("00000000000000000000000000000002".into()));
+
+        let mut response = test_request!(GET &format!("/api/v1/team/00000000000000000000000000000007/reports?limit=2&cursor={}", cursor) => OK | state = state);
+        assert!(response.headers().get("X-Next-Cursor").is_none());
+        let content: Vec<ReportV1> = get_content(&mut response).await;
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[0].id, Some("00000000000000000000000000000003".into()));
+
+        test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports?limit=0" => BAD_REQUEST | state = state);
+        test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports?cursor=not-a-cursor" => BAD_REQUEST | state = state);
+    }
+
+    #[actix_rt::test]
+    async fn get_team_reports_v1_not_opted_in() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Manager,
+                ..Default::default()
+            },
+            StoreReport {
+                id: 1,
+                team: 7,
+                metric: "test".into(),
+                value: 2.5,
+                ..Default::default()
+            }
+        ]);
+
+        test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports" => FORBIDDEN | state = state);
+        test_request!(GET "/api/v1/team/00000000000000000000000000000007/report/00000000000000000000000000000001" => FORBIDDEN | state = state);
+    }
+}
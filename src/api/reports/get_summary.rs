@@ -0,0 +1,206 @@
+use actix_web::{get, web};
+use super::{AuthToken, APIError, ensure_user_team};
+use crate::models::*;
+use super::{SummaryFilter, TeamFilter, aggregate_privacy};
+use chrono::prelude::*;
+
+#[get("/api/v1/team/{team}/reports/summary")]
+async fn get_team_summary_v1(
+    (info, query, state, token): (web::Path<TeamFilter>, web::Query<SummaryFilter>, web::Data<GlobalState>, AuthToken),
+) -> Result<web::HttpResponse, APIError> {
+    require_role!(token, "Administrator", "User");
+    require_scope!(token, "Reports.Read");
+
+    let cid = parse_uuid!(info.team, team ID);
+    let uid = parse_uuid!(token.oid, auth token oid);
+    let from = parse_time(&query.from)?;
+    let to = parse_time(&query.to)?;
+
+    ensure_user_team(&state, &token).await?;
+    state.store.send(GetTeamAssignment { principal_id: uid, team_id: cid }).await??;
+    let privacy = aggregate_privacy(&state, cid, uid).await?;
+
+    // Reports may be stored with an earlier timestamp than the one they were made at when the team
+    // jitters or coarsens its timestamps, so the range is aligned with that rather than splitting a period.
+    let from = from.map(|from| privacy.earliest_timestamp(from));
+    let to = to.map(|to| privacy.coarsen(to));
+
+    let rollups: Vec<ReportRollup> = state.store.send(GetReportRollups {
+        team: cid,
+        metric: query.metric.clone(),
+        after: from,
+    }).await??.into_iter().filter(|rollup| to.map(|to| rollup.day < to).unwrap_or(true)).collect();
+
+    let reports: Vec<Report> = state.store.send(GetReports {
+        team: cid,
+        metric: query.metric.clone(),
+        after: from,
+        ..Default::default()
+    }).await??.items.into_iter().filter(|report| to.map(|to| report.timestamp < to).unwrap_or(true)).collect();
+
+    // The budget is spent before the summary is built, so that a query which exceeds it never sees any data.
+    // Every metric and bucket gets its own noise, and one person may contribute to all of them, so each is paid for.
+    let mut response = web::HttpResponse::Ok();
+    if let Some(dp) = &privacy.differential_privacy {
+        let budget = state.store.send(SpendPrivacyBudget {
+            team_id: cid,
+            epsilon: dp.epsilon * ReportSummary::cells(&rollups, &reports, query.bucket) as f64,
+            budget: dp.budget,
+            since: Some(dp.window_start(Utc::now())),
+        }).await??;
+
+        response.header("X-Privacy-Budget-Remaining", budget.remaining.to_string());
+    }
+
+    let summaries = ReportSummary::summarize(&rollups, &reports, query.bucket, privacy.min_group_size(), privacy.differential_privacy.as_ref());
+
+    Ok(response.json(summaries.into_iter().map(|summary| summary.into()).collect::<Vec<ReportSummaryV1>>()))
+}
+
+fn parse_time(time: &Option<String>) -> Result<Option<DateTime<Utc>>, APIError> {
+    match time {
+        Some(time) => DateTime::parse_from_rfc3339(time.as_str())
+            .map(|dt| Some(dt.with_timezone(&Utc)))
+            .or(Err(APIError::new(400, "Bad Request", "The time range you provided could not be parsed. Please provide RFC3339 timestamps and try again."))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::api::test::*;
+    use chrono::prelude::*;
+
+    #[actix_rt::test]
+    async fn get_team_summary_v1() {
+        test_log_init();
+
+        let day = Utc.ymd(2020, 3, 2);
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            },
+            StoreReports {
+                reports: vec![
+                    Report { id: 1, team_id: 7, metric: "happy_sad".into(), timestamp: day.and_hms(9, 0, 0), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                    Report { id: 2, team_id: 7, metric: "happy_sad".into(), timestamp: day.and_hms(10, 0, 0), value: -1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                    Report { id: 3, team_id: 7, metric: "happy_sad".into(), timestamp: day.succ().and_hms(9, 0, 0), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                    Report { id: 4, team_id: 7, metric: "workload".into(), timestamp: day.and_hms(9, 0, 0), value: 3.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                    Report { id: 5, team_id: 7, metric: "happy_sad".into(), timestamp: Utc.ymd(2020, 4, 1).and_hms(9, 0, 0), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                ]
+            }
+        ]);
+
+        let content: Vec<ReportSummaryV1> = test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/summary?metric=happy_sad&from=2020-03-01T00:00:00Z&to=2020-03-31T00:00:00Z&bucket=week" => OK with content | state = state);
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[0].metric, "happy_sad");
+        assert_eq!(content[0].start, "2020-03-02");
+        assert_eq!(content[0].count, 3);
+        assert_eq!(content[0].median, Some(1.0));
+        assert_eq!(content[0].min, Some(-1.0));
+        assert_eq!(content[0].max, Some(1.0));
+        assert!(content[0].noise.is_none());
+
+        let content: Vec<ReportSummaryV1> = test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/summary" => OK with content | state = state);
+        assert_eq!(content.len(), 4);
+        assert_eq!(content[3].metric, "workload");
+
+        test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/summary?bucket=year" => BAD_REQUEST | state = state);
+        test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/summary?from=yesterday" => BAD_REQUEST | state = state);
+    }
+
+    #[actix_rt::test]
+    async fn get_team_summary_v1_min_group_size() {
+        test_log_init();
+
+        let day = Utc.ymd(2020, 3, 2);
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                privacy: TeamPrivacy { min_group_size: Some(2), ..Default::default() },
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            },
+            StoreReports {
+                reports: vec![
+                    Report { id: 1, team_id: 7, metric: "happy_sad".into(), timestamp: day.and_hms(9, 0, 0), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                    Report { id: 2, team_id: 7, metric: "happy_sad".into(), timestamp: day.and_hms(10, 0, 0), value: -1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                    Report { id: 3, team_id: 7, metric: "happy_sad".into(), timestamp: day.succ().and_hms(9, 0, 0), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                ]
+            }
+        ]);
+
+        test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/summary" => FORBIDDEN | state = state);
+
+        state.store.send(StoreTeamAssignment { team_id: 7, principal_id: 1, role: Role::Member, ..Default::default() })
+            .await.expect("the actor should run").expect("the assignment should be stored");
+
+        let content: Vec<ReportSummaryV1> = test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/summary" => OK with content | state = state);
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[0].start, "2020-03-02");
+        assert_eq!(content[0].count, 3);
+    }
+
+    #[actix_rt::test]
+    async fn get_team_summary_v1_differential_privacy() {
+        test_log_init();
+
+        let day = Utc.ymd(2020, 3, 2);
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                privacy: TeamPrivacy {
+                    differential_privacy: Some(DifferentialPrivacy { epsilon: 50.0, budget: 100.0, ..Default::default() }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            },
+            StoreReports {
+                reports: vec![
+                    Report { id: 1, team_id: 7, metric: "happy_sad".into(), timestamp: day.and_hms(9, 0, 0), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                    Report { id: 2, team_id: 7, metric: "happy_sad".into(), timestamp: day.and_hms(10, 0, 0), value: -1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                    Report { id: 3, team_id: 7, metric: "workload".into(), timestamp: day.and_hms(10, 0, 0), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                ]
+            }
+        ]);
+
+        let mut response = test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/summary" => OK | state = state);
+        assert_eq!(response.headers().get("X-Privacy-Budget-Remaining").expect("a remaining budget header"), "0", "each metric's bucket should cost a separate epsilon");
+        let content: Vec<ReportSummaryV1> = get_content(&mut response).await;
+        assert_eq!(content.len(), 2);
+        let noise = content[0].noise.as_ref().expect("the summary should state that noise was added");
+        assert_eq!((noise.mechanism.as_str(), noise.epsilon), ("laplace", 50.0));
+        assert!(content[0].min.is_none() && content[0].max.is_none() && content[0].median.is_none());
+
+        test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/summary" => TOO_MANY_REQUESTS | state = state);
+        test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/history" => FORBIDDEN | state = state);
+    }
+}
@@ -1,21 +1,36 @@
 
-use actix_web::web;
-use super::{AuthToken, APIError, ensure_user_team};
+use actix_web::{web, HttpRequest};
+use super::{AuthToken, APIError, ensure_user_team, idempotency_key};
+use crate::models::*;
+use chrono::prelude::*;
+use std::future::Future;
 
 mod new_report;
+mod new_response;
 mod get_reports;
 mod get_report;
+mod get_history;
+mod get_summary;
 mod remove_report;
+mod retract_report;
+mod amend_report;
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(get_reports::get_reports_v1)
         .service(get_reports::get_team_reports_v1)
+        .service(get_history::get_history_v1)
+        .service(get_history::get_team_history_v1)
+        .service(get_summary::get_team_summary_v1)
         .service(get_report::get_report_v1)
         .service(get_report::get_team_report_v1)
         .service(new_report::new_report_v1)
+        .service(new_report::new_bulk_reports_v1)
         .service(new_report::new_team_report_v1)
+        .service(new_response::new_team_response_v1)
         .service(remove_report::remove_report_v1)
-        .service(remove_report::remove_team_report_v1);
+        .service(remove_report::remove_team_report_v1)
+        .service(retract_report::retract_reports_v1)
+        .service(amend_report::amend_reports_v1);
 }
 
 #[derive(Deserialize, Serialize)]
@@ -34,8 +49,160 @@ struct TeamIdFilter {
     id: String,
 }
 
+/// The largest number of reports which may be requested in a single page.
+const MAX_PAGE_SIZE: usize = 1000;
+
 #[derive(Deserialize)]
 pub struct QueryFilter {
     metric: Option<String>,
     after: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryFilter {
+    metric: Option<String>,
+    after: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SummaryFilter {
+    metric: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    #[serde(default)]
+    bucket: SummaryBucket,
+}
+
+impl QueryFilter {
+    fn limit(&self) -> Result<Option<usize>, APIError> {
+        match self.limit {
+            Some(limit) if limit == 0 || limit > MAX_PAGE_SIZE => Err(APIError::new(400, "Bad Request", "The limit you provided is not valid. Please request between 1 and 1000 reports per page.")),
+            limit => Ok(limit)
+        }
+    }
+}
+
+/// Gets the privacy settings of a team whose reports are being aggregated, ensuring that it
+/// has enough members for none of them to be singled out.
+async fn aggregate_privacy(state: &GlobalState, team_id: u128, principal_id: u128) -> Result<TeamPrivacy, APIError> {
+    let team = state.store.send(GetTeam { id: team_id, principal_id }).await??;
+
+    let min_group_size = team.privacy.min_group_size();
+    if min_group_size > 1 {
+        let members = state.store.send(GetTeamAssignments { team_id }).await??;
+        if (members.len() as u64) < min_group_size {
+            return Err(APIError::new(403, "Forbidden", "This team has fewer members than its minimum group size, so its reports cannot be shown without identifying the people who made them."));
+        }
+    }
+
+    Ok(team.privacy)
+}
+
+/// Ensures that a team has opted in to its members retrieving its individual reports, returning
+/// its privacy settings.
+async fn require_raw_reports(state: &GlobalState, team_id: u128, principal_id: u128) -> Result<TeamPrivacy, APIError> {
+    let team = state.store.send(GetTeam { id: team_id, principal_id }).await??;
+
+    if !team.privacy.raw_reports {
+        return Err(APIError::new(403, "Forbidden", "This team does not allow its individual reports to be retrieved. Please use the team's report summary instead."));
+    }
+
+    Ok(team.privacy)
+}
+
+/// Parses the time after which reports are requested, moving it back by the team's jitter and
+/// to the start of the hour, day or week it falls in when the team coarsens its timestamps, so
+/// that the reports made after it are still included.
+fn parse_after(after: &Option<String>, privacy: &TeamPrivacy) -> Option<DateTime<Utc>> {
+    after.as_ref()
+        .and_then(|after| DateTime::parse_from_rfc3339(after.as_str()).ok())
+        .map(|dt| privacy.earliest_timestamp(dt.with_timezone(&Utc)))
+}
+
+/// Gets the teams which the caller's receipt might cover, which are those they are still a member
+/// of since reports are only ever copied to their submitter's teams.
+async fn receipt_teams(state: &GlobalState, token: &AuthToken) -> Result<Vec<Team>, APIError> {
+    let uid = parse_uuid!(token.oid, auth token oid);
+
+    ensure_user_team(state, token).await?;
+
+    match state.store.send(GetTeams { principal_id: uid }).await? {
+        Err(err) if err.code == 404 => Ok(vec![]),
+        other => other,
+    }
+}
+
+/// Makes a submission at most once for each `Idempotency-Key` its caller provides, replaying the
+/// response to the first request when it is retried rather than storing the reports again.
+///
+/// A key may only be reused for the same request, and a retry which arrives while the first
+/// request is still being handled is rejected rather than waiting for it.
+async fn idempotent<R, F>(req: &HttpRequest, state: &GlobalState, principal_id: u128, request: &R, submission: F) -> Result<web::HttpResponse, APIError>
+where
+    R: serde::Serialize,
+    F: Future<Output = Result<IdempotentResponse, APIError>>
+{
+    let key = match idempotency_key(req)? {
+        Some(key) => IdempotencyKey::new(principal_id, req.path(), &key),
+        None => return submission.await.map(|response| response.into_response(false)),
+    };
+
+    let request_hash = key.request_hash(&serde_json::to_vec(request).unwrap_or_default());
+    let existing = state.store.send(ClaimIdempotencyKey {
+        key_hash: key.hash().into(),
+        request_hash: request_hash.clone(),
+        created_at: None,
+    }).await??;
+
+    if let Some(existing) = existing {
+        if existing.request_hash != request_hash {
+            return Err(APIError::new(422, "Unprocessable Entity", "The Idempotency-Key you provided has already been used for a different request. Please use a new key for each request."));
+        }
+
+        let response = existing.response
+            .ok_or_else(|| APIError::new(409, "Conflict", "Another request with the same Idempotency-Key is still being processed. Please wait for it to complete and try again."))?;
+
+        return key.open(&response)
+            .map(|response| response.into_response(true))
+            .ok_or_else(|| APIError::new(500, "Internal Server Error", "We ran into a problem, this has been reported and will be looked at."));
+    }
+
+    match submission.await {
+        Ok(response) => {
+            // The reports have already been stored, so failing to remember the response leaves the
+            // key claimed (and retries rejected) until it expires, rather than storing them twice.
+            let completed = match key.seal(&response) {
+                Ok(sealed) => state.store.send(CompleteIdempotencyKey { key_hash: key.hash().into(), response: sealed })
+                    .await.map_err(APIError::from).and_then(|result| result),
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = completed {
+                error!("Unable to record the response to an idempotent request: {}", err);
+            }
+
+            Ok(response.into_response(false))
+        },
+        Err(err) => {
+            if let Err(release_err) = state.store.send(ReleaseIdempotencyKey { key_hash: key.hash().into() }).await.map_err(APIError::from).and_then(|result| result) {
+                warn!("Unable to release the Idempotency-Key of a failed request: {}", release_err);
+            }
+
+            Err(err)
+        }
+    }
+}
+
+/// Renders a page of reports as a JSON list, exposing the cursor for the next
+/// page of results in the `X-Next-Cursor` header.
+fn reports_page_response(page: Page<Report>) -> web::HttpResponse {
+    let mut response = web::HttpResponse::Ok();
+
+    if let Some(cursor) = page.next_cursor {
+        response.header("X-Next-Cursor", cursor);
+    }
+
+    response.json(page.items.into_iter().map(|report| report.into()).collect::<Vec<ReportV1>>())
 }
@@ -1,76 +1,192 @@
-use actix_web::{post, web};
-use super::{AuthToken, APIError, ensure_user_team};
+use actix_web::{http::StatusCode, post, web, HttpRequest};
+use super::{AuthToken, APIError, ensure_user_team, idempotent};
 use crate::models::*;
 use super::TeamFilter;
 use chrono::prelude::*;
+use std::collections::BTreeMap;
 
 
 #[post("/api/v1/reports")]
 async fn new_report_v1(
-    (new_report, state, token): (web::Json<ReportV1>, web::Data<GlobalState>, AuthToken),
-) -> Result<web::Json<Vec<ReportV1>>, APIError> {
+    (new_report, state, token, req): (web::Json<ReportV1>, web::Data<GlobalState>, AuthToken, HttpRequest),
+) -> Result<web::HttpResponse, APIError> {
     require_role!(token, "Administrator", "User");
     require_scope!(token, "Reports.Write");
-    
-    let report: Report = new_report.into_inner().into();
+
     let uid = parse_uuid!(token.oid, auth token oid);
 
-    ensure_user_team(&state, &token).await?;
+    idempotent(&req, &state, uid, &*new_report, store_reports(&state, &token, uid, std::slice::from_ref(&*new_report))).await
+}
+
+#[post("/api/v1/reports/bulk")]
+async fn new_bulk_reports_v1(
+    (new_reports, state, token, req): (web::Json<Vec<ReportV1>>, web::Data<GlobalState>, AuthToken, HttpRequest),
+) -> Result<web::HttpResponse, APIError> {
+    require_role!(token, "Administrator", "User");
+    require_scope!(token, "Reports.Write");
+
+    let uid = parse_uuid!(token.oid, auth token oid);
+
+    if new_reports.is_empty() {
+        return Err(APIError::new(400, "Bad Request", "You did not provide any reports. Please provide at least one and try again."));
+    }
+
+    if new_reports.len() > MAX_BULK_REPORTS {
+        return Err(APIError::new(413, "Payload Too Large", &format!("There are too many reports to upload at once. Please upload at most {} reports at a time and try again.", MAX_BULK_REPORTS)));
+    }
+
+    idempotent(&req, &state, uid, &*new_reports, store_reports(&state, &token, uid, &new_reports)).await
+}
+
+/// Stores a copy of each report in every team its submitter is a member of, issuing a receipt
+/// for each report which covers all of its copies.
+///
+/// Every report is checked before any of them are stored, so a report which isn't valid for one
+/// of the submitter's teams (or was made outside of the backfill window) rejects the whole upload.
+async fn store_reports(state: &GlobalState, token: &AuthToken, uid: u128, submitted: &[ReportV1]) -> Result<IdempotentResponse, APIError> {
+    ensure_user_team(state, token).await?;
 
     let teams = state.store.send(GetTeams { principal_id: uid }).await??;
 
-    let id = new_id();
-    let timestamp = Utc::now();
-
-    let mut reports: Vec<ReportV1> = Vec::new();
-    for team in teams {
-        match state.store.send(GetTeamAssignment {
-            principal_id: uid,
-            team_id: team.team_id
-        }).await? {
-            Ok(role) if role.role == Role::Manager || role.role == Role::Member => {
-                let report = state.store.send(StoreReport {
-                    id: id,
-                    team: team.team_id,
-                    metric: report.metric.clone(),
-                    timestamp: Some(timestamp),
-                    value: report.value,
-                }).await?.map(|report| report.clone().into())?;
-                reports.push(report);
-            },
+    let roles = futures::future::join_all(teams.iter().map(|team| state.store.send(GetTeamAssignment {
+        principal_id: uid,
+        team_id: team.team_id
+    }))).await;
+
+    let mut member_teams: Vec<&Team> = Vec::new();
+    for (team, role) in teams.iter().zip(roles) {
+        match role? {
+            Ok(role) if role.role == Role::Manager || role.role == Role::Member => member_teams.push(team),
             _ => {}
-        }        
+        }
+    }
+
+    let now = Utc::now();
+    let mut receipts: BTreeMap<u128, Receipt> = BTreeMap::new();
+    let mut reports: Vec<Report> = Vec::new();
+    let mut staged: Vec<Report> = Vec::new();
+    for report in submitted {
+        let timestamp = report.reported_at(now, state.report_backfill)?;
+        let receipt = Receipt::new(now);
+
+        for team in member_teams.iter() {
+            validate_report(&team.metrics, &report.metric, report.value, &report.options)?;
+
+            // Each team's copy gets its own ID (and its own jitter), so that the managers of
+            // several teams can't match up the copies to narrow down who made them
+            let copy = Report {
+                id: new_id(),
+                team_id: team.team_id,
+                metric: report.metric.clone(),
+                timestamp: team.privacy.report_timestamp(timestamp),
+                value: report.report_value(),
+                response_id: None,
+                receipt_hash: Some(receipt.hash_for(team.team_id)),
+                options: report.report_options(),
+                staged_at: team.privacy.delayed_publication.as_ref().map(|_| now),
+            };
+
+            receipts.insert(copy.id, receipt);
+            if team.privacy.delayed_publication.is_some() {
+                staged.push(copy);
+            } else {
+                reports.push(copy);
+            }
+        }
+    }
+
+    let mut batch = if reports.is_empty() {
+        ReportBatch::default()
+    } else {
+        state.store.send(StoreReports { reports }).await??
+    };
+
+    if !staged.is_empty() {
+        match state.store.send(StageReports { reports: staged.clone() }).await? {
+            Ok(staged) => {
+                batch.stored.extend(staged.stored);
+                batch.failed.extend(staged.failed);
+            },
+            Err(error) => batch.failed.extend(staged.into_iter().map(|report| ReportFailure {
+                report,
+                error: APIError::new(error.code, &error.error, &error.message),
+            })),
+        }
     }
 
-    Ok(web::Json(reports))
+    let with_receipts = |stored: Vec<Report>| -> Vec<ReportV1> {
+        stored.into_iter().map(|report| {
+            let receipt = receipts.get(&report.id).map(|receipt| receipt.to_string());
+            ReportV1 { receipt, ..report.into() }
+        }).collect()
+    };
+
+    if batch.failed.is_empty() {
+        IdempotentResponse::new(StatusCode::OK, None, &with_receipts(batch.stored))
+    } else {
+        let reports = with_receipts(std::mem::take(&mut batch.stored));
+        IdempotentResponse::new(StatusCode::MULTI_STATUS, None, &ReportBatchV1 { reports, ..ReportBatchV1::from(batch) })
+    }
 }
 
 #[post("/api/v1/team/{team}/reports")]
 async fn new_team_report_v1(
-    (new_report, info, state, token): (web::Json<ReportV1>, web::Path<TeamFilter>, web::Data<GlobalState>, AuthToken),
-) -> Result<ReportV1, APIError> {
+    (new_report, info, state, token, req): (web::Json<ReportV1>, web::Path<TeamFilter>, web::Data<GlobalState>, AuthToken, HttpRequest),
+) -> Result<web::HttpResponse, APIError> {
     require_role!(token, "Administrator", "User");
     require_scope!(token, "Reports.Write");
-    
-    let report: Report = new_report.into_inner().into();
+
     let cid = parse_uuid!(info.team, team ID);
     let uid = parse_uuid!(token.oid, auth token oid);
-        
+
+    idempotent(&req, &state, uid, &*new_report, store_team_report(&req, &state, &token, cid, uid, &new_report)).await
+}
+
+/// Stores a report in a single team, which the submitter must be a member of.
+async fn store_team_report(req: &HttpRequest, state: &GlobalState, token: &AuthToken, cid: u128, uid: u128, report: &ReportV1) -> Result<IdempotentResponse, APIError> {
     if cid == uid {
-        ensure_user_team(&state, &token).await?;
+        ensure_user_team(state, token).await?;
     }
 
     let role = state.store.send(GetTeamAssignment { principal_id: uid, team_id: cid }).await??;
 
     match role.role {
         Role::Manager | Role::Member => {
-            state.store.send(StoreReport {
+            let team = state.store.send(GetTeam { id: cid, principal_id: uid }).await??;
+            validate_report(&team.metrics, &report.metric, report.value, &report.options)?;
+
+            let now = Utc::now();
+            let timestamp = report.reported_at(now, state.report_backfill)?;
+            let receipt = Receipt::new(now);
+            let reports = vec![Report {
                 id: new_id(),
-                team: cid,
+                team_id: cid,
                 metric: report.metric.clone(),
-                timestamp: Some(Utc::now()),
-                value: report.value,
-            }).await?.map(|report| report.clone().into())
+                timestamp: team.privacy.report_timestamp(timestamp),
+                value: report.report_value(),
+                response_id: None,
+                receipt_hash: Some(receipt.hash_for(cid)),
+                options: report.report_options(),
+                staged_at: team.privacy.delayed_publication.as_ref().map(|_| now),
+            }];
+
+            let batch = if team.privacy.delayed_publication.is_some() {
+                state.store.send(StageReports { reports }).await??
+            } else {
+                state.store.send(StoreReports { reports }).await??
+            };
+
+            if let Some(failure) = batch.failed.into_iter().next() {
+                return Err(failure.error);
+            }
+
+            let report: ReportV1 = with_receipt(batch.stored.into_iter().map(|report| report.into()).collect(), &receipt).into_iter().next()
+                .ok_or_else(|| APIError::new(500, "Internal Server Error", "We ran into a problem, this has been reported and will be looked at."))?;
+
+            let location = req.url_for("get_team_report_v1", &[format!("{:0>32x}", cid), report.id.clone().unwrap_or_default()])
+                .map_err(|_| APIError::new(500, "Internal Server Error", "We ran into a problem, this has been reported and will be looked at."))?;
+
+            IdempotentResponse::new(StatusCode::CREATED, Some(location.into_string()), &report)
         },
         _ => Err(APIError::new(403, "Forbidden", "You do not have permission to add an report to this team."))
     }
@@ -80,6 +196,7 @@ async fn new_team_report_v1(
 mod tests {
     use crate::models::*;
     use crate::api::test::*;
+    use chrono::Timelike;
 
     #[actix_rt::test]
     async fn new_report_v1() {
@@ -96,6 +213,7 @@ mod tests {
                 team_id: 7,
                 principal_id: 0,
                 role: Role::Manager,
+                ..Default::default()
             }
         ]);
 
@@ -105,6 +223,9 @@ mod tests {
             timestamp: None,
             metric: "test".to_string(),
             value: 2.5,
+            response: None,
+            receipt: None,
+            options: vec![],
         } => OK with content | state = state);
 
         assert_eq!(content.len(), 2);
@@ -116,12 +237,301 @@ mod tests {
             assert_eq!(report.value, 2.5);
 
             state.store.send(GetReport {
-                team: 0,
+                team: u128::from_str_radix(report.team.clone().unwrap().as_str(), 16).unwrap(),
                 id: u128::from_str_radix(report.id.clone().unwrap().as_str(), 16).unwrap(),
             }).await.expect("the actor should have run").expect("The report should exist in the store");
         }
     }
 
+    #[actix_rt::test]
+    async fn new_report_v1_unlinkable() {
+        test_log_init();
+
+        let privacy = TeamPrivacy { timestamp_jitter_minutes: Some(60), ..Default::default() };
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 0,
+                principal_id: 0,
+                name: "My Team".into(),
+                privacy: privacy.clone(),
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 0,
+                principal_id: 0,
+                role: Role::Manager,
+                ..Default::default()
+            },
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                privacy: privacy.clone(),
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let content: Vec<ReportV1> = test_request!(POST "/api/v1/reports", ReportV1 {
+            id: None,
+            team: None,
+            timestamp: None,
+            metric: "test".to_string(),
+            value: 2.5,
+            response: None,
+            receipt: None,
+            options: vec![],
+        } => OK with content | state = state);
+        assert_eq!(content.len(), 2);
+
+        let mut copies = vec![];
+        for team in [0, 7] {
+            let reports = state.store.send(GetReports { team, ..Default::default() })
+                .await.expect("the actor should run").expect("the reports should be listed").items;
+            assert_eq!(reports.len(), 1);
+            copies.extend(reports);
+        }
+
+        assert_ne!(copies[0].id, copies[1].id, "each team's copy should have its own ID");
+        assert_ne!(copies[0].timestamp, copies[1].timestamp, "each team's copy should have its own jittered timestamp");
+        assert_eq!(copies[0].response_id, None);
+        assert_eq!(copies[1].response_id, None);
+    }
+
+    #[actix_rt::test]
+    async fn new_report_v1_delayed() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                privacy: TeamPrivacy { delayed_publication: Some(DelayedPublication::default()), ..Default::default() },
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let report = || ReportV1 { id: None, team: None, timestamp: None, metric: "test".into(), value: 2.5, response: None, receipt: None, options: vec![] };
+
+        let content: Vec<ReportV1> = test_request!(POST "/api/v1/reports", report() => OK with content | state = state);
+        assert_eq!(content.len(), 2);
+
+        let content: ReportV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", report() => CREATED with content | state = state);
+        state.store.send(GetReport {
+            team: 7,
+            id: u128::from_str_radix(content.id.unwrap().as_str(), 16).unwrap(),
+        }).await.expect("the actor should have run").expect_err("a staged report should not be readable until it is published");
+
+        let published = state.store.send(PublishStagedReports { team_id: 7, before: None, min_reports: 1 })
+            .await.expect("the actor should have run").expect("the staged reports should be published");
+        assert_eq!(published.len(), 2);
+
+        let published = state.store.send(PublishStagedReports { team_id: 0, before: None, min_reports: 1 })
+            .await.expect("the actor should have run").expect("the staged reports should be checked");
+        assert!(published.is_empty(), "reports for teams which don't delay publication should be stored straight away");
+    }
+
+    #[actix_rt::test]
+    async fn new_report_v1_backdated() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                privacy: TeamPrivacy { raw_reports: true, timestamp_granularity: Some(TimestampGranularity::Hour), ..Default::default() },
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let now = chrono::Utc::now();
+        let report = |timestamp: chrono::DateTime<chrono::Utc>| ReportV1 { id: None, team: None, timestamp: Some(timestamp.to_rfc3339()), metric: "test".into(), value: 2.5, response: None, receipt: None, options: vec![] };
+
+        let made_at = now - chrono::Duration::hours(30);
+        let content: ReportV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", report(made_at) => CREATED with content | state = state);
+        let timestamp = chrono::DateTime::parse_from_rfc3339(&content.timestamp.expect("a timestamp")).expect("a valid timestamp");
+        assert_eq!(timestamp, TimestampGranularity::Hour.truncate(made_at), "the client's timestamp should be kept, but coarsened");
+
+        let content: Vec<ReportV1> = test_request!(POST "/api/v1/reports", report(made_at) => OK with content | state = state);
+        for report in content {
+            let timestamp = chrono::DateTime::parse_from_rfc3339(&report.timestamp.expect("a timestamp")).expect("a valid timestamp");
+            assert!(timestamp.with_timezone(&chrono::Utc) < now - chrono::Duration::hours(29), "every copy should be backdated");
+        }
+
+        test_request!(POST "/api/v1/reports", report(now + chrono::Duration::hours(1)) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", report(now + chrono::Duration::hours(1)) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", report(now - chrono::Duration::hours(DEFAULT_REPORT_BACKFILL_HOURS + 1)) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", serde_json::json!({ "metric": "test", "value": 1.0, "timestamp": "yesterday" }) => BAD_REQUEST | state = state);
+    }
+
+    #[actix_rt::test]
+    async fn new_bulk_reports_v1() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let now = chrono::Utc::now();
+        let report = |hours: i64, value: f32| ReportV1 { id: None, team: None, timestamp: Some((now - chrono::Duration::hours(hours)).to_rfc3339()), metric: "test".into(), value, response: None, receipt: None, options: vec![] };
+
+        let content: Vec<ReportV1> = test_request!(POST "/api/v1/reports/bulk", vec![report(48, 1.0), report(24, 2.0), report(1, 3.0)] => OK with content | state = state);
+        assert_eq!(content.len(), 6, "each report should be copied to both teams");
+        assert_ne!(content[0].receipt, None);
+
+        let receipts: std::collections::BTreeSet<_> = content.iter().map(|r| r.receipt.clone()).collect();
+        assert_eq!(receipts.len(), 3, "each report should have its own receipt, shared by its copies");
+
+        let reports = state.store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        let mut values: Vec<(f32, bool)> = reports.iter().map(|r| (r.value, r.timestamp < now - chrono::Duration::hours(23))).collect();
+        values.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        assert_eq!(values, vec![(1.0, true), (2.0, true), (3.0, false)]);
+
+        test_request!(POST "/api/v1/reports/bulk", Vec::<ReportV1>::new() => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/reports/bulk", (0..=MAX_BULK_REPORTS).map(|_| report(1, 1.0)).collect::<Vec<_>>() => PAYLOAD_TOO_LARGE | state = state);
+
+        // A report outside of the backfill window rejects the whole upload
+        test_request!(POST "/api/v1/reports/bulk", vec![report(1, 4.0), report(DEFAULT_REPORT_BACKFILL_HOURS + 1, 4.0)] => BAD_REQUEST | state = state);
+        let reports = state.store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        assert_eq!(reports.len(), 3);
+    }
+
+    async fn submit_idempotent(state: &GlobalState, path: &str, key: &str, report: &ReportV1) -> actix_web::dev::ServiceResponse {
+        let mut app = get_test_app(state.clone()).await;
+        let req = actix_web::test::TestRequest::with_uri(path)
+            .method(http::Method::POST)
+            .set_json(report)
+            .header("Authorization", auth_token())
+            .header("Idempotency-Key", key)
+            .to_request();
+
+        actix_web::test::call_service(&mut app, req).await
+    }
+
+    #[actix_rt::test]
+    async fn new_report_v1_idempotent() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let report = |value: f32| ReportV1 { id: None, team: None, timestamp: None, metric: "test".into(), value, response: None, receipt: None, options: vec![] };
+
+        let mut response = submit_idempotent(&state, "/api/v1/reports", "retry-me", &report(2.5)).await;
+        assert_status(&mut response, http::StatusCode::OK).await;
+        assert!(response.headers().get("Idempotent-Replayed").is_none());
+        let first: Vec<ReportV1> = get_content(&mut response).await;
+        assert_eq!(first.len(), 2);
+
+        let mut response = submit_idempotent(&state, "/api/v1/reports", "retry-me", &report(2.5)).await;
+        assert_status(&mut response, http::StatusCode::OK).await;
+        assert_eq!(response.headers().get("Idempotent-Replayed").map(|h| h.to_str().unwrap()), Some("true"));
+        let replayed: Vec<ReportV1> = get_content(&mut response).await;
+        assert_eq!(replayed.iter().map(|r| r.id.clone()).collect::<Vec<_>>(), first.iter().map(|r| r.id.clone()).collect::<Vec<_>>());
+        assert_eq!(replayed[0].receipt, first[0].receipt, "the original receipt should be replayed");
+
+        let reports = state.store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        assert_eq!(reports.len(), 1, "a retried request should not store the report again");
+
+        let mut response = submit_idempotent(&state, "/api/v1/reports", "retry-me", &report(1.0)).await;
+        assert_status(&mut response, http::StatusCode::UNPROCESSABLE_ENTITY).await;
+
+        let mut response = submit_idempotent(&state, "/api/v1/reports", "", &report(1.0)).await;
+        assert_status(&mut response, http::StatusCode::BAD_REQUEST).await;
+        let mut response = submit_idempotent(&state, "/api/v1/reports", &"k".repeat(MAX_IDEMPOTENCY_KEY_LENGTH + 1), &report(1.0)).await;
+        assert_status(&mut response, http::StatusCode::BAD_REQUEST).await;
+
+        // The same key may be used for a different route, since keys are scoped to the route they're sent to
+        let mut response = submit_idempotent(&state, "/api/v1/team/00000000000000000000000000000007/reports", "retry-me", &report(1.0)).await;
+        assert_status(&mut response, http::StatusCode::CREATED).await;
+        let location = response.headers().get("Location").map(|h| h.to_str().unwrap().to_string()).expect("a location header");
+
+        let mut response = submit_idempotent(&state, "/api/v1/team/00000000000000000000000000000007/reports", "retry-me", &report(1.0)).await;
+        assert_status(&mut response, http::StatusCode::CREATED).await;
+        assert_eq!(response.headers().get("Location").map(|h| h.to_str().unwrap()), Some(location.as_str()), "the original location should be replayed");
+
+        let reports = state.store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        assert_eq!(reports.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn new_report_v1_idempotent_failure() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                metrics: vec![MetricDefinition { name: "happy_sad".into(), label: "Happiness".into(), kind: MetricKind::Binary, description: String::new() }],
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let report = |value: f32| ReportV1 { id: None, team: None, timestamp: None, metric: "happy_sad".into(), value, response: None, receipt: None, options: vec![] };
+
+        let mut response = submit_idempotent(&state, "/api/v1/team/00000000000000000000000000000007/reports", "retry-me", &report(0.5)).await;
+        assert_status(&mut response, http::StatusCode::BAD_REQUEST).await;
+
+        // A failed request releases its key, so that it may be retried with the same key once it has been fixed
+        let mut response = submit_idempotent(&state, "/api/v1/team/00000000000000000000000000000007/reports", "retry-me", &report(1.0)).await;
+        assert_status(&mut response, http::StatusCode::CREATED).await;
+        assert!(response.headers().get("Idempotent-Replayed").is_none());
+    }
+
     #[actix_rt::test]
     async fn new_team_report_v1() {
         test_log_init();
@@ -137,6 +547,7 @@ mod tests {
                 team_id: 7,
                 principal_id: 0,
                 role: Role::Manager,
+                ..Default::default()
             }
         ]);
 
@@ -146,6 +557,9 @@ mod tests {
             timestamp: None,
             metric: "test".to_string(),
             value: 2.5,
+            response: None,
+            receipt: None,
+            options: vec![],
         } => CREATED with location =~ "/api/v1/team/00000000000000000000000000000007/report/", content | state = state);
 
         assert_ne!(content.id, None);
@@ -158,4 +572,119 @@ mod tests {
             id: u128::from_str_radix(content.id.unwrap().as_str(), 16).unwrap(),
         }).await.expect("the actor should have run").expect("The report should exist in the store");
     }
-}
\ No newline at end of file
+
+    #[actix_rt::test]
+    async fn new_team_report_v1_metrics() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                metrics: vec![
+                    MetricDefinition { name: "happy_sad".into(), label: "Happiness".into(), kind: MetricKind::Binary, description: String::new() },
+                    MetricDefinition { name: "workload".into(), label: "Workload".into(), kind: MetricKind::Scale { min: 1, max: 5 }, description: String::new() },
+                ],
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let report = |metric: &str, value: f32| ReportV1 { id: None, team: None, timestamp: None, metric: metric.into(), value, response: None, receipt: None, options: vec![] };
+
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", report("happy_sad", -1.0) => CREATED | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", report("workload", 4.0) => CREATED | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", report("workload", 4.5) => BAD_REQUEST | state = state);
+
+        let err: crate::api::APIError = test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", report("happy", 1.0) => BAD_REQUEST with content | state = state);
+        assert!(err.message.contains("happy_sad, workload"), "the error should list the allowed metrics: {}", err.message);
+
+        test_request!(POST "/api/v1/reports", report("happy", 1.0) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", serde_json::json!({ "metric": "happy_sad", "value": 1e39 }) => BAD_REQUEST | state = state);
+    }
+
+    #[actix_rt::test]
+    async fn new_team_report_v1_options() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                metrics: vec![
+                    MetricDefinition { name: "mood".into(), label: "Mood".into(), kind: MetricKind::Categorical { options: vec!["calm".into(), "stressed".into()] }, description: String::new() },
+                    MetricDefinition { name: "drains".into(), label: "What is draining you?".into(), kind: MetricKind::MultiSelect { options: vec!["meetings".into(), "on-call".into(), "deadlines".into()] }, description: String::new() },
+                ],
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let content: ReportV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", serde_json::json!({ "metric": "drains", "options": ["on-call", "meetings"] }) => CREATED with content | state = state);
+        assert_eq!(content.value, 2.0, "the value should be the number of options chosen");
+        assert_eq!(content.options, vec!["meetings".to_string(), "on-call".to_string()]);
+
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", serde_json::json!({ "metric": "mood", "options": ["calm"] }) => CREATED | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", serde_json::json!({ "metric": "mood", "options": ["calm", "stressed"] }) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", serde_json::json!({ "metric": "mood", "value": 1.0 }) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", serde_json::json!({ "metric": "drains", "options": ["commute"] }) => BAD_REQUEST | state = state);
+
+        let summaries: Vec<ReportSummaryV1> = test_request!(GET "/api/v1/team/00000000000000000000000000000007/reports/summary?metric=drains" => OK with content | state = state);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].options.get("meetings"), Some(&1));
+        assert_eq!(summaries[0].options.get("on-call"), Some(&1));
+        assert_eq!(summaries[0].options.get("deadlines"), None);
+    }
+
+    #[actix_rt::test]
+    async fn new_team_report_v1_coarsened() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                privacy: TeamPrivacy { raw_reports: true, timestamp_granularity: Some(TimestampGranularity::Hour), ..Default::default() },
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let content: ReportV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", ReportV1 {
+            id: None,
+            team: None,
+            timestamp: None,
+            metric: "test".to_string(),
+            value: 2.5,
+            response: None,
+            receipt: None,
+            options: vec![],
+        } => CREATED with location =~ "/api/v1/team/00000000000000000000000000000007/report/", content | state = state);
+
+        let timestamp = chrono::DateTime::parse_from_rfc3339(&content.timestamp.expect("a timestamp")).expect("a valid timestamp");
+        assert_eq!((timestamp.minute(), timestamp.second(), timestamp.nanosecond()), (0, 0, 0));
+
+        // Reports stored at the start of an hour are still found when filtering from later in it
+        let after = (timestamp + chrono::Duration::minutes(30)).with_timezone(&chrono::Utc).to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let content: Vec<ReportV1> = test_request!(GET &format!("/api/v1/team/00000000000000000000000000000007/reports?after={}", after) => OK with content | state = state);
+        assert_eq!(content.len(), 1);
+    }
+}
@@ -0,0 +1,156 @@
+use actix_web::{post, web};
+use super::{AuthToken, APIError, ensure_user_team};
+use crate::models::*;
+use super::TeamFilter;
+use chrono::prelude::*;
+
+/// The most answers which a single survey response may hold.
+const MAX_RESPONSE_ANSWERS: usize = 50;
+
+#[post("/api/v1/team/{team}/responses")]
+async fn new_team_response_v1(
+    (response, info, state, token): (web::Json<ResponseV1>, web::Path<TeamFilter>, web::Data<GlobalState>, AuthToken),
+) -> Result<ResponseV1, APIError> {
+    require_role!(token, "Administrator", "User");
+    require_scope!(token, "Reports.Write");
+
+    let cid = parse_uuid!(info.team, team ID);
+    let uid = parse_uuid!(token.oid, auth token oid);
+
+    if response.answers.is_empty() || response.answers.len() > MAX_RESPONSE_ANSWERS {
+        return Err(APIError::new(400, "Bad Request", &format!("The response you provided is not valid. Please answer between 1 and {} questions and try again.", MAX_RESPONSE_ANSWERS)));
+    }
+
+    if cid == uid {
+        ensure_user_team(&state, &token).await?;
+    }
+
+    let role = state.store.send(GetTeamAssignment { principal_id: uid, team_id: cid }).await??;
+    if role.role != Role::Manager && role.role != Role::Member {
+        return Err(APIError::new(403, "Forbidden", "You do not have permission to add a response to this team."));
+    }
+
+    let team = state.store.send(GetTeam { id: cid, principal_id: uid }).await??;
+    for (metric, answer) in response.answers.iter() {
+        validate_report(&team.metrics, metric, answer.value(), &answer.options())?;
+    }
+
+    // Every answer shares a new response ID, rather than anything derived from the submitter, so
+    // that the answers can be analysed together without revealing who gave them.
+    let response_id = new_id();
+    let now = Utc::now();
+    let receipt = Receipt::new(now);
+    let timestamp = team.privacy.report_timestamp(now);
+    let reports: Vec<Report> = response.answers.iter().map(|(metric, answer)| Report {
+        id: new_id(),
+        team_id: cid,
+        metric: metric.clone(),
+        timestamp,
+        value: answer.value(),
+        response_id: Some(response_id),
+        receipt_hash: Some(receipt.hash_for(cid)),
+        options: answer.options(),
+        staged_at: team.privacy.delayed_publication.as_ref().map(|_| now),
+    }).collect();
+
+    let batch = if team.privacy.delayed_publication.is_some() {
+        state.store.send(StageReports { reports }).await??
+    } else {
+        state.store.send(StoreReports { reports }).await??
+    };
+
+    if let Some(failure) = batch.failed.into_iter().next() {
+        return Err(failure.error);
+    }
+
+    Ok(ResponseV1 {
+        id: Some(format!("{:0>32x}", response_id)),
+        team: Some(format!("{:0>32x}", cid)),
+        timestamp: Some(timestamp.to_rfc3339()),
+        answers: batch.stored.iter().map(|report| (report.metric.clone(), report.into())).collect(),
+        receipt: Some(receipt.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::api::test::*;
+    use std::collections::BTreeMap;
+
+    #[actix_rt::test]
+    async fn new_team_response_v1() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                metrics: vec![
+                    MetricDefinition { name: "happy_sad".into(), label: "Happiness".into(), kind: MetricKind::Binary, description: String::new() },
+                    MetricDefinition { name: "workload".into(), label: "Workload".into(), kind: MetricKind::Scale { min: 1, max: 5 }, description: String::new() },
+                    MetricDefinition { name: "blockers".into(), label: "Blockers".into(), kind: MetricKind::MultiSelect { options: vec!["meetings".into(), "reviews".into(), "builds".into()] }, description: String::new() },
+                ],
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let response = |answers: &[(&str, f32)]| ResponseV1 {
+            id: None,
+            team: None,
+            timestamp: None,
+            answers: answers.iter().map(|(metric, value)| (metric.to_string(), AnswerV1::Value(*value))).collect::<BTreeMap<_, _>>(),
+            receipt: None,
+        };
+
+        let content: ResponseV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/responses", response(&[("happy_sad", 1.0), ("workload", 4.0)]) => OK with content | state = state);
+        let response_id = content.id.expect("the response should have an ID");
+        assert_eq!(content.answers.len(), 2);
+
+        let reports = state.store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|report| report.response_id.map(|id| format!("{:0>32x}", id)) == Some(response_id.clone())));
+        assert_eq!(reports[0].timestamp, reports[1].timestamp);
+        assert_ne!(reports[0].id, reports[1].id);
+
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/responses", response(&[("happy_sad", 1.0), ("workload", 9.0)]) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/responses", response(&[]) => BAD_REQUEST | state = state);
+
+        let reports = state.store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        assert_eq!(reports.len(), 2, "a rejected response should not store any of its answers");
+
+        let with_options = |options: &[&str]| {
+            let mut response = response(&[("happy_sad", -1.0)]);
+            response.answers.insert("blockers".into(), AnswerV1::Options(options.iter().map(|o| o.to_string()).collect()));
+            response
+        };
+
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/responses", with_options(&[]) => BAD_REQUEST | state = state);
+        test_request!(POST "/api/v1/team/00000000000000000000000000000007/responses", with_options(&["lunch"]) => BAD_REQUEST | state = state);
+
+        let content: ResponseV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/responses", with_options(&["reviews", "meetings"]) => OK with content | state = state);
+        assert_eq!(content.answers.get("blockers"), Some(&AnswerV1::Options(vec!["meetings".into(), "reviews".into()])));
+        assert_eq!(content.answers.get("happy_sad"), Some(&AnswerV1::Value(-1.0)));
+
+        let reports = state.store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        let blockers = reports.iter().find(|report| report.metric == "blockers").expect("the chosen options should be stored");
+        assert_eq!((blockers.value, blockers.options.clone()), (2.0, vec!["meetings".to_string(), "reviews".to_string()]));
+    }
+
+    #[test]
+    fn answers_accept_values_or_options() {
+        let answers: BTreeMap<String, AnswerV1> = serde_json::from_str(r#"{ "workload": 4, "blockers": ["reviews"] }"#).expect("the answers should be parsed");
+        assert_eq!(answers.get("workload"), Some(&AnswerV1::Value(4.0)));
+        assert_eq!(answers.get("blockers"), Some(&AnswerV1::Options(vec!["reviews".into()])));
+    }
+}
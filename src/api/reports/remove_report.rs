@@ -84,6 +84,7 @@ mod tests {
                 team_id: 7,
                 principal_id: 0,
                 role: Role::Manager,
+                ..Default::default()
             },
             StoreReport {
                 id: 1,
@@ -0,0 +1,88 @@
+use actix_web::{post, web};
+use super::{AuthToken, APIError, receipt_teams};
+use crate::models::*;
+use chrono::prelude::*;
+
+#[post("/api/v1/reports/retract")]
+async fn retract_reports_v1(
+    (request, state, token): (web::Json<ReceiptV1>, web::Data<GlobalState>, AuthToken),
+) -> Result<web::Json<Vec<ReportV1>>, APIError> {
+    require_role!(token, "Administrator", "User");
+    require_scope!(token, "Reports.Write");
+
+    let receipt = request.parse(Utc::now())?;
+
+    let mut retracted: Vec<Report> = vec![];
+    for team in receipt_teams(&state, &token).await? {
+        retracted.extend(state.store.send(RetractReports {
+            team_id: team.team_id,
+            receipt_hash: receipt.hash_for(team.team_id),
+        }).await??);
+    }
+
+    if retracted.is_empty() {
+        return Err(APIError::new(404, "Not Found", "No reports could be found for the receipt you provided. They may have already been retracted."));
+    }
+
+    Ok(web::Json(retracted.into_iter().map(|report| report.into()).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::api::test::*;
+
+    #[actix_rt::test]
+    async fn retract_reports_v1() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 7,
+                principal_id: 0,
+                name: "Test Team".into(),
+                privacy: TeamPrivacy { delayed_publication: Some(DelayedPublication::default()), ..Default::default() },
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 7,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
+            }
+        ]);
+
+        let report = || ReportV1 { id: None, team: None, timestamp: None, metric: "test".into(), value: 2.5, response: None, receipt: None, options: vec![] };
+
+        let content: Vec<ReportV1> = test_request!(POST "/api/v1/reports", report() => OK with content | state = state);
+        assert_eq!(content.len(), 2);
+        let receipt = content[0].receipt.clone().expect("a receipt should be returned");
+        assert_eq!(content[1].receipt, Some(receipt.clone()), "every copy should share the submission's receipt");
+
+        let other: ReportV1 = test_request!(POST "/api/v1/team/00000000000000000000000000000007/reports", report() => CREATED with content | state = state);
+
+        let copies = state.store.send(GetReports { team: 0, ..Default::default() })
+            .await.expect("the actor should run").expect("the reports should be listed").items;
+        assert_ne!(copies[0].receipt_hash, None);
+        assert_ne!(copies[0].receipt_hash.as_deref(), Some(receipt.as_str()), "the receipt itself should not be stored");
+
+        let retracted: Vec<ReportV1> = test_request!(POST "/api/v1/reports/retract", ReceiptV1 { receipt: receipt.clone(), metric: None, value: None, options: vec![] } => OK with content | state = state);
+        assert_eq!(retracted.len(), 2, "the copies in every team, including staged ones, should be retracted");
+
+        state.store.send(GetReport {
+            team: 0,
+            id: u128::from_str_radix(content[0].id.clone().unwrap().as_str(), 16).unwrap(),
+        }).await.expect("the actor should run").expect_err("the report should have been retracted");
+
+        let staged = state.store.send(PublishStagedReports { team_id: 7, before: None, min_reports: 1 })
+            .await.expect("the actor should run").expect("the staged reports should be published");
+        assert_eq!(staged.iter().map(|r| r.id).collect::<Vec<_>>(), vec![u128::from_str_radix(other.id.unwrap().as_str(), 16).unwrap()],
+            "reports submitted with a different receipt should be kept");
+
+        test_request!(POST "/api/v1/reports/retract", ReceiptV1 { receipt: receipt.clone(), metric: None, value: None, options: vec![] } => NOT_FOUND | state = state);
+        test_request!(POST "/api/v1/reports/retract", ReceiptV1 { receipt: "not a receipt".into(), metric: None, value: None, options: vec![] } => BAD_REQUEST | state = state);
+
+        let expired = Receipt::new(chrono::Utc::now() - chrono::Duration::minutes(RECEIPT_GRACE_MINUTES + 1));
+        test_request!(POST "/api/v1/reports/retract", ReceiptV1 { receipt: expired.to_string(), metric: None, value: None, options: vec![] } => FORBIDDEN | state = state);
+    }
+}
@@ -34,11 +34,13 @@ mod tests {
                 team_id: 1,
                 principal_id: 0,
                 role: Role::Manager,
+                ..Default::default()
             },
             StoreTeamAssignment {
                 team_id: 1,
                 principal_id: 2,
                 role: Role::Viewer,
+                ..Default::default()
             }
         ]);
 
@@ -4,7 +4,7 @@ mod store_team_assignment;
 mod remove_team_assignment;
 
 use actix_web::web;
-use super::{AuthToken, APIError};
+use super::{AuthToken, APIError, if_match};
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg 
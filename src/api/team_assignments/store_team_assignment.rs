@@ -1,13 +1,13 @@
-use actix_web::{put, web};
-use super::{AuthToken, APIError};
+use actix_web::{put, web, HttpRequest};
+use super::{AuthToken, APIError, if_match};
 use crate::models::*;
 use super::TeamUserFilter;
 
 #[put("/api/v1/team/{team}/user/{user}")]
 async fn store_team_assignment_v1(
-    (info, team, state, token): (web::Path<TeamUserFilter>,
+    (info, team, state, token, req): (web::Path<TeamUserFilter>,
         web::Json<TeamAssignmentV1>,
-        web::Data<GlobalState>, AuthToken),
+        web::Data<GlobalState>, AuthToken, HttpRequest),
 ) -> Result<TeamAssignmentV1, APIError> {
     require_role!(token, "Administrator", "User");
     require_scope!(token, "TeamAssignments.Write");
@@ -16,11 +16,6 @@ async fn store_team_assignment_v1(
     let uid = parse_uuid!(token.oid, auth token oid);
     let tuid = parse_uuid!(info.user, user ID);
     
-    let original_team = state.store.send(GetTeam {
-        id: cid,
-        principal_id: uid
-    }).await??;
-
     if tuid == uid {
         return Err(APIError::new(400, "Bad Request", "You cannot modify your own role assignment. Please request that another team owner performs this task for you."))
     }
@@ -28,27 +23,11 @@ async fn store_team_assignment_v1(
     let role = state.store.send(GetTeamAssignment { team_id: cid, principal_id: uid }).await??;
     match role.role {
         Role::Manager => {
-            match state.store.send(GetTeam {
-                principal_id: tuid,
-                id: cid
-            }).await? {
-                Ok(_) => {},
-                Err(err) if err.code == 404 => {
-                    state.store.send(StoreTeam {
-                        principal_id: tuid,
-                        team_id: cid,
-                        name: original_team.name
-                    }).await??;
-                },
-                Err(err) => {
-                    return Err(err)
-                }
-            }
-
             state.store.send(StoreTeamAssignment {
                 principal_id: tuid,
                 team_id: cid,
                 role: team.role.as_str().into(),
+                etag: if_match(&req),
             }).await?.map(|team| team.clone().into())
         },
         _ => Err(APIError::new(403, "Forbidden", "You do not have permission to view or manage the list of users for this team."))
@@ -68,12 +47,14 @@ mod tests {
             StoreTeam {
                 team_id: 1,
                 principal_id: 0,
-                name: "Test Team".into()
+                name: "Test Team".into(),
+                ..Default::default()
             },
             StoreTeamAssignment {
                 team_id: 1,
                 principal_id: 0,
                 role: Role::Manager,
+                ..Default::default()
             }
         ]);
 
@@ -81,6 +62,7 @@ mod tests {
             team_id: None,
             user_id: None,
             role: "Manager".into(),
+            etag: None,
         } => OK with content | state = state);
 
         assert_eq!(content.team_id, Some("00000000000000000000000000000001".into()));
@@ -102,12 +84,14 @@ mod tests {
             StoreTeam {
                 team_id: 1,
                 principal_id: 0,
-                name: "Test Team".into()
+                name: "Test Team".into(),
+                ..Default::default()
             },
             StoreTeamAssignment {
                 team_id: 1,
                 principal_id: 0,
                 role: Role::Manager,
+                ..Default::default()
             }
         ]);
 
@@ -115,6 +99,56 @@ mod tests {
             team_id: None,
             user_id: None,
             role: "Viewer".into(),
+            etag: None,
         } => BAD_REQUEST | state = state);
     }
+
+    #[actix_rt::test]
+    async fn store_team_assignment_v1_if_match() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam {
+                team_id: 1,
+                principal_id: 0,
+                name: "Test Team".into(),
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 1,
+                principal_id: 0,
+                role: Role::Manager,
+                ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 1,
+                principal_id: 2,
+                role: Role::Viewer,
+                ..Default::default()
+            }
+        ]);
+
+        let response = test_request!(GET "/api/v1/team/00000000000000000000000000000001/user/00000000000000000000000000000002" => OK | state = state);
+        let etag = response.headers().get("ETag").expect("an etag header").to_str().expect("a valid etag").to_string();
+
+        state.store.send(StoreTeamAssignment {
+            team_id: 1,
+            principal_id: 2,
+            role: Role::Member,
+            ..Default::default()
+        }).await.expect("the actor should run").expect("the assignment should be updated");
+
+        let mut app = get_test_app(state.clone()).await;
+        let req = actix_web::test::TestRequest::with_uri("/api/v1/team/00000000000000000000000000000001/user/00000000000000000000000000000002")
+            .method(http::Method::PUT)
+            .set_json(&TeamAssignmentV1 { team_id: None, user_id: None, role: "Manager".into(), etag: None })
+            .header("Authorization", auth_token())
+            .header("If-Match", etag.as_str())
+            .to_request();
+        let mut response = actix_web::test::call_service(&mut app, req).await;
+        assert_status(&mut response, http::StatusCode::PRECONDITION_FAILED).await;
+
+        let assignment = state.store.send(GetTeamAssignment { team_id: 1, principal_id: 2 }).await.expect("the actor should run").expect("the assignment should exist");
+        assert_eq!(assignment.role, Role::Member);
+    }
 }
\ No newline at end of file
@@ -31,6 +31,12 @@ mod tests {
                 principal_id: 0,
                 name: "Test Team".into(),
                 ..Default::default()
+            },
+            StoreTeamAssignment {
+                team_id: 1,
+                principal_id: 0,
+                role: Role::Member,
+                ..Default::default()
             }
         ]);
 
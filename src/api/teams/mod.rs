@@ -5,7 +5,8 @@ mod store_team;
 mod remove_team;
 
 use actix_web::web;
-use super::{AuthToken, APIError};
+use super::{AuthToken, APIError, if_match};
+use crate::models::{AlertRule, MetricDefinition, MetricKind, TeamPrivacy};
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg
@@ -19,4 +20,113 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
 #[derive(Deserialize, Serialize)]
 struct TeamFilter {
     team: String,
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+struct RemoveTeamFilter {
+    #[serde(default)]
+    cascade: bool,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// The most minutes by which a team's report timestamps may be jittered.
+const MAX_TIMESTAMP_JITTER_MINUTES: u32 = 7 * 24 * 60;
+
+/// Ensures that a team's retention window, if one is set, keeps reports for at least a day.
+fn retention_days(days: Option<u32>) -> Result<Option<u32>, APIError> {
+    match days {
+        Some(0) => Err(APIError::new(400, "Bad Request", "The retention period you provided is not valid. Please keep reports for at least one day.")),
+        days => Ok(days),
+    }
+}
+
+/// Ensures that a team's minimum group size, if one is set, is at least one report, that its
+/// timestamp jitter is at most a week and that its delayed publication and differential privacy
+/// settings are usable.
+fn privacy(privacy: TeamPrivacy) -> Result<TeamPrivacy, APIError> {
+    if privacy.min_group_size == Some(0) {
+        return Err(APIError::new(400, "Bad Request", "The minimum group size you provided is not valid. Please provide a size of at least one."));
+    }
+
+    if privacy.timestamp_jitter_minutes.map(|minutes| minutes > MAX_TIMESTAMP_JITTER_MINUTES).unwrap_or_default() {
+        return Err(APIError::new(400, "Bad Request", "The timestamp jitter you provided is not valid. Please provide a jitter of at most one week."));
+    }
+
+    if let Some(schedule) = &privacy.delayed_publication {
+        if schedule.interval_hours == 0 || schedule.min_reports == 0 {
+            return Err(APIError::new(400, "Bad Request", "The delayed publication settings you provided are not valid. Please provide an interval of at least one hour and a batch of at least one report."));
+        }
+    }
+
+    if let Some(dp) = &privacy.differential_privacy {
+        if !(dp.epsilon.is_finite() && dp.epsilon > 0.0 && dp.budget.is_finite() && dp.budget >= dp.epsilon) {
+            return Err(APIError::new(400, "Bad Request", "The differential privacy settings you provided are not valid. Please provide a positive epsilon and a budget of at least that much."));
+        }
+
+        if dp.window_days == 0 || !(dp.value_bound.is_finite() && dp.value_bound > 0.0) {
+            return Err(APIError::new(400, "Bad Request", "The differential privacy settings you provided are not valid. Please provide a window of at least one day and a positive value bound."));
+        }
+
+        if privacy.raw_reports {
+            return Err(APIError::new(400, "Bad Request", "Differential privacy cannot be enabled while the team's individual reports may be retrieved. Please disable raw report access and try again."));
+        }
+    }
+
+    Ok(privacy)
+}
+
+/// Ensures that each of a team's alert rules names a metric, covers at least a day and raises
+/// alerts for at least one condition.
+fn alert_rules(alert_rules: Vec<AlertRule>) -> Result<Vec<AlertRule>, APIError> {
+    for rule in alert_rules.iter() {
+        if rule.metric.is_empty() || rule.window_days == 0 || rule.baseline_days == 0 {
+            return Err(APIError::new(400, "Bad Request", "The alert rules you provided are not valid. Please provide a metric for each rule, along with a window and baseline of at least one day."));
+        }
+
+        if rule.max_drop.is_none() && rule.below.is_none() && rule.above.is_none() {
+            return Err(APIError::new(400, "Bad Request", "The alert rules you provided are not valid. Please provide a maxDrop, below or above condition for each rule."));
+        }
+
+        if [rule.max_drop, rule.below, rule.above].iter().flatten().any(|value| !value.is_finite()) || rule.max_drop.map(|drop| drop <= 0.0).unwrap_or_default() {
+            return Err(APIError::new(400, "Bad Request", "The alert rules you provided are not valid. Please provide finite thresholds and a positive maxDrop."));
+        }
+    }
+
+    Ok(alert_rules)
+}
+
+/// Ensures that each of a team's metrics has a unique name and a label, and that the range of
+/// its scale metrics holds at least two values, and that the team's alert rules only cover them.
+fn metrics(metrics: Vec<MetricDefinition>, alert_rules: &[AlertRule]) -> Result<Vec<MetricDefinition>, APIError> {
+    for (i, metric) in metrics.iter().enumerate() {
+        if metric.name.is_empty() || metric.label.is_empty() {
+            return Err(APIError::new(400, "Bad Request", "The metrics you provided are not valid. Please provide a name and a label for each metric."));
+        }
+
+        if metrics[..i].iter().any(|other| other.name == metric.name) {
+            return Err(APIError::new(400, "Bad Request", &format!("The metrics you provided are not valid. Please provide only one definition of the {} metric.", metric.name)));
+        }
+
+        match &metric.kind {
+            MetricKind::Scale { min, max } if min >= max => {
+                return Err(APIError::new(400, "Bad Request", &format!("The metrics you provided are not valid. Please provide a min below the max of the {} metric.", metric.name)));
+            },
+            MetricKind::Categorical { options } | MetricKind::MultiSelect { options } => {
+                let duplicated = options.iter().enumerate().any(|(i, option)| options[..i].contains(option));
+                if options.is_empty() || duplicated || options.iter().any(|option| option.is_empty()) {
+                    return Err(APIError::new(400, "Bad Request", &format!("The metrics you provided are not valid. Please provide a list of distinct, non-empty options for the {} metric.", metric.name)));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    if !metrics.is_empty() {
+        if let Some(rule) = alert_rules.iter().find(|rule| !metrics.iter().any(|metric| metric.name == rule.metric)) {
+            return Err(APIError::new(400, "Bad Request", &format!("The alert rules you provided are not valid. The {} metric is not one which this team tracks.", rule.metric)));
+        }
+    }
+
+    Ok(metrics)
+}
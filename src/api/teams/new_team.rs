@@ -1,5 +1,5 @@
 use actix_web::{post, web};
-use super::{AuthToken, APIError};
+use super::{AuthToken, APIError, alert_rules, metrics, privacy, retention_days};
 use crate::models::*;
 
 #[post("/api/v1/teams")]
@@ -12,17 +12,27 @@ async fn new_team_v1(
     require_scope!(token, "Teams.Write");
     
     let uid = parse_uuid!(token.oid, auth token oid);
+    let retention_days = retention_days(team.retention_days.flatten())?;
+    let privacy = privacy(team.privacy.clone().unwrap_or_default())?;
+    let alert_rules = alert_rules(team.alert_rules.clone().unwrap_or_default())?;
+    let metrics = metrics(team.metrics.clone().unwrap_or_default(), &alert_rules)?;
         
     let team = state.store.send(StoreTeam {
         principal_id: uid,
         team_id: new_id(),
         name: team.name.clone(),
+        retention_days,
+        privacy,
+        alert_rules,
+        metrics,
+        etag: None,
     }).await??;
 
     state.store.send(StoreTeamAssignment {
         principal_id: uid,
         team_id: team.team_id,
-        role: Role::Manager
+        role: Role::Manager,
+        etag: None,
     }).await??;
     
     Ok(team.into())
@@ -41,10 +51,135 @@ mod tests {
             id: None,
             user_id: None,
             name: "Test Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: None,
+            metrics: None,
+            etag: None,
         } => CREATED with content);
 
         assert_ne!(content.id, None);
         assert_eq!(content.user_id, Some("00000000000000000000000000000000".into()));
         assert_eq!(content.name, "Test Team".to_string());
     }
-}
\ No newline at end of file
+
+    #[actix_rt::test]
+    async fn new_team_v1_invalid_privacy() {
+        test_log_init();
+
+        test_request!(POST "/api/v1/teams", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Test Team".into(),
+            retention_days: None,
+            privacy: Some(TeamPrivacy { min_group_size: Some(0), ..Default::default() }),
+            alert_rules: None,
+            metrics: None,
+            etag: None,
+        } => BAD_REQUEST);
+
+        test_request!(POST "/api/v1/teams", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Test Team".into(),
+            retention_days: None,
+            privacy: Some(TeamPrivacy {
+                raw_reports: true,
+                differential_privacy: Some(Default::default()),
+                ..Default::default()
+            }),
+            alert_rules: None,
+            metrics: None,
+            etag: None,
+        } => BAD_REQUEST);
+
+        test_request!(POST "/api/v1/teams", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Test Team".into(),
+            retention_days: None,
+            privacy: Some(TeamPrivacy {
+                differential_privacy: Some(DifferentialPrivacy { epsilon: 2.0, budget: 1.0, ..Default::default() }),
+                ..Default::default()
+            }),
+            alert_rules: None,
+            metrics: None,
+            etag: None,
+        } => BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn new_team_v1_alert_rules() {
+        test_log_init();
+
+        let rule = AlertRule { metric: "happy_sad".into(), window_days: 7, baseline_days: 28, max_drop: Some(2.0), below: None, above: None };
+
+        let content: TeamV1 = test_request!(POST "/api/v1/teams", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Test Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: Some(vec![rule.clone()]),
+            metrics: None,
+            etag: None,
+        } => CREATED with content);
+        assert_eq!(content.alert_rules, Some(vec![rule.clone()]));
+
+        test_request!(POST "/api/v1/teams", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Test Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: Some(vec![AlertRule { max_drop: None, ..rule.clone() }]),
+            metrics: None,
+            etag: None,
+        } => BAD_REQUEST);
+
+        test_request!(POST "/api/v1/teams", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Test Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: Some(vec![AlertRule { window_days: 0, ..rule }]),
+            metrics: None,
+            etag: None,
+        } => BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn new_team_v1_metrics() {
+        test_log_init();
+
+        let happy_sad = MetricDefinition { name: "happy_sad".into(), label: "Happiness".into(), kind: MetricKind::Binary, description: "How are you feeling?".into() };
+        let workload = MetricDefinition { name: "workload".into(), label: "Workload".into(), kind: MetricKind::Scale { min: 1, max: 5 }, description: String::new() };
+        let team = |metrics: Vec<MetricDefinition>, alert_rules: Vec<AlertRule>| TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Test Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: Some(alert_rules),
+            metrics: Some(metrics),
+            etag: None,
+        };
+
+        let content: TeamV1 = test_request!(POST "/api/v1/teams", team(vec![happy_sad.clone(), workload.clone()], vec![]) => CREATED with content);
+        assert_eq!(content.metrics, Some(vec![happy_sad.clone(), workload.clone()]));
+
+        test_request!(POST "/api/v1/teams", team(vec![happy_sad.clone(), happy_sad.clone()], vec![]) => BAD_REQUEST);
+        test_request!(POST "/api/v1/teams", team(vec![MetricDefinition { kind: MetricKind::Scale { min: 5, max: 5 }, ..workload }], vec![]) => BAD_REQUEST);
+        test_request!(POST "/api/v1/teams", team(vec![MetricDefinition { label: String::new(), ..happy_sad.clone() }], vec![]) => BAD_REQUEST);
+
+        let drains = |options: Vec<&str>| MetricDefinition { name: "drains".into(), label: "What is draining you?".into(), kind: MetricKind::MultiSelect { options: options.into_iter().map(|o| o.into()).collect() }, description: String::new() };
+        test_request!(POST "/api/v1/teams", team(vec![drains(vec!["meetings", "on-call"])], vec![]) => CREATED);
+        test_request!(POST "/api/v1/teams", team(vec![drains(vec![])], vec![]) => BAD_REQUEST);
+        test_request!(POST "/api/v1/teams", team(vec![drains(vec!["meetings", "meetings"])], vec![]) => BAD_REQUEST);
+        test_request!(POST "/api/v1/teams", team(vec![drains(vec!["meetings", ""])], vec![]) => BAD_REQUEST);
+        test_request!(POST "/api/v1/teams", team(vec![happy_sad], vec![
+            AlertRule { metric: "burnout".into(), window_days: 7, baseline_days: 28, max_drop: Some(2.0), below: None, above: None }
+        ]) => BAD_REQUEST);
+    }
+}
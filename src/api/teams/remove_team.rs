@@ -1,11 +1,11 @@
 use actix_web::{delete, web};
 use super::{AuthToken, APIError};
 use crate::models::*;
-use super::TeamFilter;
+use super::{RemoveTeamFilter, TeamFilter};
 
 #[delete("/api/v1/team/{team}")]
 async fn remove_team_v1(
-    (info, state, token): (web::Path<TeamFilter>, web::Data<GlobalState>, AuthToken),
+    (info, query, state, token): (web::Path<TeamFilter>, web::Query<RemoveTeamFilter>, web::Data<GlobalState>, AuthToken),
 ) -> Result<web::HttpResponse, APIError> {
     require_role!(token, "Administrator", "User");
     require_scope!(token, "Teams.Write");
@@ -13,9 +13,30 @@ async fn remove_team_v1(
     let cid = parse_uuid!(info.team, team ID);
     let uid = parse_uuid!(token.oid, auth token oid);
 
-    state.store.send(RemoveTeam { id: cid, principal_id: uid }).await??;
+    if query.cascade {
+        let role = state.store.send(GetTeamAssignment { team_id: cid, principal_id: uid }).await??;
+        if role.role != Role::Manager {
+            return Err(APIError::new(403, "Forbidden", "Only the managers of a team may delete it for all of its members."));
+        }
 
-    state.store.send(RemoveTeamAssignment { team_id: cid, principal_id: uid }).await??;
+        let deletion = state.store.send(DeleteTeam { team_id: cid, dry_run: query.dry_run }).await??;
+
+        return Ok(web::HttpResponse::Ok().json(TeamDeletionV1::new(deletion, query.dry_run)));
+    }
+
+    if query.dry_run {
+        return Err(APIError::new(400, "Bad Request", "A dry run can only be requested when deleting a team for all of its members. Please add cascade=true to your request and try again."));
+    }
+
+    // A missing assignment means that an earlier attempt to leave the team stopped part way
+    // through, so the team record is still cleaned up below if nobody else is a member.
+    match state.store.send(RemoveTeamAssignment { team_id: cid, principal_id: uid }).await? {
+        Err(err) if err.code == 404 => {},
+        other => other?,
+    }
+
+    // The team itself is only removed once its last member has left
+    state.store.send(RemoveTeam { id: cid }).await??;
 
     Ok(web::HttpResponse::NoContent().finish())
 }
@@ -40,6 +61,7 @@ mod tests {
                 team_id: 1,
                 principal_id: 0,
                 role: Role::Manager,
+                ..Default::default()
             }
         ]);
 
@@ -55,4 +77,99 @@ mod tests {
             principal_id: 1
         }).await.expect("the actor should have run").expect_err("The role assignment should not exist anymore");
     }
-}
\ No newline at end of file
+
+    #[actix_rt::test]
+    async fn remove_team_v1_retry() {
+        test_log_init();
+
+        // The assignment was removed by an earlier attempt which failed before removing the team
+        test_state!(state = [
+            StoreTeam {
+                team_id: 1,
+                principal_id: 0,
+                name: "Test Team".into(),
+                ..Default::default()
+            }
+        ]);
+
+        test_request!(DELETE "/api/v1/team/00000000000000000000000000000001" => NO_CONTENT | state = state);
+
+        state.store.send(StoreTeamAssignment {
+            team_id: 1,
+            principal_id: 0,
+            role: Role::Manager,
+            ..Default::default()
+        }).await.expect("the actor should have run").expect("the role assignment should be stored");
+
+        state.store.send(GetTeam {
+            id: 1,
+            principal_id: 0
+        }).await.expect("the actor should have run").expect_err("The team should not exist anymore");
+    }
+
+    async fn cascade_state() -> GlobalState {
+        test_state!(state = [
+            StoreTeam { team_id: 1, principal_id: 0, name: "Test Team".into(), ..Default::default() },
+            StoreTeam { team_id: 1, principal_id: 2, name: "Test Team".into(), ..Default::default() },
+            StoreTeam { team_id: 3, principal_id: 2, name: "Other Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 1, principal_id: 0, role: Role::Manager, ..Default::default() },
+            StoreTeamAssignment { team_id: 1, principal_id: 2, role: Role::Member, ..Default::default() },
+            StoreTeamAssignment { team_id: 3, principal_id: 2, role: Role::Manager, ..Default::default() },
+            StoreReport { id: 1, team: 1, metric: "happy_sad".into(), value: 1.0, ..Default::default() },
+            StoreReport { id: 2, team: 1, metric: "happy_sad".into(), value: -1.0, ..Default::default() },
+            StageReports { reports: vec![Report { id: 3, team_id: 1, metric: "happy_sad".into(), timestamp: chrono::Utc::now(), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: Some(chrono::Utc::now()) }] },
+            StoreAlert { alert: Alert { id: 4, team_id: 1, metric: "happy_sad".into(), raised_at: chrono::Utc::now(), ..Default::default() } }
+        ]);
+
+        state
+    }
+
+    #[actix_rt::test]
+    async fn remove_team_v1_cascade() {
+        test_log_init();
+
+        let state = cascade_state().await;
+
+        let content: TeamDeletionV1 = test_request!(DELETE "/api/v1/team/00000000000000000000000000000001?cascade=true" => OK with content | state = state);
+        assert!(!content.dry_run);
+        assert_eq!(content.teams.len(), 2);
+        assert_eq!(content.members.len(), 2);
+        assert_eq!(content.reports, 2);
+        assert_eq!((content.staged_reports, content.alerts), (1, 1));
+
+        state.store.send(GetTeam { id: 1, principal_id: 2 }).await.expect("the actor should have run").expect_err("The member's team should not exist anymore");
+        state.store.send(GetTeamAssignment { team_id: 1, principal_id: 2 }).await.expect("the actor should have run").expect_err("The member's role assignment should not exist anymore");
+        state.store.send(GetReport { team: 1, id: 1 }).await.expect("the actor should have run").expect_err("The team's reports should not exist anymore");
+        state.store.send(GetTeam { id: 3, principal_id: 2 }).await.expect("the actor should have run").expect("Other teams should not be affected");
+    }
+
+    #[actix_rt::test]
+    async fn remove_team_v1_cascade_dry_run() {
+        test_log_init();
+
+        let state = cascade_state().await;
+
+        let content: TeamDeletionV1 = test_request!(DELETE "/api/v1/team/00000000000000000000000000000001?cascade=true&dry_run=true" => OK with content | state = state);
+        assert!(content.dry_run);
+        assert_eq!(content.teams.len(), 2);
+        assert_eq!(content.reports, 2);
+        assert_eq!((content.staged_reports, content.alerts), (1, 1), "the preview should list everything which the deletion would remove");
+
+        state.store.send(GetTeam { id: 1, principal_id: 2 }).await.expect("the actor should have run").expect("The member's team should still exist");
+        state.store.send(GetReport { team: 1, id: 1 }).await.expect("the actor should have run").expect("The team's reports should still exist");
+    }
+
+    #[actix_rt::test]
+    async fn remove_team_v1_cascade_requires_manager() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam { team_id: 1, principal_id: 0, name: "Test Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 1, principal_id: 0, role: Role::Member, ..Default::default() }
+        ]);
+
+        test_request!(DELETE "/api/v1/team/00000000000000000000000000000001?cascade=true" => FORBIDDEN | state = state);
+
+        state.store.send(GetTeam { id: 1, principal_id: 0 }).await.expect("the actor should have run").expect("The team should still exist");
+    }
+}
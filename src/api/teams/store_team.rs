@@ -1,13 +1,13 @@
-use actix_web::{put, web};
-use super::{AuthToken, APIError};
+use actix_web::{put, web, HttpRequest};
+use super::{AuthToken, APIError, if_match, alert_rules, metrics, privacy, retention_days};
 use crate::models::*;
 use super::TeamFilter;
 
 #[put("/api/v1/team/{team}")]
 async fn store_team_v1(
-    (info, team, state, token): (web::Path<TeamFilter>,
+    (info, team, state, token, req): (web::Path<TeamFilter>,
         web::Json<TeamV1>,
-        web::Data<GlobalState>, AuthToken),
+        web::Data<GlobalState>, AuthToken, HttpRequest),
 ) -> Result<TeamV1, APIError> {
     require_role!(token, "Administrator", "User");
     require_scope!(token, "Teams.Write");
@@ -15,10 +15,48 @@ async fn store_team_v1(
     let cid = parse_uuid!(info.team, team ID);
     let uid = parse_uuid!(token.oid, auth token oid);
 
+    let existing = match state.store.send(GetTeamAssignment { team_id: cid, principal_id: uid }).await? {
+        Ok(role) if role.role == Role::Manager => Some(state.store.send(GetTeam { id: cid, principal_id: uid }).await??),
+        Ok(_) => return Err(APIError::new(403, "Forbidden", "Only the managers of a team may rename it.")),
+        Err(err) if err.code != 403 && err.code != 404 => return Err(err),
+        Err(_) => {
+            // Teams which don't have any members yet are created, with the caller as their manager
+            let members = match state.store.send(GetTeamAssignments { team_id: cid }).await? {
+                Err(err) if err.code == 404 => vec![],
+                other => other?,
+            };
+            if !members.is_empty() {
+                return Err(APIError::new(403, "Forbidden", "Only the managers of a team may rename it."));
+            }
+
+            None
+        }
+    };
+
+    // Settings which were left out of the request keep their current values
+    let retention_days = retention_days(team.retention_days.unwrap_or_else(|| existing.as_ref().and_then(|t| t.retention_days)))?;
+    let privacy = privacy(team.privacy.clone().or_else(|| existing.as_ref().map(|t| t.privacy.clone())).unwrap_or_default())?;
+    let alert_rules = alert_rules(team.alert_rules.clone().or_else(|| existing.as_ref().map(|t| t.alert_rules.clone())).unwrap_or_default())?;
+    let metrics = metrics(team.metrics.clone().or_else(|| existing.as_ref().map(|t| t.metrics.clone())).unwrap_or_default(), &alert_rules)?;
+
+    if existing.is_none() {
+        state.store.send(StoreTeamAssignment {
+            principal_id: uid,
+            team_id: cid,
+            role: Role::Manager,
+            etag: None,
+        }).await??;
+    }
+
     state.store.send(StoreTeam {
         principal_id: uid,
         team_id: cid,
         name: team.name.clone(),
+        retention_days,
+        privacy,
+        alert_rules,
+        metrics,
+        etag: if_match(&req),
     }).await?.map(|team| team.clone().into())
 }
 
@@ -35,10 +73,147 @@ mod tests {
             id: None,
             user_id: None,
             name: "Test Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: None,
+            metrics: None,
+            etag: None,
         } => OK with content);
 
         assert_eq!(content.id, Some("00000000000000000000000000000001".into()));
         assert_eq!(content.user_id, Some("00000000000000000000000000000000".into()));
         assert_eq!(content.name, "Test Team".to_string());
     }
-}
\ No newline at end of file
+
+    #[actix_rt::test]
+    async fn store_team_v1_if_match() {
+        test_log_init();
+
+        test_state!(state = []);
+
+        let response = test_request!(PUT "/api/v1/team/00000000000000000000000000000001", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Test Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: None,
+            metrics: None,
+            etag: None,
+        } => OK | state = state);
+        let etag = response.headers().get("ETag").expect("an etag header").to_str().expect("a valid etag").to_string();
+
+        let mut app = get_test_app(state.clone()).await;
+        let req = actix_web::test::TestRequest::with_uri("/api/v1/team/00000000000000000000000000000001")
+            .method(http::Method::PUT)
+            .set_json(&TeamV1 { id: None, user_id: None, name: "Renamed Team".into(), retention_days: None, privacy: None, alert_rules: None, metrics: None, etag: None })
+            .header("Authorization", auth_token())
+            .header("If-Match", etag.as_str())
+            .to_request();
+        let mut response = actix_web::test::call_service(&mut app, req).await;
+        assert_status(&mut response, http::StatusCode::OK).await;
+        assert_ne!(response.headers().get("ETag").expect("an etag header").to_str().unwrap(), etag);
+
+        let req = actix_web::test::TestRequest::with_uri("/api/v1/team/00000000000000000000000000000001")
+            .method(http::Method::PUT)
+            .set_json(&TeamV1 { id: None, user_id: None, name: "Conflicting Team".into(), retention_days: None, privacy: None, alert_rules: None, metrics: None, etag: None })
+            .header("Authorization", auth_token())
+            .header("If-Match", etag.as_str())
+            .to_request();
+        let mut response = actix_web::test::call_service(&mut app, req).await;
+        assert_status(&mut response, http::StatusCode::PRECONDITION_FAILED).await;
+
+        let team = state.store.send(GetTeam { id: 1, principal_id: 0 }).await.expect("the actor should run").expect("the team should exist");
+        assert_eq!(team.name, "Renamed Team");
+    }
+
+    #[actix_rt::test]
+    async fn store_team_v1_renames_for_members() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam { team_id: 1, principal_id: 0, name: "Test Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 1, principal_id: 0, role: Role::Manager, ..Default::default() },
+            StoreTeamAssignment { team_id: 1, principal_id: 2, role: Role::Member, ..Default::default() }
+        ]);
+
+        test_request!(PUT "/api/v1/team/00000000000000000000000000000001", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Renamed Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: None,
+            metrics: None,
+            etag: None,
+        } => OK | state = state);
+
+        let team = state.store.send(GetTeam { id: 1, principal_id: 2 }).await.expect("the actor should run").expect("the member should still have the team");
+        assert_eq!(team.name, "Renamed Team");
+    }
+
+    #[actix_rt::test]
+    async fn store_team_v1_keeps_omitted_settings() {
+        test_log_init();
+
+        let privacy = TeamPrivacy { min_group_size: Some(5), ..Default::default() };
+        test_state!(state = [
+            StoreTeam { team_id: 1, principal_id: 0, name: "Test Team".into(), retention_days: Some(30), privacy: privacy.clone(), ..Default::default() },
+            StoreTeamAssignment { team_id: 1, principal_id: 0, role: Role::Manager, ..Default::default() }
+        ]);
+
+        let content: TeamV1 = test_request!(PUT "/api/v1/team/00000000000000000000000000000001", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Renamed Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: None,
+            metrics: None,
+            etag: None,
+        } => OK with content | state = state);
+        assert_eq!(content.privacy, Some(privacy.clone()));
+
+        let team = state.store.send(GetTeam { id: 1, principal_id: 0 }).await.expect("the actor should run").expect("the team should exist");
+        assert_eq!(team.name, "Renamed Team");
+        assert_eq!(team.retention_days, Some(30));
+        assert_eq!(team.privacy, privacy);
+
+        test_request!(PUT "/api/v1/team/00000000000000000000000000000001", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Renamed Team".into(),
+            retention_days: Some(None),
+            privacy: None,
+            alert_rules: None,
+            metrics: None,
+            etag: None,
+        } => OK | state = state);
+
+        let team = state.store.send(GetTeam { id: 1, principal_id: 0 }).await.expect("the actor should run").expect("the team should exist");
+        assert_eq!(team.retention_days, None);
+        assert_eq!(team.privacy, privacy);
+    }
+
+    #[actix_rt::test]
+    async fn store_team_v1_requires_manager() {
+        test_log_init();
+
+        test_state!(state = [
+            StoreTeam { team_id: 1, principal_id: 2, name: "Test Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 1, principal_id: 2, role: Role::Manager, ..Default::default() },
+            StoreTeamAssignment { team_id: 1, principal_id: 0, role: Role::Member, ..Default::default() }
+        ]);
+
+        test_request!(PUT "/api/v1/team/00000000000000000000000000000001", TeamV1 {
+            id: None,
+            user_id: None,
+            name: "Renamed Team".into(),
+            retention_days: None,
+            privacy: None,
+            alert_rules: None,
+            metrics: None,
+            etag: None,
+        } => FORBIDDEN | state = state);
+    }
+}
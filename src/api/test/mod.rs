@@ -1,5 +1,7 @@
 use crate::api::configure;
 use crate::models::*;
+use crate::store::{MemoryStore, Store};
+use actix::Actor;
 use actix_web::{test, web, App};
 use oidc::token::Jws;
 use web::BytesMut;
@@ -10,6 +12,10 @@ pub fn test_log_init() {
     let _ = env_logger::builder().is_test(true).filter_level(log::LevelFilter::Debug).try_init();
 }
 
+pub fn get_test_state() -> GlobalState {
+    GlobalState::with_store(Store::new(MemoryStore::new().start()))
+}
+
 pub async fn get_test_app(state: GlobalState) -> impl actix_web::dev::Service<Request = actix_http::Request, Response = actix_web::dev::ServiceResponse<actix_web::dev::Body>, Error = actix_web::Error> {
     test::init_service(
         App::new()
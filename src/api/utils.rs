@@ -1,5 +1,27 @@
 use crate::models::*;
 use super::{AuthToken, APIError};
+use actix_web::HttpRequest;
+
+/// Gets the ETag from the request's `If-Match` header, if one was provided.
+pub fn if_match(req: &HttpRequest) -> Option<String> {
+    req.headers().get("If-Match")
+        .and_then(|etag| etag.to_str().ok())
+        .map(|etag| etag.trim().to_string())
+}
+
+/// Gets the key from the request's `Idempotency-Key` header, if one was provided.
+pub fn idempotency_key(req: &HttpRequest) -> Result<Option<String>, APIError> {
+    let key = match req.headers().get("Idempotency-Key") {
+        Some(key) => key.to_str().map(|key| key.trim()).unwrap_or_default(),
+        None => return Ok(None),
+    };
+
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(APIError::new(400, "Bad Request", &format!("The Idempotency-Key you provided is not valid. Please provide a key of between 1 and {} printable ASCII characters and try again.", MAX_IDEMPOTENCY_KEY_LENGTH)));
+    }
+
+    Ok(Some(key.to_string()))
+}
 
 pub async fn ensure_user_team(state: &GlobalState, token: &AuthToken) -> Result<(), APIError> {
     let uid = u128::from_str_radix(token.oid.replace("-", "").as_str(), 16)
@@ -16,6 +38,22 @@ pub async fn ensure_user_team(state: &GlobalState, token: &AuthToken) -> Result<
         }
     }
 
+    // The assignment is only written when it needs to change, so that its ETag isn't bumped on every request
+    let assignment = match state.store.send(GetTeamAssignment { team_id: uid, principal_id: uid }).await? {
+        // Stores report a missing assignment as either forbidden or not found
+        Err(err) if err.code == 403 || err.code == 404 => None,
+        other => Some(other?),
+    };
+
+    if assignment.map(|a| a.role != Role::Manager).unwrap_or(true) {
+        state.store.send(StoreTeamAssignment {
+            team_id: uid,
+            principal_id: uid,
+            role: Role::Manager,
+            etag: None,
+        }).await??;
+    }
+
     match state.store.send(GetTeam {
         id: uid,
         principal_id: uid,
@@ -28,15 +66,14 @@ pub async fn ensure_user_team(state: &GlobalState, token: &AuthToken) -> Result<
                 team_id: uid,
                 principal_id: uid,
                 name: "My Team".into(),
+                retention_days: None,
+                privacy: Default::default(),
+                alert_rules: vec![],
+                metrics: vec![],
+                etag: None,
             }).await??;
         }
     }
 
-    state.store.send(StoreTeamAssignment {
-        team_id: uid,
-        principal_id: uid,
-        role: Role::Manager,
-    }).await??;
-
     Ok(())
-}
\ No newline at end of file
+}
@@ -11,8 +11,12 @@ extern crate uuid;
 
 #[macro_use] mod macros;
 
+mod alerting;
 mod api;
+mod migrate;
 mod models;
+mod publishing;
+mod retention;
 mod store;
 
 use actix_cors::Cors;
@@ -34,7 +38,19 @@ async fn main() -> std::io::Result<()> {
         sentry::integrations::env_logger::init(None, Default::default());
     }
 
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("migrate") => return migrate::run(&args[2..]).await,
+        Some("reconcile-teams") => return migrate::reconcile_teams(&args[2..]).await,
+        _ => {},
+    }
+
     let state = models::GlobalState::new();
+
+    actix::Actor::start(retention::RetentionActor::from_env(state.store.clone()));
+    actix::Actor::start(alerting::AlertActor::new(state.store.clone()));
+    actix::Actor::start(publishing::PublicationActor::new(state.store.clone()));
+
     let metrics = PrometheusMetrics::new_with_registry(prometheus::default_registry().clone(), "rex", Some("/api/v1/metrics"), None).unwrap();
 
     HttpServer::new(move || {
@@ -0,0 +1,554 @@
+use crate::api::APIError;
+use crate::models::*;
+use crate::store::Store;
+use std::collections::{BTreeSet, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The number of reports which are read from the source store at a time.
+const MIGRATION_PAGE_SIZE: usize = 1000;
+
+/// The number of reports which are written to the destination store at a time, which is small
+/// enough for table storage to write them in a single batch.
+const MIGRATION_BATCH_SIZE: usize = 50;
+
+/// Tracks which parts of a migration have already been completed so that an
+/// interrupted migration can be resumed without copying everything again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    users: bool,
+    principals: HashSet<u128>,
+    teams: HashSet<u128>,
+    /// The members of already migrated teams whose own teams have not been migrated yet,
+    /// since they can't be discovered again once their team has been skipped.
+    #[serde(default)]
+    pending: BTreeSet<u128>,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> io::Result<Self> {
+        match std::fs::read(path) {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Checkpoint::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, serde_json::to_vec(self)?)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MigrationSummary {
+    pub users: usize,
+    pub teams: usize,
+    pub team_assignments: usize,
+    pub reports: usize,
+    pub staged_reports: usize,
+    pub rollups: usize,
+    pub privacy_spends: usize,
+    pub alerts: usize,
+    pub mismatches: usize,
+}
+
+/// Copies every user, team, team assignment and report (along with each team's staged reports,
+/// rollups, privacy budget spends and alerts) from one store into another.
+///
+/// Since there is no way to list every team in a store, teams are discovered by walking
+/// from each user's principal to the teams they are a member of, and from each team to
+/// the principals which have been assigned to it.
+pub struct Migration {
+    from: Store,
+    to: Store,
+    checkpoint_path: PathBuf,
+    checkpoint: Checkpoint,
+    summary: MigrationSummary,
+}
+
+impl Migration {
+    pub fn new(from: Store, to: Store, checkpoint_path: PathBuf) -> io::Result<Self> {
+        let checkpoint = Checkpoint::load(&checkpoint_path)?;
+
+        Ok(Self {
+            from,
+            to,
+            checkpoint_path,
+            checkpoint,
+            summary: MigrationSummary::default(),
+        })
+    }
+
+    pub async fn run(mut self) -> Result<MigrationSummary, APIError> {
+        // Teams stored by earlier versions can only be listed once they have been reconciled
+        self.from.send(ReconcileTeams {}).await??;
+
+        let users = self.from.send(GetUsers {}).await??;
+        let mut principals: BTreeSet<u128> = users.iter().map(|u| u.principal_id).collect();
+        principals.extend(self.checkpoint.pending.iter().cloned());
+
+        if !self.checkpoint.users {
+            for user in users {
+                let migrated = self.to.send(StoreUser {
+                    email_hash: user.email_hash,
+                    principal_id: user.principal_id,
+                    first_name: user.first_name.clone(),
+                }).await??;
+
+                self.verify("user", format!("{:0>32x}", user.email_hash), &user, &migrated);
+                self.summary.users += 1;
+            }
+
+            self.checkpoint.users = true;
+            self.save_checkpoint()?;
+            println!("Migrated {} users", self.summary.users);
+        }
+
+        while let Some(principal_id) = principals.iter().next().cloned() {
+            principals.remove(&principal_id);
+            if self.checkpoint.principals.contains(&principal_id) {
+                continue;
+            }
+
+            for team in not_found_as_empty(self.from.send(GetTeams { principal_id }).await?)? {
+                // Every member lists the same team, which only needs to be copied the first time it is found
+                if self.checkpoint.teams.contains(&team.team_id) {
+                    continue;
+                }
+
+                let migrated = self.to.send(StoreTeam {
+                    team_id: team.team_id,
+                    principal_id: team.user_id,
+                    name: team.name.clone(),
+                    retention_days: team.retention_days,
+                    privacy: team.privacy.clone(),
+                    alert_rules: team.alert_rules.clone(),
+                    metrics: team.metrics.clone(),
+                    etag: None,
+                }).await??;
+
+                self.verify("team", format!("{:0>32x}", team.team_id), &Team { etag: None, ..team.clone() }, &Team { etag: None, ..migrated });
+                self.summary.teams += 1;
+
+                for member in self.migrate_team(team.team_id).await? {
+                    if !self.checkpoint.principals.contains(&member) {
+                        principals.insert(member);
+                    }
+                }
+            }
+
+            self.checkpoint.principals.insert(principal_id);
+            self.checkpoint.pending.remove(&principal_id);
+            self.save_checkpoint()?;
+        }
+
+        println!(
+            "Migrated {} users, {} teams, {} team assignments, {} reports, {} staged reports, {} report rollups, {} privacy budget spends and {} alerts with {} mismatches",
+            self.summary.users,
+            self.summary.teams,
+            self.summary.team_assignments,
+            self.summary.reports,
+            self.summary.staged_reports,
+            self.summary.rollups,
+            self.summary.privacy_spends,
+            self.summary.alerts,
+            self.summary.mismatches);
+
+        Ok(self.summary)
+    }
+
+    /// Migrates the assignments and reports for a team, returning the principals
+    /// which are members of it.
+    async fn migrate_team(&mut self, team_id: u128) -> Result<Vec<u128>, APIError> {
+        let mut members = Vec::new();
+
+        for assignment in not_found_as_empty(self.from.send(GetTeamAssignments { team_id }).await?)? {
+            let migrated = self.to.send(StoreTeamAssignment {
+                team_id: assignment.team_id,
+                principal_id: assignment.user_id,
+                role: assignment.role,
+                etag: None,
+            }).await??;
+
+            self.verify("team assignment", format!("{:0>32x}/{:0>32x}", assignment.team_id, assignment.user_id), &TeamAssignment { etag: None, ..assignment.clone() }, &TeamAssignment { etag: None, ..migrated });
+            self.summary.team_assignments += 1;
+            members.push(assignment.user_id);
+        }
+
+        let mut reports = 0;
+        let mut cursor = None;
+        loop {
+            let page = self.from.send(GetReports { team: team_id, limit: Some(MIGRATION_PAGE_SIZE), cursor, ..Default::default() }).await??;
+
+            // Reports are copied as they are, since StoreReport only accepts the fields a new report is submitted with
+            for chunk in page.items.chunks(MIGRATION_BATCH_SIZE) {
+                let batch = self.to.send(StoreReports { reports: chunk.to_vec() }).await??;
+                if let Some(failure) = batch.failed.into_iter().next() {
+                    return Err(failure.error);
+                }
+
+                for (report, migrated) in chunk.iter().zip(batch.stored.iter()) {
+                    self.verify("report", format!("{:0>32x}/{:0>32x}", report.team_id, report.id), report, migrated);
+                    self.summary.reports += 1;
+                    reports += 1;
+                }
+            }
+
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        // Staged reports are copied into the destination's staging area, so that they are still held until the team's next release
+        let staged = self.from.send(GetStagedReports { team_id }).await??;
+        for chunk in staged.chunks(MIGRATION_BATCH_SIZE) {
+            let batch = self.to.send(StageReports { reports: chunk.to_vec() }).await??;
+            if let Some(failure) = batch.failed.into_iter().next() {
+                return Err(failure.error);
+            }
+
+            for (report, migrated) in chunk.iter().zip(batch.stored.iter()) {
+                self.verify("staged report", format!("{:0>32x}/{:0>32x}", report.team_id, report.id), report, migrated);
+                self.summary.staged_reports += 1;
+            }
+        }
+
+        let rollups = self.from.send(GetReportRollups { team: team_id, ..Default::default() }).await??;
+        if !rollups.is_empty() {
+            self.to.send(StoreReportRollups { rollups: rollups.clone() }).await??;
+
+            let migrated = self.to.send(GetReportRollups { team: team_id, ..Default::default() }).await??;
+            self.verify("report rollups", format!("{:0>32x}", team_id), &rollups, &migrated);
+            self.summary.rollups += rollups.len();
+        }
+
+        // Spends are replaced rather than added to, so that copying them again after an interruption doesn't count them twice
+        let spends = self.from.send(GetPrivacySpends { team_id }).await??;
+        if !spends.is_empty() {
+            self.to.send(StorePrivacySpends { team_id, spends: spends.clone() }).await??;
+
+            let migrated = self.to.send(GetPrivacySpends { team_id }).await??;
+            self.verify("privacy budget spends", format!("{:0>32x}", team_id), &spends, &migrated);
+            self.summary.privacy_spends += spends.len();
+        }
+
+        // Alerts keep their acknowledgements, so that alerts which have already been dealt with aren't raised again
+        let alerts = self.from.send(GetAlerts { team_id }).await??;
+        for alert in alerts.iter() {
+            let migrated = self.to.send(StoreAlert { alert: alert.clone() }).await??;
+
+            self.verify("alert", format!("{:0>32x}/{:0>32x}", alert.team_id, alert.id), alert, &migrated);
+            self.summary.alerts += 1;
+        }
+
+        println!("Migrated {} team assignments, {} reports, {} staged reports, {} report rollups, {} privacy budget spends and {} alerts for team {:0>32x}", members.len(), reports, staged.len(), rollups.len(), spends.len(), alerts.len(), team_id);
+
+        // The members are recorded along with the team so that resuming the migration still visits them
+        let pending: Vec<u128> = members.iter().filter(|member| !self.checkpoint.principals.contains(member)).cloned().collect();
+        self.checkpoint.pending.extend(pending);
+        self.checkpoint.teams.insert(team_id);
+        self.save_checkpoint()?;
+
+        Ok(members)
+    }
+
+    fn verify<T: PartialEq + std::fmt::Debug>(&mut self, kind: &str, key: String, expected: &T, actual: &T) {
+        if expected != actual {
+            eprintln!("Mismatched {} {}: expected {:?} but the destination stored {:?}", kind, key, expected, actual);
+            self.summary.mismatches += 1;
+        }
+    }
+
+    fn save_checkpoint(&self) -> Result<(), APIError> {
+        self.checkpoint.save(&self.checkpoint_path).map_err(|err| {
+            error!("Unable to save the migration checkpoint: {}", err);
+            APIError::new(500, "Internal Server Error", "The migration checkpoint could not be saved.")
+        })
+    }
+}
+
+/// The memory store reports a missing partition as a 404 rather than as an empty list.
+fn not_found_as_empty<T>(result: Result<Vec<T>, APIError>) -> Result<Vec<T>, APIError> {
+    match result {
+        Err(err) if err.code == 404 => Ok(vec![]),
+        other => other,
+    }
+}
+
+const USAGE: &str = "Usage: burnout migrate --from <backend> --to <backend> [--checkpoint <path>]";
+
+/// Runs the `burnout migrate` command with the arguments which followed it.
+pub async fn run(args: &[String]) -> io::Result<()> {
+    let mut from = None;
+    let mut to = None;
+    let mut checkpoint = PathBuf::from("burnout-migrate.json");
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--from", Some(value)) => from = Some(value.clone()),
+            ("--to", Some(value)) => to = Some(value.clone()),
+            ("--checkpoint", Some(value)) => checkpoint = PathBuf::from(value),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, USAGE)),
+        }
+    }
+
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) if from != to => (from, to),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, USAGE)),
+    };
+
+    let source = Store::from_name(&from)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("The '{}' storage backend is not supported by this build.", from)))?;
+    let destination = Store::from_name(&to)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("The '{}' storage backend is not supported by this build.", to)))?;
+
+    println!("Migrating from '{}' to '{}' (checkpoint: {})", from, to, checkpoint.display());
+
+    let summary = Migration::new(source, destination, checkpoint.clone())?
+        .run().await
+        .map_err(|err| io::Error::other(err.to_string()))?;
+
+    std::fs::remove_file(&checkpoint)?;
+
+    if summary.mismatches > 0 {
+        return Err(io::Error::other(format!("{} records did not match after being migrated.", summary.mismatches)));
+    }
+
+    Ok(())
+}
+
+/// Runs the `burnout reconcile-teams` command, which merges the copies of each team stored by
+/// earlier versions into a single record and repairs the membership index of the configured store.
+pub async fn reconcile_teams(args: &[String]) -> io::Result<()> {
+    if !args.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Usage: burnout reconcile-teams"));
+    }
+
+    let reconciliation = Store::from_env()
+        .send(ReconcileTeams {}).await
+        .map_err(APIError::from)
+        .and_then(|result| result)
+        .map_err(|err| io::Error::other(err.to_string()))?;
+
+    println!(
+        "Reconciled {} teams ({} with diverged names) and {} team memberships",
+        reconciliation.teams,
+        reconciliation.diverged,
+        reconciliation.memberships);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::test_state;
+    use actix::Actor;
+
+    fn checkpoint_path() -> PathBuf {
+        std::env::temp_dir().join(format!("burnout-migrate-{:0>32x}.json", new_id()))
+    }
+
+    #[actix_rt::test]
+    async fn migrate_memory_to_memory() {
+        let to = Store::new(MemoryStore::new().start());
+
+        test_state!(from = [
+            StoreUser { email_hash: 1, principal_id: 10, first_name: "Test".into() },
+            StoreTeam { team_id: 7, principal_id: 10, name: "Test Team".into(), ..Default::default() },
+            StoreTeam { team_id: 7, principal_id: 11, name: "Test Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 7, principal_id: 10, role: Role::Manager, ..Default::default() },
+            StoreTeamAssignment { team_id: 7, principal_id: 11, role: Role::Member, ..Default::default() },
+            StoreReport { id: 1, team: 7, metric: "happy_sad".into(), value: 1.0, ..Default::default() },
+            StoreReportRollups { rollups: vec![ReportRollup {
+                team_id: 7,
+                metric: "happy_sad".into(),
+                day: chrono::Utc::now().date().and_hms(0, 0, 0),
+                count: 2,
+                sum: 1.0,
+                min: 0.0,
+                max: 1.0,
+                sum_squares: 1.0,
+                option_counts: Default::default(),
+            }] },
+            SpendPrivacyBudget { team_id: 7, epsilon: 0.5, budget: 1.0, since: None },
+            StoreAlert { alert: Alert { id: 3, team_id: 7, metric: "happy_sad".into(), raised_at: chrono::Utc::now(), acknowledged_by: Some(10), acknowledged_at: Some(chrono::Utc::now()), ..Default::default() } }
+        ]);
+
+        let path = checkpoint_path();
+        let summary = Migration::new(from.store.clone(), to.clone(), path.clone()).expect("a new migration")
+            .run().await.expect("the migration should succeed");
+
+        assert_eq!(summary, MigrationSummary {
+            users: 1,
+            teams: 1,
+            team_assignments: 2,
+            reports: 1,
+            staged_reports: 0,
+            rollups: 1,
+            privacy_spends: 1,
+            alerts: 1,
+            mismatches: 0,
+        });
+
+        let team = to.send(GetTeam { id: 7, principal_id: 11 }).await.expect("the actor should run").expect("the member's team should have been migrated");
+        assert_eq!(team.name, "Test Team");
+
+        let report = to.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect("the report should have been migrated");
+        assert_eq!(report.value, 1.0);
+
+        to.send(SpendPrivacyBudget { team_id: 7, epsilon: 0.75, budget: 1.0, since: None }).await.expect("the actor should run")
+            .expect_err("the budget spent before the migration should still count");
+
+        let alerts = to.send(GetAlerts { team_id: 7 }).await.expect("the actor should run").expect("the alerts should have been migrated");
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].acknowledged_by, Some(10), "the alert's acknowledgement should have been kept");
+
+        std::fs::remove_file(&path).expect("the checkpoint should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn migrate_survey_responses() {
+        let to = Store::new(MemoryStore::new().start());
+        let answer = |id: u128, metric: &str| Report { id, team_id: 7, metric: metric.into(), timestamp: chrono::Utc::now(), value: 1.0, response_id: Some(5), receipt_hash: None, options: vec![], staged_at: None };
+
+        test_state!(from = [
+            StoreUser { email_hash: 1, principal_id: 10, first_name: "Test".into() },
+            StoreTeam { team_id: 7, principal_id: 10, name: "Test Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 7, principal_id: 10, role: Role::Manager, ..Default::default() },
+            StoreReports { reports: vec![answer(1, "happy_sad"), answer(2, "workload")] }
+        ]);
+
+        let path = checkpoint_path();
+        let summary = Migration::new(from.store.clone(), to.clone(), path.clone()).expect("a new migration")
+            .run().await.expect("the migration should succeed");
+
+        assert_eq!((summary.reports, summary.mismatches), (2, 0));
+
+        let report = to.send(GetReport { id: 2, team: 7 }).await.expect("the actor should run").expect("the answer should have been migrated");
+        assert_eq!(report.response_id, Some(5), "the answer should still belong to its survey response");
+
+        std::fs::remove_file(&path).expect("the checkpoint should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn migrate_receipted_reports() {
+        let to = Store::new(MemoryStore::new().start());
+        let receipt_hash = Receipt::new(chrono::Utc::now()).hash_for(7);
+
+        test_state!(from = [
+            StoreUser { email_hash: 1, principal_id: 10, first_name: "Test".into() },
+            StoreTeam { team_id: 7, principal_id: 10, name: "Test Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 7, principal_id: 10, role: Role::Manager, ..Default::default() },
+            StoreReports { reports: vec![Report { id: 1, team_id: 7, metric: "happy_sad".into(), timestamp: chrono::Utc::now(), value: 1.0, response_id: None, receipt_hash: Some(receipt_hash.clone()), options: vec![], staged_at: None }] }
+        ]);
+
+        let path = checkpoint_path();
+        let summary = Migration::new(from.store.clone(), to.clone(), path.clone()).expect("a new migration")
+            .run().await.expect("the migration should succeed");
+
+        assert_eq!((summary.reports, summary.mismatches), (1, 0));
+
+        let amended = to.send(AmendReports { team_id: 7, receipt_hash: receipt_hash.clone(), metric: None, value: -1.0, options: vec![] }).await.expect("the actor should run").expect("the report should be amended");
+        assert_eq!(amended.len(), 1, "the receipt should still cover the migrated report");
+
+        let retracted = to.send(RetractReports { team_id: 7, receipt_hash }).await.expect("the actor should run").expect("the report should be retracted");
+        assert_eq!(retracted.len(), 1);
+        to.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("the retracted report should have been removed");
+
+        std::fs::remove_file(&path).expect("the checkpoint should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn migrate_staged_reports() {
+        let to = Store::new(MemoryStore::new().start());
+
+        test_state!(from = [
+            StoreUser { email_hash: 1, principal_id: 10, first_name: "Test".into() },
+            StoreTeam { team_id: 7, principal_id: 10, name: "Test Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 7, principal_id: 10, role: Role::Manager, ..Default::default() },
+            StageReports { reports: vec![Report { id: 1, team_id: 7, metric: "happy_sad".into(), timestamp: chrono::Utc::now(), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: None }] }
+        ]);
+
+        let path = checkpoint_path();
+        let summary = Migration::new(from.store.clone(), to.clone(), path.clone()).expect("a new migration")
+            .run().await.expect("the migration should succeed");
+
+        assert_eq!((summary.reports, summary.staged_reports, summary.mismatches), (0, 1, 0));
+
+        to.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("the staged report should still be held");
+
+        let staged = to.send(GetStagedReports { team_id: 7 }).await.expect("the actor should run").expect("the staged reports should be listed");
+        assert_eq!(staged.len(), 1, "the staged report should have been migrated");
+
+        std::fs::remove_file(&path).expect("the checkpoint should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn migrate_resumes_from_checkpoint() {
+        let to = Store::new(MemoryStore::new().start());
+
+        test_state!(from = [
+            StoreUser { email_hash: 1, principal_id: 10, first_name: "Test".into() },
+            StoreTeam { team_id: 7, principal_id: 10, name: "Test Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 7, principal_id: 10, role: Role::Manager, ..Default::default() },
+            StoreReport { id: 1, team: 7, metric: "happy_sad".into(), value: 1.0, ..Default::default() }
+        ]);
+
+        let path = checkpoint_path();
+        Checkpoint {
+            users: true,
+            principals: HashSet::new(),
+            teams: vec![7].into_iter().collect(),
+            pending: BTreeSet::new(),
+        }.save(&path).expect("the checkpoint should be saved");
+
+        let summary = Migration::new(from.store.clone(), to.clone(), path.clone()).expect("a new migration")
+            .run().await.expect("the migration should succeed");
+
+        assert_eq!(summary.users, 0);
+        assert_eq!(summary.teams, 0);
+        assert_eq!(summary.reports, 0);
+
+        to.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("the already migrated team should have been skipped");
+
+        std::fs::remove_file(&path).expect("the checkpoint should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn migrate_resumes_with_pending_members() {
+        let to = Store::new(MemoryStore::new().start());
+
+        // Principal 11 has no user record, so is only discovered as a member of team 7
+        test_state!(from = [
+            StoreUser { email_hash: 1, principal_id: 10, first_name: "Test".into() },
+            StoreTeam { team_id: 7, principal_id: 10, name: "Test Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 7, principal_id: 10, role: Role::Manager, ..Default::default() },
+            StoreTeamAssignment { team_id: 7, principal_id: 11, role: Role::Member, ..Default::default() },
+            StoreTeam { team_id: 8, principal_id: 11, name: "Other Team".into(), ..Default::default() },
+            StoreTeamAssignment { team_id: 8, principal_id: 11, role: Role::Manager, ..Default::default() },
+            StoreReport { id: 2, team: 8, metric: "happy_sad".into(), value: 1.0, ..Default::default() }
+        ]);
+
+        // Interrupt the migration once the first team has been migrated
+        let path = checkpoint_path();
+        let mut migration = Migration::new(from.store.clone(), to.clone(), path.clone()).expect("a new migration");
+        migration.from.send(ReconcileTeams {}).await.expect("the actor should run").expect("the teams should be reconciled");
+        migration.checkpoint.users = true;
+        migration.migrate_team(7).await.expect("the team should be migrated");
+
+        let summary = Migration::new(from.store.clone(), to.clone(), path.clone()).expect("a resumed migration")
+            .run().await.expect("the migration should succeed");
+
+        assert_eq!(summary.reports, 1);
+
+        let team = to.send(GetTeam { id: 8, principal_id: 11 }).await.expect("the actor should run").expect("the member's other team should have been migrated");
+        assert_eq!(team.name, "Other Team");
+        to.send(GetReport { id: 2, team: 8 }).await.expect("the actor should run").expect("the member's other team's reports should have been migrated");
+
+        std::fs::remove_file(&path).expect("the checkpoint should be removed");
+    }
+}
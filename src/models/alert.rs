@@ -0,0 +1,309 @@
+use actix::prelude::*;
+use crate::api::APIError;
+use super::{laplace_noise, new_id, DifferentialPrivacy, Report};
+use chrono::prelude::*;
+
+/// The fewest reports which a rolling mean (or its baseline) must be built from before it is
+/// compared, so that a single report cannot raise an alert.
+pub const MIN_ALERT_REPORTS: u64 = 3;
+
+/// A condition on one of a team's metrics which raises an alert when it is met.
+///
+/// The rolling mean covers the last `window_days`, and is compared with a baseline built
+/// from the `baseline_days` which came before it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertRule {
+    pub metric: String,
+    #[serde(default = "AlertRule::default_window_days")]
+    pub window_days: u32,
+    #[serde(default = "AlertRule::default_baseline_days")]
+    pub baseline_days: u32,
+    /// Raises an alert when the rolling mean falls this many standard errors below the baseline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_drop: Option<f64>,
+    /// Raises an alert when the rolling mean falls below this value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub below: Option<f64>,
+    /// Raises an alert when the rolling mean rises above this value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub above: Option<f64>,
+}
+
+impl AlertRule {
+    fn default_window_days() -> u32 {
+        7
+    }
+
+    fn default_baseline_days() -> u32 {
+        28
+    }
+
+    /// Gets the earliest time from which reports are needed to evaluate this rule.
+    pub fn start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - chrono::Duration::days(self.window_days as i64 + self.baseline_days as i64)
+    }
+
+    /// Evaluates this rule against a team's reports, returning the alerts it raises. Means are
+    /// only compared once they are built from at least `min_reports` reports.
+    ///
+    /// When the team uses differential privacy, the counts, means and variances are all noisy
+    /// estimates built from values clamped to its `value_bound`, so that neither the alerts nor
+    /// the means they record reveal the exact data. Each evaluation then costs a single `epsilon`,
+    /// since the window and the baseline cover different reports.
+    pub fn evaluate(&self, team_id: u128, reports: &[Report], now: DateTime<Utc>, min_reports: u64, privacy: Option<&DifferentialPrivacy>) -> Vec<Alert> {
+        let window_start = now - chrono::Duration::days(self.window_days as i64);
+        let stats = |from: DateTime<Utc>, to: DateTime<Utc>| -> Stats {
+            let values: Vec<f64> = reports.iter()
+                .filter(|r| r.metric == self.metric && r.timestamp >= from && r.timestamp < to)
+                .map(|r| r.value as f64)
+                .collect();
+
+            Stats::new(&values, privacy)
+        };
+
+        let window = stats(window_start, now);
+        if window.count < min_reports as f64 {
+            return vec![];
+        }
+
+        let window_mean = window.mean;
+        let alert = |kind: AlertKind, baseline_mean: Option<f64>, threshold: Option<f64>| Alert {
+            id: new_id(),
+            team_id,
+            metric: self.metric.clone(),
+            kind,
+            raised_at: now,
+            window_mean,
+            baseline_mean,
+            threshold,
+            acknowledged_by: None,
+            acknowledged_at: None,
+        };
+
+        let mut alerts = vec![];
+
+        if let Some(max_drop) = self.max_drop {
+            let baseline = stats(self.start(now), window_start);
+            if baseline.count >= min_reports as f64 {
+                let stderr = baseline.stddev / window.count.sqrt();
+
+                // A baseline without any variation makes every drop significant
+                let significant = if stderr > 0.0 { (baseline.mean - window_mean) / stderr > max_drop } else { window_mean < baseline.mean };
+                if significant {
+                    alerts.push(alert(AlertKind::Drop, Some(baseline.mean), Some(max_drop)));
+                }
+            }
+        }
+
+        if let Some(below) = self.below.filter(|&below| window_mean < below) {
+            alerts.push(alert(AlertKind::Below, None, Some(below)));
+        }
+
+        if let Some(above) = self.above.filter(|&above| window_mean > above) {
+            alerts.push(alert(AlertKind::Above, None, Some(above)));
+        }
+
+        alerts
+    }
+}
+
+/// The count, mean and population standard deviation of a metric's values over a period.
+struct Stats {
+    count: f64,
+    mean: f64,
+    stddev: f64,
+}
+
+impl Stats {
+    /// Builds the statistics for a set of values, adding Laplace noise to the count, sum and sum
+    /// of squares (splitting the privacy loss evenly between them) if the team uses differential privacy.
+    fn new(values: &[f64], privacy: Option<&DifferentialPrivacy>) -> Self {
+        let bound = privacy.map(|dp| dp.value_bound).unwrap_or(f64::INFINITY);
+        let values: Vec<f64> = values.iter().map(|v| v.clamp(-bound, bound)).collect();
+
+        let mut count = values.len() as f64;
+        let mut sum: f64 = values.iter().sum();
+        let mut sum_squares: f64 = values.iter().map(|v| v.powi(2)).sum();
+
+        if let Some(dp) = privacy {
+            let scale = 3.0 / dp.epsilon;
+            count += laplace_noise(scale);
+            sum += laplace_noise(scale * dp.value_bound);
+            sum_squares += laplace_noise(scale * dp.value_bound.powi(2));
+        }
+
+        let mean = (sum / count.max(1.0)).clamp(-bound, bound);
+        Self {
+            count,
+            mean,
+            stddev: (sum_squares / count.max(1.0) - mean.powi(2)).max(0.0).sqrt(),
+        }
+    }
+}
+
+/// The condition which caused an alert to be raised.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertKind {
+    /// The rolling mean dropped significantly below the team's baseline.
+    #[default]
+    Drop,
+    /// The rolling mean fell below the rule's `below` threshold.
+    Below,
+    /// The rolling mean rose above the rule's `above` threshold.
+    Above,
+}
+
+impl AlertKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertKind::Drop => "drop",
+            AlertKind::Below => "below",
+            AlertKind::Above => "above",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Self {
+        match kind {
+            "below" => AlertKind::Below,
+            "above" => AlertKind::Above,
+            _ => AlertKind::Drop,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alert {
+    pub id: u128,
+    pub team_id: u128,
+    pub metric: String,
+    pub kind: AlertKind,
+    pub raised_at: DateTime<Utc>,
+    pub window_mean: f64,
+    pub baseline_mean: Option<f64>,
+    /// The `below` or `above` value which was crossed, or the `max_drop` which was exceeded.
+    pub threshold: Option<f64>,
+    pub acknowledged_by: Option<u128>,
+    pub acknowledged_at: Option<DateTime<Utc>>,
+}
+
+impl Default for Alert {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            team_id: 0,
+            metric: String::new(),
+            kind: AlertKind::default(),
+            raised_at: Utc.timestamp(0, 0),
+            window_mean: 0.0,
+            baseline_mean: None,
+            threshold: None,
+            acknowledged_by: None,
+            acknowledged_at: None,
+        }
+    }
+}
+
+// Lists a team's alerts, with the most recently raised first.
+actor_message!(GetAlerts(team_id: u128) -> Vec<Alert>);
+
+actor_message!(StoreAlert(alert: Alert) -> Alert);
+
+// Records that a principal has acknowledged an alert, keeping the original acknowledgement if there was one.
+actor_message!(AcknowledgeAlert(team_id: u128, id: u128, principal_id: u128) -> Alert);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AlertV1 {
+    pub id: String,
+    pub team: String,
+    pub metric: String,
+    pub kind: AlertKind,
+    #[serde(rename = "raisedAt")]
+    pub raised_at: DateTime<Utc>,
+    #[serde(rename = "windowMean")]
+    pub window_mean: f64,
+    #[serde(rename = "baselineMean", default, skip_serializing_if = "Option::is_none")]
+    pub baseline_mean: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f64>,
+    #[serde(rename = "acknowledgedBy", default, skip_serializing_if = "Option::is_none")]
+    pub acknowledged_by: Option<String>,
+    #[serde(rename = "acknowledgedAt", default, skip_serializing_if = "Option::is_none")]
+    pub acknowledged_at: Option<DateTime<Utc>>,
+}
+
+json_responder!(AlertV1);
+
+impl From<Alert> for AlertV1 {
+    fn from(alert: Alert) -> Self {
+        Self {
+            id: format!("{:0>32x}", alert.id),
+            team: format!("{:0>32x}", alert.team_id),
+            metric: alert.metric,
+            kind: alert.kind,
+            raised_at: alert.raised_at,
+            window_mean: alert.window_mean,
+            baseline_mean: alert.baseline_mean,
+            threshold: alert.threshold,
+            acknowledged_by: alert.acknowledged_by.map(|id| format!("{:0>32x}", id)),
+            acknowledged_at: alert.acknowledged_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(id: u128, timestamp: DateTime<Utc>, value: f32) -> Report {
+        Report { id, team_id: 7, metric: "happy_sad".into(), timestamp, value, response_id: None, receipt_hash: None, options: vec![], staged_at: None }
+    }
+
+    fn reports(now: DateTime<Utc>, baseline: &[f32], window: &[f32]) -> Vec<Report> {
+        baseline.iter().enumerate().map(|(i, &v)| report(i as u128, now - chrono::Duration::days(10 + i as i64), v))
+            .chain(window.iter().enumerate().map(|(i, &v)| report(100 + i as u128, now - chrono::Duration::hours(1 + i as i64), v)))
+            .collect()
+    }
+
+    #[test]
+    fn raises_alerts_for_drops() {
+        let now = Utc::now();
+        let rule = AlertRule { metric: "happy_sad".into(), window_days: 7, baseline_days: 28, max_drop: Some(2.0), below: None, above: None };
+
+        let alerts = rule.evaluate(7, &reports(now, &[1.0, 1.0, -1.0, 1.0, 1.0, 1.0], &[-1.0, -1.0, -1.0, 1.0]), now, MIN_ALERT_REPORTS, None);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, AlertKind::Drop);
+        assert_eq!(alerts[0].window_mean, -0.5);
+        assert_eq!(alerts[0].baseline_mean, Some(4.0 / 6.0));
+
+        assert!(rule.evaluate(7, &reports(now, &[1.0, 1.0, -1.0, 1.0, 1.0, 1.0], &[1.0, 1.0, -1.0, 1.0]), now, MIN_ALERT_REPORTS, None).is_empty());
+        assert!(rule.evaluate(7, &reports(now, &[1.0, 1.0], &[-1.0, -1.0, -1.0]), now, MIN_ALERT_REPORTS, None).is_empty(), "a small baseline should not be compared");
+    }
+
+    #[test]
+    fn raises_alerts_for_thresholds() {
+        let now = Utc::now();
+        let rule = AlertRule { metric: "happy_sad".into(), window_days: 7, baseline_days: 28, max_drop: None, below: Some(0.0), above: Some(-0.9) };
+
+        let alerts = rule.evaluate(7, &reports(now, &[], &[-1.0, -1.0, 1.0]), now, MIN_ALERT_REPORTS, None);
+        assert_eq!(alerts.iter().map(|a| a.kind).collect::<Vec<_>>(), vec![AlertKind::Below, AlertKind::Above]);
+        assert_eq!(alerts[0].threshold, Some(0.0));
+
+        assert!(rule.evaluate(7, &reports(now, &[], &[-1.0, -1.0, 1.0]), now, 4, None).is_empty(), "a small window should not be compared");
+    }
+
+    #[test]
+    fn adds_noise_for_private_teams() {
+        let now = Utc::now();
+        let rule = AlertRule { metric: "happy_sad".into(), window_days: 7, baseline_days: 28, max_drop: None, below: Some(0.0), above: None };
+        let privacy = DifferentialPrivacy { epsilon: 100.0, ..Default::default() };
+
+        let mut window = vec![-1.0; 20];
+        window.push(1e6);
+        let alerts = rule.evaluate(7, &reports(now, &[], &window), now, MIN_ALERT_REPORTS, Some(&privacy));
+        assert_eq!(alerts.len(), 1, "an outlier should be clamped rather than hiding the drop");
+        assert_ne!(alerts[0].window_mean, -19.0 / 21.0, "the exact mean should not be recorded");
+        assert!((alerts[0].window_mean + 19.0 / 21.0).abs() < 0.1, "the noisy mean should be close to {}, but was {}", -19.0 / 21.0, alerts[0].window_mean);
+    }
+}
@@ -0,0 +1,183 @@
+use actix::prelude::*;
+use crate::api::APIError;
+use chrono::prelude::*;
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+
+/// How long a request's `Idempotency-Key` is remembered for, after which it may be reused.
+pub const IDEMPOTENCY_KEY_TTL_HOURS: i64 = 24;
+
+/// The longest `Idempotency-Key` which is accepted.
+pub const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// A request which was made with an `Idempotency-Key`, along with its response once it completes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    pub key_hash: String,
+    /// Identifies the request which the key was first used with, so that reusing it for a
+    /// different request can be rejected.
+    pub request_hash: String,
+    pub created_at: DateTime<Utc>,
+    /// The sealed response, which is only set once the request has completed.
+    pub response: Option<String>,
+}
+
+impl IdempotencyRecord {
+    /// Checks whether this record has been kept for long enough that its key may be reused.
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.created_at <= now - chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS)
+    }
+}
+
+// Claims an idempotency key for a new request, unless it has already been claimed within its TTL,
+// in which case the existing record is returned instead.
+actor_message!(ClaimIdempotencyKey(key_hash: String, request_hash: String, created_at: Option<DateTime<Utc>>) -> Option<IdempotencyRecord>);
+
+// Records the sealed response to the request which claimed an idempotency key.
+actor_message!(CompleteIdempotencyKey(key_hash: String, response: String) -> ());
+
+// Releases an idempotency key whose request failed, so that it may be retried.
+actor_message!(ReleaseIdempotencyKey(key_hash: String) -> ());
+
+// Removes the idempotency keys which were claimed before `before`, returning the number removed.
+actor_message!(PurgeIdempotencyKeys(before: Option<DateTime<Utc>>) -> usize);
+
+/// The response to a request which was made with an `Idempotency-Key`, which is replayed when
+/// the request is retried.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IdempotentResponse {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    pub body: serde_json::Value,
+}
+
+impl IdempotentResponse {
+    pub fn new<T: serde::Serialize>(status: actix_web::http::StatusCode, location: Option<String>, body: &T) -> Result<Self, APIError> {
+        Ok(Self {
+            status: status.as_u16(),
+            location,
+            body: serde_json::to_value(body)
+                .map_err(|_| APIError::new(500, "Internal Server Error", "We ran into a problem, this has been reported and will be looked at."))?,
+        })
+    }
+
+    /// Renders the response, marking it with an `Idempotent-Replayed` header if it is being replayed.
+    pub fn into_response(self, replayed: bool) -> actix_web::HttpResponse {
+        let mut response = actix_web::HttpResponse::build(actix_web::http::StatusCode::from_u16(self.status).unwrap_or(actix_web::http::StatusCode::OK));
+
+        if let Some(location) = self.location {
+            response.header("Location", location);
+        }
+
+        if replayed {
+            response.header("Idempotent-Replayed", "true");
+        }
+
+        response.json(self.body)
+    }
+}
+
+/// An `Idempotency-Key` provided by a caller, scoped to them and to the route it was sent to.
+///
+/// The key is only ever stored as a hash, and the response is sealed with a secret derived from
+/// it, since responses list the IDs (and receipt) of every copy of a report and would otherwise
+/// let anyone with access to the store link those copies together.
+pub struct IdempotencyKey {
+    hash: String,
+    secret: [u8; 32],
+}
+
+impl IdempotencyKey {
+    pub fn new(principal_id: u128, route: &str, key: &str) -> Self {
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(digest(&SHA256, format!("idempotency-secret\0{:0>32x}\0{}\0{}", principal_id, route, key).as_bytes()).as_ref());
+
+        Self {
+            hash: hex(digest(&SHA256, format!("idempotency-key\0{:0>32x}\0{}\0{}", principal_id, route, key).as_bytes()).as_ref()),
+            secret,
+        }
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Hashes a request's body so that it can be compared with later uses of the same key,
+    /// without the stored hash revealing what was submitted.
+    pub fn request_hash(&self, body: &[u8]) -> String {
+        let mut input = self.secret.to_vec();
+        input.extend_from_slice(body);
+        hex(digest(&SHA256, &input).as_ref())
+    }
+
+    pub fn seal(&self, response: &IdempotentResponse) -> Result<String, APIError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+
+        let sealed = serde_json::to_vec(response).ok()
+            .and_then(|mut sealed| self.cipher().seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed).ok().map(|_| sealed))
+            .ok_or_else(|| APIError::new(500, "Internal Server Error", "We ran into a problem, this has been reported and will be looked at."))?;
+
+        Ok(hex(&nonce) + &hex(&sealed))
+    }
+
+    /// Opens a response sealed with this key, returning `None` if it was sealed with another.
+    pub fn open(&self, sealed: &str) -> Option<IdempotentResponse> {
+        let mut sealed = unhex(sealed)?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&sealed[..NONCE_LEN]);
+
+        let response = self.cipher().open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed[NONCE_LEN..]).ok()?;
+        serde_json::from_slice(response).ok()
+    }
+
+    fn cipher(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &self.secret).expect("a 256-bit key"))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes().chunks(2)
+        .map(|pair| std::str::from_utf8(pair).ok()
+            .filter(|pair| pair.len() == 2)
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seals_responses() {
+        let key = IdempotencyKey::new(1, "/api/v1/reports", "retry-me");
+        let response = IdempotentResponse { status: 201, location: Some("/api/v1/report/1".into()), body: serde_json::json!({ "metric": "happy_sad", "value": 1.0 }) };
+
+        let sealed = key.seal(&response).expect("the response should be sealed");
+        assert!(!sealed.contains("happy_sad"), "the response should not be stored in the clear");
+        assert_eq!(key.open(&sealed), Some(response));
+
+        assert_eq!(IdempotencyKey::new(2, "/api/v1/reports", "retry-me").open(&sealed), None, "another principal should not be able to open the response");
+        assert_eq!(key.open("not sealed"), None);
+    }
+
+    #[test]
+    fn scopes_keys() {
+        let key = IdempotencyKey::new(1, "/api/v1/reports", "retry-me");
+
+        assert_eq!(key.hash(), IdempotencyKey::new(1, "/api/v1/reports", "retry-me").hash());
+        assert_ne!(key.hash(), IdempotencyKey::new(2, "/api/v1/reports", "retry-me").hash());
+        assert_ne!(key.hash(), IdempotencyKey::new(1, "/api/v1/team/00000000000000000000000000000007/reports", "retry-me").hash());
+        assert_ne!(key.request_hash(b"{}"), key.request_hash(b"{\"value\":1}"));
+    }
+}
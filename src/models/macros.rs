@@ -48,4 +48,30 @@ macro_rules! json_responder {
             }
         }
     };
+
+    ($t:ty => ($req:ident, $model:ident) -> $location:expr; etag = $etag:expr) => {
+        impl actix_web::Responder for $t {
+            type Error = actix_web::Error;
+            type Future = futures::future::Ready<Result<actix_web::HttpResponse, actix_web::Error>>;
+
+            fn respond_to(self, $req: &actix_web::HttpRequest) -> Self::Future {
+                let $model = &self;
+                let mut response = if $req.method() == http::Method::POST {
+                    let mut response = actix_web::HttpResponse::Created();
+                    response.header("Location", $location.expect("a location url").into_string());
+                    response
+                } else {
+                    actix_web::HttpResponse::Ok()
+                };
+
+                if let Some(etag) = $etag {
+                    response.header("ETag", etag);
+                }
+
+                futures::future::ready(Ok(response
+                    .content_type("application/json")
+                    .json(&self)))
+            }
+        }
+    };
 }
\ No newline at end of file
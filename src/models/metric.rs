@@ -0,0 +1,155 @@
+use crate::api::APIError;
+
+/// One of the metrics which a team's members may report on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MetricDefinition {
+    /// The name which reports use to refer to this metric.
+    pub name: String,
+    /// The human readable name shown to the team's members.
+    pub label: String,
+    #[serde(flatten)]
+    pub kind: MetricKind,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+}
+
+/// The values which a metric accepts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum MetricKind {
+    /// Either -1 or 1, such as a thumbs up or down.
+    Binary,
+    /// A whole number between `min` and `max`, inclusive.
+    Scale { min: i32, max: i32 },
+    /// Any finite number.
+    Continuous,
+    /// Exactly one of a list of options.
+    Categorical { options: Vec<String> },
+    /// One or more of a list of options, each chosen at most once.
+    MultiSelect { options: Vec<String> },
+}
+
+impl MetricDefinition {
+    /// Checks whether a report's value (or the options chosen for it) is one which this metric accepts.
+    pub fn accepts(&self, value: f32, chosen: &[String]) -> bool {
+        let valid_options = |options: &[String]| chosen.iter().all(|option| options.contains(option))
+            && chosen.iter().enumerate().all(|(i, option)| !chosen[..i].contains(option));
+
+        match &self.kind {
+            MetricKind::Binary => chosen.is_empty() && (value == 1.0 || value == -1.0),
+            MetricKind::Scale { min, max } => chosen.is_empty() && value.fract() == 0.0 && value >= *min as f32 && value <= *max as f32,
+            MetricKind::Continuous => chosen.is_empty() && value.is_finite(),
+            MetricKind::Categorical { options } => chosen.len() == 1 && valid_options(options),
+            MetricKind::MultiSelect { options } => !chosen.is_empty() && valid_options(options),
+        }
+    }
+
+    fn expected(&self) -> String {
+        match &self.kind {
+            MetricKind::Binary => "either -1 or 1".into(),
+            MetricKind::Scale { min, max } => format!("a whole number from {} to {}", min, max),
+            MetricKind::Continuous => "a finite number".into(),
+            MetricKind::Categorical { options } => format!("exactly one of the options {}", options.join(", ")),
+            MetricKind::MultiSelect { options } => format!("one or more of the options {}, without repeating any", options.join(", ")),
+        }
+    }
+}
+
+/// Ensures that a report matches one of a team's metrics, or that it at least has a finite value
+/// if the team hasn't registered any metrics. Options may only be chosen for the categorical and
+/// multi-select metrics a team has registered, in which case the value is ignored.
+pub fn validate_report(metrics: &[MetricDefinition], metric: &str, value: f32, options: &[String]) -> Result<(), APIError> {
+    if options.is_empty() && !value.is_finite() {
+        return Err(APIError::new(400, "Bad Request", "The value you provided is not a finite number. Please provide a valid value and try again."));
+    }
+
+    if metrics.is_empty() {
+        if !options.is_empty() {
+            return Err(APIError::new(400, "Bad Request", "Options may only be chosen for categorical and multi-select metrics, which this team has not registered. Please provide a value instead and try again."));
+        }
+
+        return Ok(());
+    }
+
+    match metrics.iter().find(|definition| definition.name == metric) {
+        Some(definition) if definition.accepts(value, options) => Ok(()),
+        Some(definition) => Err(APIError::new(400, "Bad Request", &format!(
+            "The value you provided for the {} metric is not valid. Please provide {} and try again.",
+            definition.name,
+            definition.expected()))),
+        None => Err(APIError::new(400, "Bad Request", &format!(
+            "The metric you provided is not one which this team tracks. Please use one of the following metrics: {}.",
+            metrics.iter().map(|definition| definition.name.as_str()).collect::<Vec<_>>().join(", ")))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics() -> Vec<MetricDefinition> {
+        vec![
+            MetricDefinition { name: "happy_sad".into(), label: "Happiness".into(), kind: MetricKind::Binary, description: String::new() },
+            MetricDefinition { name: "workload".into(), label: "Workload".into(), kind: MetricKind::Scale { min: 1, max: 5 }, description: String::new() },
+            MetricDefinition { name: "hours".into(), label: "Hours worked".into(), kind: MetricKind::Continuous, description: String::new() },
+            MetricDefinition { name: "mood".into(), label: "Mood".into(), kind: MetricKind::Categorical { options: vec!["calm".into(), "stressed".into()] }, description: String::new() },
+            MetricDefinition { name: "drains".into(), label: "What is draining you?".into(), kind: MetricKind::MultiSelect { options: vec!["meetings".into(), "on-call".into(), "deadlines".into()] }, description: String::new() },
+        ]
+    }
+
+    fn options(options: &[&str]) -> Vec<String> {
+        options.iter().map(|option| option.to_string()).collect()
+    }
+
+    #[test]
+    fn validates_reports() {
+        let metrics = metrics();
+
+        assert!(validate_report(&metrics, "happy_sad", -1.0, &[]).is_ok());
+        assert!(validate_report(&metrics, "happy_sad", 0.0, &[]).is_err());
+        assert!(validate_report(&metrics, "workload", 5.0, &[]).is_ok());
+        assert!(validate_report(&metrics, "workload", 2.5, &[]).is_err());
+        assert!(validate_report(&metrics, "workload", 6.0, &[]).is_err());
+        assert!(validate_report(&metrics, "hours", 37.5, &[]).is_ok());
+        assert!(validate_report(&metrics, "hours", f32::INFINITY, &[]).is_err());
+        assert!(validate_report(&[], "anything", f32::NAN, &[]).is_err());
+        assert!(validate_report(&[], "anything", 2.5, &[]).is_ok());
+
+        let err = validate_report(&metrics, "happy_sadd", 1.0, &[]).expect_err("an unknown metric should be rejected");
+        assert_eq!(err.code, 400);
+        assert!(err.message.contains("happy_sad, workload, hours"), "the error should list the allowed metrics: {}", err.message);
+    }
+
+    #[test]
+    fn validates_options() {
+        let metrics = metrics();
+
+        assert!(validate_report(&metrics, "mood", f32::NAN, &options(&["calm"])).is_ok());
+        assert!(validate_report(&metrics, "mood", f32::NAN, &options(&["calm", "stressed"])).is_err());
+        assert!(validate_report(&metrics, "mood", 1.0, &[]).is_err());
+        assert!(validate_report(&metrics, "drains", f32::NAN, &options(&["meetings", "on-call"])).is_ok());
+        assert!(validate_report(&metrics, "drains", f32::NAN, &options(&["meetings", "meetings"])).is_err());
+        assert!(validate_report(&metrics, "drains", f32::NAN, &options(&["commute"])).is_err());
+        assert!(validate_report(&metrics, "drains", f32::NAN, &[]).is_err());
+        assert!(validate_report(&metrics, "workload", 3.0, &options(&["calm"])).is_err());
+        assert!(validate_report(&[], "anything", 1.0, &options(&["calm"])).is_err());
+
+        let err = validate_report(&metrics, "drains", f32::NAN, &options(&["commute"])).expect_err("an unknown option should be rejected");
+        assert!(err.message.contains("meetings, on-call, deadlines"), "the error should list the allowed options: {}", err.message);
+
+        let json = serde_json::to_value(&metrics[4]).expect("the metric should serialize");
+        assert_eq!(json["kind"], "multiselect");
+        assert_eq!(json["options"], serde_json::json!(["meetings", "on-call", "deadlines"]));
+    }
+
+    #[test]
+    fn serializes_kinds() {
+        let json = serde_json::to_value(&metrics()[1]).expect("the metric should serialize");
+        assert_eq!(json, serde_json::json!({ "name": "workload", "label": "Workload", "kind": "scale", "min": 1, "max": 5 }));
+
+        let definition: MetricDefinition = serde_json::from_value(serde_json::json!({ "name": "happy_sad", "label": "Happiness", "kind": "binary", "description": "How are you feeling?" }))
+            .expect("the metric should deserialize");
+        assert_eq!(definition.kind, MetricKind::Binary);
+        assert_eq!(definition.description, "How are you feeling?");
+    }
+}
@@ -1,16 +1,30 @@
 #[macro_use] mod macros;
 
+mod alert;
 mod team;
 mod report;
+mod rollup;
+mod summary;
 mod team_assignment;
 mod health;
+mod idempotency;
+mod metric;
+mod page;
+mod privacy;
+mod receipt;
 mod user;
 
-use actix::prelude::*;
-
+pub use alert::*;
 pub use team::*;
 pub use health::*;
+pub use idempotency::*;
+pub use metric::*;
+pub use page::*;
+pub use privacy::*;
+pub use receipt::*;
 pub use report::*;
+pub use rollup::*;
+pub use summary::*;
 pub use team_assignment::*;
 pub use user::*;
 
@@ -21,13 +35,29 @@ pub fn new_id() -> u128 {
 
 #[derive(Clone)]
 pub struct GlobalState {
-    pub store: Addr<crate::store::Store>,
+    pub store: crate::store::Store,
+    /// How far in the past a report's timestamp may be, for reports which were queued by a client
+    /// while it was offline.
+    pub report_backfill: chrono::Duration,
 }
 
 impl GlobalState {
+    /// Creates the state with reports being backdated by up to the number of hours in the
+    /// `REPORT_BACKFILL_HOURS` environment variable, if it has been set.
     pub fn new() -> Self {
+        let backfill_hours = std::env::var("REPORT_BACKFILL_HOURS").ok().map(|hours| hours.parse()
+            .expect("Set the REPORT_BACKFILL_HOURS environment variable to a whole number of hours before starting the server."));
+
+        Self {
+            report_backfill: chrono::Duration::hours(backfill_hours.unwrap_or(DEFAULT_REPORT_BACKFILL_HOURS)),
+            ..Self::with_store(crate::store::Store::from_env())
+        }
+    }
+
+    pub fn with_store(store: crate::store::Store) -> Self {
         Self {
-            store: crate::store::Store::new().start(),
+            store,
+            report_backfill: chrono::Duration::hours(DEFAULT_REPORT_BACKFILL_HOURS),
         }
     }
 }
\ No newline at end of file
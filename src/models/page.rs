@@ -0,0 +1,50 @@
+use crate::api::APIError;
+
+/// A single page of results from a store, along with the opaque cursor which
+/// can be used to retrieve the next page (if there is one).
+#[derive(Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Default for Page<T> {
+    fn default() -> Self {
+        Self {
+            items: vec![],
+            next_cursor: None,
+        }
+    }
+}
+
+impl<T> Page<T> {
+    /// Builds a page from the items which follow the previous page's cursor, using the
+    /// key of the last item as the next cursor if there were more items than the limit.
+    pub fn from_items<K>(mut items: Vec<T>, limit: Option<usize>, key: K) -> Self
+    where
+        K: Fn(&T) -> u128
+    {
+        let next_cursor = match limit {
+            Some(limit) if items.len() > limit => {
+                items.truncate(limit);
+                items.last().map(|item| format!("{:0>32x}", key(item)))
+            },
+            _ => None
+        };
+
+        Self {
+            items,
+            next_cursor,
+        }
+    }
+
+    /// Parses a cursor which was previously returned as a page's `next_cursor`.
+    pub fn parse_cursor(cursor: &Option<String>) -> Result<Option<u128>, APIError> {
+        match cursor {
+            Some(cursor) => u128::from_str_radix(cursor, 16)
+                .map(Some)
+                .or(Err(APIError::new(400, "Bad Request", "The cursor you provided could not be parsed. Please use the cursor returned with the previous page of results."))),
+            None => Ok(None)
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use actix::prelude::*;
+use crate::api::APIError;
+use chrono::prelude::*;
+use rand::Rng;
+
+/// A query which spent some of a team's privacy budget.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrivacySpend {
+    pub team_id: u128,
+    pub spent_at: DateTime<Utc>,
+    pub epsilon: f64,
+}
+
+// Spends `epsilon` of a team's privacy budget, failing without spending anything if more than
+// `budget` would then have been spent since `since`. Spends made before `since` may be discarded.
+actor_message!(SpendPrivacyBudget(team_id: u128, epsilon: f64, budget: f64, since: Option<DateTime<Utc>>) -> PrivacyBudget);
+
+// Lists the spends of a team's privacy budget which are still being kept, ordered by when they were made.
+actor_message!(GetPrivacySpends(team_id: u128) -> Vec<PrivacySpend>);
+
+// Replaces the spends recorded against a team's privacy budget, so that they can be copied between stores.
+actor_message!(StorePrivacySpends(team_id: u128, spends: Vec<PrivacySpend>) -> ());
+
+/// How much of a team's privacy budget has been spent within its current window.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PrivacyBudget {
+    pub spent: f64,
+    pub remaining: f64,
+}
+
+impl PrivacyBudget {
+    /// Works out the budget which is left after spending `epsilon` on top of what has already
+    /// been `spent`, or fails if that would exceed the team's budget.
+    pub fn spend(spent: f64, epsilon: f64, budget: f64) -> Result<Self, APIError> {
+        // Allow for the rounding error which builds up when adding many fractional spends
+        if spent + epsilon > budget + 1e-9 {
+            return Err(APIError::new(429, "Too Many Requests", "This team has used up its privacy budget, so its reports cannot be summarized again until some of its earlier queries fall outside of the budget window."));
+        }
+
+        Ok(Self {
+            spent: spent + epsilon,
+            remaining: (budget - spent - epsilon).max(0.0),
+        })
+    }
+}
+
+/// Samples noise from a Laplace distribution centred on zero with the given scale.
+pub fn laplace_noise(scale: f64) -> f64 {
+    let mut rng = rand::thread_rng();
+    loop {
+        // The range includes -0.5, which would give infinite noise, so it is drawn again
+        if let Some(noise) = laplace_sample(scale, rng.gen_range(-0.5..0.5)) {
+            return noise;
+        }
+    }
+}
+
+/// Maps a sample drawn uniformly from the open interval (-0.5, 0.5) onto a Laplace distribution,
+/// returning `None` for samples outside of it.
+fn laplace_sample(scale: f64, u: f64) -> Option<f64> {
+    if u <= -0.5 || u >= 0.5 {
+        return None;
+    }
+
+    Some(-scale * u.signum() * (1.0 - 2.0 * u.abs()).ln())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spends_budget() {
+        assert_eq!(PrivacyBudget::spend(0.0, 0.5, 1.0).expect("the budget should allow it"), PrivacyBudget { spent: 0.5, remaining: 0.5 });
+        assert_eq!(PrivacyBudget::spend(0.5, 0.5, 1.0).expect("the budget should allow it").remaining, 0.0);
+        assert_eq!(PrivacyBudget::spend(1.0, 0.5, 1.0).expect_err("the budget should be exhausted").code, 429);
+    }
+
+    #[test]
+    fn samples_laplace_noise() {
+        let samples: Vec<f64> = (0..10000).map(|_| laplace_noise(2.0)).collect();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let mean_abs = samples.iter().map(|s| s.abs()).sum::<f64>() / samples.len() as f64;
+
+        // The mean absolute deviation of a Laplace distribution is its scale
+        assert!(mean.abs() < 0.2, "the noise should be centred on zero, but its mean was {}", mean);
+        assert!((mean_abs - 2.0).abs() < 0.2, "the noise should have a scale of 2, but it was {}", mean_abs);
+    }
+
+    #[test]
+    fn rejects_laplace_boundary() {
+        assert_eq!(laplace_sample(2.0, -0.5), None, "the lower bound of the range would give infinite noise");
+        assert_eq!(laplace_sample(2.0, 0.0), Some(0.0));
+        assert!(laplace_sample(2.0, -0.5 + f64::EPSILON).expect("samples just inside the range should be used").is_finite());
+    }
+}
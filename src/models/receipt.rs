@@ -0,0 +1,131 @@
+use actix::prelude::*;
+use crate::api::APIError;
+use super::{new_id, Report, ReportV1};
+use chrono::prelude::*;
+
+/// How long after a report is submitted its receipt may be used to retract or amend it.
+pub const RECEIPT_GRACE_MINUTES: i64 = 60;
+
+/// A secret handed to whoever submitted a report, which lets them retract or amend it without
+/// the report recording who they are.
+///
+/// Only a hash of the receipt is stored, and each team's copy of a report gets a different hash
+/// so that the copies can't be matched up. The time it was issued is part of the receipt (and
+/// so is covered by the hash) rather than being stored next to the report, where it would give
+/// away the report's exact timestamp.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Receipt {
+    secret: u128,
+    issued_at: i64,
+}
+
+impl Receipt {
+    pub fn new(issued_at: DateTime<Utc>) -> Self {
+        Self {
+            secret: new_id(),
+            issued_at: issued_at.timestamp(),
+        }
+    }
+
+    /// Parses a receipt which was handed out earlier, returning `None` if it is malformed.
+    pub fn parse(receipt: &str) -> Option<Self> {
+        if receipt.len() != 48 || !receipt.is_ascii() {
+            return None;
+        }
+
+        Some(Self {
+            secret: u128::from_str_radix(&receipt[..32], 16).ok()?,
+            issued_at: i64::from_str_radix(&receipt[32..], 16).ok()?,
+        })
+    }
+
+    /// Gets the hash which is stored next to a team's copy of the reports this receipt covers.
+    pub fn hash_for(&self, team_id: u128) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, format!("{}:{:0>32x}", self, team_id).as_bytes());
+        digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Checks whether the grace period in which this receipt may be used has passed.
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        now.timestamp() - self.issued_at > RECEIPT_GRACE_MINUTES * 60
+    }
+}
+
+impl std::fmt::Display for Receipt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:0>32x}{:0>16x}", self.secret, self.issued_at)
+    }
+}
+
+// Lists a team's reports (including those which are still staged) which were submitted with a receipt.
+actor_message!(GetReceiptReports(team_id: u128, receipt_hash: String) -> Vec<Report>);
+
+// Removes a team's reports (including those which are still staged) which were submitted with a receipt.
+actor_message!(RetractReports(team_id: u128, receipt_hash: String) -> Vec<Report>);
+
+// Changes the value and chosen options of a team's reports which were submitted with a receipt, limited to a single metric if one is given.
+actor_message!(AmendReports(team_id: u128, receipt_hash: String, metric: Option<String>, value: f32, options: Vec<String>) -> Vec<Report>);
+
+/// A request to retract or amend the reports which were submitted with a receipt.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReceiptV1 {
+    pub receipt: String,
+    /// Limits an amendment to the answer for this metric, when the receipt covers a survey response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metric: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<f32>,
+    /// The options to choose instead, when amending a categorical or multi-select metric.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl ReceiptV1 {
+    /// Parses the receipt, ensuring that it is still within its grace period.
+    pub fn parse(&self, now: DateTime<Utc>) -> Result<Receipt, APIError> {
+        let receipt = Receipt::parse(&self.receipt)
+            .ok_or_else(|| APIError::new(400, "Bad Request", "The receipt you provided is not valid. Please provide the receipt you were given when you submitted your report and try again."))?;
+
+        if receipt.expired(now) {
+            return Err(APIError::new(403, "Forbidden", &format!("The receipt you provided has expired. Reports may only be retracted or amended within {} minutes of being submitted.", RECEIPT_GRACE_MINUTES)));
+        }
+
+        Ok(receipt)
+    }
+}
+
+/// Attaches a receipt to reports which are being returned to the person who submitted them.
+pub fn with_receipt(reports: Vec<ReportV1>, receipt: &Receipt) -> Vec<ReportV1> {
+    reports.into_iter().map(|report| ReportV1 { receipt: Some(receipt.to_string()), ..report }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_receipts() {
+        let issued_at = Utc.ymd(2020, 3, 4).and_hms(14, 3, 17);
+        let receipt = Receipt::new(issued_at);
+
+        assert_eq!(Receipt::parse(&receipt.to_string()), Some(receipt));
+        assert_eq!(Receipt::parse("not a receipt"), None);
+        assert_eq!(Receipt::parse(&"z".repeat(48)), None);
+
+        assert!(!receipt.expired(issued_at + chrono::Duration::minutes(RECEIPT_GRACE_MINUTES)));
+        assert!(receipt.expired(issued_at + chrono::Duration::minutes(RECEIPT_GRACE_MINUTES + 1)));
+    }
+
+    #[test]
+    fn hashes_receipts_per_team() {
+        let receipt = Receipt::new(Utc::now());
+
+        assert_eq!(receipt.hash_for(7).len(), 64);
+        assert_eq!(receipt.hash_for(7), receipt.hash_for(7));
+        assert_ne!(receipt.hash_for(7), receipt.hash_for(8), "each team's copy should have its own hash");
+        assert_ne!(receipt.hash_for(7), Receipt::new(Utc::now()).hash_for(7));
+
+        let forged = Receipt { issued_at: receipt.issued_at + 3600, ..receipt };
+        assert_ne!(receipt.hash_for(7), forged.hash_for(7), "extending a receipt should change its hash");
+    }
+}
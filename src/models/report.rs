@@ -1,25 +1,99 @@
 use actix::prelude::*;
 use crate::api::APIError;
-use super::new_id;
+use super::{new_id, Page};
 use chrono::prelude::*;
+use std::collections::BTreeMap;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// How far in the past a report's timestamp may be when `REPORT_BACKFILL_HOURS` hasn't been set.
+pub const DEFAULT_REPORT_BACKFILL_HOURS: i64 = 72;
+
+/// How far ahead of the server a client's clock may be before its reports are rejected as being
+/// made in the future.
+pub const MAX_CLOCK_SKEW_SECONDS: i64 = 300;
+
+/// The most reports which may be uploaded in a single bulk submission.
+pub const MAX_BULK_REPORTS: usize = 50;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Report {
     pub id: u128,
     pub team_id: u128,
     pub timestamp: DateTime<Utc>,
     pub metric: String,
-    pub value: f32
+    pub value: f32,
+    /// The survey response which this report answered a question of, shared by every answer in it.
+    #[serde(default)]
+    pub response_id: Option<u128>,
+    /// The hash of the receipt which lets the report's submitter retract or amend it.
+    #[serde(default)]
+    pub receipt_hash: Option<String>,
+    /// The options chosen for a categorical or multi-select metric, in which case the value is
+    /// the number of options chosen.
+    #[serde(default)]
+    pub options: Vec<String>,
+    /// When the report was placed in its team's staging area, which is only kept until it is
+    /// published. Its timestamp can't be used instead, since that may have been coarsened or backdated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staged_at: Option<DateTime<Utc>>,
+}
+
+impl Report {
+    /// Checks whether a staged report is due to be published by a release made at `before`.
+    /// Reports staged by earlier versions don't record when they were staged, so their
+    /// timestamp is used instead.
+    pub fn staged_before(&self, before: Option<DateTime<Utc>>) -> bool {
+        before.map(|before| self.staged_at.unwrap_or(self.timestamp) < before).unwrap_or(true)
+    }
 }
 
 actor_message!(GetReport(id: u128, team: u128) -> Report);
 
-actor_message!(GetReports(team: u128, metric: Option<String>, after: Option<DateTime<Utc>>) -> Vec<Report>);
+actor_message!(GetReports(team: u128, metric: Option<String>, after: Option<DateTime<Utc>>, limit: Option<usize>, cursor: Option<String>) -> Page<Report>);
 
-actor_message!(StoreReport(id: u128, team: u128, metric: String, timestamp: Option<DateTime<Utc>>, value: f32) -> Report);
+actor_message!(StoreReport(id: u128, team: u128, metric: String, timestamp: Option<DateTime<Utc>>, value: f32, options: Vec<String>) -> Report);
+
+actor_message!(StoreReports(reports: Vec<Report>) -> ReportBatch);
 
 actor_message!(RemoveReport(id: u128, team: u128) -> ());
 
+actor_message!(PurgeReports() -> Vec<ReportPurge>);
+
+// Holds reports in their teams' staging areas, where they can't be read until they are published.
+// Like StoreReports, backends which can't stage every team's reports at once list those which failed.
+actor_message!(StageReports(reports: Vec<Report>) -> ReportBatch);
+
+// Lists the reports held in a team's staging area which are yet to be published.
+actor_message!(GetStagedReports(team_id: u128) -> Vec<Report>);
+
+// Publishes the reports staged for a team with a timestamp before `before` (or all of them),
+// but only once there are at least `min_reports` of them, returning those which were published.
+actor_message!(PublishStagedReports(team_id: u128, before: Option<DateTime<Utc>>, min_reports: usize) -> Vec<Report>);
+
+/// The outcome of storing a batch of reports.
+///
+/// Backends which can write the batch atomically either store every report or return an
+/// error, while those which cannot will list the reports which failed to be stored.
+#[derive(Debug, Default)]
+pub struct ReportBatch {
+    pub stored: Vec<Report>,
+    pub failed: Vec<ReportFailure>,
+}
+
+/// The reports (and daily rollups) which were removed from a team because they had outlived
+/// its retention period.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportPurge {
+    pub team_id: u128,
+    pub before: DateTime<Utc>,
+    pub reports: usize,
+    pub rollups: usize,
+}
+
+#[derive(Debug)]
+pub struct ReportFailure {
+    pub report: Report,
+    pub error: APIError,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ReportV1 {
@@ -27,7 +101,23 @@ pub struct ReportV1 {
     pub id: Option<String>,
     pub timestamp: Option<String>,
     pub metric: String,
+    /// May be omitted for categorical and multi-select metrics, whose value is the number of options chosen.
+    #[serde(default = "missing_value")]
     pub value: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    /// The receipt which lets the report's submitter retract or amend it, only returned when it is submitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<String>,
+    /// The options chosen for a categorical or multi-select metric.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+/// Reports which don't provide a value are given one which isn't finite, so that they are
+/// rejected unless they are answering a categorical or multi-select metric.
+fn missing_value() -> f32 {
+    f32::NAN
 }
 
 json_responder!(ReportV1 => (req, model) -> if req.uri().path().contains("/team/") {
@@ -47,7 +137,52 @@ impl From<Report> for ReportV1 {
             timestamp: Some(report.timestamp.to_rfc3339()),
             metric: report.metric.clone(),
             value: report.value,
+            response: report.response_id.map(|id| format!("{:0>32x}", id)),
+            receipt: None,
+            options: report.options,
+        }
+    }
+}
+
+impl ReportV1 {
+    /// Gets the value to store for the report, which is the number of options chosen when it
+    /// answers a categorical or multi-select metric.
+    pub fn report_value(&self) -> f32 {
+        if self.options.is_empty() {
+            self.value
+        } else {
+            self.options.len() as f32
+        }
+    }
+
+    /// Gets the options chosen for the report in a consistent order, so that the order in which
+    /// they were chosen isn't kept.
+    pub fn report_options(&self) -> Vec<String> {
+        let mut options = self.options.clone();
+        options.sort();
+        options
+    }
+
+    /// Gets the time at which the report was made, which is its timestamp if one was provided and
+    /// `now` otherwise. Reports may be backdated by up to `backfill`, so that those queued while a
+    /// client was offline keep the time they were made, but may not be made in the future.
+    pub fn reported_at(&self, now: DateTime<Utc>, backfill: chrono::Duration) -> Result<DateTime<Utc>, APIError> {
+        let timestamp = match &self.timestamp {
+            Some(timestamp) => DateTime::parse_from_rfc3339(timestamp)
+                .map(|dt| dt.with_timezone(&Utc))
+                .map_err(|_| APIError::new(400, "Bad Request", "The timestamp you provided could not be parsed. Please provide an RFC 3339 timestamp and try again."))?,
+            None => return Ok(now),
+        };
+
+        if timestamp > now + chrono::Duration::seconds(MAX_CLOCK_SKEW_SECONDS) {
+            return Err(APIError::new(400, "Bad Request", "The timestamp you provided is in the future. Please check your device's clock and try again."));
+        }
+
+        if timestamp < now - backfill {
+            return Err(APIError::new(400, "Bad Request", &format!("The timestamp you provided is too far in the past. Reports may only be submitted up to {} hours after they were made.", backfill.num_hours())));
         }
+
+        Ok(timestamp.min(now))
     }
 }
 
@@ -58,7 +193,113 @@ impl Into<Report> for ReportV1 {
             team_id: self.team.clone().and_then(|id| u128::from_str_radix(&id, 16).ok()).unwrap_or_default(),
             timestamp: self.timestamp.clone().and_then(|ts| DateTime::parse_from_rfc3339(ts.as_str()).ok()).map(|dt| dt.with_timezone(&Utc)).unwrap_or_else(|| Utc::now()),
             metric: self.metric.clone(),
-            value: self.value.clone(),
+            value: self.report_value(),
+            response_id: self.response.clone().and_then(|id| u128::from_str_radix(&id, 16).ok()),
+            receipt_hash: None,
+            options: self.report_options(),
+            staged_at: None,
         }
     }
-}
\ No newline at end of file
+}
+
+/// The answers to several of a team's metrics, submitted together as a single survey response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseV1 {
+    pub id: Option<String>,
+    pub team: Option<String>,
+    pub timestamp: Option<String>,
+    pub answers: BTreeMap<String, AnswerV1>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<String>,
+}
+
+json_responder!(ResponseV1);
+
+/// A single answer in a survey response, which is either a value or the options chosen for a
+/// categorical or multi-select metric.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AnswerV1 {
+    Value(f32),
+    Options(Vec<String>),
+}
+
+impl AnswerV1 {
+    /// Gets the value of the answer, which is the number of options chosen if it chose any.
+    /// Answers which chose no options are given a value which isn't finite, so that they are rejected.
+    pub fn value(&self) -> f32 {
+        match self {
+            AnswerV1::Value(value) => *value,
+            AnswerV1::Options(options) if options.is_empty() => f32::NAN,
+            AnswerV1::Options(options) => options.len() as f32,
+        }
+    }
+
+    /// Gets the options chosen for the answer in a consistent order.
+    pub fn options(&self) -> Vec<String> {
+        match self {
+            AnswerV1::Value(_) => vec![],
+            AnswerV1::Options(options) => {
+                let mut options = options.clone();
+                options.sort();
+                options
+            },
+        }
+    }
+}
+
+impl From<&Report> for AnswerV1 {
+    fn from(report: &Report) -> Self {
+        if report.options.is_empty() {
+            AnswerV1::Value(report.value)
+        } else {
+            AnswerV1::Options(report.options.clone())
+        }
+    }
+}
+
+/// The response to a batch of reports which could only be partially stored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportBatchV1 {
+    pub reports: Vec<ReportV1>,
+    pub failures: Vec<ReportFailureV1>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportFailureV1 {
+    pub team: String,
+    #[serde(flatten)]
+    pub error: APIError,
+}
+
+impl From<ReportBatch> for ReportBatchV1 {
+    fn from(batch: ReportBatch) -> Self {
+        Self {
+            reports: batch.stored.into_iter().map(|report| report.into()).collect(),
+            failures: batch.failed.into_iter().map(|failure| ReportFailureV1 {
+                team: format!("{:0>32x}", failure.report.team_id),
+                error: failure.error,
+            }).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reported_at() {
+        let now = Utc.ymd(2020, 3, 4).and_hms(14, 0, 0);
+        let backfill = chrono::Duration::hours(DEFAULT_REPORT_BACKFILL_HOURS);
+        let report = |timestamp: Option<DateTime<Utc>>| ReportV1 { id: None, team: None, timestamp: timestamp.map(|ts| ts.to_rfc3339()), metric: "test".into(), value: 1.0, response: None, receipt: None, options: vec![] };
+
+        assert_eq!(report(None).reported_at(now, backfill).unwrap(), now);
+        assert_eq!(report(Some(now - chrono::Duration::hours(5))).reported_at(now, backfill).unwrap(), now - chrono::Duration::hours(5));
+        assert_eq!(report(Some(now + chrono::Duration::seconds(30))).reported_at(now, backfill).unwrap(), now, "a clock which is slightly ahead should be tolerated");
+
+        assert_eq!(report(Some(now + chrono::Duration::hours(1))).reported_at(now, backfill).unwrap_err().code, 400);
+        assert_eq!(report(Some(now - backfill - chrono::Duration::seconds(1))).reported_at(now, backfill).unwrap_err().code, 400);
+        assert_eq!(ReportV1 { timestamp: Some("yesterday".into()), ..report(None) }.reported_at(now, backfill).unwrap_err().code, 400);
+    }
+}
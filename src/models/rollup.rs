@@ -0,0 +1,212 @@
+use actix::prelude::*;
+use crate::api::APIError;
+use super::Report;
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+
+/// The aggregate of a team's reports for a single metric over one (UTC) day, which is kept
+/// in place of the raw reports once they are old enough to be rolled up.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReportRollup {
+    pub team_id: u128,
+    pub metric: String,
+    /// The start of the day which this rollup covers.
+    pub day: DateTime<Utc>,
+    pub count: u64,
+    pub sum: f64,
+    pub min: f32,
+    pub max: f32,
+    pub sum_squares: f64,
+    /// The number of times each option was chosen, for categorical and multi-select metrics.
+    #[serde(default)]
+    pub option_counts: BTreeMap<String, u64>,
+}
+
+impl ReportRollup {
+    /// Gets the start of the day on which the given time falls.
+    pub fn day_of(timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        timestamp.date().and_hms(0, 0, 0)
+    }
+
+    /// Rolls up a single report, clamping its value to `bound` if one is given so that no report
+    /// can contribute more than that to the rollup.
+    pub fn from_report(report: &Report, bound: Option<f64>) -> Self {
+        let value = match bound {
+            Some(bound) => (report.value as f64).clamp(-bound, bound) as f32,
+            None => report.value,
+        };
+
+        Self {
+            team_id: report.team_id,
+            metric: report.metric.clone(),
+            day: ReportRollup::day_of(report.timestamp),
+            count: 1,
+            sum: value as f64,
+            min: value,
+            max: value,
+            sum_squares: (value as f64).powi(2),
+            option_counts: report.options.iter().map(|option| (option.clone(), 1)).collect(),
+        }
+    }
+
+    /// Whether the whole of the day this rollup covers falls before the given time.
+    pub fn ends_before(&self, before: DateTime<Utc>) -> bool {
+        self.day + chrono::Duration::days(1) <= before
+    }
+
+    /// Combines another rollup for the same team, metric and day into this one.
+    pub fn merge(&mut self, other: &ReportRollup) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum_squares += other.sum_squares;
+
+        for (option, count) in other.option_counts.iter() {
+            *self.option_counts.entry(option.clone()).or_insert(0) += count;
+        }
+    }
+
+    /// Rolls up a set of reports (and any existing rollups which they should be combined
+    /// with) into a single rollup for each team, metric and day, ordered by those fields.
+    /// Each report's value is clamped to the bound returned for it, if there is one.
+    pub fn combine<'a, R, B>(existing: R, reports: &[Report], bound: B) -> Vec<ReportRollup>
+    where
+        R: IntoIterator<Item = &'a ReportRollup>,
+        B: Fn(&Report) -> Option<f64>,
+    {
+        let mut rollups: BTreeMap<(u128, String, DateTime<Utc>), ReportRollup> = BTreeMap::new();
+
+        for rollup in existing.into_iter().cloned().chain(reports.iter().map(|report| ReportRollup::from_report(report, bound(report)))) {
+            match rollups.get_mut(&(rollup.team_id, rollup.metric.clone(), rollup.day)) {
+                Some(combined) => combined.merge(&rollup),
+                None => {
+                    rollups.insert((rollup.team_id, rollup.metric.clone(), rollup.day), rollup);
+                }
+            }
+        }
+
+        rollups.into_values().collect()
+    }
+}
+
+// Replaces every report made before the start of the day on which `before` falls with a
+// rollup for its team, metric and day, combining them with any existing rollups.
+actor_message!(RollupReports(before: Option<DateTime<Utc>>) -> ReportCompaction);
+
+actor_message!(GetReportRollups(team: u128, metric: Option<String>, after: Option<DateTime<Utc>>) -> Vec<ReportRollup>);
+
+// Stores each rollup, replacing any existing rollup for the same team, metric and day.
+actor_message!(StoreReportRollups(rollups: Vec<ReportRollup>) -> ());
+
+/// The number of reports which were replaced by rollups, and the number of rollups they
+/// were combined into.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReportCompaction {
+    pub reports: usize,
+    pub rollups: usize,
+}
+
+/// The daily history of a metric, combining rollups with the reports which are yet to be rolled up.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportHistoryV1 {
+    pub team: String,
+    pub metric: String,
+    pub day: String,
+    pub count: u64,
+    pub mean: f64,
+    pub min: f32,
+    pub max: f32,
+    pub sum: f64,
+    #[serde(rename = "sumSquares")]
+    pub sum_squares: f64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, u64>,
+}
+
+impl From<ReportRollup> for ReportHistoryV1 {
+    fn from(rollup: ReportRollup) -> Self {
+        Self {
+            team: format!("{:0>32x}", rollup.team_id),
+            metric: rollup.metric.clone(),
+            day: rollup.day.format("%Y-%m-%d").to_string(),
+            count: rollup.count,
+            mean: if rollup.count > 0 { rollup.sum / rollup.count as f64 } else { 0.0 },
+            min: rollup.min,
+            max: rollup.max,
+            sum: rollup.sum,
+            sum_squares: rollup.sum_squares,
+            options: rollup.option_counts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(id: u128, metric: &str, timestamp: DateTime<Utc>, value: f32) -> Report {
+        Report { id, team_id: 7, metric: metric.into(), timestamp, value, response_id: None, receipt_hash: None, options: vec![], staged_at: None }
+    }
+
+    #[test]
+    fn combines_reports_by_day() {
+        let day = Utc.ymd(2020, 3, 1);
+        let existing = ReportRollup::from_report(&report(1, "happy_sad", day.and_hms(8, 0, 0), 2.0), None);
+
+        let rollups = ReportRollup::combine(&[existing], &[
+            report(2, "happy_sad", day.and_hms(23, 59, 59), 4.0),
+            report(3, "happy_sad", day.succ().and_hms(0, 0, 0), 1.0),
+            report(4, "workload", day.and_hms(12, 0, 0), 3.0),
+        ], |_| None);
+
+        assert_eq!(rollups.len(), 3);
+        assert_eq!(rollups[0], ReportRollup {
+            team_id: 7,
+            metric: "happy_sad".into(),
+            day: day.and_hms(0, 0, 0),
+            count: 2,
+            sum: 6.0,
+            min: 2.0,
+            max: 4.0,
+            sum_squares: 20.0,
+            option_counts: BTreeMap::new(),
+        });
+        assert_eq!(rollups[1].day, day.succ().and_hms(0, 0, 0));
+        assert_eq!(rollups[2].metric, "workload");
+    }
+
+    #[test]
+    fn clamps_values() {
+        let day = Utc.ymd(2020, 3, 1);
+
+        let rollups = ReportRollup::combine(&[], &[
+            report(1, "happy_sad", day.and_hms(8, 0, 0), 1e6),
+            report(2, "happy_sad", day.and_hms(9, 0, 0), -0.5),
+            report(3, "workload", day.and_hms(9, 0, 0), 1e6),
+        ], |r| if r.metric == "happy_sad" { Some(1.0) } else { None });
+
+        assert_eq!((rollups[0].sum, rollups[0].min, rollups[0].max, rollups[0].sum_squares), (0.5, -0.5, 1.0, 1.25));
+        assert_eq!(rollups[1].sum, 1e6, "values without a bound should be kept as they are");
+    }
+
+    #[test]
+    fn counts_options() {
+        let day = Utc.ymd(2020, 3, 1);
+        let chose = |id: u128, options: &[&str]| Report {
+            value: options.len() as f32,
+            options: options.iter().map(|o| o.to_string()).collect(),
+            ..report(id, "blockers", day.and_hms(9, 0, 0), 0.0)
+        };
+
+        let rollups = ReportRollup::combine(&[ReportRollup::from_report(&chose(1, &["meetings"]), None)], &[
+            chose(2, &["meetings", "tooling"]),
+            chose(3, &["tooling"]),
+        ], |_| None);
+
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].count, 3);
+        assert_eq!(rollups[0].option_counts.get("meetings"), Some(&2));
+        assert_eq!(rollups[0].option_counts.get("tooling"), Some(&2));
+    }
+}
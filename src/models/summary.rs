@@ -0,0 +1,438 @@
+use super::{laplace_noise, DifferentialPrivacy, Report, ReportRollup};
+use chrono::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The period of time which each entry in a report summary covers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SummaryBucket {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl SummaryBucket {
+    /// Gets the start of the bucket which the given time falls into, with weeks starting on Monday.
+    pub fn start_of(self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        let date = timestamp.date();
+        match self {
+            SummaryBucket::Day => date.and_hms(0, 0, 0),
+            SummaryBucket::Week => (date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64)).and_hms(0, 0, 0),
+            SummaryBucket::Month => Utc.ymd(date.year(), date.month(), 1).and_hms(0, 0, 0),
+        }
+    }
+}
+
+/// The aggregate statistics for a single metric over one bucket of time.
+///
+/// The median can only be calculated from raw reports, so it is omitted for any bucket
+/// which includes reports that have already been rolled up. Once noise has been added, only
+/// the count and mean are kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportSummary {
+    pub metric: String,
+    pub start: DateTime<Utc>,
+    pub count: u64,
+    pub mean: f64,
+    pub median: Option<f64>,
+    pub stddev: Option<f64>,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    /// The number of times each option was chosen, for categorical and multi-select metrics.
+    pub options: BTreeMap<String, u64>,
+    /// The epsilon of the Laplace noise which was added to the count and mean, if any.
+    pub noise: Option<f64>,
+}
+
+impl ReportSummary {
+    /// Counts the pairs of metric and bucket which summarizing these rollups and reports adds noise
+    /// to, each of which spends `epsilon` from the team's privacy budget.
+    pub fn cells(rollups: &[ReportRollup], reports: &[Report], bucket: SummaryBucket) -> usize {
+        rollups.iter().map(|rollup| (rollup.metric.as_str(), bucket.start_of(rollup.day)))
+            .chain(reports.iter().map(|report| (report.metric.as_str(), bucket.start_of(report.timestamp))))
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+
+    /// Summarizes a team's rollups and raw reports into buckets for each metric, ordered by
+    /// metric and then by the start of each bucket. Buckets holding fewer than `min_group_size`
+    /// reports are merged with their neighbours.
+    ///
+    /// When the team uses differential privacy, each value is clamped to its `value_bound` and
+    /// Laplace noise is added to every bucket's count and sum before any buckets are merged, so
+    /// that the merging only ever sees noisy counts. The privacy loss is split evenly between the
+    /// count and the sum, so each bucket costs `epsilon`; since one person (or one survey response)
+    /// may contribute to every metric and bucket, the whole summary costs `epsilon` for each of
+    /// its [ReportSummary::cells]. Rollups keep each metric's own range, so their values can no longer be
+    /// clamped one by one; instead their sums are clamped to the largest which their count allows.
+    pub fn summarize(rollups: &[ReportRollup], reports: &[Report], bucket: SummaryBucket, min_group_size: u64, privacy: Option<&DifferentialPrivacy>) -> Vec<ReportSummary> {
+        let mut buckets: BTreeMap<(String, DateTime<Utc>), Bucket> = BTreeMap::new();
+        let bound = privacy.map(|dp| dp.value_bound);
+
+        for rollup in rollups {
+            let rollup = bounded(rollup.clone(), bound);
+            let key = (rollup.metric.clone(), bucket.start_of(rollup.day));
+            match buckets.get_mut(&key) {
+                Some(entry) => {
+                    entry.combined.merge(&rollup);
+                    entry.rolled_up = true;
+                },
+                None => {
+                    buckets.insert(key, Bucket { combined: rollup, values: vec![], rolled_up: true, noisy: None });
+                }
+            }
+        }
+
+        for report in reports {
+            let rollup = ReportRollup::from_report(report, bound);
+            let key = (report.metric.clone(), bucket.start_of(report.timestamp));
+            match buckets.get_mut(&key) {
+                Some(entry) => {
+                    entry.combined.merge(&rollup);
+                    entry.values.push(report.value);
+                },
+                None => {
+                    buckets.insert(key, Bucket { combined: rollup, values: vec![report.value], rolled_up: false, noisy: None });
+                }
+            }
+        }
+
+        if let Some(dp) = privacy {
+            let scale = 2.0 / dp.epsilon;
+            for entry in buckets.values_mut() {
+                entry.noisy = Some(NoisyTotals {
+                    count: entry.combined.count as f64 + laplace_noise(scale),
+                    sum: entry.combined.sum + laplace_noise(scale * dp.value_bound),
+                });
+            }
+        }
+
+        let buckets = coalesce(
+            buckets.into_iter().map(|((_, start), bucket)| (start, bucket)).collect(),
+            min_group_size,
+            |(_, a), (_, b)| a.combined.metric == b.combined.metric,
+            |(_, bucket)| bucket.count(),
+            |(_, into), (_, bucket)| {
+                into.combined.merge(&bucket.combined);
+                into.values.extend(bucket.values);
+                into.rolled_up |= bucket.rolled_up;
+                into.noisy = match (into.noisy, bucket.noisy) {
+                    (Some(a), Some(b)) => Some(NoisyTotals { count: a.count + b.count, sum: a.sum + b.sum }),
+                    _ => None,
+                };
+            });
+
+        buckets.into_iter().map(|(start, entry)| {
+            if let (Some(noisy), Some(dp)) = (entry.noisy, privacy) {
+                return ReportSummary {
+                    metric: entry.combined.metric.clone(),
+                    start,
+                    count: entry.count(),
+                    mean: (noisy.sum / noisy.count.max(1.0)).clamp(-dp.value_bound, dp.value_bound),
+                    median: None,
+                    stddev: None,
+                    min: None,
+                    max: None,
+                    options: BTreeMap::new(),
+                    noise: Some(dp.epsilon),
+                };
+            }
+
+            let Bucket { combined, values, rolled_up, .. } = entry;
+            let count = combined.count.max(1) as f64;
+            let mean = combined.sum / count;
+
+            ReportSummary {
+                metric: combined.metric.clone(),
+                start,
+                count: combined.count,
+                mean,
+                median: if rolled_up { None } else { median(values) },
+                stddev: Some((combined.sum_squares / count - mean.powi(2)).max(0.0).sqrt()),
+                min: Some(combined.min),
+                max: Some(combined.max),
+                options: combined.option_counts,
+                noise: None,
+            }
+        }).collect()
+    }
+}
+
+/// The reports which have been combined into a bucket, along with their raw values if none
+/// of them have been rolled up.
+struct Bucket {
+    combined: ReportRollup,
+    values: Vec<f32>,
+    rolled_up: bool,
+    /// The bucket's count and sum with noise added, when the team uses differential privacy.
+    noisy: Option<NoisyTotals>,
+}
+
+impl Bucket {
+    /// The number of reports in the bucket, which is the noisy estimate if noise has been added.
+    fn count(&self) -> u64 {
+        match self.noisy {
+            Some(noisy) => noisy.count.round().max(0.0) as u64,
+            None => self.combined.count,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct NoisyTotals {
+    count: f64,
+    sum: f64,
+}
+
+/// Clamps a rollup's sum so that no report in it can contribute more than `bound`, if one is given.
+fn bounded(mut rollup: ReportRollup, bound: Option<f64>) -> ReportRollup {
+    if let Some(bound) = bound {
+        let limit = bound * rollup.count as f64;
+        rollup.sum = rollup.sum.clamp(-limit, limit);
+    }
+
+    rollup
+}
+
+/// Merges each group built from fewer than `min_size` reports into the groups which follow it
+/// in the same series, keeping the position of the earliest. A small group at the end of a series
+/// is merged into the one before it instead, while a series which never reaches `min_size` is
+/// suppressed entirely.
+pub fn coalesce<T, S, C, M>(groups: Vec<T>, min_size: u64, same_series: S, size: C, merge: M) -> Vec<T>
+where
+    S: Fn(&T, &T) -> bool,
+    C: Fn(&T) -> u64,
+    M: Fn(&mut T, T),
+{
+    let mut coalesced: Vec<T> = vec![];
+    let mut pending: Option<T> = None;
+
+    for group in groups {
+        pending = match pending.take() {
+            Some(mut into) if same_series(&into, &group) => {
+                merge(&mut into, group);
+                Some(into)
+            },
+            Some(small) => {
+                merge_remainder(&mut coalesced, small, &same_series, &merge);
+                Some(group)
+            },
+            None => Some(group),
+        };
+
+        if let Some(group) = pending.take() {
+            if size(&group) >= min_size {
+                coalesced.push(group);
+            } else {
+                pending = Some(group);
+            }
+        }
+    }
+
+    if let Some(small) = pending {
+        merge_remainder(&mut coalesced, small, &same_series, &merge);
+    }
+
+    coalesced
+}
+
+/// Merges a group which is too small to stand on its own into the previous group of its series, if there is one.
+fn merge_remainder<T, S: Fn(&T, &T) -> bool, M: Fn(&mut T, T)>(coalesced: &mut [T], small: T, same_series: &S, merge: &M) {
+    match coalesced.last_mut() {
+        Some(last) if same_series(last, &small) => merge(last, small),
+        _ => {}
+    }
+}
+
+fn median(mut values: Vec<f32>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        Some((values[middle - 1] as f64 + values[middle] as f64) / 2.0)
+    } else {
+        Some(values[middle] as f64)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportSummaryV1 {
+    pub metric: String,
+    pub start: String,
+    pub count: u64,
+    pub mean: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub median: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stddev: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f32>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, u64>,
+    /// Describes the noise which was added to the count and mean, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noise: Option<SummaryNoiseV1>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SummaryNoiseV1 {
+    pub mechanism: String,
+    pub epsilon: f64,
+}
+
+impl From<ReportSummary> for ReportSummaryV1 {
+    fn from(summary: ReportSummary) -> Self {
+        Self {
+            metric: summary.metric,
+            start: summary.start.format("%Y-%m-%d").to_string(),
+            count: summary.count,
+            mean: summary.mean,
+            median: summary.median,
+            stddev: summary.stddev,
+            min: summary.min,
+            max: summary.max,
+            options: summary.options,
+            noise: summary.noise.map(|epsilon| SummaryNoiseV1 { mechanism: "laplace".into(), epsilon }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(id: u128, timestamp: DateTime<Utc>, value: f32) -> Report {
+        Report { id, team_id: 7, metric: "happy_sad".into(), timestamp, value, response_id: None, receipt_hash: None, options: vec![], staged_at: None }
+    }
+
+    #[test]
+    fn bucket_starts() {
+        let timestamp = Utc.ymd(2020, 3, 5).and_hms(13, 30, 0);
+
+        assert_eq!(SummaryBucket::Day.start_of(timestamp), Utc.ymd(2020, 3, 5).and_hms(0, 0, 0));
+        assert_eq!(SummaryBucket::Week.start_of(timestamp), Utc.ymd(2020, 3, 2).and_hms(0, 0, 0));
+        assert_eq!(SummaryBucket::Month.start_of(timestamp), Utc.ymd(2020, 3, 1).and_hms(0, 0, 0));
+    }
+
+    #[test]
+    fn counts_cells() {
+        let day = Utc.ymd(2020, 3, 2);
+        let mut reports = vec![report(1, day.and_hms(9, 0, 0), 1.0), report(2, day.and_hms(17, 0, 0), -1.0), report(3, day.succ().and_hms(9, 0, 0), 1.0)];
+        reports.push(Report { metric: "workload".into(), ..report(4, day.and_hms(9, 0, 0), 3.0) });
+
+        assert_eq!(ReportSummary::cells(&[], &reports, SummaryBucket::Day), 3, "each metric should cost a cell for each day it was reported on");
+        assert_eq!(ReportSummary::cells(&[], &reports, SummaryBucket::Week), 2);
+        assert_eq!(ReportSummary::cells(&[], &[], SummaryBucket::Day), 0);
+    }
+
+    #[test]
+    fn summarizes_reports() {
+        let day = Utc.ymd(2020, 3, 2);
+        let summaries = ReportSummary::summarize(&[], &[
+            report(1, day.and_hms(9, 0, 0), 1.0),
+            report(2, day.and_hms(10, 0, 0), 2.0),
+            report(3, day.and_hms(11, 0, 0), 4.0),
+            report(4, day.and_hms(12, 0, 0), 5.0),
+            report(5, (day + chrono::Duration::days(7)).and_hms(9, 0, 0), 3.0),
+        ], SummaryBucket::Week, 1, None);
+
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].start, day.and_hms(0, 0, 0));
+        assert_eq!(summaries[0].count, 4);
+        assert_eq!(summaries[0].mean, 3.0);
+        assert_eq!(summaries[0].median, Some(3.0));
+        assert_eq!(summaries[0].stddev, Some(2.5f64.sqrt()));
+        assert_eq!((summaries[0].min, summaries[0].max), (Some(1.0), Some(5.0)));
+        assert_eq!(summaries[1].count, 1);
+        assert_eq!(summaries[1].stddev, Some(0.0));
+    }
+
+    #[test]
+    fn omits_medians_for_rollups() {
+        let day = Utc.ymd(2020, 3, 2);
+        let rollup = ReportRollup::from_report(&report(1, day.and_hms(9, 0, 0), 1.0), None);
+
+        let summaries = ReportSummary::summarize(&[rollup], &[report(2, day.and_hms(10, 0, 0), 3.0)], SummaryBucket::Month, 1, None);
+
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].count, 2);
+        assert_eq!(summaries[0].mean, 2.0);
+        assert_eq!(summaries[0].median, None);
+    }
+
+    #[test]
+    fn counts_options() {
+        let day = Utc.ymd(2020, 3, 2);
+        let chose = |id: u128, options: &[&str]| Report {
+            metric: "blockers".into(),
+            value: options.len() as f32,
+            options: options.iter().map(|o| o.to_string()).collect(),
+            ..report(id, day.and_hms(9, 0, 0), 0.0)
+        };
+
+        let rollup = ReportRollup::from_report(&chose(1, &["meetings", "tooling"]), None);
+        let summaries = ReportSummary::summarize(&[rollup], &[chose(2, &["meetings"]), chose(3, &["reviews"])], SummaryBucket::Week, 1, None);
+
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].count, 3);
+        assert_eq!(summaries[0].options.iter().map(|(o, c)| (o.as_str(), *c)).collect::<Vec<_>>(), vec![("meetings", 2), ("reviews", 1), ("tooling", 1)]);
+
+        let privacy = DifferentialPrivacy { epsilon: 100.0, ..Default::default() };
+        let noisy = ReportSummary::summarize(&[], &[chose(2, &["meetings"]), chose(3, &["reviews"])], SummaryBucket::Week, 1, Some(&privacy));
+        assert!(noisy[0].options.is_empty(), "option counts should be withheld once noise is added");
+    }
+
+    #[test]
+    fn merges_small_buckets() {
+        let day = Utc.ymd(2020, 3, 2);
+        let reports: Vec<Report> = [(0, 1), (1, 2), (2, 1), (4, 3), (5, 1)].iter()
+            .flat_map(|&(offset, count)| (0..count).map(move |i| report(offset * 10 + i, (day + chrono::Duration::days(offset as i64)).and_hms(9, 0, 0), 1.0)))
+            .collect();
+
+        let summaries = ReportSummary::summarize(&[], &reports, SummaryBucket::Day, 3, None);
+        assert_eq!(summaries.iter().map(|s| (s.start.day(), s.count)).collect::<Vec<_>>(), vec![(2, 3), (4, 5)]);
+        assert_eq!(summaries[0].median, Some(1.0));
+
+        assert!(ReportSummary::summarize(&[], &reports, SummaryBucket::Day, 9, None).is_empty());
+    }
+
+    #[test]
+    fn adds_noise() {
+        let day = Utc.ymd(2020, 3, 2);
+        let reports: Vec<Report> = (0..100).map(|i| report(i, day.and_hms(9, 0, 0), if i % 4 == 0 { -1.0 } else { 1.0 })).collect();
+        let privacy = DifferentialPrivacy { epsilon: 1.0, ..Default::default() };
+
+        let summary = ReportSummary::summarize(&[], &reports, SummaryBucket::Day, 1, Some(&privacy)).remove(0);
+
+        assert_eq!(summary.noise, Some(1.0));
+        assert_eq!((summary.median, summary.stddev, summary.min, summary.max), (None, None, None, None));
+        assert!(summary.mean >= -1.0 && summary.mean <= 1.0);
+        // The noise is unbounded, but exceeding these bounds would take a one-in-a-billion draw
+        assert!((summary.count as f64 - 100.0).abs() < 50.0, "the count should be close to 100, but was {}", summary.count);
+    }
+
+    #[test]
+    fn bounds_outliers() {
+        let day = Utc.ymd(2020, 3, 2);
+        let mut reports: Vec<Report> = (0..1000).map(|i| report(i, day.and_hms(9, 0, 0), 0.0)).collect();
+        reports.push(report(1000, day.and_hms(10, 0, 0), 1e6));
+        let rollup = ReportRollup::from_report(&report(1001, day.and_hms(11, 0, 0), -1e6), None);
+        let privacy = DifferentialPrivacy { epsilon: 1000.0, ..Default::default() };
+
+        let summary = ReportSummary::summarize(&[rollup], &reports, SummaryBucket::Day, 1, Some(&privacy)).remove(0);
+
+        // With so little noise, an unclamped outlier would push the mean to the value bound
+        let limit = privacy.value_bound / summary.count as f64;
+        assert!(summary.mean.abs() <= limit + 1e-4, "an outlier should move the mean by at most {}, but it was {}", limit, summary.mean);
+
+        let positive = ReportSummary::summarize(&[], &reports, SummaryBucket::Day, 1, Some(&privacy)).remove(0);
+        assert!((positive.mean - limit).abs() <= 1e-4, "the outlier should count as the value bound, giving a mean of {} rather than {}", limit, positive.mean);
+    }
+}
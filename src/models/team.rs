@@ -1,31 +1,265 @@
 use actix::prelude::*;
 use crate::api::APIError;
-use super::new_id;
+use super::{new_id, AlertRule, MetricDefinition, MetricKind, TeamAssignment, TeamAssignmentV1};
+use chrono::prelude::*;
+use rand::Rng;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// A team, as seen by one of its members.
+///
+/// Each team has a single canonical record holding its name, while `user_id` identifies
+/// the principal it was retrieved for. Membership is derived from [TeamAssignment]s.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Team {
     pub team_id: u128,
     pub user_id: u128,
     pub name: String,
+    /// The number of days for which reports submitted to this team are kept, if limited.
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+    #[serde(default)]
+    pub privacy: TeamPrivacy,
+    /// The conditions on the team's metrics which raise alerts.
+    #[serde(default)]
+    pub alert_rules: Vec<AlertRule>,
+    /// The metrics which the team's members may report on, with any metric accepted if none are registered.
+    #[serde(default)]
+    pub metrics: Vec<MetricDefinition>,
+    #[serde(default)]
+    pub etag: Option<String>,
+}
+
+/// The settings which control how much of a team's report data its members can see.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TeamPrivacy {
+    /// The smallest number of reports (and team members) which any aggregate may be built from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_group_size: Option<u32>,
+    /// Whether the team's members may retrieve its individual reports.
+    pub raw_reports: bool,
+    /// Adds noise to the team's aggregates, if set, so that they can't be used to infer any one report.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub differential_privacy: Option<DifferentialPrivacy>,
+    /// Truncates the timestamps of the team's reports before they are stored, if set, so that
+    /// they can't be matched against other activity.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp_granularity: Option<TimestampGranularity>,
+    /// Moves the timestamps of the team's reports back by a random amount of up to this many
+    /// minutes, if set, so that copies of a report stored for different teams can't be matched up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp_jitter_minutes: Option<u32>,
+    /// Holds the team's reports in a staging area, if set, releasing them to readers in batches.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delayed_publication: Option<DelayedPublication>,
+}
+
+/// The schedule on which a team's staged reports are released to readers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DelayedPublication {
+    /// The number of hours between releases, which happen at multiples of this since the Unix epoch.
+    pub interval_hours: u32,
+    /// The fewest reports which may be released in a batch, with smaller batches held until the next release.
+    pub min_reports: u32,
+}
+
+impl Default for DelayedPublication {
+    fn default() -> Self {
+        Self {
+            interval_hours: 24,
+            min_reports: 5,
+        }
+    }
+}
+
+impl DelayedPublication {
+    /// Gets the time of the most recent release at or before `now`, before which staged reports
+    /// are ready to be published.
+    pub fn last_release(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let interval = self.interval_hours.max(1) as i64 * 3600;
+        Utc.timestamp(now.timestamp() - now.timestamp().rem_euclid(interval), 0)
+    }
+}
+
+/// The precision with which a team's report timestamps are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampGranularity {
+    Hour,
+    Day,
+    /// Weeks start on Monday, as they do in report summaries.
+    Week,
+}
+
+impl TimestampGranularity {
+    /// Truncates a time to the start of the hour, day or week it falls in.
+    pub fn truncate(self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        let date = timestamp.date();
+        match self {
+            TimestampGranularity::Hour => date.and_hms(timestamp.hour(), 0, 0),
+            TimestampGranularity::Day => date.and_hms(0, 0, 0),
+            TimestampGranularity::Week => (date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64)).and_hms(0, 0, 0),
+        }
+    }
+}
+
+/// The settings used to add calibrated Laplace noise to a team's aggregates, along with the
+/// budget which limits how often they may be queried.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DifferentialPrivacy {
+    /// The privacy loss which each query spends from the team's budget.
+    pub epsilon: f64,
+    /// The total privacy loss which may be spent within each window.
+    pub budget: f64,
+    /// The number of days over which queries count towards the budget.
+    pub window_days: u32,
+    /// The largest magnitude which any report's value is expected to have, with larger values clamped to it before noise is added.
+    pub value_bound: f64,
+}
+
+impl Default for DifferentialPrivacy {
+    fn default() -> Self {
+        Self {
+            epsilon: 0.5,
+            budget: 5.0,
+            window_days: 7,
+            value_bound: 1.0,
+        }
+    }
+}
+
+impl DifferentialPrivacy {
+    /// Gets the time after which queries count towards the budget.
+    pub fn window_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - chrono::Duration::days(self.window_days as i64)
+    }
+}
+
+impl TeamPrivacy {
+    /// The smallest group which may be reported on, with every group permitted if no minimum is set.
+    pub fn min_group_size(&self) -> u64 {
+        self.min_group_size.unwrap_or(1).max(1) as u64
+    }
+
+    /// Truncates a time to the team's timestamp granularity, leaving it as it is if none is set.
+    pub fn coarsen(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        self.timestamp_granularity.map(|granularity| granularity.truncate(timestamp)).unwrap_or(timestamp)
+    }
+
+    /// Gets the timestamp to store for a report made at the given time, with the team's jitter
+    /// applied before it is coarsened.
+    pub fn report_timestamp(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        let jitter = match self.timestamp_jitter_minutes {
+            Some(minutes) if minutes > 0 => rand::thread_rng().gen_range(0..=minutes as i64 * 60_000),
+            _ => 0,
+        };
+
+        self.coarsen(timestamp - chrono::Duration::milliseconds(jitter))
+    }
+
+    /// Gets the earliest timestamp which could have been stored for a report made at the given time.
+    pub fn earliest_timestamp(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        self.coarsen(timestamp - chrono::Duration::minutes(self.timestamp_jitter_minutes.unwrap_or_default() as i64))
+    }
+}
+
+impl Team {
+    /// Gets this team as it is seen by one of its members.
+    pub fn for_principal(self, principal_id: u128) -> Self {
+        Self { user_id: principal_id, ..self }
+    }
+
+    /// Gets the largest magnitude which a valid report on one of this team's metrics can have, if
+    /// the metric has a bounded range. This is what reports are clamped to as they are rolled up;
+    /// the differential privacy `value_bound` is only applied when they are summarized, since the
+    /// reports themselves are gone once they have been rolled up.
+    pub fn metric_bound(&self, metric: &str) -> Option<f64> {
+        match self.metrics.iter().find(|definition| definition.name == metric).map(|definition| &definition.kind) {
+            Some(MetricKind::Binary) => Some(1.0),
+            Some(MetricKind::Scale { min, max }) => Some(min.unsigned_abs().max(max.unsigned_abs()) as f64),
+            Some(MetricKind::Categorical { .. }) => Some(1.0),
+            Some(MetricKind::MultiSelect { options }) => Some(options.len() as f64),
+            Some(MetricKind::Continuous) | None => None,
+        }
+    }
+
+    /// Gets the time before which this team's reports have expired, if it has a retention period.
+    pub fn retention_cutoff(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.retention_days.map(|days| now - chrono::Duration::days(days as i64))
+    }
 }
 
 actor_message!(GetTeam(id: u128, principal_id: u128) -> Team);
 
 actor_message!(GetTeams(principal_id: u128) -> Vec<Team>);
 
-actor_message!(StoreTeam(team_id: u128, principal_id: u128, name: String) -> Team);
+// Lists every team's canonical record, regardless of whether its members have a user record.
+actor_message!(GetAllTeams() -> Vec<Team>);
+
+actor_message!(StoreTeam(team_id: u128, principal_id: u128, name: String, retention_days: Option<u32>, privacy: TeamPrivacy, alert_rules: Vec<AlertRule>, metrics: Vec<MetricDefinition>, etag: Option<String>) -> Team);
+
+// Removes a team's canonical record, which is only done once it has no members left.
+actor_message!(RemoveTeam(id: u128) -> ());
+
+actor_message!(DeleteTeam(team_id: u128, dry_run: bool) -> TeamDeletion);
+
+actor_message!(ReconcileTeams() -> TeamReconciliation);
+
+/// The repairs made when reconciling the teams stored by earlier versions, which kept a
+/// separate copy of each team for every one of its members.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TeamReconciliation {
+    /// The number of teams which were given a canonical record.
+    pub teams: usize,
+    /// The number of those teams whose members' copies had different names.
+    pub diverged: usize,
+    /// The number of membership index entries which were added or removed.
+    pub memberships: usize,
+}
 
-actor_message!(RemoveTeam(id: u128, principal_id: u128) -> ());
+/// Everything which is (or, for a dry run, would be) removed when a team is deleted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TeamDeletion {
+    pub teams: Vec<Team>,
+    pub team_assignments: Vec<TeamAssignment>,
+    pub reports: usize,
+    /// The reports which are still waiting to be published.
+    pub staged_reports: usize,
+    pub rollups: usize,
+    pub alerts: usize,
+}
 
+/// A team as it is exchanged over the API. Any of the team's settings which are left out when
+/// a team is stored keep their current values.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TeamV1 {
     pub id: Option<String>,
     #[serde(rename = "userId")]
     pub user_id: Option<String>,
     pub name: String,
+    /// Omitted if the retention window is unchanged, or `null` if it should be removed.
+    #[serde(rename = "retentionDays", default, deserialize_with = "explicit_null", skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<Option<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub privacy: Option<TeamPrivacy>,
+    #[serde(rename = "alertRules", default, skip_serializing_if = "Option::is_none")]
+    pub alert_rules: Option<Vec<AlertRule>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<Vec<MetricDefinition>>,
+    #[serde(skip)]
+    pub etag: Option<String>,
 }
 
-json_responder!(TeamV1 => (req, model) -> req.url_for("get_team_v1", vec![model.id.clone().expect("a team id")]));
+/// Distinguishes a field which was explicitly set to `null` from one which was left out.
+fn explicit_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de> {
+    <Option<T> as serde::Deserialize>::deserialize(deserializer).map(Some)
+}
+
+json_responder!(TeamV1 => (req, model) -> req.url_for("get_team_v1", vec![model.id.clone().expect("a team id")]); etag = model.etag.clone());
 
 impl From<Team> for TeamV1 {
     fn from(record: Team) -> Self {
@@ -33,6 +267,11 @@ impl From<Team> for TeamV1 {
             id: Some(format!("{:0>32x}", record.team_id)),
             user_id: Some(format!("{:0>32x}", record.user_id)),
             name: record.name.clone(),
+            retention_days: record.retention_days.map(Some),
+            privacy: Some(record.privacy.clone()),
+            alert_rules: Some(record.alert_rules.clone()).filter(|rules| !rules.is_empty()),
+            metrics: Some(record.metrics.clone()).filter(|metrics| !metrics.is_empty()),
+            etag: record.etag.clone(),
         }
     }
 }
@@ -43,6 +282,102 @@ impl Into<Team> for TeamV1 {
             user_id: self.user_id.clone().and_then(|id| u128::from_str_radix(&id, 16).ok()).unwrap_or_default(),
             team_id: self.id.clone().and_then(|id| u128::from_str_radix(&id, 16).ok()).unwrap_or_else(|| new_id()),
             name: self.name.clone(),
+            retention_days: self.retention_days.flatten(),
+            privacy: self.privacy.clone().unwrap_or_default(),
+            alert_rules: self.alert_rules.clone().unwrap_or_default(),
+            metrics: self.metrics.clone().unwrap_or_default(),
+            etag: self.etag.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TeamDeletionV1 {
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+    pub teams: Vec<TeamV1>,
+    pub members: Vec<TeamAssignmentV1>,
+    pub reports: usize,
+    #[serde(rename = "stagedReports")]
+    pub staged_reports: usize,
+    pub rollups: usize,
+    pub alerts: usize,
+}
+
+impl TeamDeletionV1 {
+    pub fn new(deletion: TeamDeletion, dry_run: bool) -> Self {
+        Self {
+            dry_run,
+            teams: deletion.teams.into_iter().map(|team| team.into()).collect(),
+            members: deletion.team_assignments.into_iter().map(|assignment| assignment.into()).collect(),
+            reports: deletion.reports,
+            staged_reports: deletion.staged_reports,
+            rollups: deletion.rollups,
+            alerts: deletion.alerts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metric_bounds() {
+        let team = Team {
+            team_id: 1,
+            user_id: 1,
+            name: "Test Team".into(),
+            retention_days: None,
+            privacy: Default::default(),
+            alert_rules: vec![],
+            metrics: vec![
+                MetricDefinition { name: "workload".into(), label: "Workload".into(), kind: MetricKind::Scale { min: -2, max: 5 }, description: String::new() },
+                MetricDefinition { name: "hours".into(), label: "Hours worked".into(), kind: MetricKind::Continuous, description: String::new() },
+            ],
+            etag: None,
+        };
+
+        assert_eq!(team.metric_bound("workload"), Some(5.0));
+        assert_eq!(team.metric_bound("hours"), None);
+        assert_eq!(team.metric_bound("unknown"), None);
+
+        let private = Team { privacy: TeamPrivacy { differential_privacy: Some(DifferentialPrivacy { value_bound: 1.0, ..Default::default() }), ..Default::default() }, ..team };
+        assert_eq!(private.metric_bound("workload"), Some(5.0), "the differential privacy bound should not narrow the metric's range");
+    }
+
+    #[test]
+    fn coarsens_timestamps() {
+        let timestamp = Utc.ymd(2020, 3, 4).and_hms(14, 3, 17);
+
+        assert_eq!(TimestampGranularity::Hour.truncate(timestamp), Utc.ymd(2020, 3, 4).and_hms(14, 0, 0));
+        assert_eq!(TimestampGranularity::Day.truncate(timestamp), Utc.ymd(2020, 3, 4).and_hms(0, 0, 0));
+        assert_eq!(TimestampGranularity::Week.truncate(timestamp), Utc.ymd(2020, 3, 2).and_hms(0, 0, 0));
+        assert_eq!(TeamPrivacy::default().coarsen(timestamp), timestamp);
+    }
+
+    #[test]
+    fn jitters_timestamps() {
+        let timestamp = Utc.ymd(2020, 3, 4).and_hms(14, 3, 17);
+        let privacy = TeamPrivacy { timestamp_jitter_minutes: Some(30), ..Default::default() };
+
+        for _ in 0..100 {
+            let jittered = privacy.report_timestamp(timestamp);
+            assert!(jittered <= timestamp && jittered >= privacy.earliest_timestamp(timestamp), "{} should be within 30 minutes before {}", jittered, timestamp);
         }
+
+        let privacy = TeamPrivacy { timestamp_granularity: Some(TimestampGranularity::Hour), ..privacy };
+        assert_eq!(privacy.earliest_timestamp(timestamp), Utc.ymd(2020, 3, 4).and_hms(13, 0, 0));
+        assert_eq!(TeamPrivacy::default().report_timestamp(timestamp), timestamp);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn schedules_releases() {
+        let daily = DelayedPublication::default();
+        assert_eq!(daily.last_release(Utc.ymd(2020, 3, 4).and_hms(14, 3, 17)), Utc.ymd(2020, 3, 4).and_hms(0, 0, 0));
+        assert_eq!(daily.last_release(Utc.ymd(2020, 3, 4).and_hms(0, 0, 0)), Utc.ymd(2020, 3, 4).and_hms(0, 0, 0));
+
+        let six_hourly = DelayedPublication { interval_hours: 6, ..Default::default() };
+        assert_eq!(six_hourly.last_release(Utc.ymd(2020, 3, 4).and_hms(14, 3, 17)), Utc.ymd(2020, 3, 4).and_hms(12, 0, 0));
+    }
+}
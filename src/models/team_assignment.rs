@@ -37,18 +37,20 @@ impl Into<String> for Role {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TeamAssignment {
     pub user_id: u128,
     pub team_id: u128,
     pub role: Role,
+    #[serde(default)]
+    pub etag: Option<String>,
 }
 
 actor_message!(GetTeamAssignment(team_id: u128, principal_id: u128) -> TeamAssignment);
 
 actor_message!(GetTeamAssignments(team_id: u128) -> Vec<TeamAssignment>);
 
-actor_message!(StoreTeamAssignment(team_id: u128, principal_id: u128, role: Role) -> TeamAssignment);
+actor_message!(StoreTeamAssignment(team_id: u128, principal_id: u128, role: Role, etag: Option<String>) -> TeamAssignment);
 
 actor_message!(RemoveTeamAssignment(team_id: u128, principal_id: u128) -> ());
 
@@ -60,12 +62,14 @@ pub struct TeamAssignmentV1 {
     #[serde(rename="userId")]
     pub user_id: Option<String>,
     pub role: String,
+    #[serde(skip)]
+    pub etag: Option<String>,
 }
 
 json_responder!(TeamAssignmentV1 => (req, model) -> req.url_for("get_team_assignment_v3", &vec![
     model.team_id.clone().expect("a team id"),
     model.user_id.clone().expect("a user id")
-]));
+]); etag = model.etag.clone());
 
 impl From<TeamAssignment> for TeamAssignmentV1 {
     fn from(record: TeamAssignment) -> Self {
@@ -73,6 +77,7 @@ impl From<TeamAssignment> for TeamAssignmentV1 {
             user_id: Some(format!("{:0>32x}", record.user_id)),
             team_id: Some(format!("{:0>32x}", record.team_id)),
             role: record.role.into(),
+            etag: record.etag.clone(),
         }
     }
 }
@@ -83,6 +88,7 @@ impl Into<TeamAssignment> for TeamAssignmentV1 {
             user_id: self.user_id.clone().and_then(|id| u128::from_str_radix(&id, 16).ok()).unwrap_or_default(),
             team_id: self.team_id.clone().and_then(|id| u128::from_str_radix(&id, 16).ok()).unwrap_or_default(),
             role: self.role.as_str().into(),
+            etag: self.etag.clone(),
         }
     }
 }
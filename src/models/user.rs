@@ -1,7 +1,7 @@
 use actix::prelude::*;
 use crate::api::APIError;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct User {
     pub principal_id: u128,
     pub email_hash: u128,
@@ -10,6 +10,8 @@ pub struct User {
 
 actor_message!(GetUser(email_hash: u128) -> User);
 
+actor_message!(GetUsers() -> Vec<User>);
+
 actor_message!(StoreUser(email_hash: u128, principal_id: u128, first_name: String) -> User);
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -0,0 +1,126 @@
+use actix::prelude::*;
+use chrono::prelude::*;
+use prometheus::IntCounterVec;
+use std::time::Duration;
+use crate::api::APIError;
+use crate::models::*;
+use crate::store::Store;
+
+/// How often teams' staging areas are checked for reports which are due to be released.
+const PUBLICATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    static ref REPORTS_PUBLISHED: IntCounterVec = register_int_counter_vec!(
+        "rex_reports_published_total",
+        "The number of staged reports which have been published to their teams, by outcome of the release.",
+        &["outcome"]
+    ).unwrap();
+}
+
+/// Periodically releases the reports staged by teams which delay their publication, once each
+/// team's release is due and enough reports have been staged.
+pub struct PublicationActor {
+    store: Store,
+}
+
+impl PublicationActor {
+    pub fn new(store: Store) -> Self {
+        Self { store }
+    }
+
+    fn schedule_publication(&self, ctx: &mut Context<Self>) {
+        let store = self.store.clone();
+
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = publish(&store, Utc::now()).await {
+                error!("Unable to publish staged reports: {}", err);
+            }
+        }));
+    }
+}
+
+impl Actor for PublicationActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.schedule_publication(ctx);
+        ctx.run_interval(PUBLICATION_INTERVAL, |actor, ctx| actor.schedule_publication(ctx));
+    }
+}
+
+/// Publishes the reports which each team staged before its most recent release as of `now`,
+/// returning the number of reports published. Teams whose release fails are logged and skipped.
+///
+/// Reports staged after a release are held until the next one, and a team's reports are held
+/// until there are at least its `min_reports` of them, so that a release cannot be narrowed down
+/// to a handful of members. Teams which have stopped delaying publication have any reports they
+/// staged earlier published straight away.
+pub async fn publish(store: &Store, now: DateTime<Utc>) -> Result<usize, APIError> {
+    let mut published = 0;
+
+    for team in store.get_all_teams().await? {
+        let (before, min_reports) = match &team.privacy.delayed_publication {
+            Some(schedule) => (Some(schedule.last_release(now)), schedule.min_reports as usize),
+            None => (None, 0),
+        };
+
+        let result = store.send(PublishStagedReports { team_id: team.team_id, before, min_reports })
+            .await.map_err(APIError::from).and_then(|result| result);
+
+        match result {
+            Ok(reports) if reports.is_empty() => {},
+            Ok(reports) => {
+                info!("Published {} staged reports for team {:0>32x}", reports.len(), team.team_id);
+                REPORTS_PUBLISHED.with_label_values(&["success"]).inc_by(reports.len() as i64);
+                published += reports.len();
+            },
+            // A team whose release fails is retried on the next run, without holding up the releases of the teams after it
+            Err(err) => {
+                error!("Unable to publish staged reports for team {:0>32x}: {}", team.team_id, err);
+                REPORTS_PUBLISHED.with_label_values(&["failure"]).inc();
+            },
+        }
+    }
+
+    Ok(published)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::test::get_test_state;
+
+    #[actix_rt::test]
+    async fn publishes_staged_reports() {
+        let state = get_test_state();
+        let now = Utc.ymd(2020, 3, 4).and_hms(14, 0, 0);
+
+        // The team's only member has no user record, which shouldn't stop its reports being published
+        state.store.send(StoreTeam {
+            team_id: 7,
+            principal_id: 1,
+            name: "Test Team".into(),
+            privacy: TeamPrivacy { delayed_publication: Some(DelayedPublication { interval_hours: 24, min_reports: 2 }), ..Default::default() },
+            ..Default::default()
+        }).await.expect("the actor should run").expect("the team should be stored");
+        state.store.send(StoreTeamAssignment { team_id: 7, principal_id: 1, role: Role::Manager, ..Default::default() })
+            .await.expect("the actor should run").expect("the assignment should be stored");
+
+        // Each report's timestamp has been coarsened and jittered back to an earlier day, so only
+        // when it was staged shows which release it belongs to
+        let report = |id: u128, staged_at: DateTime<Utc>| Report { id, team_id: 7, metric: "happy_sad".into(), timestamp: staged_at.date().pred().and_hms(0, 0, 0), value: 1.0, response_id: None, receipt_hash: None, options: vec![], staged_at: Some(staged_at) };
+        state.store.send(StageReports { reports: vec![report(1, now - chrono::Duration::days(1)), report(2, now - chrono::Duration::hours(1))] })
+            .await.expect("the actor should run").expect("the reports should be staged");
+
+        assert_eq!(publish(&state.store, now).await.expect("the publication should succeed"), 0, "a release with too few reports should be held");
+
+        state.store.send(StageReports { reports: vec![report(3, now - chrono::Duration::hours(20))] })
+            .await.expect("the actor should run").expect("the reports should be staged");
+        assert_eq!(publish(&state.store, now).await.expect("the publication should succeed"), 2);
+        assert_eq!(publish(&state.store, now).await.expect("the publication should succeed"), 0, "published reports should not be released again");
+
+        let published = state.store.send(GetReport { id: 3, team: 7 }).await.expect("the actor should run").expect("the published report should be readable");
+        assert_eq!(published.staged_at, None, "published reports should not keep when they were staged");
+        state.store.send(GetReport { id: 2, team: 7 }).await.expect("the actor should run").expect_err("the report staged after the release should be held");
+    }
+}
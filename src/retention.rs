@@ -0,0 +1,209 @@
+use actix::prelude::*;
+use chrono::prelude::*;
+use prometheus::{IntCounter, IntCounterVec};
+use std::time::Duration;
+use crate::api::APIError;
+use crate::models::*;
+use crate::store::Store;
+
+/// How often reports which have outlived their team's retention period are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    static ref REPORTS_PURGED: IntCounter = register_int_counter!(
+        "rex_reports_purged_total",
+        "The number of reports which have been removed because they outlived their team's retention period."
+    ).unwrap();
+
+    static ref REPORTS_ROLLED_UP: IntCounter = register_int_counter!(
+        "rex_reports_rolled_up_total",
+        "The number of reports which have been replaced by daily rollups."
+    ).unwrap();
+
+    static ref REPORT_PURGES: IntCounterVec = register_int_counter_vec!(
+        "rex_report_purges_total",
+        "The number of times expired reports have been purged, by outcome.",
+        &["outcome"]
+    ).unwrap();
+}
+
+/// Periodically removes the reports which have outlived the retention period of their team,
+/// and replaces those older than `rollup_days` (if it is set) with daily rollups.
+pub struct RetentionActor {
+    store: Store,
+    rollup_days: Option<u32>,
+}
+
+impl RetentionActor {
+    pub fn new(store: Store, rollup_days: Option<u32>) -> Self {
+        Self { store, rollup_days }
+    }
+
+    /// Creates the actor with reports being rolled up once they are older than the number
+    /// of days in the `REPORT_ROLLUP_DAYS` environment variable, if it has been set.
+    pub fn from_env(store: Store) -> Self {
+        let rollup_days = std::env::var("REPORT_ROLLUP_DAYS").ok().map(|days| days.parse()
+            .expect("Set the REPORT_ROLLUP_DAYS environment variable to a whole number of days before starting the server."));
+
+        Self::new(store, rollup_days)
+    }
+
+    fn schedule_purge(&self, ctx: &mut Context<Self>) {
+        let store = self.store.clone();
+        let rollup_days = self.rollup_days;
+
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = purge(&store).await {
+                error!("Unable to purge expired reports: {}", err);
+            }
+
+            if let Some(days) = rollup_days {
+                if let Err(err) = rollup(&store, Utc::now() - chrono::Duration::days(days as i64)).await {
+                    error!("Unable to roll up old reports: {}", err);
+                }
+            }
+
+            if let Err(err) = purge_idempotency_keys(&store, Utc::now()).await {
+                error!("Unable to purge expired idempotency keys: {}", err);
+            }
+        }));
+    }
+}
+
+impl Actor for RetentionActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.schedule_purge(ctx);
+        ctx.run_interval(PURGE_INTERVAL, |actor, ctx| actor.schedule_purge(ctx));
+    }
+}
+
+/// Purges expired reports from every team with a retention period, returning the number removed.
+pub async fn purge(store: &Store) -> Result<usize, APIError> {
+    let purges = match store.send(PurgeReports {}).await.map_err(APIError::from).and_then(|result| result) {
+        Ok(purges) => purges,
+        Err(err) => {
+            REPORT_PURGES.with_label_values(&["failure"]).inc();
+            return Err(err);
+        }
+    };
+
+    REPORT_PURGES.with_label_values(&["success"]).inc();
+
+    let mut total = 0;
+    for purge in purges.iter().filter(|p| p.reports > 0) {
+        info!("Purged {} reports made before {} from team {:0>32x}", purge.reports, purge.before.to_rfc3339(), purge.team_id);
+        total += purge.reports;
+    }
+
+    REPORTS_PURGED.inc_by(total as i64);
+
+    Ok(total)
+}
+
+/// Replaces the reports made before the day on which `before` falls with daily rollups.
+pub async fn rollup(store: &Store, before: DateTime<Utc>) -> Result<ReportCompaction, APIError> {
+    let compaction = store.send(RollupReports { before: Some(before) }).await??;
+
+    if compaction.reports > 0 {
+        info!("Rolled up {} reports made before {} into {} daily rollups", compaction.reports, ReportRollup::day_of(before).to_rfc3339(), compaction.rollups);
+        REPORTS_ROLLED_UP.inc_by(compaction.reports as i64);
+    }
+
+    Ok(compaction)
+}
+
+/// Removes the idempotency keys which have outlived their TTL as of `now`, returning the number removed.
+pub async fn purge_idempotency_keys(store: &Store, now: DateTime<Utc>) -> Result<usize, APIError> {
+    let purged = store.send(PurgeIdempotencyKeys { before: Some(now - chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS)) }).await??;
+
+    if purged > 0 {
+        info!("Purged {} expired idempotency keys", purged);
+    }
+
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::test::get_test_state;
+
+    #[actix_rt::test]
+    async fn purges_expired_reports() {
+        let state = get_test_state();
+        let now = Utc::now();
+
+        state.store.send(StoreTeam { team_id: 7, principal_id: 1, name: "Test Team".into(), retention_days: Some(180), ..Default::default() })
+            .await.expect("the actor should run").expect("the team should be stored");
+        state.store.send(StoreReport { id: 1, team: 7, metric: "happy_sad".into(), timestamp: Some(now - chrono::Duration::days(181)), value: 1.0, options: vec![] })
+            .await.expect("the actor should run").expect("the report should be stored");
+        state.store.send(StoreReport { id: 2, team: 7, metric: "happy_sad".into(), timestamp: Some(now - chrono::Duration::days(179)), value: 1.0, options: vec![] })
+            .await.expect("the actor should run").expect("the report should be stored");
+
+        let before = REPORTS_PURGED.get();
+        assert_eq!(purge(&state.store).await.expect("the purge should succeed"), 1);
+        assert!(REPORTS_PURGED.get() > before);
+
+        state.store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("the expired report should be removed");
+        state.store.send(GetReport { id: 2, team: 7 }).await.expect("the actor should run").expect("the recent report should be kept");
+
+        assert_eq!(purge(&state.store).await.expect("the purge should succeed"), 0);
+    }
+
+    #[actix_rt::test]
+    async fn purges_expired_idempotency_keys() {
+        let state = get_test_state();
+        let now = Utc::now();
+
+        state.store.send(ClaimIdempotencyKey { key_hash: "old".into(), request_hash: "abc".into(), created_at: Some(now - chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS + 1)) })
+            .await.expect("the actor should run").expect("the key should be claimed");
+        state.store.send(ClaimIdempotencyKey { key_hash: "new".into(), request_hash: "abc".into(), created_at: Some(now) })
+            .await.expect("the actor should run").expect("the key should be claimed");
+
+        assert_eq!(purge_idempotency_keys(&state.store, now).await.expect("the purge should succeed"), 1);
+
+        let existing = state.store.send(ClaimIdempotencyKey { key_hash: "new".into(), request_hash: "abc".into(), created_at: Some(now) })
+            .await.expect("the actor should run").expect("the key should be checked");
+        assert!(existing.is_some(), "the recent key should be kept");
+    }
+
+    #[actix_rt::test]
+    async fn rolls_up_old_reports() {
+        let state = get_test_state();
+        let now = Utc::now();
+
+        state.store.send(StoreReport { id: 1, team: 7, metric: "happy_sad".into(), timestamp: Some(now - chrono::Duration::days(40)), value: 1.0, options: vec![] })
+            .await.expect("the actor should run").expect("the report should be stored");
+        state.store.send(StoreReport { id: 2, team: 7, metric: "happy_sad".into(), timestamp: Some(now), value: 1.0, options: vec![] })
+            .await.expect("the actor should run").expect("the report should be stored");
+
+        let compaction = rollup(&state.store, now - chrono::Duration::days(30)).await.expect("the rollup should succeed");
+        assert_eq!(compaction, ReportCompaction { reports: 1, rollups: 1 });
+
+        let rollups = state.store.send(GetReportRollups { team: 7, ..Default::default() }).await.expect("the actor should run").expect("the rollups should be listed");
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].count, 1);
+    }
+
+    #[actix_rt::test]
+    async fn clamps_rolled_up_reports() {
+        let state = get_test_state();
+        let now = Utc::now();
+        let privacy = TeamPrivacy { differential_privacy: Some(DifferentialPrivacy { value_bound: 1.0, ..Default::default() }), ..Default::default() };
+        let metrics = vec![MetricDefinition { name: "workload".into(), label: "Workload".into(), kind: MetricKind::Scale { min: 1, max: 5 }, description: String::new() }];
+
+        state.store.send(StoreTeam { team_id: 7, principal_id: 1, name: "Test Team".into(), privacy, metrics, ..Default::default() })
+            .await.expect("the actor should run").expect("the team should be stored");
+        state.store.send(StoreReport { id: 1, team: 7, metric: "workload".into(), timestamp: Some(now - chrono::Duration::days(31)), value: 4.0, options: vec![] })
+            .await.expect("the actor should run").expect("the report should be stored");
+        state.store.send(StoreReport { id: 2, team: 7, metric: "workload".into(), timestamp: Some(now - chrono::Duration::days(31)), value: 1e6, options: vec![] })
+            .await.expect("the actor should run").expect("the report should be stored");
+
+        rollup(&state.store, now - chrono::Duration::days(30)).await.expect("the rollup should succeed");
+
+        let rollups = state.store.send(GetReportRollups { team: 7, ..Default::default() }).await.expect("the actor should run").expect("the rollups should be listed");
+        assert_eq!((rollups[0].sum, rollups[0].max), (9.0, 5.0), "values should be clamped to the metric's range rather than the team's privacy bound");
+    }
+}
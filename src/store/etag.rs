@@ -0,0 +1,60 @@
+use crate::api::APIError;
+
+/// Gets the ETag served for an entity stored before ETags were introduced, which is treated as
+/// the version before the first one a write produces (matching the version SQLite defaults to).
+pub fn legacy_etag() -> Option<String> {
+    Some("\"0\"".into())
+}
+
+/// Ensures that the `If-Match` ETag provided for a write (if any) matches the
+/// current version of the entity being replaced.
+pub fn check_etag(existing: Option<&Option<String>>, expected: &Option<String>) -> Result<(), APIError> {
+    match (existing.map(|etag| etag.clone().or_else(legacy_etag)), expected) {
+        (_, None) => Ok(()),
+        (Some(_), Some(expected)) if expected == "*" => Ok(()),
+        (Some(Some(current)), Some(expected)) if &current == expected => Ok(()),
+        _ => Err(APIError::precondition_failed())
+    }
+}
+
+/// Generates the ETag for the next version of an entity from the ETag of its current version.
+pub fn next_etag(existing: Option<&Option<String>>) -> Option<String> {
+    let version = existing
+        .and_then(|etag| etag.as_ref())
+        .and_then(|etag| etag.trim_matches('"').parse::<u64>().ok())
+        .unwrap_or_default();
+
+    Some(format!("\"{}\"", version + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_current_etag() {
+        let current = Some("\"2\"".to_string());
+
+        check_etag(Some(&current), &Some("\"2\"".into())).expect("the current etag should match");
+        check_etag(Some(&current), &Some("*".into())).expect("a wildcard should match");
+        check_etag(Some(&current), &None).expect("a write without an etag should be allowed");
+
+        let err = check_etag(Some(&current), &Some("\"1\"".into())).expect_err("a stale etag should not match");
+        assert_eq!(err.code, 412);
+    }
+
+    #[test]
+    fn matches_legacy_entities_without_an_etag() {
+        check_etag(Some(&None), &legacy_etag()).expect("the legacy etag should match an entity without one");
+        check_etag(Some(&None), &Some("*".into())).expect("a wildcard should match an entity without one");
+
+        let err = check_etag(Some(&None), &Some("\"1\"".into())).expect_err("any other etag should not match an entity without one");
+        assert_eq!(err.code, 412);
+    }
+
+    #[test]
+    fn rejects_missing_entities() {
+        let err = check_etag(None, &Some("*".into())).expect_err("there is no entity to match");
+        assert_eq!(err.code, 412);
+    }
+}
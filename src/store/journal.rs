@@ -0,0 +1,176 @@
+use crate::models::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// A single change made to the [super::MemoryStore], recorded in the journal so that it
+/// can be replayed on top of the most recent snapshot when the store is reloaded.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum JournalEntry {
+    StoreReport(Report),
+    StoreReports(Vec<Report>),
+    RemoveReport { team: u128, id: u128 },
+    PurgeReports { team_id: u128, before: chrono::DateTime<chrono::Utc> },
+    RollupReports { before: chrono::DateTime<chrono::Utc> },
+    StoreReportRollups(Vec<ReportRollup>),
+    StageReports(Vec<Report>),
+    /// Moves a team's staged reports made before `before` into its published reports.
+    PublishStagedReports { team_id: u128, before: Option<chrono::DateTime<chrono::Utc>> },
+    /// Removes a team's published and staged reports which were submitted with a receipt.
+    RetractReports { team_id: u128, receipt_hash: String },
+    /// Changes the value and options of a team's published and staged reports which were submitted with a receipt.
+    /// Earlier versions only changed the value, so entries which they recorded have no options.
+    AmendReports {
+        team_id: u128,
+        receipt_hash: String,
+        metric: Option<String>,
+        value: f32,
+        #[serde(default)]
+        options: Vec<String>,
+    },
+    /// Records a spend of a team's privacy budget, discarding any made before `since`.
+    SpendPrivacyBudget { spend: PrivacySpend, since: Option<chrono::DateTime<chrono::Utc>> },
+    /// Replaces every spend of a team's privacy budget.
+    StorePrivacySpends { team_id: u128, spends: Vec<PrivacySpend> },
+    StoreAlert(Alert),
+    StoreTeam(Team),
+    /// Earlier versions also recorded the `principal_id` whose copy of the team was removed,
+    /// which is ignored now that there is a single record for each team.
+    RemoveTeam { id: u128 },
+    DeleteTeam { team_id: u128 },
+    StoreTeamAssignment(TeamAssignment),
+    RemoveTeamAssignment { team_id: u128, principal_id: u128 },
+    StoreUser(User),
+    /// Claims an idempotency key, replacing any expired record which held it.
+    ClaimIdempotencyKey(IdempotencyRecord),
+    CompleteIdempotencyKey { key_hash: String, response: String },
+    ReleaseIdempotencyKey { key_hash: String },
+    PurgeIdempotencyKeys { before: chrono::DateTime<chrono::Utc> },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub reports: Vec<Report>,
+    pub teams: Vec<Team>,
+    pub team_assignments: Vec<TeamAssignment>,
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub rollups: Vec<ReportRollup>,
+    #[serde(default)]
+    pub privacy_spends: Vec<PrivacySpend>,
+    #[serde(default)]
+    pub alerts: Vec<Alert>,
+    #[serde(default)]
+    pub staged_reports: Vec<Report>,
+    #[serde(default)]
+    pub idempotency_keys: Vec<IdempotencyRecord>,
+}
+
+/// Persists the contents of a [super::MemoryStore] to a directory on disk using a
+/// `snapshot.json` file holding the full state of the store at some point in time and
+/// a `journal.jsonl` file holding every change made since that snapshot was written.
+pub struct Journal {
+    snapshot_path: PathBuf,
+    journal_path: PathBuf,
+    journal: File,
+    entries: usize,
+    snapshotted_at: Instant,
+}
+
+impl Journal {
+    /// Opens the journal in the given directory, returning it along with the snapshot
+    /// and journal entries which should be loaded into the store.
+    pub fn open(path: &Path) -> io::Result<(Self, Snapshot, Vec<JournalEntry>)> {
+        fs::create_dir_all(path)?;
+
+        let snapshot_path = path.join("snapshot.json");
+        let journal_path = path.join("journal.jsonl");
+
+        let snapshot = match File::open(&snapshot_path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(err) => return Err(err),
+        };
+
+        let mut entries = Vec::new();
+        match File::open(&journal_path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+
+                    match serde_json::from_str(&line) {
+                        Ok(entry) => entries.push(entry),
+                        Err(err) => {
+                            // A partially written entry can only be the result of the process being
+                            // killed part way through an append, so everything after it is discarded.
+                            warn!("Ignoring an unreadable entry at the end of the memory store journal: {}", err);
+                            break;
+                        }
+                    }
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+
+        let journal = OpenOptions::new().create(true).append(true).open(&journal_path)?;
+
+        Ok((Self {
+            snapshot_path,
+            journal_path,
+            journal,
+            entries: entries.len(),
+            snapshotted_at: Instant::now(),
+        }, snapshot, entries))
+    }
+
+    /// The number of entries which have been written to the journal since the last snapshot.
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    /// The time which has passed since the last snapshot was written (or the journal was opened).
+    pub fn since_snapshot(&self) -> Duration {
+        self.snapshotted_at.elapsed()
+    }
+
+    pub fn append(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        self.journal.write_all(&line)?;
+        self.entries += 1;
+
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.journal.sync_data()
+    }
+
+    /// Atomically replaces the snapshot on disk and truncates the journal, since every
+    /// entry it holds is now reflected in the snapshot.
+    pub fn snapshot(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        let temp_path = self.snapshot_path.with_extension("json.tmp");
+
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            serde_json::to_writer(&mut writer, snapshot)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+
+        fs::rename(&temp_path, &self.snapshot_path)?;
+
+        File::create(&self.journal_path)?.sync_all()?;
+        self.journal = OpenOptions::new().append(true).open(&self.journal_path)?;
+        self.entries = 0;
+        self.snapshotted_at = Instant::now();
+
+        Ok(())
+    }
+}
@@ -1,32 +1,423 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{collections::{BTreeMap, BTreeSet}, ops::Bound, path::Path, sync::Arc, time::Duration};
 use crate::models::*;
 use crate::api::APIError;
-use std::sync::RwLock;
+use super::journal::{Journal, JournalEntry, Snapshot};
+use super::etag::{check_etag, legacy_etag, next_etag};
+use super::reconcile::{canonical_team_name, has_diverged};
+use std::sync::{Mutex, RwLock};
 use chrono::prelude::*;
 use actix::prelude::*;
 
+/// How often the journal is synced to disk and checked to see whether it should be compacted.
+const JOURNAL_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The number of journal entries which may accumulate before a new snapshot is written.
+const JOURNAL_SNAPSHOT_THRESHOLD: usize = 1000;
+
+/// The longest time for which journal entries may accumulate before a new snapshot is written.
+const JOURNAL_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+type TeamRollups = BTreeMap<(String, DateTime<Utc>), ReportRollup>;
+
 pub struct MemoryStore {
     started_at: chrono::DateTime<chrono::Utc>,
     reports: Arc<RwLock<BTreeMap<u128, BTreeMap<u128, Report>>>>,
-    teams: Arc<RwLock<BTreeMap<u128, BTreeMap<u128, Team>>>>,
+    /// The reports held for each team until they are published.
+    staged_reports: Arc<RwLock<BTreeMap<u128, BTreeMap<u128, Report>>>>,
+    /// The daily rollups for each team, keyed by their metric and day.
+    rollups: Arc<RwLock<BTreeMap<u128, TeamRollups>>>,
+    /// The queries which have spent each team's privacy budget within its current window.
+    privacy_spends: Arc<RwLock<BTreeMap<u128, Vec<PrivacySpend>>>>,
+    alerts: Arc<RwLock<BTreeMap<u128, BTreeMap<u128, Alert>>>>,
+    teams: Arc<RwLock<BTreeMap<u128, Team>>>,
     team_assignments: Arc<RwLock<BTreeMap<u128, BTreeMap<u128, TeamAssignment>>>>,
+    /// The teams which each principal is a member of, derived from their team assignments.
+    memberships: Arc<RwLock<BTreeMap<u128, BTreeSet<u128>>>>,
     users: Arc<RwLock<BTreeMap<u128, User>>>,
+    /// The requests which were made with an idempotency key, keyed by its hash.
+    idempotency_keys: Arc<RwLock<BTreeMap<String, IdempotencyRecord>>>,
+    journal: Option<Arc<Mutex<Journal>>>,
 }
 
 impl MemoryStore {
     pub fn new() -> Self {
+        match std::env::var("MEMORY_STORE_PATH") {
+            Ok(path) => Self::open(Path::new(&path)).expect("a readable memory store snapshot"),
+            Err(_) => Self::empty(),
+        }
+    }
+
+    /// Opens a memory store which is persisted to the given directory, loading any
+    /// existing snapshot and journal from it.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let (journal, snapshot, entries) = Journal::open(path)?;
+
+        let store = Self::empty();
+        store.load(snapshot);
+
+        info!("Replaying {} entries from the memory store journal", entries.len());
+        for entry in entries {
+            store.apply(entry);
+        }
+
+        let mut journal = journal;
+        journal.snapshot(&store.snapshot())?;
+
+        Ok(Self {
+            journal: Some(Arc::new(Mutex::new(journal))),
+            ..store
+        })
+    }
+
+    fn empty() -> Self {
         Self {
             started_at: chrono::Utc::now(),
             reports: Arc::new(RwLock::new(BTreeMap::new())),
+            staged_reports: Arc::new(RwLock::new(BTreeMap::new())),
+            rollups: Arc::new(RwLock::new(BTreeMap::new())),
+            privacy_spends: Arc::new(RwLock::new(BTreeMap::new())),
+            alerts: Arc::new(RwLock::new(BTreeMap::new())),
             teams: Arc::new(RwLock::new(BTreeMap::new())),
             team_assignments: Arc::new(RwLock::new(BTreeMap::new())),
+            memberships: Arc::new(RwLock::new(BTreeMap::new())),
             users: Arc::new(RwLock::new(BTreeMap::new())),
+            idempotency_keys: Arc::new(RwLock::new(BTreeMap::new())),
+            journal: None,
+        }
+    }
+
+    fn load(&self, snapshot: Snapshot) {
+        for report in snapshot.reports {
+            self.apply(JournalEntry::StoreReport(report));
+        }
+
+        self.apply(JournalEntry::StoreReportRollups(snapshot.rollups));
+        self.apply(JournalEntry::StageReports(snapshot.staged_reports));
+
+        for spend in snapshot.privacy_spends {
+            self.privacy_spends.write().unwrap()
+                .entry(spend.team_id)
+                .or_default()
+                .push(spend);
+        }
+
+        for alert in snapshot.alerts {
+            self.apply(JournalEntry::StoreAlert(alert));
+        }
+
+        for team_assignment in snapshot.team_assignments {
+            self.apply(JournalEntry::StoreTeamAssignment(team_assignment));
+        }
+
+        // Snapshots written by earlier versions hold a copy of each team for every member
+        let mut copies: BTreeMap<u128, Vec<Team>> = BTreeMap::new();
+        for team in snapshot.teams {
+            copies.entry(team.team_id).or_default().push(team);
+        }
+
+        let mut diverged = 0;
+        for (team_id, copies) in copies {
+            if has_diverged(&copies) {
+                diverged += 1;
+            }
+
+            let assignments: Vec<TeamAssignment> = self.team_assignments.read().unwrap()
+                .get(&team_id)
+                .map(|c| c.values().cloned().collect())
+                .unwrap_or_default();
+
+            let name = canonical_team_name(&copies, &assignments).unwrap_or_default();
+            self.apply(JournalEntry::StoreTeam(Team { name, ..copies[0].clone() }));
+        }
+
+        if diverged > 0 {
+            info!("Reconciled {} teams whose members had diverged copies in the memory store snapshot", diverged);
+        }
+
+        for user in snapshot.users {
+            self.apply(JournalEntry::StoreUser(user));
+        }
+
+        for record in snapshot.idempotency_keys {
+            self.apply(JournalEntry::ClaimIdempotencyKey(record));
+        }
+    }
+
+    fn apply(&self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::StoreReport(report) => {
+                self.reports.write().unwrap()
+                    .entry(report.team_id)
+                    .or_default()
+                    .insert(report.id, report);
+            },
+            JournalEntry::StoreReports(reports) => {
+                let mut is = self.reports.write().unwrap();
+                for report in reports {
+                    is.entry(report.team_id)
+                        .or_default()
+                        .insert(report.id, report);
+                }
+            },
+            JournalEntry::RemoveReport { team, id } => {
+                self.reports.write().unwrap()
+                    .get_mut(&team)
+                    .map(|c| c.remove(&id));
+            },
+            JournalEntry::PurgeReports { team_id, before } => {
+                if let Some(reports) = self.reports.write().unwrap().get_mut(&team_id) {
+                    reports.retain(|_, r| r.timestamp >= before);
+                }
+
+                if let Some(rollups) = self.rollups.write().unwrap().get_mut(&team_id) {
+                    rollups.retain(|_, r| !r.ends_before(before));
+                }
+            },
+            JournalEntry::RollupReports { before } => {
+                let before = ReportRollup::day_of(before);
+                let teams = self.teams.read().unwrap();
+                let mut reports = self.reports.write().unwrap();
+                let mut rollups = self.rollups.write().unwrap();
+
+                for (team_id, items) in reports.iter_mut() {
+                    let expired: Vec<Report> = items.values().filter(|r| r.timestamp < before).cloned().collect();
+                    if expired.is_empty() {
+                        continue;
+                    }
+
+                    let team_rollups = rollups.entry(*team_id).or_default();
+                    let team = teams.get(team_id);
+                    for rollup in ReportRollup::combine(&[], &expired, |r| team.and_then(|team| team.metric_bound(&r.metric))) {
+                        match team_rollups.get_mut(&(rollup.metric.clone(), rollup.day)) {
+                            Some(existing) => existing.merge(&rollup),
+                            None => {
+                                team_rollups.insert((rollup.metric.clone(), rollup.day), rollup);
+                            }
+                        }
+                    }
+
+                    items.retain(|_, r| r.timestamp >= before);
+                }
+            },
+            JournalEntry::StoreReportRollups(items) => {
+                let mut rollups = self.rollups.write().unwrap();
+                for rollup in items {
+                    rollups.entry(rollup.team_id)
+                        .or_default()
+                        .insert((rollup.metric.clone(), rollup.day), rollup);
+                }
+            },
+            JournalEntry::StageReports(reports) => {
+                let mut staged = self.staged_reports.write().unwrap();
+                for report in reports {
+                    staged.entry(report.team_id)
+                        .or_default()
+                        .insert(report.id, report);
+                }
+            },
+            JournalEntry::PublishStagedReports { team_id, before } => {
+                let published: Vec<Report> = match self.staged_reports.write().unwrap().get_mut(&team_id) {
+                    Some(staged) => {
+                        let ready: Vec<u128> = staged.values().filter(|r| r.staged_before(before)).map(|r| r.id).collect();
+                        ready.into_iter().filter_map(|id| staged.remove(&id)).collect()
+                    },
+                    None => vec![],
+                };
+
+                let mut reports = self.reports.write().unwrap();
+                for report in published {
+                    reports.entry(report.team_id)
+                        .or_default()
+                        .insert(report.id, Report { staged_at: None, ..report });
+                }
+            },
+            JournalEntry::RetractReports { team_id, receipt_hash } => {
+                for reports in [&self.reports, &self.staged_reports] {
+                    if let Some(reports) = reports.write().unwrap().get_mut(&team_id) {
+                        reports.retain(|_, r| r.receipt_hash.as_ref() != Some(&receipt_hash));
+                    }
+                }
+            },
+            JournalEntry::AmendReports { team_id, receipt_hash, metric, value, options } => {
+                for reports in [&self.reports, &self.staged_reports] {
+                    if let Some(reports) = reports.write().unwrap().get_mut(&team_id) {
+                        for report in reports.values_mut().filter(|r| r.receipt_hash.as_ref() == Some(&receipt_hash) && metric.as_ref().map(|m| &r.metric == m).unwrap_or(true)) {
+                            report.value = value;
+                            report.options = options.clone();
+                        }
+                    }
+                }
+            },
+            JournalEntry::SpendPrivacyBudget { spend, since } => {
+                let mut privacy_spends = self.privacy_spends.write().unwrap();
+                let spends = privacy_spends.entry(spend.team_id).or_default();
+                if let Some(since) = since {
+                    spends.retain(|s| s.spent_at >= since);
+                }
+                spends.push(spend);
+            },
+            JournalEntry::StorePrivacySpends { team_id, spends } => {
+                self.privacy_spends.write().unwrap().insert(team_id, spends);
+            },
+            JournalEntry::StoreAlert(alert) => {
+                self.alerts.write().unwrap()
+                    .entry(alert.team_id)
+                    .or_default()
+                    .insert(alert.id, alert);
+            },
+            JournalEntry::StoreTeam(team) => {
+                // Teams stored before ETags were introduced are served with a stable one
+                self.teams.write().unwrap()
+                    .insert(team.team_id, Team { etag: team.etag.clone().or_else(legacy_etag), ..team });
+            },
+            JournalEntry::RemoveTeam { id } => {
+                let has_members = self.team_assignments.read().unwrap()
+                    .get(&id)
+                    .map(|c| !c.is_empty())
+                    .unwrap_or_default();
+
+                if !has_members {
+                    self.teams.write().unwrap().remove(&id);
+                }
+            },
+            JournalEntry::DeleteTeam { team_id } => {
+                self.teams.write().unwrap().remove(&team_id);
+
+                if let Some(assignments) = self.team_assignments.write().unwrap().remove(&team_id) {
+                    let mut memberships = self.memberships.write().unwrap();
+                    for principal_id in assignments.keys() {
+                        memberships.get_mut(principal_id).map(|c| c.remove(&team_id));
+                    }
+                }
+
+                self.reports.write().unwrap().remove(&team_id);
+                self.staged_reports.write().unwrap().remove(&team_id);
+                self.rollups.write().unwrap().remove(&team_id);
+                self.privacy_spends.write().unwrap().remove(&team_id);
+                self.alerts.write().unwrap().remove(&team_id);
+            },
+            JournalEntry::StoreTeamAssignment(team_assignment) => {
+                self.memberships.write().unwrap()
+                    .entry(team_assignment.user_id)
+                    .or_default()
+                    .insert(team_assignment.team_id);
+
+                self.team_assignments.write().unwrap()
+                    .entry(team_assignment.team_id)
+                    .or_default()
+                    .insert(team_assignment.user_id, TeamAssignment { etag: team_assignment.etag.clone().or_else(legacy_etag), ..team_assignment });
+            },
+            JournalEntry::RemoveTeamAssignment { team_id, principal_id } => {
+                self.memberships.write().unwrap()
+                    .get_mut(&principal_id)
+                    .map(|c| c.remove(&team_id));
+
+                self.team_assignments.write().unwrap()
+                    .get_mut(&team_id)
+                    .map(|c| c.remove(&principal_id));
+            },
+            JournalEntry::StoreUser(user) => {
+                self.users.write().unwrap()
+                    .insert(user.email_hash, user);
+            },
+            JournalEntry::ClaimIdempotencyKey(record) => {
+                self.idempotency_keys.write().unwrap()
+                    .insert(record.key_hash.clone(), record);
+            },
+            JournalEntry::CompleteIdempotencyKey { key_hash, response } => {
+                if let Some(record) = self.idempotency_keys.write().unwrap().get_mut(&key_hash) {
+                    record.response = Some(response);
+                }
+            },
+            JournalEntry::ReleaseIdempotencyKey { key_hash } => {
+                self.idempotency_keys.write().unwrap().remove(&key_hash);
+            },
+            JournalEntry::PurgeIdempotencyKeys { before } => {
+                self.idempotency_keys.write().unwrap().retain(|_, record| record.created_at >= before);
+            },
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            reports: self.reports.read().unwrap().values().flat_map(|c| c.values().cloned()).collect(),
+            teams: self.teams.read().unwrap().values().cloned().collect(),
+            team_assignments: self.team_assignments.read().unwrap().values().flat_map(|c| c.values().cloned()).collect(),
+            users: self.users.read().unwrap().values().cloned().collect(),
+            rollups: self.rollups.read().unwrap().values().flat_map(|c| c.values().cloned()).collect(),
+            privacy_spends: self.privacy_spends.read().unwrap().values().flat_map(|c| c.iter().cloned()).collect(),
+            alerts: self.alerts.read().unwrap().values().flat_map(|c| c.values().cloned()).collect(),
+            staged_reports: self.staged_reports.read().unwrap().values().flat_map(|c| c.values().cloned()).collect(),
+            idempotency_keys: self.idempotency_keys.read().unwrap().values().cloned().collect(),
+        }
+    }
+
+    /// Journals a change and then applies it. Handlers validate their changes beforehand, so
+    /// that only changes which are applied are ever written to the journal, and must not hold
+    /// any of the store's locks since a snapshot is written as soon as the journal is too long.
+    fn commit(&self, entry: JournalEntry) -> Result<(), APIError> {
+        let snapshot_due = match &self.journal {
+            Some(journal) => {
+                let mut journal = journal.lock()
+                    .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+
+                journal.append(&entry).map_err(|err| {
+                    error!("Unable to write to the memory store journal: {}", err);
+                    APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later.")
+                })?;
+
+                journal.len() >= JOURNAL_SNAPSHOT_THRESHOLD
+            },
+            None => false
+        };
+
+        self.apply(entry);
+
+        if snapshot_due {
+            self.checkpoint(false);
+        }
+
+        Ok(())
+    }
+
+    /// Syncs the journal to disk, writing a new snapshot if it has grown large enough or the
+    /// last one is old enough (or unconditionally if `force` is set).
+    fn checkpoint(&self, force: bool) {
+        if let Some(journal) = &self.journal {
+            let mut journal = match journal.lock() {
+                Ok(journal) => journal,
+                Err(_) => return
+            };
+
+            let snapshot_due = journal.len() >= JOURNAL_SNAPSHOT_THRESHOLD
+                || (journal.len() > 0 && (force || journal.since_snapshot() >= JOURNAL_SNAPSHOT_INTERVAL));
+
+            let result = if snapshot_due {
+                debug!("Writing a memory store snapshot to replace {} journal entries", journal.len());
+                journal.snapshot(&self.snapshot())
+            } else {
+                journal.sync()
+            };
+
+            if let Err(err) = result {
+                error!("Unable to persist the memory store: {}", err);
+            }
         }
     }
 }
 
 impl Actor for MemoryStore {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.journal.is_some() {
+            ctx.run_interval(JOURNAL_SYNC_INTERVAL, |store, _| store.checkpoint(false));
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.checkpoint(true);
+    }
 }
 
 impl Handler<GetHealth> for MemoryStore {
@@ -58,16 +449,21 @@ impl Handler<GetReport> for MemoryStore {
 }
 
 impl Handler<GetReports> for MemoryStore {
-    type Result = Result<Vec<Report>, APIError>;
+    type Result = Result<Page<Report>, APIError>;
 
     fn handle(&mut self, msg: GetReports, _: &mut Self::Context) -> Self::Result {
 
         let is = self.reports.read()
             .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
 
-        is.get(&msg.team)
-            .ok_or(APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again."))
-            .map(|items| items.iter().filter(|(_, i)| {
+        let cursor = Page::<Report>::parse_cursor(&msg.cursor)?;
+
+        // A team without any reports has an empty page, as it does in every other store
+        let items = is.get(&msg.team)
+            .map(|items| items.range((match cursor {
+                Some(cursor) => Bound::Excluded(cursor),
+                None => Bound::Unbounded
+            }, Bound::Unbounded)).filter(|(_, i)| {
                 match msg.after.clone() {
                     Some(after) => {
                         if i.timestamp < after {
@@ -87,7 +483,12 @@ impl Handler<GetReports> for MemoryStore {
                 }
 
                 true
-            }).map(|(_id, report)| report.clone()).collect())
+            })
+            .take(msg.limit.map(|l| l + 1).unwrap_or(usize::MAX))
+            .map(|(_id, report)| report.clone()).collect::<Vec<Report>>())
+            .unwrap_or_default();
+
+        Ok(Page::from_items(items, msg.limit, |r| r.id))
     }
 }
 
@@ -95,41 +496,338 @@ impl Handler<StoreReport> for MemoryStore {
     type Result = Result<Report, APIError>;
 
     fn handle(&mut self, msg: StoreReport, _: &mut Self::Context) -> Self::Result {
-
-        let mut is = self.reports.write()
-            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
-
         let report = Report {
             id: msg.id,
             team_id: msg.team,
             metric: msg.metric.clone(),
             timestamp: msg.timestamp.clone().unwrap_or_else(|| Utc::now()),
             value: msg.value,
+            response_id: None,
+            receipt_hash: None,
+            options: msg.options.clone(),
+            staged_at: None,
         };
-        
-        is.entry(msg.team)
-            .or_insert_with(|| BTreeMap::new())
-            .insert(report.id, report.clone());
+
+        self.commit(JournalEntry::StoreReport(report.clone()))?;
 
         Ok(report)
     }
 }
 
+impl Handler<StoreReports> for MemoryStore {
+    type Result = Result<ReportBatch, APIError>;
+
+    fn handle(&mut self, msg: StoreReports, _: &mut Self::Context) -> Self::Result {
+        // The batch is journaled as a single entry so that it is replayed all-or-nothing
+        self.commit(JournalEntry::StoreReports(msg.reports.clone()))?;
+
+        Ok(ReportBatch {
+            stored: msg.reports,
+            failed: vec![],
+        })
+    }
+}
+
+impl Handler<StageReports> for MemoryStore {
+    type Result = Result<ReportBatch, APIError>;
+
+    fn handle(&mut self, msg: StageReports, _: &mut Self::Context) -> Self::Result {
+        self.commit(JournalEntry::StageReports(msg.reports.clone()))?;
+
+        Ok(ReportBatch {
+            stored: msg.reports,
+            failed: vec![],
+        })
+    }
+}
+
+impl Handler<GetStagedReports> for MemoryStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: GetStagedReports, _: &mut Self::Context) -> Self::Result {
+        Ok(self.staged_reports.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .get(&msg.team_id)
+            .map(|c| c.values().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+impl Handler<PublishStagedReports> for MemoryStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: PublishStagedReports, _: &mut Self::Context) -> Self::Result {
+        let ready: Vec<Report> = self.staged_reports.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .get(&msg.team_id)
+            .map(|staged| staged.values().filter(|r| r.staged_before(msg.before)).map(|r| Report { staged_at: None, ..r.clone() }).collect())
+            .unwrap_or_default();
+
+        if ready.is_empty() || ready.len() < msg.min_reports {
+            return Ok(vec![]);
+        }
+
+        self.commit(JournalEntry::PublishStagedReports { team_id: msg.team_id, before: msg.before })?;
+
+        Ok(ready)
+    }
+}
+
+impl MemoryStore {
+    /// Gets a team's reports, whether published or staged, which were submitted with a receipt.
+    fn receipt_reports(&self, team_id: u128, receipt_hash: &str) -> Result<Vec<Report>, APIError> {
+        let mut matching = vec![];
+        for reports in [&self.reports, &self.staged_reports] {
+            let reports = reports.read()
+                .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+
+            matching.extend(reports.get(&team_id).into_iter()
+                .flat_map(|reports| reports.values())
+                .filter(|r| r.receipt_hash.as_deref() == Some(receipt_hash))
+                .cloned());
+        }
+
+        Ok(matching)
+    }
+}
+
+impl Handler<GetReceiptReports> for MemoryStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: GetReceiptReports, _: &mut Self::Context) -> Self::Result {
+        self.receipt_reports(msg.team_id, &msg.receipt_hash)
+    }
+}
+
+impl Handler<RetractReports> for MemoryStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: RetractReports, _: &mut Self::Context) -> Self::Result {
+        let reports = self.receipt_reports(msg.team_id, &msg.receipt_hash)?;
+        if reports.is_empty() {
+            return Ok(reports);
+        }
+
+        self.commit(JournalEntry::RetractReports { team_id: msg.team_id, receipt_hash: msg.receipt_hash })?;
+
+        Ok(reports)
+    }
+}
+
+impl Handler<AmendReports> for MemoryStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: AmendReports, _: &mut Self::Context) -> Self::Result {
+        let amended = self.receipt_reports(msg.team_id, &msg.receipt_hash)?
+            .into_iter()
+            .filter(|r| msg.metric.as_ref().map(|m| &r.metric == m).unwrap_or(true))
+            .map(|r| Report { value: msg.value, options: msg.options.clone(), ..r })
+            .collect::<Vec<Report>>();
+        if amended.is_empty() {
+            return Ok(amended);
+        }
+
+        self.commit(JournalEntry::AmendReports { team_id: msg.team_id, receipt_hash: msg.receipt_hash.clone(), metric: msg.metric.clone(), value: msg.value, options: msg.options.clone() })?;
+
+        Ok(amended)
+    }
+}
+
 impl Handler<RemoveReport> for MemoryStore {
     type Result = Result<(), APIError>;
 
     fn handle(&mut self, msg: RemoveReport, _: &mut Self::Context) -> Self::Result {
-
-        let mut is = self.reports.write()
-            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
-
-        is.get_mut(&msg.team)
+        self.reports.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .get(&msg.team)
             .ok_or(APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again."))
             .and_then(|c|
-                c.remove(&msg.id)
+                c.get(&msg.id)
                 .map(|_| ())
-                .ok_or(APIError::new(404, "Not Found", "The report ID you provided could not be found. Please check it and try again.")))
-            
+                .ok_or(APIError::new(404, "Not Found", "The report ID you provided could not be found. Please check it and try again.")))?;
+
+        self.commit(JournalEntry::RemoveReport { team: msg.team, id: msg.id })
+    }
+}
+
+impl Handler<PurgeReports> for MemoryStore {
+    type Result = Result<Vec<ReportPurge>, APIError>;
+
+    fn handle(&mut self, _: PurgeReports, _: &mut Self::Context) -> Self::Result {
+        let now = Utc::now();
+        let mut purges = vec![];
+
+        let teams: Vec<Team> = self.teams.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .values().cloned().collect();
+
+        for team in teams {
+            if let Some(before) = team.retention_cutoff(now) {
+                let reports = self.reports.read()
+                    .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+                    .get(&team.team_id)
+                    .map(|c| c.values().filter(|r| r.timestamp < before).count())
+                    .unwrap_or_default();
+                let rollups = self.rollups.read()
+                    .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+                    .get(&team.team_id)
+                    .map(|c| c.values().filter(|r| r.ends_before(before)).count())
+                    .unwrap_or_default();
+
+                if reports > 0 || rollups > 0 {
+                    self.commit(JournalEntry::PurgeReports { team_id: team.team_id, before })?;
+                }
+
+                purges.push(ReportPurge { team_id: team.team_id, before, reports, rollups });
+            }
+        }
+
+        Ok(purges)
+    }
+}
+
+impl Handler<RollupReports> for MemoryStore {
+    type Result = Result<ReportCompaction, APIError>;
+
+    fn handle(&mut self, msg: RollupReports, _: &mut Self::Context) -> Self::Result {
+        let before = ReportRollup::day_of(msg.before.unwrap_or_else(Utc::now));
+
+        let expired: Vec<Report> = self.reports.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .values()
+            .flat_map(|c| c.values().filter(|r| r.timestamp < before).cloned())
+            .collect();
+
+        if expired.is_empty() {
+            return Ok(ReportCompaction::default());
+        }
+
+        self.commit(JournalEntry::RollupReports { before })?;
+
+        Ok(ReportCompaction {
+            reports: expired.len(),
+            rollups: ReportRollup::combine(&[], &expired, |_| None).len(),
+        })
+    }
+}
+
+impl Handler<GetReportRollups> for MemoryStore {
+    type Result = Result<Vec<ReportRollup>, APIError>;
+
+    fn handle(&mut self, msg: GetReportRollups, _: &mut Self::Context) -> Self::Result {
+        let rollups = self.rollups.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+
+        Ok(rollups.get(&msg.team)
+            .map(|c| c.values()
+                .filter(|r| msg.metric.as_ref().map(|metric| &r.metric == metric).unwrap_or(true))
+                .filter(|r| msg.after.map(|after| r.day >= ReportRollup::day_of(after)).unwrap_or(true))
+                .cloned()
+                .collect())
+            .unwrap_or_default())
+    }
+}
+
+impl Handler<StoreReportRollups> for MemoryStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: StoreReportRollups, _: &mut Self::Context) -> Self::Result {
+        self.commit(JournalEntry::StoreReportRollups(msg.rollups))?;
+
+        Ok(())
+    }
+}
+
+impl Handler<SpendPrivacyBudget> for MemoryStore {
+    type Result = Result<PrivacyBudget, APIError>;
+
+    fn handle(&mut self, msg: SpendPrivacyBudget, _: &mut Self::Context) -> Self::Result {
+        let since = msg.since;
+
+        let spent: f64 = self.privacy_spends.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .get(&msg.team_id)
+            .map(|c| c.iter().filter(|s| since.map(|since| s.spent_at >= since).unwrap_or(true)).map(|s| s.epsilon).sum())
+            .unwrap_or_default();
+
+        let budget = PrivacyBudget::spend(spent, msg.epsilon, msg.budget)?;
+
+        let spend = PrivacySpend { team_id: msg.team_id, spent_at: Utc::now(), epsilon: msg.epsilon };
+        self.commit(JournalEntry::SpendPrivacyBudget { spend, since })?;
+
+        Ok(budget)
+    }
+}
+
+impl Handler<GetPrivacySpends> for MemoryStore {
+    type Result = Result<Vec<PrivacySpend>, APIError>;
+
+    fn handle(&mut self, msg: GetPrivacySpends, _: &mut Self::Context) -> Self::Result {
+        let mut spends: Vec<PrivacySpend> = self.privacy_spends.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .get(&msg.team_id)
+            .cloned()
+            .unwrap_or_default();
+
+        spends.sort_by_key(|spend| spend.spent_at);
+
+        Ok(spends)
+    }
+}
+
+impl Handler<StorePrivacySpends> for MemoryStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: StorePrivacySpends, _: &mut Self::Context) -> Self::Result {
+        self.commit(JournalEntry::StorePrivacySpends { team_id: msg.team_id, spends: msg.spends })
+    }
+}
+
+impl Handler<GetAlerts> for MemoryStore {
+    type Result = Result<Vec<Alert>, APIError>;
+
+    fn handle(&mut self, msg: GetAlerts, _: &mut Self::Context) -> Self::Result {
+        let mut alerts: Vec<Alert> = self.alerts.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .get(&msg.team_id)
+            .map(|c| c.values().cloned().collect())
+            .unwrap_or_default();
+
+        alerts.sort_by(|a, b| b.raised_at.cmp(&a.raised_at).then(a.id.cmp(&b.id)));
+
+        Ok(alerts)
+    }
+}
+
+impl Handler<StoreAlert> for MemoryStore {
+    type Result = Result<Alert, APIError>;
+
+    fn handle(&mut self, msg: StoreAlert, _: &mut Self::Context) -> Self::Result {
+        self.commit(JournalEntry::StoreAlert(msg.alert.clone()))?;
+
+        Ok(msg.alert)
+    }
+}
+
+impl Handler<AcknowledgeAlert> for MemoryStore {
+    type Result = Result<Alert, APIError>;
+
+    fn handle(&mut self, msg: AcknowledgeAlert, _: &mut Self::Context) -> Self::Result {
+        let mut alert = self.alerts.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .get(&msg.team_id)
+            .and_then(|c| c.get(&msg.id))
+            .cloned()
+            .ok_or(APIError::new(404, "Not Found", "The alert ID you provided could not be found. Please check it and try again."))?;
+
+        if alert.acknowledged_at.is_none() {
+            alert.acknowledged_by = Some(msg.principal_id);
+            alert.acknowledged_at = Some(Utc::now());
+
+            self.commit(JournalEntry::StoreAlert(alert.clone()))?;
+        }
+
+        Ok(alert)
     }
 }
 
@@ -138,15 +836,16 @@ impl Handler<GetTeam> for MemoryStore {
 
     fn handle(&mut self, msg: GetTeam, _: &mut Self::Context) -> Self::Result {
 
+        let memberships = self.memberships.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
         let is = self.teams.read()
             .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
 
-        is.get(&msg.principal_id)
-            .ok_or(APIError::new(404, "Not Found", "The principal ID you provided could not be found. This likely means that you do not yet have any teams."))
-            .and_then(|c| 
-                c.get(&msg.id).map(|i| i.clone())
-                .ok_or(APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again.")))
-            
+        memberships.get(&msg.principal_id)
+            .filter(|c| c.contains(&msg.id))
+            .and_then(|_| is.get(&msg.id))
+            .map(|team| team.clone().for_principal(msg.principal_id))
+            .ok_or(APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again."))
     }
 }
 
@@ -154,13 +853,28 @@ impl Handler<GetTeams> for MemoryStore {
     type Result = Result<Vec<Team>, APIError>;
 
     fn handle(&mut self, msg: GetTeams, _: &mut Self::Context) -> Self::Result {
+        let memberships = self.memberships.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
         let is = self.teams.read()
             .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
 
-        is.get(&msg.principal_id)
+        memberships.get(&msg.principal_id)
+            .filter(|c| !c.is_empty())
             .ok_or(APIError::new(404, "Not Found", "The principal ID you provided could not be found. This probably means that you do not yet have any teams."))
             .map(|items| items.iter()
-                .map(|(_id, team)| team.clone()).collect())
+                .filter_map(|id| is.get(id))
+                .map(|team| team.clone().for_principal(msg.principal_id)).collect())
+    }
+}
+
+impl Handler<GetAllTeams> for MemoryStore {
+    type Result = Result<Vec<Team>, APIError>;
+
+    fn handle(&mut self, _: GetAllTeams, _: &mut Self::Context) -> Self::Result {
+        let is = self.teams.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+
+        Ok(is.values().cloned().collect())
     }
 }
 
@@ -168,19 +882,25 @@ impl Handler<StoreTeam> for MemoryStore {
     type Result = Result<Team, APIError>;
 
     fn handle(&mut self, msg: StoreTeam, _: &mut Self::Context) -> Self::Result {
-
-        let mut is = self.teams.write()
+        let is = self.teams.read()
             .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
 
+        let existing = is.get(&msg.team_id).map(|t| &t.etag);
+        check_etag(existing, &msg.etag)?;
+
         let team = Team {
             team_id: msg.team_id,
             user_id: msg.principal_id,
             name: msg.name.clone(),
+            retention_days: msg.retention_days,
+            privacy: msg.privacy.clone(),
+            alert_rules: msg.alert_rules.clone(),
+            metrics: msg.metrics.clone(),
+            etag: next_etag(existing),
         };
-        
-        is.entry(msg.principal_id)
-            .or_insert_with(|| BTreeMap::new())
-            .insert(team.team_id, team.clone());
+
+        drop(is);
+        self.commit(JournalEntry::StoreTeam(team.clone()))?;
 
         Ok(team)
     }
@@ -190,19 +910,87 @@ impl Handler<RemoveTeam> for MemoryStore {
     type Result = Result<(), APIError>;
 
     fn handle(&mut self, msg: RemoveTeam, _: &mut Self::Context) -> Self::Result {
+        // Removing a team which has already been removed succeeds, so that retries are safe
+        if !self.teams.read().map(|is| is.contains_key(&msg.id)).unwrap_or_default() {
+            debug!("Could not find a team record for {}, it has already been removed.", msg.id);
+            return Ok(());
+        }
+
+        self.commit(JournalEntry::RemoveTeam { id: msg.id })?;
+
+        Ok(())
+    }
+}
+
+impl Handler<DeleteTeam> for MemoryStore {
+    type Result = Result<TeamDeletion, APIError>;
+
+    fn handle(&mut self, msg: DeleteTeam, _: &mut Self::Context) -> Self::Result {
+        let deletion = {
+            let teams = self.teams.read()
+                .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+            let team_assignments = self.team_assignments.read()
+                .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+            let reports = self.reports.read()
+                .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+            let staged_reports = self.staged_reports.read()
+                .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+            let rollups = self.rollups.read()
+                .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+            let alerts = self.alerts.read()
+                .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+
+            let team_assignments: Vec<TeamAssignment> = team_assignments.get(&msg.team_id).map(|c| c.values().cloned().collect()).unwrap_or_default();
+
+            TeamDeletion {
+                teams: teams.get(&msg.team_id)
+                    .map(|team| team_assignments.iter().map(|a| team.clone().for_principal(a.user_id)).collect())
+                    .unwrap_or_default(),
+                team_assignments,
+                reports: reports.get(&msg.team_id).map(|c| c.len()).unwrap_or_default(),
+                staged_reports: staged_reports.get(&msg.team_id).map(|c| c.len()).unwrap_or_default(),
+                rollups: rollups.get(&msg.team_id).map(|c| c.len()).unwrap_or_default(),
+                alerts: alerts.get(&msg.team_id).map(|c| c.len()).unwrap_or_default(),
+            }
+        };
+
+        if !msg.dry_run {
+            self.commit(JournalEntry::DeleteTeam { team_id: msg.team_id })?;
+        }
+
+        Ok(deletion)
+    }
+}
+
+impl Handler<ReconcileTeams> for MemoryStore {
+    type Result = Result<TeamReconciliation, APIError>;
 
-        let mut is = self.teams.write()
+    /// Teams are reconciled as snapshots are loaded, so this only rebuilds the membership index.
+    fn handle(&mut self, _: ReconcileTeams, _: &mut Self::Context) -> Self::Result {
+        let team_assignments = self.team_assignments.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+        let mut memberships = self.memberships.write()
             .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
 
-        is.get_mut(&msg.principal_id)
-            .ok_or(APIError::new(404, "Not Found", "The principal ID you provided could not be found. This likely means that you do not yet have any teams."))
-            .and_then(|c|
-                c.remove(&msg.id)
-                .map(|_| ())
-                .ok_or_else(|| {
-                    debug!("Could not find a team entry for {} in the current user's team list ({}).", msg.id, msg.principal_id);
-                    APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again.")
-                }))
+        let mut expected: BTreeMap<u128, BTreeSet<u128>> = BTreeMap::new();
+        for assignment in team_assignments.values().flat_map(|c| c.values()) {
+            expected.entry(assignment.user_id).or_default().insert(assignment.team_id);
+        }
+
+        let principals: BTreeSet<u128> = expected.keys().chain(memberships.keys()).cloned().collect();
+        let changes = principals.iter().map(|principal_id| {
+            let empty = BTreeSet::new();
+            let actual = memberships.get(principal_id).unwrap_or(&empty);
+            let expected = expected.get(principal_id).unwrap_or(&empty);
+            actual.symmetric_difference(expected).count()
+        }).sum();
+
+        *memberships = expected;
+
+        Ok(TeamReconciliation {
+            memberships: changes,
+            ..Default::default()
+        })
     }
 }
 
@@ -245,18 +1033,22 @@ impl Handler<StoreTeamAssignment> for MemoryStore {
 
     fn handle(&mut self, msg: StoreTeamAssignment, _: &mut Self::Context) -> Self::Result {
 
-        let mut is = self.team_assignments.write()
-            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+        let team_assignment = {
+            let is = self.team_assignments.read()
+                .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
 
-        let team_assignment = TeamAssignment {
-            team_id: msg.team_id,
-            user_id: msg.principal_id,
-            role: msg.role.clone(),
+            let existing = is.get(&msg.team_id).and_then(|c| c.get(&msg.principal_id)).map(|a| &a.etag);
+            check_etag(existing, &msg.etag)?;
+
+            TeamAssignment {
+                team_id: msg.team_id,
+                user_id: msg.principal_id,
+                role: msg.role,
+                etag: next_etag(existing),
+            }
         };
-        
-        is.entry(msg.team_id)
-            .or_insert_with(|| BTreeMap::new())
-            .insert(team_assignment.user_id, team_assignment.clone());
+
+        self.commit(JournalEntry::StoreTeamAssignment(team_assignment.clone()))?;
 
         Ok(team_assignment)
     }
@@ -267,21 +1059,27 @@ impl Handler<RemoveTeamAssignment> for MemoryStore {
 
     fn handle(&mut self, msg: RemoveTeamAssignment, _: &mut Self::Context) -> Self::Result {
 
-        let mut is = self.team_assignments.write()
-            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+        {
+            let is = self.team_assignments.read()
+                .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
 
-        is.get_mut(&msg.team_id)
-        .ok_or_else(|| {
-            debug!("Could not find a team entry for {} in role assignments.", msg.team_id);
-            APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again.")
-        })
-            .and_then(|c|
-                c.remove(&msg.principal_id)
-                .map(|_| ())
+            is.get(&msg.team_id)
                 .ok_or_else(|| {
-                    debug!("Could not find an entry for the user {} in the team role assignments table for {}", msg.principal_id, msg.team_id);
-                    APIError::new(404, "Not Found", "The principal ID you provided could not be found. This likely means that you do not yet have any teams.")
-                }))
+                    debug!("Could not find a team entry for {} in role assignments.", msg.team_id);
+                    APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again.")
+                })
+                .and_then(|c|
+                    c.get(&msg.principal_id)
+                    .map(|_| ())
+                    .ok_or_else(|| {
+                        debug!("Could not find an entry for the user {} in the team role assignments table for {}", msg.principal_id, msg.team_id);
+                        APIError::new(404, "Not Found", "The principal ID you provided could not be found. This likely means that you do not yet have any teams.")
+                    }))?;
+        }
+
+        self.commit(JournalEntry::RemoveTeamAssignment { team_id: msg.team_id, principal_id: msg.principal_id })?;
+
+        Ok(())
     }
 }
 
@@ -298,21 +1096,357 @@ impl Handler<GetUser> for MemoryStore {
     }
 }
 
+impl Handler<GetUsers> for MemoryStore {
+    type Result = Result<Vec<User>, APIError>;
+
+    fn handle(&mut self, _: GetUsers, _: &mut Self::Context) -> Self::Result {
+        let users = self.users.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
+
+        Ok(users.values().cloned().collect())
+    }
+}
+
 impl Handler<StoreUser> for MemoryStore {
     type Result = Result<User, APIError>;
 
     fn handle(&mut self, msg: StoreUser, _: &mut Self::Context) -> Self::Result {
-        let mut users = self.users.write()
-            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?;
-
         let user = User {
             principal_id: msg.principal_id,
             email_hash: msg.email_hash,
             first_name: msg.first_name.clone()
         };
 
-        users.insert(msg.email_hash, user.clone());
+        self.commit(JournalEntry::StoreUser(user.clone()))?;
 
         Ok(user)
     }
-}
\ No newline at end of file
+}
+
+impl Handler<ClaimIdempotencyKey> for MemoryStore {
+    type Result = Result<Option<IdempotencyRecord>, APIError>;
+
+    fn handle(&mut self, msg: ClaimIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+        let now = msg.created_at.unwrap_or_else(Utc::now);
+
+        let existing = self.idempotency_keys.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .get(&msg.key_hash)
+            .filter(|record| !record.expired(now))
+            .cloned();
+
+        if existing.is_some() {
+            return Ok(existing);
+        }
+
+        let record = IdempotencyRecord { key_hash: msg.key_hash, request_hash: msg.request_hash, created_at: now, response: None };
+        self.commit(JournalEntry::ClaimIdempotencyKey(record))?;
+
+        Ok(None)
+    }
+}
+
+impl MemoryStore {
+    fn has_idempotency_key(&self, key_hash: &str) -> Result<bool, APIError> {
+        Ok(self.idempotency_keys.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .contains_key(key_hash))
+    }
+}
+
+impl Handler<CompleteIdempotencyKey> for MemoryStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: CompleteIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+        if !self.has_idempotency_key(&msg.key_hash)? {
+            return Ok(());
+        }
+
+        self.commit(JournalEntry::CompleteIdempotencyKey { key_hash: msg.key_hash, response: msg.response })?;
+
+        Ok(())
+    }
+}
+
+impl Handler<ReleaseIdempotencyKey> for MemoryStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: ReleaseIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+        if !self.has_idempotency_key(&msg.key_hash)? {
+            return Ok(());
+        }
+
+        self.commit(JournalEntry::ReleaseIdempotencyKey { key_hash: msg.key_hash })?;
+
+        Ok(())
+    }
+}
+
+impl Handler<PurgeIdempotencyKeys> for MemoryStore {
+    type Result = Result<usize, APIError>;
+
+    fn handle(&mut self, msg: PurgeIdempotencyKeys, _: &mut Self::Context) -> Self::Result {
+        let before = msg.before.unwrap_or_else(|| Utc::now() - chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS));
+
+        let expired = self.idempotency_keys.read()
+            .map_err(|_| APIError::new(500, "Internal Server Error", "The service is currently unavailable, please try again later."))?
+            .values()
+            .filter(|record| record.created_at < before)
+            .count();
+
+        if expired > 0 {
+            self.commit(JournalEntry::PurgeIdempotencyKeys { before })?;
+        }
+
+        Ok(expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    struct Stop;
+
+    impl Handler<Stop> for MemoryStore {
+        type Result = ();
+
+        fn handle(&mut self, _: Stop, ctx: &mut Self::Context) -> Self::Result {
+            ctx.stop();
+        }
+    }
+
+    /// Stops the store and waits for its final checkpoint to be written, so that it can be
+    /// safely reopened.
+    async fn close(store: Addr<MemoryStore>) {
+        store.send(Stop).await.expect("the actor should run");
+
+        while store.connected() {
+            actix_rt::time::delay_for(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[actix_rt::test]
+    async fn journal_round_trip() {
+        let path = std::env::temp_dir().join(format!("burnout-{:0>32x}", new_id()));
+
+        {
+            let store = MemoryStore::open(&path).expect("a new memory store").start();
+
+            store.send(StoreReport { id: 1, team: 7, metric: "happy_sad".into(), value: 1.0, ..Default::default() })
+                .await.expect("the actor should run").expect("the report should be stored");
+            store.send(StoreReport { id: 2, team: 7, metric: "happy_sad".into(), value: -1.0, ..Default::default() })
+                .await.expect("the actor should run").expect("the report should be stored");
+            store.send(RemoveReport { id: 2, team: 7 })
+                .await.expect("the actor should run").expect("the report should be removed");
+            store.send(StoreReports { reports: vec![
+                Report { id: 3, team_id: 7, metric: "happy_sad".into(), value: 0.5, timestamp: Utc::now(), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                Report { id: 3, team_id: 8, metric: "happy_sad".into(), value: 0.5, timestamp: Utc::now(), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            ] }).await.expect("the actor should run").expect("the reports should be stored");
+            store.send(StoreTeam { team_id: 7, principal_id: 0, name: "Test Team".into(), ..Default::default() })
+                .await.expect("the actor should run").expect("the team should be stored");
+            store.send(StoreTeamAssignment { team_id: 7, principal_id: 0, role: Role::Manager, ..Default::default() })
+                .await.expect("the actor should run").expect("the assignment should be stored");
+
+            close(store).await;
+        }
+
+        let store = MemoryStore::open(&path).expect("the existing memory store").start();
+
+        let report = store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect("the report should have been reloaded");
+        assert_eq!(report.value, 1.0);
+
+        store.send(GetReport { id: 2, team: 7 }).await.expect("the actor should run").expect_err("the removed report should not have been reloaded");
+        store.send(GetReport { id: 3, team: 8 }).await.expect("the actor should run").expect("the batched report should have been reloaded");
+
+        let team = store.send(GetTeam { id: 7, principal_id: 0 }).await.expect("the actor should run").expect("the team should have been reloaded");
+        assert_eq!(team.name, "Test Team");
+
+        std::fs::remove_dir_all(&path).expect("the temporary directory should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn rejected_changes_are_not_journaled() {
+        let path = std::env::temp_dir().join(format!("burnout-{:0>32x}", new_id()));
+
+        {
+            let store = MemoryStore::open(&path).expect("a new memory store").start();
+
+            store.send(RemoveReport { id: 1, team: 7 })
+                .await.expect("the actor should run").expect_err("a missing report should not be removed");
+            store.send(AmendReports { team_id: 7, receipt_hash: "missing".into(), metric: None, value: 1.0, options: vec![] })
+                .await.expect("the actor should run").expect("a missing receipt should amend nothing");
+            store.send(ReleaseIdempotencyKey { key_hash: "missing".into() })
+                .await.expect("the actor should run").expect("a missing key should be released");
+
+            close(store).await;
+        }
+
+        let (journal, _, entries) = Journal::open(&path).expect("the existing journal");
+        assert_eq!(journal.len(), 0);
+        assert!(entries.is_empty(), "no changes should have been journaled");
+
+        std::fs::remove_dir_all(&path).expect("the temporary directory should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn get_reports_without_any() {
+        let store = MemoryStore::new().start();
+
+        let page = store.send(GetReports { team: 7, ..Default::default() })
+            .await.expect("the actor should run").expect("a team without reports should have an empty page");
+        assert!(page.items.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[actix_rt::test]
+    async fn rollup_reports() {
+        let path = std::env::temp_dir().join(format!("burnout-{:0>32x}", new_id()));
+        let day = Utc.ymd(2020, 3, 1);
+
+        {
+            let store = MemoryStore::open(&path).expect("a new memory store").start();
+
+            store.send(StoreReports { reports: vec![
+                Report { id: 1, team_id: 7, metric: "happy_sad".into(), value: 1.0, timestamp: day.and_hms(9, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                Report { id: 2, team_id: 7, metric: "happy_sad".into(), value: -1.0, timestamp: day.and_hms(17, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                Report { id: 3, team_id: 7, metric: "happy_sad".into(), value: 0.5, timestamp: day.succ().and_hms(9, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            ] }).await.expect("the actor should run").expect("the reports should be stored");
+
+            let compaction = store.send(RollupReports { before: Some(day.succ().and_hms(12, 0, 0)) })
+                .await.expect("the actor should run").expect("the reports should be rolled up");
+            assert_eq!(compaction, ReportCompaction { reports: 2, rollups: 1 });
+
+            close(store).await;
+        }
+
+        let store = MemoryStore::open(&path).expect("the existing memory store").start();
+
+        store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("the rolled up report should have been removed");
+        store.send(GetReport { id: 3, team: 7 }).await.expect("the actor should run").expect("the report from the current day should be kept");
+
+        let rollups = store.send(GetReportRollups { team: 7, ..Default::default() }).await.expect("the actor should run").expect("the rollups should be listed");
+        assert_eq!(rollups, vec![ReportRollup {
+            team_id: 7,
+            metric: "happy_sad".into(),
+            day: day.and_hms(0, 0, 0),
+            count: 2,
+            sum: 0.0,
+            min: -1.0,
+            max: 1.0,
+            sum_squares: 2.0,
+            option_counts: Default::default(),
+        }]);
+
+        std::fs::remove_dir_all(&path).expect("the temporary directory should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn spend_privacy_budget() {
+        let path = std::env::temp_dir().join(format!("burnout-{:0>32x}", new_id()));
+
+        {
+            let store = MemoryStore::open(&path).expect("a new memory store").start();
+
+            let budget = store.send(SpendPrivacyBudget { team_id: 7, epsilon: 1.0, budget: 2.0, since: None })
+                .await.expect("the actor should run").expect("the budget should be spent");
+            assert_eq!(budget, PrivacyBudget { spent: 1.0, remaining: 1.0 });
+
+            close(store).await;
+        }
+
+        let store = MemoryStore::open(&path).expect("the existing memory store").start();
+
+        store.send(SpendPrivacyBudget { team_id: 7, epsilon: 1.0, budget: 2.0, since: None })
+            .await.expect("the actor should run").expect("the rest of the budget should be spent");
+        store.send(SpendPrivacyBudget { team_id: 7, epsilon: 1.0, budget: 2.0, since: None })
+            .await.expect("the actor should run").expect_err("the reloaded budget should be exhausted");
+        store.send(SpendPrivacyBudget { team_id: 8, epsilon: 1.0, budget: 2.0, since: None })
+            .await.expect("the actor should run").expect("other teams should have their own budget");
+
+        let budget = store.send(SpendPrivacyBudget { team_id: 7, epsilon: 1.0, budget: 2.0, since: Some(Utc::now()) })
+            .await.expect("the actor should run").expect("spends outside of the window should not count");
+        assert_eq!(budget.spent, 1.0);
+
+        std::fs::remove_dir_all(&path).expect("the temporary directory should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn publish_staged_reports() {
+        let path = std::env::temp_dir().join(format!("burnout-{:0>32x}", new_id()));
+        let day = Utc.ymd(2020, 3, 1);
+
+        {
+            let store = MemoryStore::open(&path).expect("a new memory store").start();
+
+            store.send(StageReports { reports: vec![
+                Report { id: 1, team_id: 7, metric: "happy_sad".into(), value: 1.0, timestamp: day.and_hms(9, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                Report { id: 2, team_id: 7, metric: "happy_sad".into(), value: -1.0, timestamp: day.and_hms(17, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+                Report { id: 3, team_id: 7, metric: "happy_sad".into(), value: 0.5, timestamp: day.succ().and_hms(9, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            ] }).await.expect("the actor should run").expect("the reports should be staged");
+
+            store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("a staged report should not be readable");
+
+            let published = store.send(PublishStagedReports { team_id: 7, before: Some(day.succ().and_hms(0, 0, 0)), min_reports: 3 })
+                .await.expect("the actor should run").expect("the staged reports should be checked");
+            assert!(published.is_empty(), "too few reports were ready to be published");
+
+            close(store).await;
+        }
+
+        let store = MemoryStore::open(&path).expect("the existing memory store").start();
+
+        let published = store.send(PublishStagedReports { team_id: 7, before: Some(day.succ().and_hms(0, 0, 0)), min_reports: 2 })
+            .await.expect("the actor should run").expect("the staged reports should be published");
+        assert_eq!(published.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1, 2]);
+
+        store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect("the published report should be readable");
+        store.send(GetReport { id: 3, team: 7 }).await.expect("the actor should run").expect_err("the later report should still be staged");
+
+        let published = store.send(PublishStagedReports { team_id: 7, before: None, min_reports: 1 })
+            .await.expect("the actor should run").expect("the staged reports should be published");
+        assert_eq!(published.len(), 1);
+
+        std::fs::remove_dir_all(&path).expect("the temporary directory should be removed");
+    }
+
+    #[actix_rt::test]
+    async fn reconcile_legacy_snapshot() {
+        let path = std::env::temp_dir().join(format!("burnout-{:0>32x}", new_id()));
+        std::fs::create_dir_all(&path).expect("the temporary directory should be created");
+
+        let snapshot = Snapshot {
+            teams: vec![
+                Team { team_id: 7, user_id: 1, name: "Renamed Team".into(), retention_days: None, privacy: Default::default(), alert_rules: vec![], metrics: vec![], etag: None },
+                Team { team_id: 7, user_id: 2, name: "Test Team".into(), retention_days: None, privacy: Default::default(), alert_rules: vec![], metrics: vec![], etag: None },
+            ],
+            team_assignments: vec![
+                TeamAssignment { team_id: 7, user_id: 1, role: Role::Manager, etag: None },
+                TeamAssignment { team_id: 7, user_id: 2, role: Role::Member, etag: None },
+            ],
+            ..Default::default()
+        };
+        std::fs::write(path.join("snapshot.json"), serde_json::to_vec(&snapshot).expect("the snapshot should serialize"))
+            .expect("the snapshot should be written");
+
+        let store = MemoryStore::open(&path).expect("the existing memory store").start();
+
+        let team = store.send(GetTeam { id: 7, principal_id: 2 }).await.expect("the actor should run").expect("the member should have the team");
+        assert_eq!(team.name, "Renamed Team");
+        assert_eq!(team.user_id, 2);
+        assert_eq!(team.etag, Some("\"0\"".into()), "the legacy team should be served with a stable etag");
+
+        let err = store.send(StoreTeam { team_id: 7, principal_id: 2, name: "Stale Team".into(), etag: Some("\"1\"".into()), ..Default::default() })
+            .await.expect("the actor should run").expect_err("an etag which the legacy team was never served with should not match");
+        assert_eq!(err.code, 412);
+        store.send(StoreTeam { team_id: 7, principal_id: 2, name: "Updated Team".into(), etag: team.etag.clone(), ..Default::default() })
+            .await.expect("the actor should run").expect("the legacy team's etag should match");
+
+        let reconciliation = store.send(ReconcileTeams {}).await.expect("the actor should run").expect("the teams should be reconciled");
+        assert_eq!(reconciliation, TeamReconciliation::default());
+
+        std::fs::remove_dir_all(&path).expect("the temporary directory should be removed");
+    }
+}
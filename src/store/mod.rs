@@ -1,11 +1,152 @@
-#[cfg(any(test, not(feature = "table_storage")))]
+mod etag;
+mod journal;
 mod memory;
+mod odata;
+mod reconcile;
+mod tablestorage;
 
-#[cfg(any(test, not(feature = "table_storage")))]
-pub type Store = memory::MemoryStore;
+#[cfg(feature = "sqlite_storage")]
+mod sqlite;
 
-#[cfg(all(not(test), feature = "table_storage"))]
-mod tablestorage;
+use actix::prelude::*;
+use crate::models::*;
+
+pub use memory::MemoryStore;
+pub use tablestorage::TableStorage;
+
+#[cfg(feature = "sqlite_storage")]
+pub use sqlite::SqliteStore;
+
+/// The backend which is used when the `STORAGE_BACKEND` environment variable
+/// has not been set.
+#[cfg(feature = "table_storage")]
+const DEFAULT_BACKEND: &str = "table_storage";
+
+#[cfg(not(feature = "table_storage"))]
+const DEFAULT_BACKEND: &str = "memory";
+
+macro_rules! store_messages {
+    ($($field:ident: $msg:ty),* $(,)?) => {
+        /// A handle to the storage backend which was selected at startup.
+        ///
+        /// It holds a [Recipient] for every message that a backend is expected to
+        /// handle, which allows any actor implementing those handlers to be used
+        /// without the rest of the application needing to know its concrete type.
+        #[derive(Clone)]
+        pub struct Store {
+            $($field: Recipient<$msg>),*
+        }
+
+        impl Store {
+            pub fn new<A>(addr: Addr<A>) -> Self
+            where
+                A: Actor $(+ Handler<$msg>)*,
+                A::Context: $(dev::ToEnvelope<A, $msg> +)*
+            {
+                Self {
+                    $($field: addr.clone().recipient()),*
+                }
+            }
+        }
+
+        $(
+            impl StoreMessage for $msg {
+                fn recipient(store: &Store) -> &Recipient<Self> {
+                    &store.$field
+                }
+            }
+        )*
+    };
+}
+
+/// A message which can be sent to the storage backend through a [Store].
+pub trait StoreMessage: Message + Send + Sized
+where
+    Self::Result: Send,
+{
+    fn recipient(store: &Store) -> &Recipient<Self>;
+}
+
+store_messages! {
+    get_health: GetHealth,
+
+    get_report: GetReport,
+    get_reports: GetReports,
+    store_report: StoreReport,
+    store_reports: StoreReports,
+    remove_report: RemoveReport,
+    purge_reports: PurgeReports,
+    stage_reports: StageReports,
+    get_staged_reports: GetStagedReports,
+    publish_staged_reports: PublishStagedReports,
+    get_receipt_reports: GetReceiptReports,
+    retract_reports: RetractReports,
+    amend_reports: AmendReports,
+    claim_idempotency_key: ClaimIdempotencyKey,
+    complete_idempotency_key: CompleteIdempotencyKey,
+    release_idempotency_key: ReleaseIdempotencyKey,
+    purge_idempotency_keys: PurgeIdempotencyKeys,
+    rollup_reports: RollupReports,
+    get_report_rollups: GetReportRollups,
+    store_report_rollups: StoreReportRollups,
+    spend_privacy_budget: SpendPrivacyBudget,
+    get_privacy_spends: GetPrivacySpends,
+    store_privacy_spends: StorePrivacySpends,
+
+    get_alerts: GetAlerts,
+    store_alert: StoreAlert,
+    acknowledge_alert: AcknowledgeAlert,
+
+    get_team: GetTeam,
+    get_teams: GetTeams,
+    get_all_teams: GetAllTeams,
+    store_team: StoreTeam,
+    remove_team: RemoveTeam,
+    delete_team: DeleteTeam,
+    reconcile_teams: ReconcileTeams,
+
+    get_team_assignment: GetTeamAssignment,
+    get_team_assignments: GetTeamAssignments,
+    store_team_assignment: StoreTeamAssignment,
+    remove_team_assignment: RemoveTeamAssignment,
+
+    get_user: GetUser,
+    get_users: GetUsers,
+    store_user: StoreUser,
+}
+
+impl Store {
+    /// Starts the storage backend named by the `STORAGE_BACKEND` environment variable.
+    pub fn from_env() -> Self {
+        let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| DEFAULT_BACKEND.into());
+
+        Store::from_name(&backend)
+            .unwrap_or_else(|| panic!("The '{}' storage backend is not supported by this build, set STORAGE_BACKEND to a supported backend before starting the server.", backend))
+    }
+
+    /// Starts the storage backend with the given name, if it is supported by this build.
+    pub fn from_name(backend: &str) -> Option<Self> {
+        info!("Using the '{}' storage backend", backend);
+
+        match backend {
+            "memory" => Some(Store::new(MemoryStore::new().start())),
+            "table_storage" => Some(Store::new(TableStorage::new().start())),
+            #[cfg(feature = "sqlite_storage")]
+            "sqlite" => Some(Store::new(SyncArbiter::start(1, SqliteStore::new))),
+            _ => None
+        }
+    }
+
+    pub fn send<M>(&self, msg: M) -> RecipientRequest<M>
+    where
+        M: StoreMessage + 'static,
+        M::Result: Send,
+    {
+        M::recipient(self).send(msg)
+    }
 
-#[cfg(all(not(test), feature = "table_storage"))]
-pub type Store = tablestorage::TableStorage;
+    /// Gets every team in the store.
+    pub async fn get_all_teams(&self) -> Result<Vec<Team>, crate::api::APIError> {
+        self.send(GetAllTeams {}).await?
+    }
+}
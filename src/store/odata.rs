@@ -0,0 +1,272 @@
+use chrono::prelude::*;
+use std::fmt;
+
+/// The characters which are left unescaped when a filter expression is placed in a URL.
+const FILTER_CHARACTERS: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A literal value which may appear on the right hand side of a comparison.
+///
+/// Every property we write is stored as a string, so each literal is converted into the same
+/// string representation that is written to the table. Only the system `Timestamp` property
+/// is compared as a date.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    String(String),
+    DateTime(DateTime<Utc>),
+}
+
+impl From<&str> for Literal {
+    fn from(value: &str) -> Self {
+        Literal::String(value.to_string())
+    }
+}
+
+impl From<String> for Literal {
+    fn from(value: String) -> Self {
+        Literal::String(value)
+    }
+}
+
+impl From<u128> for Literal {
+    fn from(id: u128) -> Self {
+        Literal::String(format!("{:0>32x}", id))
+    }
+}
+
+impl From<DateTime<Utc>> for Literal {
+    fn from(timestamp: DateTime<Utc>) -> Self {
+        Literal::String(format_timestamp(timestamp))
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::String(value) => write!(f, "'{}'", value.replace('\'', "''")),
+            Literal::DateTime(timestamp) => write!(f, "datetime'{}'", timestamp.to_rfc3339_opts(SecondsFormat::Micros, true)),
+        }
+    }
+}
+
+/// Formats a timestamp with a fixed width so that comparing the strings in a filter
+/// orders them in the same way as the timestamps they represent.
+pub fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+}
+
+impl Operator {
+    #[cfg(test)]
+    fn compare<T: PartialOrd>(&self, actual: T, value: T) -> bool {
+        match self {
+            Operator::Eq => actual == value,
+            Operator::Gt => actual > value,
+            Operator::Ge => actual >= value,
+            Operator::Lt => actual < value,
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operator::Eq => "eq",
+            Operator::Gt => "gt",
+            Operator::Ge => "ge",
+            Operator::Lt => "lt",
+        })
+    }
+}
+
+/// A typed OData filter expression for a table storage query.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    Compare(&'static str, Operator, Literal),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    pub fn eq<L: Into<Literal>>(property: &'static str, value: L) -> Self {
+        Filter::Compare(property, Operator::Eq, value.into())
+    }
+
+    pub fn gt<L: Into<Literal>>(property: &'static str, value: L) -> Self {
+        Filter::Compare(property, Operator::Gt, value.into())
+    }
+
+    pub fn ge<L: Into<Literal>>(property: &'static str, value: L) -> Self {
+        Filter::Compare(property, Operator::Ge, value.into())
+    }
+
+    pub fn lt<L: Into<Literal>>(property: &'static str, value: L) -> Self {
+        Filter::Compare(property, Operator::Lt, value.into())
+    }
+
+    /// Compares the time at which an entity was last written, which is the only timestamp
+    /// available on entities written before the `ReportedAt` property was introduced.
+    pub fn written(op: Operator, timestamp: DateTime<Utc>) -> Self {
+        Filter::Compare("Timestamp", op, Literal::DateTime(timestamp))
+    }
+
+    pub fn and(self, other: Filter) -> Self {
+        Filter::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: Filter) -> Self {
+        Filter::Or(Box::new(self), Box::new(other))
+    }
+
+    /// Adds a clause to this filter only if one is provided.
+    pub fn and_maybe(self, other: Option<Filter>) -> Self {
+        match other {
+            Some(other) => self.and(other),
+            None => self,
+        }
+    }
+
+    /// Evaluates the filter against an entity in the same way that table storage would,
+    /// allowing queries to be checked without a storage account.
+    #[cfg(test)]
+    pub fn matches(&self, entity: &serde_json::Value) -> bool {
+        match self {
+            Filter::Compare(property, op, Literal::String(value)) => match entity.get(property).and_then(|v| v.as_str()) {
+                Some(actual) => op.compare(actual, value.as_str()),
+                None => false,
+            },
+            Filter::Compare(property, op, Literal::DateTime(value)) => match entity.get(property).and_then(|v| v.as_str()).and_then(|v| DateTime::parse_from_rfc3339(v).ok()) {
+                Some(actual) => op.compare(actual.with_timezone(&Utc), *value),
+                None => false,
+            },
+            Filter::And(left, right) => left.matches(entity) && right.matches(entity),
+            Filter::Or(left, right) => left.matches(entity) || right.matches(entity),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Compare(property, op, value) => write!(f, "{} {} {}", property, op, value),
+            Filter::And(left, right) => write!(f, "({}) and ({})", left, right),
+            Filter::Or(left, right) => write!(f, "({}) or ({})", left, right),
+        }
+    }
+}
+
+/// The query string options used when listing entities from a table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    pub filter: Option<Filter>,
+    /// The properties to return for each entity, or all of them if this is empty.
+    pub select: Vec<&'static str>,
+    pub top: Option<usize>,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn select(mut self, properties: &[&'static str]) -> Self {
+        self.select = properties.to_vec();
+        self
+    }
+
+    pub fn top(mut self, top: Option<usize>) -> Self {
+        self.top = top;
+        self
+    }
+}
+
+/// Renders the query as a URL query string, escaping the filter expression.
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+
+        if let Some(filter) = &self.filter {
+            parts.push(format!("$filter={}", percent_encoding::utf8_percent_encode(&filter.to_string(), FILTER_CHARACTERS)));
+        }
+
+        if !self.select.is_empty() {
+            parts.push(format!("$select={}", self.select.join(",")));
+        }
+
+        if let Some(top) = self.top {
+            parts.push(format!("$top={}", top));
+        }
+
+        f.write_str(&parts.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_and_escapes_literals() {
+        let filter = Filter::eq("Metric", "it's").and(Filter::eq("PartitionKey", 1u128));
+
+        assert_eq!(filter.to_string(), "(Metric eq 'it''s') and (PartitionKey eq '00000000000000000000000000000001')");
+    }
+
+    #[test]
+    fn cannot_inject_clauses() {
+        let filter = Filter::eq("Metric", "x' or PartitionKey gt '");
+
+        assert_eq!(filter.to_string(), "Metric eq 'x'' or PartitionKey gt '''");
+        assert!(!filter.matches(&serde_json::json!({ "Metric": "happy_sad", "PartitionKey": "a" })));
+    }
+
+    #[test]
+    fn encodes_query_strings() {
+        let query = Query::new().filter(Filter::eq("Metric", "a&b=c #1")).top(Some(5));
+
+        assert_eq!(query.to_string(), "$filter=Metric%20eq%20%27a%26b%3Dc%20%231%27&$top=5");
+        assert_eq!(Query::new().to_string(), "");
+    }
+
+    #[test]
+    fn selects_properties() {
+        let query = Query::new().filter(Filter::eq("PartitionKey", 1u128)).select(&["PartitionKey", "RowKey"]);
+
+        assert_eq!(query.to_string(), "$filter=PartitionKey%20eq%20%2700000000000000000000000000000001%27&$select=PartitionKey,RowKey");
+    }
+
+    #[test]
+    fn timestamps_order_as_strings() {
+        let earlier = Utc.ymd(2020, 1, 1).and_hms_milli(9, 0, 0, 5);
+        let later = Utc.ymd(2020, 1, 1).and_hms(10, 0, 0);
+
+        assert!(format_timestamp(earlier) < format_timestamp(later));
+        assert!(Filter::ge("ReportedAt", earlier).matches(&serde_json::json!({ "ReportedAt": format_timestamp(later) })));
+        assert!(!Filter::ge("ReportedAt", later).matches(&serde_json::json!({ "ReportedAt": format_timestamp(earlier) })));
+    }
+
+    #[test]
+    fn compares_write_times_as_dates() {
+        let cutoff = Utc.ymd(2020, 1, 1).and_hms(10, 0, 0);
+        let filter = Filter::lt("ReportedAt", cutoff).or(Filter::written(Operator::Lt, cutoff));
+
+        assert_eq!(filter.to_string(), "(ReportedAt lt '2020-01-01T10:00:00.000000000Z') or (Timestamp lt datetime'2020-01-01T10:00:00.000000Z')");
+        assert!(filter.matches(&serde_json::json!({ "Timestamp": "2020-01-01T09:59:59.9999999Z" })));
+        assert!(!filter.matches(&serde_json::json!({ "Timestamp": "2020-01-01T10:00:00Z" })));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::models::*;
+use std::collections::{BTreeMap, HashSet};
+
+/// Picks the name a team should keep when the copies which earlier versions stored for each
+/// of its members have diverged.
+///
+/// The names used by the team's managers are preferred (since only they could rename it for
+/// everyone), falling back to every copy if none of the managers have one. The most common
+/// name wins, with ties going to the copy held by the lowest principal ID so that every
+/// backend reaches the same decision.
+pub fn canonical_team_name(copies: &[Team], assignments: &[TeamAssignment]) -> Option<String> {
+    let managers: HashSet<u128> = assignments.iter()
+        .filter(|a| a.role == Role::Manager)
+        .map(|a| a.user_id)
+        .collect();
+
+    let candidates: Vec<&Team> = if copies.iter().any(|t| managers.contains(&t.user_id)) {
+        copies.iter().filter(|t| managers.contains(&t.user_id)).collect()
+    } else {
+        copies.iter().collect()
+    };
+
+    let mut names: BTreeMap<&str, (usize, u128)> = BTreeMap::new();
+    for team in candidates {
+        let entry = names.entry(team.name.as_str()).or_insert((0, team.user_id));
+        entry.0 += 1;
+        entry.1 = entry.1.min(team.user_id);
+    }
+
+    names.into_iter()
+        .max_by(|(_, (a_count, a_user)), (_, (b_count, b_user))| a_count.cmp(b_count).then(b_user.cmp(a_user)))
+        .map(|(name, _)| name.to_string())
+}
+
+/// Whether the copies of a team disagree about its name.
+pub fn has_diverged(copies: &[Team]) -> bool {
+    copies.iter().any(|t| t.name != copies[0].name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copy(user_id: u128, name: &str) -> Team {
+        Team { team_id: 1, user_id, name: name.into(), retention_days: None, privacy: Default::default(), alert_rules: vec![], metrics: vec![], etag: None }
+    }
+
+    fn assignment(user_id: u128, role: Role) -> TeamAssignment {
+        TeamAssignment { team_id: 1, user_id, role, etag: None }
+    }
+
+    #[test]
+    fn prefers_managers() {
+        let copies = vec![copy(1, "Old"), copy(2, "Old"), copy(3, "Renamed")];
+        let assignments = vec![assignment(1, Role::Member), assignment(2, Role::Member), assignment(3, Role::Manager)];
+
+        assert_eq!(canonical_team_name(&copies, &assignments), Some("Renamed".into()));
+        assert!(has_diverged(&copies));
+    }
+
+    #[test]
+    fn prefers_most_common_name() {
+        let copies = vec![copy(3, "B"), copy(1, "A"), copy(2, "B")];
+
+        assert_eq!(canonical_team_name(&copies, &[]), Some("B".into()));
+    }
+
+    #[test]
+    fn breaks_ties_by_principal() {
+        let copies = vec![copy(2, "B"), copy(1, "A")];
+
+        assert_eq!(canonical_team_name(&copies, &[]), Some("A".into()));
+        assert_eq!(canonical_team_name(&[], &[]), None);
+    }
+}
@@ -0,0 +1,1594 @@
+use crate::models::*;
+use crate::api::APIError;
+use super::etag::{check_etag, next_etag};
+use super::reconcile::{canonical_team_name, has_diverged};
+use std::collections::BTreeMap;
+use chrono::prelude::*;
+use actix::prelude::*;
+use rusqlite::{params, Connection, OptionalExtension, Row, NO_PARAMS};
+use rusqlite::types::Type;
+use serde::de::DeserializeOwned;
+
+/// Stores everything in a SQLite database. It must be started on a [SyncArbiter], since every
+/// query blocks its thread until SQLite has finished with it.
+pub struct SqliteStore {
+    started_at: chrono::DateTime<chrono::Utc>,
+    connection: Connection,
+}
+
+/// The schema migrations which are applied, in order, when the store is opened.
+/// The index of the last applied migration is tracked using SQLite's `user_version`
+/// pragma, so new migrations should only ever be appended to this list.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE reports (
+        team_id TEXT NOT NULL,
+        id TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        metric TEXT NOT NULL,
+        value REAL NOT NULL,
+        PRIMARY KEY (team_id, id)
+    );
+
+    CREATE INDEX reports_metric ON reports (team_id, metric, timestamp);
+
+    CREATE TABLE teams (
+        principal_id TEXT NOT NULL,
+        team_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        PRIMARY KEY (principal_id, team_id)
+    );
+
+    CREATE TABLE team_assignments (
+        team_id TEXT NOT NULL,
+        principal_id TEXT NOT NULL,
+        role TEXT NOT NULL,
+        PRIMARY KEY (team_id, principal_id)
+    );
+
+    CREATE TABLE users (
+        email_hash TEXT NOT NULL PRIMARY KEY,
+        principal_id TEXT NOT NULL,
+        first_name TEXT NOT NULL
+    );
+    ",
+    "
+    ALTER TABLE teams ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE team_assignments ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+    ",
+    "
+    ALTER TABLE teams RENAME TO legacy_teams;
+
+    CREATE TABLE teams (
+        team_id TEXT NOT NULL PRIMARY KEY,
+        name TEXT NOT NULL,
+        version INTEGER NOT NULL DEFAULT 0
+    );
+
+    CREATE INDEX team_memberships ON team_assignments (principal_id, team_id);
+
+    -- Keeps the name which canonical_team_name would pick: the most common of the managers'
+    -- copies (or of every copy if no manager has one), with ties going to the lowest principal.
+    INSERT INTO teams (team_id, name, version)
+    SELECT team_id, name, 1 FROM (
+        SELECT team_id, name, ROW_NUMBER() OVER (PARTITION BY team_id ORDER BY by_manager DESC, copies DESC, first_principal) AS rank
+        FROM (
+            SELECT legacy_teams.team_id, legacy_teams.name, team_assignments.role IS 'Manager' AS by_manager, COUNT(*) AS copies, MIN(legacy_teams.principal_id) AS first_principal
+            FROM legacy_teams
+            LEFT JOIN team_assignments ON team_assignments.team_id = legacy_teams.team_id AND team_assignments.principal_id = legacy_teams.principal_id
+            GROUP BY legacy_teams.team_id, legacy_teams.name, by_manager
+        )
+    ) WHERE rank = 1;
+    ",
+    "
+    ALTER TABLE teams ADD COLUMN retention_days INTEGER;
+    ",
+    "
+    CREATE TABLE report_rollups (
+        team_id TEXT NOT NULL,
+        metric TEXT NOT NULL,
+        day TEXT NOT NULL,
+        count INTEGER NOT NULL,
+        sum REAL NOT NULL,
+        min REAL NOT NULL,
+        max REAL NOT NULL,
+        sum_squares REAL NOT NULL,
+        PRIMARY KEY (team_id, metric, day)
+    );
+    ",
+    "
+    ALTER TABLE teams ADD COLUMN privacy TEXT;
+    ",
+    "
+    CREATE TABLE privacy_spends (
+        team_id TEXT NOT NULL,
+        spent_at TEXT NOT NULL,
+        epsilon REAL NOT NULL
+    );
+
+    CREATE INDEX privacy_spends_team ON privacy_spends (team_id, spent_at);
+    ",
+    "
+    ALTER TABLE teams ADD COLUMN alert_rules TEXT;
+
+    CREATE TABLE alerts (
+        team_id TEXT NOT NULL,
+        id TEXT NOT NULL,
+        metric TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        raised_at TEXT NOT NULL,
+        window_mean REAL NOT NULL,
+        baseline_mean REAL,
+        threshold REAL,
+        acknowledged_by TEXT,
+        acknowledged_at TEXT,
+        PRIMARY KEY (team_id, id)
+    );
+    ",
+    "
+    ALTER TABLE teams ADD COLUMN metrics TEXT;
+    ",
+    "
+    ALTER TABLE reports ADD COLUMN response_id TEXT;
+    ",
+    "
+    CREATE TABLE staged_reports (
+        team_id TEXT NOT NULL,
+        id TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        metric TEXT NOT NULL,
+        value REAL NOT NULL,
+        response_id TEXT,
+        PRIMARY KEY (team_id, id)
+    );
+    ",
+    "
+    ALTER TABLE reports ADD COLUMN receipt_hash TEXT;
+    ALTER TABLE staged_reports ADD COLUMN receipt_hash TEXT;
+    CREATE INDEX reports_receipt_hash ON reports (team_id, receipt_hash) WHERE receipt_hash IS NOT NULL;
+    ",
+    "
+    CREATE TABLE idempotency_keys (
+        key_hash TEXT NOT NULL PRIMARY KEY,
+        request_hash TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        response TEXT
+    );
+
+    CREATE INDEX idempotency_keys_created_at ON idempotency_keys (created_at);
+    ",
+    "
+    ALTER TABLE reports ADD COLUMN options TEXT;
+    ALTER TABLE staged_reports ADD COLUMN options TEXT;
+    ALTER TABLE report_rollups ADD COLUMN option_counts TEXT;
+    ",
+    "
+    ALTER TABLE staged_reports ADD COLUMN staged_at TEXT;
+    ",
+];
+
+/// Selects each team along with the principals which are members of it.
+const TEAM_MEMBERS_QUERY: &str = "SELECT teams.team_id, team_assignments.principal_id, teams.name, teams.retention_days, teams.privacy, teams.alert_rules, teams.metrics, teams.version FROM teams INNER JOIN team_assignments ON team_assignments.team_id = teams.team_id";
+
+impl SqliteStore {
+    pub fn new() -> Self {
+        let path = std::env::var("SQLITE_DATABASE_PATH").unwrap_or_else(|_| "burnout.db".into());
+
+        Self::open(&path).expect("a valid SQLite database")
+    }
+
+    pub fn open(path: &str) -> Result<Self, rusqlite::Error> {
+        let connection = Connection::open(path)?;
+        SqliteStore::migrate(&connection)?;
+
+        Ok(Self {
+            started_at: chrono::Utc::now(),
+            connection,
+        })
+    }
+
+    fn migrate(connection: &Connection) -> Result<(), rusqlite::Error> {
+        let version: i64 = connection.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            info!("Applying SQLite schema migration {}", index + 1);
+            connection.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", migration, index + 1))?;
+        }
+
+        Ok(())
+    }
+
+    fn id(id: u128) -> String {
+        format!("{:0>32x}", id)
+    }
+
+    fn etag(version: i64) -> Option<String> {
+        Some(format!("\"{}\"", version))
+    }
+
+    fn version(etag: &Option<String>) -> i64 {
+        etag.as_ref().and_then(|etag| etag.trim_matches('"').parse().ok()).unwrap_or_default()
+    }
+
+    fn timestamp(timestamp: DateTime<Utc>) -> String {
+        timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)
+    }
+
+    /// Encodes the options chosen for a report, which are left empty for numeric reports.
+    fn options(options: &[String]) -> Option<String> {
+        if options.is_empty() {
+            None
+        } else {
+            serde_json::to_string(options).ok()
+        }
+    }
+
+    /// Reports a value which could not be decoded, rather than silently replacing it with a default.
+    fn conversion_error<E: std::error::Error + Send + Sync + 'static>(row: &Row, column: &str, err: E) -> rusqlite::Error {
+        match row.column_index(column) {
+            Ok(index) => rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(err)),
+            Err(err) => err,
+        }
+    }
+
+    fn parse_id(row: &Row, column: &str) -> Result<u128, rusqlite::Error> {
+        let id = row.get::<_, String>(column)?;
+        u128::from_str_radix(&id, 16).map_err(|err| SqliteStore::conversion_error(row, column, err))
+    }
+
+    fn parse_optional_id(row: &Row, column: &str) -> Result<Option<u128>, rusqlite::Error> {
+        row.get::<_, Option<String>>(column)?
+            .map(|id| u128::from_str_radix(&id, 16).map_err(|err| SqliteStore::conversion_error(row, column, err)))
+            .transpose()
+    }
+
+    fn parse_timestamp(row: &Row, column: &str) -> Result<DateTime<Utc>, rusqlite::Error> {
+        let timestamp = row.get::<_, String>(column)?;
+        DateTime::parse_from_rfc3339(&timestamp)
+            .map(|dt| dt.with_timezone(&Utc))
+            .map_err(|err| SqliteStore::conversion_error(row, column, err))
+    }
+
+    fn parse_optional_timestamp(row: &Row, column: &str) -> Result<Option<DateTime<Utc>>, rusqlite::Error> {
+        row.get::<_, Option<String>>(column)?
+            .map(|timestamp| DateTime::parse_from_rfc3339(&timestamp)
+                .map(|dt| dt.with_timezone(&Utc))
+                .map_err(|err| SqliteStore::conversion_error(row, column, err)))
+            .transpose()
+    }
+
+    /// Decodes a column holding JSON, using the type's default if the column is `NULL`.
+    fn parse_json<T: DeserializeOwned + Default>(row: &Row, column: &str) -> Result<T, rusqlite::Error> {
+        row.get::<_, Option<String>>(column)?
+            .map(|json| serde_json::from_str(&json).map_err(|err| SqliteStore::conversion_error(row, column, err)))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn report_from_row(row: &Row) -> Result<Report, rusqlite::Error> {
+        Ok(Report {
+            id: SqliteStore::parse_id(row, "id")?,
+            team_id: SqliteStore::parse_id(row, "team_id")?,
+            timestamp: SqliteStore::parse_timestamp(row, "timestamp")?,
+            metric: row.get("metric")?,
+            value: row.get::<_, f64>("value")? as f32,
+            response_id: SqliteStore::parse_optional_id(row, "response_id")?,
+            receipt_hash: row.get("receipt_hash")?,
+            options: SqliteStore::parse_json(row, "options")?,
+            staged_at: None,
+        })
+    }
+
+    fn staged_report_from_row(row: &Row) -> Result<Report, rusqlite::Error> {
+        Ok(Report {
+            staged_at: SqliteStore::parse_optional_timestamp(row, "staged_at")?,
+            ..SqliteStore::report_from_row(row)?
+        })
+    }
+
+    fn idempotency_record_from_row(row: &Row) -> Result<IdempotencyRecord, rusqlite::Error> {
+        Ok(IdempotencyRecord {
+            key_hash: row.get("key_hash")?,
+            request_hash: row.get("request_hash")?,
+            created_at: SqliteStore::parse_timestamp(row, "created_at")?,
+            response: row.get("response")?,
+        })
+    }
+
+    fn rollup_from_row(row: &Row) -> Result<ReportRollup, rusqlite::Error> {
+        Ok(ReportRollup {
+            team_id: SqliteStore::parse_id(row, "team_id")?,
+            metric: row.get("metric")?,
+            day: SqliteStore::parse_timestamp(row, "day")?,
+            count: row.get::<_, i64>("count")? as u64,
+            sum: row.get("sum")?,
+            min: row.get::<_, f64>("min")? as f32,
+            max: row.get::<_, f64>("max")? as f32,
+            sum_squares: row.get("sum_squares")?,
+            option_counts: SqliteStore::parse_json(row, "option_counts")?,
+        })
+    }
+
+    fn store_rollup(connection: &Connection, rollup: &ReportRollup) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO report_rollups (team_id, metric, day, count, sum, min, max, sum_squares, option_counts) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                SqliteStore::id(rollup.team_id),
+                rollup.metric,
+                SqliteStore::timestamp(rollup.day),
+                rollup.count as i64,
+                rollup.sum,
+                rollup.min as f64,
+                rollup.max as f64,
+                rollup.sum_squares,
+                if rollup.option_counts.is_empty() { None } else { serde_json::to_string(&rollup.option_counts).ok() },
+            ]).map(|_| ())
+    }
+
+    fn alert_from_row(row: &Row) -> Result<Alert, rusqlite::Error> {
+        Ok(Alert {
+            id: SqliteStore::parse_id(row, "id")?,
+            team_id: SqliteStore::parse_id(row, "team_id")?,
+            metric: row.get("metric")?,
+            kind: AlertKind::parse(&row.get::<_, String>("kind")?),
+            raised_at: SqliteStore::parse_timestamp(row, "raised_at")?,
+            window_mean: row.get("window_mean")?,
+            baseline_mean: row.get("baseline_mean")?,
+            threshold: row.get("threshold")?,
+            acknowledged_by: SqliteStore::parse_optional_id(row, "acknowledged_by")?,
+            acknowledged_at: SqliteStore::parse_optional_timestamp(row, "acknowledged_at")?,
+        })
+    }
+
+    fn store_alert(connection: &Connection, alert: &Alert) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO alerts (team_id, id, metric, kind, raised_at, window_mean, baseline_mean, threshold, acknowledged_by, acknowledged_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                SqliteStore::id(alert.team_id),
+                SqliteStore::id(alert.id),
+                alert.metric,
+                alert.kind.as_str(),
+                SqliteStore::timestamp(alert.raised_at),
+                alert.window_mean,
+                alert.baseline_mean,
+                alert.threshold,
+                alert.acknowledged_by.map(SqliteStore::id),
+                alert.acknowledged_at.map(SqliteStore::timestamp),
+            ]).map(|_| ())
+    }
+
+    fn team_from_row(row: &Row) -> Result<Team, rusqlite::Error> {
+        Ok(Team {
+            team_id: SqliteStore::parse_id(row, "team_id")?,
+            user_id: SqliteStore::parse_id(row, "principal_id")?,
+            name: row.get("name")?,
+            retention_days: row.get("retention_days")?,
+            privacy: SqliteStore::parse_json(row, "privacy")?,
+            alert_rules: SqliteStore::parse_json(row, "alert_rules")?,
+            metrics: SqliteStore::parse_json(row, "metrics")?,
+            etag: SqliteStore::etag(row.get("version")?),
+        })
+    }
+
+    fn team_assignment_from_row(row: &Row) -> Result<TeamAssignment, rusqlite::Error> {
+        Ok(TeamAssignment {
+            team_id: SqliteStore::parse_id(row, "team_id")?,
+            user_id: SqliteStore::parse_id(row, "principal_id")?,
+            role: row.get::<_, String>("role")?.as_str().into(),
+            etag: SqliteStore::etag(row.get("version")?),
+        })
+    }
+
+    fn user_from_row(row: &Row) -> Result<User, rusqlite::Error> {
+        Ok(User {
+            email_hash: SqliteStore::parse_id(row, "email_hash")?,
+            principal_id: SqliteStore::parse_id(row, "principal_id")?,
+            first_name: row.get("first_name")?,
+        })
+    }
+}
+
+impl Actor for SqliteStore {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<GetHealth> for SqliteStore {
+    type Result = Result<Health, APIError>;
+
+    fn handle(&mut self, _: GetHealth, _: &mut Self::Context) -> Self::Result {
+        Ok(Health {
+            ok: true,
+            started_at: self.started_at,
+        })
+    }
+}
+
+impl Handler<GetReport> for SqliteStore {
+    type Result = Result<Report, APIError>;
+
+    fn handle(&mut self, msg: GetReport, _: &mut Self::Context) -> Self::Result {
+        self.connection.query_row(
+            "SELECT * FROM reports WHERE team_id = ?1 AND id = ?2",
+            params![SqliteStore::id(msg.team), SqliteStore::id(msg.id)],
+            SqliteStore::report_from_row)
+            .optional()?
+            .ok_or(APIError::new(404, "Not Found", "The report ID you provided could not be found. Please check it and try again."))
+    }
+}
+
+impl Handler<GetReports> for SqliteStore {
+    type Result = Result<Page<Report>, APIError>;
+
+    fn handle(&mut self, msg: GetReports, _: &mut Self::Context) -> Self::Result {
+        let cursor = Page::<Report>::parse_cursor(&msg.cursor)?;
+
+        let mut statement = self.connection.prepare(
+            "SELECT * FROM reports WHERE team_id = ?1 AND (?2 IS NULL OR metric = ?2) AND (?3 IS NULL OR timestamp >= ?3) AND (?4 IS NULL OR id > ?4) ORDER BY id LIMIT ?5")?;
+
+        let reports = statement.query_map(
+            params![
+                SqliteStore::id(msg.team),
+                msg.metric,
+                msg.after.map(SqliteStore::timestamp),
+                cursor.map(SqliteStore::id),
+                msg.limit.map(|l| l as i64 + 1).unwrap_or(-1)
+            ],
+            SqliteStore::report_from_row)?
+            .collect::<Result<Vec<Report>, rusqlite::Error>>()?;
+
+        Ok(Page::from_items(reports, msg.limit, |r| r.id))
+    }
+}
+
+impl Handler<StoreReport> for SqliteStore {
+    type Result = Result<Report, APIError>;
+
+    fn handle(&mut self, msg: StoreReport, _: &mut Self::Context) -> Self::Result {
+        let report = Report {
+            id: msg.id,
+            team_id: msg.team,
+            metric: msg.metric.clone(),
+            timestamp: msg.timestamp.unwrap_or_else(Utc::now),
+            value: msg.value,
+            response_id: None,
+            receipt_hash: None,
+            options: msg.options.clone(),
+            staged_at: None,
+        };
+
+        self.connection.execute(
+            "INSERT OR REPLACE INTO reports (team_id, id, timestamp, metric, value, options) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![SqliteStore::id(report.team_id), SqliteStore::id(report.id), SqliteStore::timestamp(report.timestamp), report.metric, report.value as f64, SqliteStore::options(&report.options)])?;
+
+        Ok(report)
+    }
+}
+
+impl Handler<StoreReports> for SqliteStore {
+    type Result = Result<ReportBatch, APIError>;
+
+    fn handle(&mut self, msg: StoreReports, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+
+        for report in msg.reports.iter() {
+            transaction.execute(
+                "INSERT OR REPLACE INTO reports (team_id, id, timestamp, metric, value, response_id, receipt_hash, options) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![SqliteStore::id(report.team_id), SqliteStore::id(report.id), SqliteStore::timestamp(report.timestamp), report.metric, report.value as f64, report.response_id.map(SqliteStore::id), report.receipt_hash, SqliteStore::options(&report.options)])?;
+        }
+
+        transaction.commit()?;
+
+        Ok(ReportBatch {
+            stored: msg.reports,
+            failed: vec![],
+        })
+    }
+}
+
+impl Handler<RemoveReport> for SqliteStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: RemoveReport, _: &mut Self::Context) -> Self::Result {
+        match self.connection.execute(
+            "DELETE FROM reports WHERE team_id = ?1 AND id = ?2",
+            params![SqliteStore::id(msg.team), SqliteStore::id(msg.id)])? {
+            0 => Err(APIError::new(404, "Not Found", "The report ID you provided could not be found. Please check it and try again.")),
+            _ => Ok(())
+        }
+    }
+}
+
+impl Handler<StageReports> for SqliteStore {
+    type Result = Result<ReportBatch, APIError>;
+
+    fn handle(&mut self, msg: StageReports, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+
+        for report in msg.reports.iter() {
+            transaction.execute(
+                "INSERT OR REPLACE INTO staged_reports (team_id, id, timestamp, metric, value, response_id, receipt_hash, options, staged_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![SqliteStore::id(report.team_id), SqliteStore::id(report.id), SqliteStore::timestamp(report.timestamp), report.metric, report.value as f64, report.response_id.map(SqliteStore::id), report.receipt_hash, SqliteStore::options(&report.options), report.staged_at.map(SqliteStore::timestamp)])?;
+        }
+
+        transaction.commit()?;
+
+        Ok(ReportBatch {
+            stored: msg.reports,
+            failed: vec![],
+        })
+    }
+}
+
+impl Handler<GetStagedReports> for SqliteStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: GetStagedReports, _: &mut Self::Context) -> Self::Result {
+        Ok(self.connection.prepare("SELECT * FROM staged_reports WHERE team_id = ?1 ORDER BY id")?
+            .query_map(params![SqliteStore::id(msg.team_id)], SqliteStore::staged_report_from_row)?
+            .collect::<Result<Vec<Report>, rusqlite::Error>>()?)
+    }
+}
+
+impl Handler<PublishStagedReports> for SqliteStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: PublishStagedReports, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+        let team_id = SqliteStore::id(msg.team_id);
+        let before = msg.before.map(SqliteStore::timestamp);
+
+        // Reports staged by earlier versions didn't record when they were staged, so their timestamp is used instead
+        let ready = transaction.prepare("SELECT * FROM staged_reports WHERE team_id = ?1 AND (?2 IS NULL OR COALESCE(staged_at, timestamp) < ?2) ORDER BY id")?
+            .query_map(params![team_id, before], SqliteStore::report_from_row)?
+            .collect::<Result<Vec<Report>, rusqlite::Error>>()?;
+
+        if ready.is_empty() || ready.len() < msg.min_reports {
+            return Ok(vec![]);
+        }
+
+        transaction.execute(
+            "INSERT OR REPLACE INTO reports (team_id, id, timestamp, metric, value, response_id, receipt_hash, options) SELECT team_id, id, timestamp, metric, value, response_id, receipt_hash, options FROM staged_reports WHERE team_id = ?1 AND (?2 IS NULL OR COALESCE(staged_at, timestamp) < ?2)",
+            params![team_id, before])?;
+        transaction.execute(
+            "DELETE FROM staged_reports WHERE team_id = ?1 AND (?2 IS NULL OR COALESCE(staged_at, timestamp) < ?2)",
+            params![team_id, before])?;
+
+        transaction.commit()?;
+
+        Ok(ready)
+    }
+}
+
+impl SqliteStore {
+    /// Gets a team's reports, whether published or staged, which were submitted with a receipt.
+    fn receipt_reports(transaction: &rusqlite::Transaction, team_id: &str, receipt_hash: &str) -> Result<Vec<Report>, rusqlite::Error> {
+        let mut reports = vec![];
+        for (table, from_row) in [("reports", SqliteStore::report_from_row as fn(&Row) -> Result<Report, rusqlite::Error>), ("staged_reports", SqliteStore::staged_report_from_row)] {
+            reports.extend(transaction.prepare(&format!("SELECT * FROM {} WHERE team_id = ?1 AND receipt_hash = ?2 ORDER BY id", table))?
+                .query_map(params![team_id, receipt_hash], from_row)?
+                .collect::<Result<Vec<Report>, rusqlite::Error>>()?);
+        }
+
+        Ok(reports)
+    }
+}
+
+impl Handler<GetReceiptReports> for SqliteStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: GetReceiptReports, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+        let reports = SqliteStore::receipt_reports(&transaction, &SqliteStore::id(msg.team_id), &msg.receipt_hash)?;
+        transaction.commit()?;
+
+        Ok(reports)
+    }
+}
+
+impl Handler<RetractReports> for SqliteStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: RetractReports, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+        let team_id = SqliteStore::id(msg.team_id);
+
+        let reports = SqliteStore::receipt_reports(&transaction, &team_id, &msg.receipt_hash)?;
+        transaction.execute("DELETE FROM reports WHERE team_id = ?1 AND receipt_hash = ?2", params![team_id, msg.receipt_hash])?;
+        transaction.execute("DELETE FROM staged_reports WHERE team_id = ?1 AND receipt_hash = ?2", params![team_id, msg.receipt_hash])?;
+
+        transaction.commit()?;
+
+        Ok(reports)
+    }
+}
+
+impl Handler<AmendReports> for SqliteStore {
+    type Result = Result<Vec<Report>, APIError>;
+
+    fn handle(&mut self, msg: AmendReports, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+        let team_id = SqliteStore::id(msg.team_id);
+
+        for table in ["reports", "staged_reports"] {
+            transaction.execute(
+                &format!("UPDATE {} SET value = ?3, options = ?5 WHERE team_id = ?1 AND receipt_hash = ?2 AND (?4 IS NULL OR metric = ?4)", table),
+                params![team_id, msg.receipt_hash, msg.value as f64, msg.metric, SqliteStore::options(&msg.options)])?;
+        }
+
+        let reports = SqliteStore::receipt_reports(&transaction, &team_id, &msg.receipt_hash)?
+            .into_iter().filter(|r| msg.metric.as_ref().map(|m| &r.metric == m).unwrap_or(true)).collect();
+        transaction.commit()?;
+
+        Ok(reports)
+    }
+}
+
+impl Handler<PurgeReports> for SqliteStore {
+    type Result = Result<Vec<ReportPurge>, APIError>;
+
+    fn handle(&mut self, _: PurgeReports, _: &mut Self::Context) -> Self::Result {
+        let now = Utc::now();
+        let transaction = self.connection.transaction()?;
+
+        let teams = transaction.prepare("SELECT team_id, retention_days FROM teams WHERE retention_days IS NOT NULL")?
+            .query_map(NO_PARAMS, |row| Ok((SqliteStore::parse_id(row, "team_id")?, row.get::<_, u32>("retention_days")?)))?
+            .collect::<Result<Vec<(u128, u32)>, rusqlite::Error>>()?;
+
+        let mut purges = vec![];
+        for (team_id, days) in teams {
+            let before = now - chrono::Duration::days(days as i64);
+            let reports = transaction.execute(
+                "DELETE FROM reports WHERE team_id = ?1 AND timestamp < ?2",
+                params![SqliteStore::id(team_id), SqliteStore::timestamp(before)])?;
+            let rollups = transaction.execute(
+                "DELETE FROM report_rollups WHERE team_id = ?1 AND day <= ?2",
+                params![SqliteStore::id(team_id), SqliteStore::timestamp(before - chrono::Duration::days(1))])?;
+
+            purges.push(ReportPurge { team_id, before, reports, rollups });
+        }
+
+        transaction.commit()?;
+
+        Ok(purges)
+    }
+}
+
+impl Handler<RollupReports> for SqliteStore {
+    type Result = Result<ReportCompaction, APIError>;
+
+    fn handle(&mut self, msg: RollupReports, _: &mut Self::Context) -> Self::Result {
+        let before = SqliteStore::timestamp(ReportRollup::day_of(msg.before.unwrap_or_else(Utc::now)));
+        let transaction = self.connection.transaction()?;
+
+        let expired = transaction.prepare("SELECT * FROM reports WHERE timestamp < ?1")?
+            .query_map(params![before], SqliteStore::report_from_row)?
+            .collect::<Result<Vec<Report>, rusqlite::Error>>()?;
+
+        // Teams are only read for their settings, so they aren't joined to any of their members
+        let teams = transaction.prepare("SELECT *, team_id AS principal_id FROM teams")?
+            .query_map(NO_PARAMS, SqliteStore::team_from_row)?
+            .map(|team| team.map(|team| (team.team_id, team)))
+            .collect::<Result<BTreeMap<u128, Team>, rusqlite::Error>>()?;
+
+        let rollups = ReportRollup::combine(&[], &expired, |r| teams.get(&r.team_id).and_then(|team| team.metric_bound(&r.metric)));
+        for rollup in rollups.iter() {
+            let existing = transaction.query_row(
+                "SELECT * FROM report_rollups WHERE team_id = ?1 AND metric = ?2 AND day = ?3",
+                params![SqliteStore::id(rollup.team_id), rollup.metric, SqliteStore::timestamp(rollup.day)],
+                SqliteStore::rollup_from_row)
+                .optional()?;
+
+            let mut combined = rollup.clone();
+            if let Some(existing) = existing {
+                combined.merge(&existing);
+            }
+
+            SqliteStore::store_rollup(&transaction, &combined)?;
+        }
+
+        transaction.execute("DELETE FROM reports WHERE timestamp < ?1", params![before])?;
+        transaction.commit()?;
+
+        Ok(ReportCompaction {
+            reports: expired.len(),
+            rollups: rollups.len(),
+        })
+    }
+}
+
+impl Handler<GetReportRollups> for SqliteStore {
+    type Result = Result<Vec<ReportRollup>, APIError>;
+
+    fn handle(&mut self, msg: GetReportRollups, _: &mut Self::Context) -> Self::Result {
+        let after = msg.after.map(|after| SqliteStore::timestamp(ReportRollup::day_of(after)));
+
+        let mut statement = self.connection.prepare("
+            SELECT * FROM report_rollups
+            WHERE team_id = ?1 AND (?2 IS NULL OR metric = ?2) AND (?3 IS NULL OR day >= ?3)
+            ORDER BY metric, day")?;
+
+        let rollups = statement.query_map(params![SqliteStore::id(msg.team), msg.metric, after], SqliteStore::rollup_from_row)?
+            .collect::<Result<Vec<ReportRollup>, rusqlite::Error>>()?;
+
+        Ok(rollups)
+    }
+}
+
+impl Handler<StoreReportRollups> for SqliteStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: StoreReportRollups, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+
+        for rollup in msg.rollups.iter() {
+            SqliteStore::store_rollup(&transaction, rollup)?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+impl Handler<SpendPrivacyBudget> for SqliteStore {
+    type Result = Result<PrivacyBudget, APIError>;
+
+    fn handle(&mut self, msg: SpendPrivacyBudget, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+        let team_id = SqliteStore::id(msg.team_id);
+
+        if let Some(since) = msg.since {
+            transaction.execute("DELETE FROM privacy_spends WHERE team_id = ?1 AND spent_at < ?2", params![team_id, SqliteStore::timestamp(since)])?;
+        }
+
+        let spent: f64 = transaction.query_row("SELECT COALESCE(SUM(epsilon), 0.0) FROM privacy_spends WHERE team_id = ?1", params![team_id], |row| row.get(0))?;
+        let budget = PrivacyBudget::spend(spent, msg.epsilon, msg.budget)?;
+
+        transaction.execute(
+            "INSERT INTO privacy_spends (team_id, spent_at, epsilon) VALUES (?1, ?2, ?3)",
+            params![team_id, SqliteStore::timestamp(Utc::now()), msg.epsilon])?;
+        transaction.commit()?;
+
+        Ok(budget)
+    }
+}
+
+impl Handler<GetPrivacySpends> for SqliteStore {
+    type Result = Result<Vec<PrivacySpend>, APIError>;
+
+    fn handle(&mut self, msg: GetPrivacySpends, _: &mut Self::Context) -> Self::Result {
+        Ok(self.connection.prepare("SELECT * FROM privacy_spends WHERE team_id = ?1 ORDER BY spent_at")?
+            .query_map(params![SqliteStore::id(msg.team_id)], |row| Ok(PrivacySpend {
+                team_id: SqliteStore::parse_id(row, "team_id")?,
+                spent_at: SqliteStore::parse_timestamp(row, "spent_at")?,
+                epsilon: row.get("epsilon")?,
+            }))?
+            .collect::<Result<Vec<PrivacySpend>, rusqlite::Error>>()?)
+    }
+}
+
+impl Handler<StorePrivacySpends> for SqliteStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: StorePrivacySpends, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+        let team_id = SqliteStore::id(msg.team_id);
+
+        transaction.execute("DELETE FROM privacy_spends WHERE team_id = ?1", params![team_id])?;
+        for spend in msg.spends.iter() {
+            transaction.execute(
+                "INSERT INTO privacy_spends (team_id, spent_at, epsilon) VALUES (?1, ?2, ?3)",
+                params![team_id, SqliteStore::timestamp(spend.spent_at), spend.epsilon])?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+impl Handler<GetAlerts> for SqliteStore {
+    type Result = Result<Vec<Alert>, APIError>;
+
+    fn handle(&mut self, msg: GetAlerts, _: &mut Self::Context) -> Self::Result {
+        Ok(self.connection.prepare("SELECT * FROM alerts WHERE team_id = ?1 ORDER BY raised_at DESC, id")?
+            .query_map(params![SqliteStore::id(msg.team_id)], SqliteStore::alert_from_row)?
+            .collect::<Result<Vec<Alert>, rusqlite::Error>>()?)
+    }
+}
+
+impl Handler<StoreAlert> for SqliteStore {
+    type Result = Result<Alert, APIError>;
+
+    fn handle(&mut self, msg: StoreAlert, _: &mut Self::Context) -> Self::Result {
+        SqliteStore::store_alert(&self.connection, &msg.alert)?;
+
+        Ok(msg.alert)
+    }
+}
+
+impl Handler<AcknowledgeAlert> for SqliteStore {
+    type Result = Result<Alert, APIError>;
+
+    fn handle(&mut self, msg: AcknowledgeAlert, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+
+        let mut alert = transaction.query_row(
+            "SELECT * FROM alerts WHERE team_id = ?1 AND id = ?2",
+            params![SqliteStore::id(msg.team_id), SqliteStore::id(msg.id)],
+            SqliteStore::alert_from_row)
+            .optional()?
+            .ok_or(APIError::new(404, "Not Found", "The alert ID you provided could not be found. Please check it and try again."))?;
+
+        if alert.acknowledged_at.is_none() {
+            alert.acknowledged_by = Some(msg.principal_id);
+            alert.acknowledged_at = Some(Utc::now());
+            SqliteStore::store_alert(&transaction, &alert)?;
+            transaction.commit()?;
+        }
+
+        Ok(alert)
+    }
+}
+
+impl Handler<GetTeam> for SqliteStore {
+    type Result = Result<Team, APIError>;
+
+    fn handle(&mut self, msg: GetTeam, _: &mut Self::Context) -> Self::Result {
+        self.connection.query_row(
+            &format!("{} WHERE teams.team_id = ?1 AND team_assignments.principal_id = ?2", TEAM_MEMBERS_QUERY),
+            params![SqliteStore::id(msg.id), SqliteStore::id(msg.principal_id)],
+            SqliteStore::team_from_row)
+            .optional()?
+            .ok_or(APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again."))
+    }
+}
+
+impl Handler<GetTeams> for SqliteStore {
+    type Result = Result<Vec<Team>, APIError>;
+
+    fn handle(&mut self, msg: GetTeams, _: &mut Self::Context) -> Self::Result {
+        let mut statement = self.connection.prepare(&format!("{} WHERE team_assignments.principal_id = ?1 ORDER BY teams.team_id", TEAM_MEMBERS_QUERY))?;
+
+        let teams = statement.query_map(params![SqliteStore::id(msg.principal_id)], SqliteStore::team_from_row)?
+            .collect::<Result<Vec<Team>, rusqlite::Error>>()?;
+
+        Ok(teams)
+    }
+}
+
+impl Handler<GetAllTeams> for SqliteStore {
+    type Result = Result<Vec<Team>, APIError>;
+
+    fn handle(&mut self, _: GetAllTeams, _: &mut Self::Context) -> Self::Result {
+        let mut statement = self.connection.prepare("SELECT *, team_id AS principal_id FROM teams ORDER BY team_id")?;
+
+        let teams = statement.query_map(NO_PARAMS, SqliteStore::team_from_row)?
+            .collect::<Result<Vec<Team>, rusqlite::Error>>()?;
+
+        Ok(teams)
+    }
+}
+
+impl Handler<StoreTeam> for SqliteStore {
+    type Result = Result<Team, APIError>;
+
+    fn handle(&mut self, msg: StoreTeam, _: &mut Self::Context) -> Self::Result {
+        let existing = self.connection.query_row(
+            "SELECT version FROM teams WHERE team_id = ?1",
+            params![SqliteStore::id(msg.team_id)],
+            |row| row.get(0))
+            .optional()?
+            .map(SqliteStore::etag);
+        check_etag(existing.as_ref(), &msg.etag)?;
+
+        let team = Team {
+            team_id: msg.team_id,
+            user_id: msg.principal_id,
+            name: msg.name.clone(),
+            retention_days: msg.retention_days,
+            privacy: msg.privacy.clone(),
+            alert_rules: msg.alert_rules.clone(),
+            metrics: msg.metrics.clone(),
+            etag: next_etag(existing.as_ref()),
+        };
+
+        self.connection.execute(
+            "INSERT OR REPLACE INTO teams (team_id, name, retention_days, privacy, alert_rules, metrics, version) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                SqliteStore::id(team.team_id),
+                team.name,
+                team.retention_days,
+                serde_json::to_string(&team.privacy).ok(),
+                serde_json::to_string(&team.alert_rules).ok(),
+                serde_json::to_string(&team.metrics).ok(),
+                SqliteStore::version(&team.etag),
+            ])?;
+
+        Ok(team)
+    }
+}
+
+impl Handler<RemoveTeam> for SqliteStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: RemoveTeam, _: &mut Self::Context) -> Self::Result {
+        // Removing a team which has already been removed succeeds, so that retries are safe
+        self.connection.execute(
+            "DELETE FROM teams WHERE team_id = ?1 AND NOT EXISTS (SELECT 1 FROM team_assignments WHERE team_id = ?1)",
+            params![SqliteStore::id(msg.id)])?;
+
+        Ok(())
+    }
+}
+
+impl Handler<DeleteTeam> for SqliteStore {
+    type Result = Result<TeamDeletion, APIError>;
+
+    fn handle(&mut self, msg: DeleteTeam, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+        let team_id = SqliteStore::id(msg.team_id);
+
+        let teams = transaction.prepare(&format!("{} WHERE teams.team_id = ?1 ORDER BY team_assignments.principal_id", TEAM_MEMBERS_QUERY))?
+            .query_map(params![team_id], SqliteStore::team_from_row)?
+            .collect::<Result<Vec<Team>, rusqlite::Error>>()?;
+
+        let team_assignments = transaction.prepare("SELECT * FROM team_assignments WHERE team_id = ?1 ORDER BY principal_id")?
+            .query_map(params![team_id], SqliteStore::team_assignment_from_row)?
+            .collect::<Result<Vec<TeamAssignment>, rusqlite::Error>>()?;
+
+        let count = |table: &str| transaction.query_row(&format!("SELECT COUNT(*) FROM {} WHERE team_id = ?1", table), params![team_id], |row| row.get::<_, i64>(0)).map(|count| count as usize);
+        let reports = count("reports")?;
+        let staged_reports = count("staged_reports")?;
+        let rollups = count("report_rollups")?;
+        let alerts = count("alerts")?;
+
+        if !msg.dry_run {
+            transaction.execute("DELETE FROM teams WHERE team_id = ?1", params![team_id])?;
+            transaction.execute("DELETE FROM team_assignments WHERE team_id = ?1", params![team_id])?;
+            transaction.execute("DELETE FROM reports WHERE team_id = ?1", params![team_id])?;
+            transaction.execute("DELETE FROM staged_reports WHERE team_id = ?1", params![team_id])?;
+            transaction.execute("DELETE FROM report_rollups WHERE team_id = ?1", params![team_id])?;
+            transaction.execute("DELETE FROM privacy_spends WHERE team_id = ?1", params![team_id])?;
+            transaction.execute("DELETE FROM alerts WHERE team_id = ?1", params![team_id])?;
+            transaction.commit()?;
+        }
+
+        Ok(TeamDeletion {
+            teams,
+            team_assignments,
+            reports,
+            staged_reports,
+            rollups,
+            alerts,
+        })
+    }
+}
+
+impl Handler<ReconcileTeams> for SqliteStore {
+    type Result = Result<TeamReconciliation, APIError>;
+
+    /// Replaces the copies of each team held in `legacy_teams` with a single canonical record.
+    /// The membership index is maintained by SQLite, so it never needs to be repaired.
+    fn handle(&mut self, _: ReconcileTeams, _: &mut Self::Context) -> Self::Result {
+        let transaction = self.connection.transaction()?;
+
+        let mut copies: BTreeMap<u128, Vec<Team>> = BTreeMap::new();
+        for team in transaction.prepare("SELECT *, NULL AS retention_days, NULL AS privacy, NULL AS alert_rules, NULL AS metrics FROM legacy_teams")?
+            .query_map(NO_PARAMS, SqliteStore::team_from_row)?
+            .collect::<Result<Vec<Team>, rusqlite::Error>>()? {
+            copies.entry(team.team_id).or_default().push(team);
+        }
+
+        let mut reconciliation = TeamReconciliation::default();
+        for (team_id, copies) in copies {
+            let assignments = transaction.prepare("SELECT * FROM team_assignments WHERE team_id = ?1")?
+                .query_map(params![SqliteStore::id(team_id)], SqliteStore::team_assignment_from_row)?
+                .collect::<Result<Vec<TeamAssignment>, rusqlite::Error>>()?;
+
+            let name = canonical_team_name(&copies, &assignments).unwrap_or_default();
+            let created = transaction.execute(
+                "INSERT OR IGNORE INTO teams (team_id, name, version) VALUES (?1, ?2, 1)",
+                params![SqliteStore::id(team_id), name])?;
+
+            if created > 0 {
+                reconciliation.teams += 1;
+                if has_diverged(&copies) {
+                    reconciliation.diverged += 1;
+                }
+            }
+        }
+
+        transaction.execute("DELETE FROM legacy_teams", NO_PARAMS)?;
+        transaction.commit()?;
+
+        Ok(reconciliation)
+    }
+}
+
+impl Handler<GetTeamAssignment> for SqliteStore {
+    type Result = Result<TeamAssignment, APIError>;
+
+    fn handle(&mut self, msg: GetTeamAssignment, _: &mut Self::Context) -> Self::Result {
+        self.connection.query_row(
+            "SELECT * FROM team_assignments WHERE team_id = ?1 AND principal_id = ?2",
+            params![SqliteStore::id(msg.team_id), SqliteStore::id(msg.principal_id)],
+            SqliteStore::team_assignment_from_row)
+            .optional()?
+            .ok_or(APIError::new(403, "Forbidden", "You do not have permission to access this resource."))
+    }
+}
+
+impl Handler<GetTeamAssignments> for SqliteStore {
+    type Result = Result<Vec<TeamAssignment>, APIError>;
+
+    fn handle(&mut self, msg: GetTeamAssignments, _: &mut Self::Context) -> Self::Result {
+        let mut statement = self.connection.prepare("SELECT * FROM team_assignments WHERE team_id = ?1 ORDER BY principal_id")?;
+
+        let team_assignments = statement.query_map(params![SqliteStore::id(msg.team_id)], SqliteStore::team_assignment_from_row)?
+            .collect::<Result<Vec<TeamAssignment>, rusqlite::Error>>()?;
+
+        Ok(team_assignments)
+    }
+}
+
+impl Handler<StoreTeamAssignment> for SqliteStore {
+    type Result = Result<TeamAssignment, APIError>;
+
+    fn handle(&mut self, msg: StoreTeamAssignment, _: &mut Self::Context) -> Self::Result {
+        let existing = self.connection.query_row(
+            "SELECT version FROM team_assignments WHERE team_id = ?1 AND principal_id = ?2",
+            params![SqliteStore::id(msg.team_id), SqliteStore::id(msg.principal_id)],
+            |row| row.get(0))
+            .optional()?
+            .map(SqliteStore::etag);
+        check_etag(existing.as_ref(), &msg.etag)?;
+
+        let team_assignment = TeamAssignment {
+            team_id: msg.team_id,
+            user_id: msg.principal_id,
+            role: msg.role,
+            etag: next_etag(existing.as_ref()),
+        };
+
+        let role: String = team_assignment.role.into();
+        self.connection.execute(
+            "INSERT OR REPLACE INTO team_assignments (team_id, principal_id, role, version) VALUES (?1, ?2, ?3, ?4)",
+            params![SqliteStore::id(team_assignment.team_id), SqliteStore::id(team_assignment.user_id), role, SqliteStore::version(&team_assignment.etag)])?;
+
+        Ok(team_assignment)
+    }
+}
+
+impl Handler<RemoveTeamAssignment> for SqliteStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: RemoveTeamAssignment, _: &mut Self::Context) -> Self::Result {
+        match self.connection.execute(
+            "DELETE FROM team_assignments WHERE team_id = ?1 AND principal_id = ?2",
+            params![SqliteStore::id(msg.team_id), SqliteStore::id(msg.principal_id)])? {
+            0 => {
+                debug!("Could not find an entry for the user {} in the team role assignments table for {}", msg.principal_id, msg.team_id);
+                Err(APIError::new(404, "Not Found", "The principal ID you provided could not be found. This likely means that you do not yet have any teams."))
+            },
+            _ => Ok(())
+        }
+    }
+}
+
+impl Handler<GetUser> for SqliteStore {
+    type Result = Result<User, APIError>;
+
+    fn handle(&mut self, msg: GetUser, _: &mut Self::Context) -> Self::Result {
+        self.connection.query_row(
+            "SELECT * FROM users WHERE email_hash = ?1",
+            params![SqliteStore::id(msg.email_hash)],
+            SqliteStore::user_from_row)
+            .optional()?
+            .ok_or(APIError::new(404, "Not Found", "No user could be found with the email hash you provided. Please check it and try again."))
+    }
+}
+
+impl Handler<GetUsers> for SqliteStore {
+    type Result = Result<Vec<User>, APIError>;
+
+    fn handle(&mut self, _: GetUsers, _: &mut Self::Context) -> Self::Result {
+        let mut statement = self.connection.prepare("SELECT * FROM users ORDER BY email_hash")?;
+
+        let users = statement.query_map(NO_PARAMS, SqliteStore::user_from_row)?
+            .collect::<Result<Vec<User>, rusqlite::Error>>()?;
+
+        Ok(users)
+    }
+}
+
+impl Handler<StoreUser> for SqliteStore {
+    type Result = Result<User, APIError>;
+
+    fn handle(&mut self, msg: StoreUser, _: &mut Self::Context) -> Self::Result {
+        let user = User {
+            principal_id: msg.principal_id,
+            email_hash: msg.email_hash,
+            first_name: msg.first_name.clone()
+        };
+
+        self.connection.execute(
+            "INSERT OR REPLACE INTO users (email_hash, principal_id, first_name) VALUES (?1, ?2, ?3)",
+            params![SqliteStore::id(user.email_hash), SqliteStore::id(user.principal_id), user.first_name])?;
+
+        Ok(user)
+    }
+}
+
+impl Handler<ClaimIdempotencyKey> for SqliteStore {
+    type Result = Result<Option<IdempotencyRecord>, APIError>;
+
+    fn handle(&mut self, msg: ClaimIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+        let now = msg.created_at.unwrap_or_else(Utc::now);
+        let transaction = self.connection.transaction()?;
+
+        let existing = transaction.query_row(
+            "SELECT * FROM idempotency_keys WHERE key_hash = ?1",
+            params![msg.key_hash],
+            SqliteStore::idempotency_record_from_row)
+            .optional()?
+            .filter(|record| !record.expired(now));
+
+        if existing.is_none() {
+            transaction.execute(
+                "INSERT OR REPLACE INTO idempotency_keys (key_hash, request_hash, created_at, response) VALUES (?1, ?2, ?3, NULL)",
+                params![msg.key_hash, msg.request_hash, SqliteStore::timestamp(now)])?;
+        }
+
+        transaction.commit()?;
+
+        Ok(existing)
+    }
+}
+
+impl Handler<CompleteIdempotencyKey> for SqliteStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: CompleteIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+        self.connection.execute(
+            "UPDATE idempotency_keys SET response = ?2 WHERE key_hash = ?1",
+            params![msg.key_hash, msg.response])?;
+
+        Ok(())
+    }
+}
+
+impl Handler<ReleaseIdempotencyKey> for SqliteStore {
+    type Result = Result<(), APIError>;
+
+    fn handle(&mut self, msg: ReleaseIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+        self.connection.execute(
+            "DELETE FROM idempotency_keys WHERE key_hash = ?1",
+            params![msg.key_hash])?;
+
+        Ok(())
+    }
+}
+
+impl Handler<PurgeIdempotencyKeys> for SqliteStore {
+    type Result = Result<usize, APIError>;
+
+    fn handle(&mut self, msg: PurgeIdempotencyKeys, _: &mut Self::Context) -> Self::Result {
+        let before = msg.before.unwrap_or_else(|| Utc::now() - chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS));
+
+        Ok(self.connection.execute(
+            "DELETE FROM idempotency_keys WHERE created_at < ?1",
+            params![SqliteStore::timestamp(before)])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts an opened store on a [SyncArbiter], as it would be when the server is running.
+    fn start(store: SqliteStore) -> Addr<SqliteStore> {
+        let store = std::sync::Mutex::new(Some(store));
+        SyncArbiter::start(1, move || store.lock().unwrap().take().expect("the store should only be started once"))
+    }
+
+    #[actix_rt::test]
+    async fn migrations_are_idempotent() {
+        let connection = Connection::open_in_memory().expect("an in-memory database");
+        SqliteStore::migrate(&connection).expect("the initial migration should succeed");
+        SqliteStore::migrate(&connection).expect("re-running migrations should be a no-op");
+
+        let version: i64 = connection.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+    }
+
+    #[actix_rt::test]
+    async fn migrations_copy_legacy_teams() {
+        let connection = Connection::open_in_memory().expect("an in-memory database");
+        connection.execute_batch(&format!("{} {} PRAGMA user_version = 2;", MIGRATIONS[0], MIGRATIONS[1])).expect("the earlier schema should be created");
+        connection.execute_batch("
+            INSERT INTO teams (principal_id, team_id, name, version) VALUES
+                ('00000000000000000000000000000001', '00000000000000000000000000000007', 'Test Team', 1),
+                ('00000000000000000000000000000002', '00000000000000000000000000000007', 'Test Team', 1),
+                ('00000000000000000000000000000003', '00000000000000000000000000000007', 'Renamed Team', 1),
+                ('00000000000000000000000000000001', '00000000000000000000000000000008', 'Other Team', 1);
+            INSERT INTO team_assignments (team_id, principal_id, role, version) VALUES
+                ('00000000000000000000000000000007', '00000000000000000000000000000001', 'Member', 1),
+                ('00000000000000000000000000000007', '00000000000000000000000000000002', 'Member', 1),
+                ('00000000000000000000000000000007', '00000000000000000000000000000003', 'Manager', 1),
+                ('00000000000000000000000000000008', '00000000000000000000000000000001', 'Manager', 1);
+        ").expect("the legacy teams should be inserted");
+
+        SqliteStore::migrate(&connection).expect("the migrations should succeed");
+        let store = start(SqliteStore { started_at: Utc::now(), connection });
+
+        let teams = store.send(GetTeams { principal_id: 1 }).await.expect("the actor should run").expect("the teams should be listed");
+        assert_eq!(teams.len(), 2, "the legacy teams should be readable without reconciling them");
+        assert_eq!(teams[0].name, "Renamed Team", "the manager's name should be kept");
+        assert_eq!(teams[1].name, "Other Team");
+    }
+
+    #[actix_rt::test]
+    async fn reports_round_trip() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+        let after = Utc::now();
+
+        store.send(StoreReport { id: 1, team: 7, metric: "happy_sad".into(), timestamp: Some(after), value: 1.0, options: vec![] })
+            .await.expect("the actor should run").expect("the report should be stored");
+        store.send(StoreReport { id: 2, team: 7, metric: "other".into(), timestamp: Some(after - chrono::Duration::days(1)), value: -1.0, options: vec![] })
+            .await.expect("the actor should run").expect("the report should be stored");
+
+        let report = store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect("the report should exist");
+        assert_eq!(report.metric, "happy_sad");
+        assert_eq!(report.value, 1.0);
+        assert_eq!(report.timestamp, after);
+
+        let reports = store.send(GetReports { team: 7, after: Some(after), ..Default::default() }).await.expect("the actor should run").expect("the reports should be listed");
+        assert_eq!(reports.items.len(), 1);
+
+        let reports = store.send(GetReports { team: 7, metric: Some("other".into()), ..Default::default() }).await.expect("the actor should run").expect("the reports should be listed");
+        assert_eq!(reports.items.len(), 1);
+        assert_eq!(reports.items[0].id, 2);
+
+        let reports = store.send(GetReports { team: 7, limit: Some(1), ..Default::default() }).await.expect("the actor should run").expect("the reports should be listed");
+        assert_eq!(reports.items.len(), 1);
+        assert_eq!(reports.items[0].id, 1);
+
+        let reports = store.send(GetReports { team: 7, limit: Some(1), cursor: reports.next_cursor, ..Default::default() }).await.expect("the actor should run").expect("the reports should be listed");
+        assert_eq!(reports.items.len(), 1);
+        assert_eq!(reports.items[0].id, 2);
+        assert_eq!(reports.next_cursor, None);
+
+        store.send(RemoveReport { id: 1, team: 7 }).await.expect("the actor should run").expect("the report should be removed");
+        store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("the report should not exist anymore");
+    }
+
+    #[actix_rt::test]
+    async fn report_batches() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+        let timestamp = Utc::now();
+
+        let batch = store.send(StoreReports { reports: vec![
+            Report { id: 1, team_id: 7, metric: "happy_sad".into(), value: 1.0, timestamp, response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            Report { id: 1, team_id: 8, metric: "drains".into(), value: 2.0, timestamp, response_id: Some(3), receipt_hash: None, options: vec!["meetings".into(), "on-call".into()], staged_at: None },
+        ] }).await.expect("the actor should run").expect("the reports should be stored");
+
+        assert_eq!(batch.stored.len(), 2);
+        assert!(batch.failed.is_empty());
+
+        let report = store.send(GetReport { id: 1, team: 8 }).await.expect("the actor should run").expect("the report should exist");
+        assert_eq!(report.timestamp, timestamp);
+        assert_eq!(report.response_id, Some(3));
+        assert_eq!(report.options, vec!["meetings".to_string(), "on-call".to_string()]);
+    }
+
+    #[actix_rt::test]
+    async fn delete_team() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+
+        store.send(StoreTeam { team_id: 7, principal_id: 1, name: "Test Team".into(), ..Default::default() })
+            .await.expect("the actor should run").expect("the team should be stored");
+        store.send(StoreTeam { team_id: 7, principal_id: 2, name: "Test Team".into(), ..Default::default() })
+            .await.expect("the actor should run").expect("the team should be stored");
+        store.send(StoreTeamAssignment { team_id: 7, principal_id: 1, role: Role::Manager, ..Default::default() })
+            .await.expect("the actor should run").expect("the assignment should be stored");
+        store.send(StoreTeamAssignment { team_id: 7, principal_id: 2, role: Role::Member, ..Default::default() })
+            .await.expect("the actor should run").expect("the assignment should be stored");
+        store.send(StoreReport { id: 1, team: 7, metric: "happy_sad".into(), value: 1.0, ..Default::default() })
+            .await.expect("the actor should run").expect("the report should be stored");
+
+        let staged = Report { id: 2, team_id: 7, metric: "happy_sad".into(), value: 1.0, timestamp: Utc::now(), response_id: None, receipt_hash: None, options: vec![], staged_at: Some(Utc::now()) };
+        store.send(StoreReportRollups { rollups: vec![ReportRollup::from_report(&staged, None)] })
+            .await.expect("the actor should run").expect("the rollup should be stored");
+        store.send(StageReports { reports: vec![staged] })
+            .await.expect("the actor should run").expect("the report should be staged");
+        store.send(StoreAlert { alert: Alert { id: 3, team_id: 7, metric: "happy_sad".into(), raised_at: Utc::now(), ..Default::default() } })
+            .await.expect("the actor should run").expect("the alert should be stored");
+
+        let preview = store.send(DeleteTeam { team_id: 7, dry_run: true }).await.expect("the actor should run").expect("the dry run should succeed");
+        assert_eq!(preview.teams.len(), 2);
+        assert_eq!(preview.team_assignments.len(), 2);
+        assert_eq!((preview.reports, preview.staged_reports, preview.rollups, preview.alerts), (1, 1, 1, 1));
+
+        store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect("a dry run should not remove anything");
+
+        let deletion = store.send(DeleteTeam { team_id: 7, dry_run: false }).await.expect("the actor should run").expect("the team should be deleted");
+        assert_eq!(deletion, preview);
+
+        store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("the report should have been removed");
+        store.send(GetTeam { id: 7, principal_id: 2 }).await.expect("the actor should run").expect_err("the member's team should have been removed");
+    }
+
+    #[actix_rt::test]
+    async fn publish_staged_reports() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+        let day = Utc.ymd(2020, 3, 1);
+
+        store.send(StageReports { reports: vec![
+            Report { id: 1, team_id: 7, metric: "happy_sad".into(), value: 1.0, timestamp: day.and_hms(9, 0, 0), response_id: Some(4), receipt_hash: None, options: vec![], staged_at: None },
+            Report { id: 2, team_id: 7, metric: "happy_sad".into(), value: -1.0, timestamp: day.and_hms(17, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            Report { id: 3, team_id: 7, metric: "happy_sad".into(), value: 0.5, timestamp: day.succ().and_hms(9, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            Report { id: 4, team_id: 7, metric: "happy_sad".into(), value: 1.0, timestamp: day.and_hms(0, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: Some(day.succ().and_hms(12, 0, 0)) },
+        ] }).await.expect("the actor should run").expect("the reports should be staged");
+
+        store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("a staged report should not be readable");
+
+        let staged = store.send(GetStagedReports { team_id: 7 }).await.expect("the actor should run").expect("the staged reports should be listed");
+        assert_eq!(staged.iter().map(|r| (r.id, r.response_id)).collect::<Vec<_>>(), vec![(1, Some(4)), (2, None), (3, None), (4, None)]);
+        assert_eq!(staged[3].staged_at, Some(day.succ().and_hms(12, 0, 0)));
+
+        let published = store.send(PublishStagedReports { team_id: 7, before: Some(day.succ().and_hms(0, 0, 0)), min_reports: 3 })
+            .await.expect("the actor should run").expect("the staged reports should be checked");
+        assert!(published.is_empty(), "too few reports were ready to be published");
+
+        let published = store.send(PublishStagedReports { team_id: 7, before: Some(day.succ().and_hms(0, 0, 0)), min_reports: 2 })
+            .await.expect("the actor should run").expect("the staged reports should be published");
+        assert_eq!(published.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1, 2]);
+
+        let report = store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect("the published report should be readable");
+        assert_eq!(report.response_id, Some(4));
+        store.send(GetReport { id: 3, team: 7 }).await.expect("the actor should run").expect_err("the later report should still be staged");
+        store.send(GetReport { id: 4, team: 7 }).await.expect("the actor should run").expect_err("a report staged after the release should still be staged, whatever its timestamp");
+
+        let published = store.send(PublishStagedReports { team_id: 7, before: Some(day.succ().and_hms(0, 0, 0)), min_reports: 1 })
+            .await.expect("the actor should run").expect("the staged reports should be checked");
+        assert!(published.is_empty(), "published reports should not be published again");
+    }
+
+    #[actix_rt::test]
+    async fn receipt_reports() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+        let timestamp = Utc.ymd(2020, 3, 1).and_hms(9, 0, 0);
+        let report = |id: u128, metric: &str, receipt_hash: Option<&str>| Report { id, team_id: 7, metric: metric.into(), value: 1.0, timestamp, response_id: None, receipt_hash: receipt_hash.map(|h| h.into()), options: vec![], staged_at: None };
+
+        store.send(StoreReports { reports: vec![report(1, "happy_sad", Some("abc")), report(2, "workload", Some("abc")), report(3, "happy_sad", None)] })
+            .await.expect("the actor should run").expect("the reports should be stored");
+        store.send(StageReports { reports: vec![report(4, "happy_sad", Some("abc"))] })
+            .await.expect("the actor should run").expect("the report should be staged");
+
+        let reports = store.send(GetReceiptReports { team_id: 7, receipt_hash: "abc".into() }).await.expect("the actor should run").expect("the reports should be found");
+        assert_eq!(reports.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1, 2, 4]);
+        assert_eq!(reports[0].receipt_hash.as_deref(), Some("abc"));
+
+        let amended = store.send(AmendReports { team_id: 7, receipt_hash: "abc".into(), metric: Some("happy_sad".into()), value: -1.0, options: vec![] })
+            .await.expect("the actor should run").expect("the reports should be amended");
+        assert_eq!(amended.iter().map(|r| (r.id, r.value)).collect::<Vec<_>>(), vec![(1, -1.0), (4, -1.0)]);
+
+        let report = store.send(GetReport { id: 2, team: 7 }).await.expect("the actor should run").expect("the report should exist");
+        assert_eq!(report.value, 1.0, "reports for other metrics should not be amended");
+
+        store.send(AmendReports { team_id: 7, receipt_hash: "abc".into(), metric: Some("workload".into()), value: 2.0, options: vec!["meetings".into(), "reviews".into()] })
+            .await.expect("the actor should run").expect("the reports should be amended");
+        let report = store.send(GetReport { id: 2, team: 7 }).await.expect("the actor should run").expect("the report should exist");
+        assert_eq!((report.value, report.options), (2.0, vec!["meetings".to_string(), "reviews".to_string()]));
+
+        let retracted = store.send(RetractReports { team_id: 7, receipt_hash: "abc".into() })
+            .await.expect("the actor should run").expect("the reports should be retracted");
+        assert_eq!(retracted.len(), 3);
+
+        store.send(GetReport { id: 3, team: 7 }).await.expect("the actor should run").expect("reports without the receipt should be kept");
+        let published = store.send(PublishStagedReports { team_id: 7, before: None, min_reports: 1 })
+            .await.expect("the actor should run").expect("the staged reports should be checked");
+        assert!(published.is_empty(), "the staged report should have been retracted");
+    }
+
+    #[actix_rt::test]
+    async fn idempotency_keys() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+        let now = Utc.ymd(2020, 3, 1).and_hms(9, 0, 0);
+        let claim = |created_at: DateTime<Utc>| ClaimIdempotencyKey { key_hash: "abc".into(), request_hash: "def".into(), created_at: Some(created_at) };
+
+        let claimed = store.send(claim(now)).await.expect("the actor should run").expect("the key should be claimed");
+        assert_eq!(claimed, None, "a new key should be claimed");
+
+        let pending = store.send(claim(now)).await.expect("the actor should run").expect("the key should be checked");
+        assert_eq!(pending.map(|r| (r.request_hash, r.response)), Some(("def".into(), None)));
+
+        store.send(CompleteIdempotencyKey { key_hash: "abc".into(), response: "sealed".into() })
+            .await.expect("the actor should run").expect("the key should be completed");
+        let completed = store.send(claim(now + chrono::Duration::hours(1))).await.expect("the actor should run").expect("the key should be checked");
+        assert_eq!(completed.and_then(|r| r.response), Some("sealed".into()));
+
+        store.send(ReleaseIdempotencyKey { key_hash: "abc".into() })
+            .await.expect("the actor should run").expect("the key should be released");
+        assert_eq!(store.send(claim(now)).await.expect("the actor should run").expect("the key should be claimed"), None, "a released key should be claimable");
+
+        let expired = store.send(claim(now + chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS))).await.expect("the actor should run").expect("the key should be claimed");
+        assert_eq!(expired, None, "an expired key should be claimable again");
+
+        let purged = store.send(PurgeIdempotencyKeys { before: Some(now + chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS + 1)) })
+            .await.expect("the actor should run").expect("the keys should be purged");
+        assert_eq!(purged, 1);
+    }
+
+    #[actix_rt::test]
+    async fn purge_reports() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+        let now = Utc::now();
+
+        store.send(StoreTeam { team_id: 7, principal_id: 1, name: "Test Team".into(), retention_days: Some(30), ..Default::default() })
+            .await.expect("the actor should run").expect("the team should be stored");
+        store.send(StoreTeam { team_id: 8, principal_id: 1, name: "Other Team".into(), ..Default::default() })
+            .await.expect("the actor should run").expect("the team should be stored");
+        store.send(StoreReports { reports: vec![
+            Report { id: 1, team_id: 7, metric: "happy_sad".into(), value: 1.0, timestamp: now - chrono::Duration::days(31), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            Report { id: 2, team_id: 7, metric: "happy_sad".into(), value: 1.0, timestamp: now - chrono::Duration::days(29), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            Report { id: 3, team_id: 8, metric: "happy_sad".into(), value: 1.0, timestamp: now - chrono::Duration::days(365), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+        ] }).await.expect("the actor should run").expect("the reports should be stored");
+
+        let purges = store.send(PurgeReports {}).await.expect("the actor should run").expect("the reports should be purged");
+        assert_eq!(purges.len(), 1);
+        assert_eq!(purges[0].team_id, 7);
+        assert_eq!(purges[0].reports, 1);
+
+        store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("the expired report should be removed");
+        store.send(GetReport { id: 2, team: 7 }).await.expect("the actor should run").expect("the recent report should be kept");
+        store.send(GetReport { id: 3, team: 8 }).await.expect("the actor should run").expect("teams without a retention period should keep their reports");
+    }
+
+    #[actix_rt::test]
+    async fn rollup_reports() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+        let day = Utc.ymd(2020, 3, 1);
+
+        store.send(StoreReportRollups { rollups: vec![ReportRollup {
+            team_id: 7,
+            metric: "happy_sad".into(),
+            day: day.and_hms(0, 0, 0),
+            count: 1,
+            sum: 2.0,
+            min: 2.0,
+            max: 2.0,
+            sum_squares: 4.0,
+            option_counts: Default::default(),
+        }] }).await.expect("the actor should run").expect("the rollup should be stored");
+        store.send(StoreReports { reports: vec![
+            Report { id: 1, team_id: 7, metric: "happy_sad".into(), value: 1.0, timestamp: day.and_hms(9, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            Report { id: 2, team_id: 7, metric: "happy_sad".into(), value: -1.0, timestamp: day.and_hms(17, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+            Report { id: 3, team_id: 7, metric: "happy_sad".into(), value: 0.5, timestamp: day.succ().and_hms(9, 0, 0), response_id: None, receipt_hash: None, options: vec![], staged_at: None },
+        ] }).await.expect("the actor should run").expect("the reports should be stored");
+
+        let compaction = store.send(RollupReports { before: Some(day.succ().and_hms(12, 0, 0)) })
+            .await.expect("the actor should run").expect("the reports should be rolled up");
+        assert_eq!(compaction, ReportCompaction { reports: 2, rollups: 1 });
+
+        store.send(GetReport { id: 1, team: 7 }).await.expect("the actor should run").expect_err("the rolled up report should have been removed");
+        store.send(GetReport { id: 3, team: 7 }).await.expect("the actor should run").expect("the report from the current day should be kept");
+
+        let rollups = store.send(GetReportRollups { team: 7, metric: Some("happy_sad".into()), after: Some(day.and_hms(12, 0, 0)) })
+            .await.expect("the actor should run").expect("the rollups should be listed");
+        assert_eq!(rollups, vec![ReportRollup {
+            team_id: 7,
+            metric: "happy_sad".into(),
+            day: day.and_hms(0, 0, 0),
+            count: 3,
+            sum: 2.0,
+            min: -1.0,
+            max: 2.0,
+            sum_squares: 6.0,
+            option_counts: Default::default(),
+        }]);
+    }
+
+    #[actix_rt::test]
+    async fn team_etags() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+
+        let team = store.send(StoreTeam { team_id: 7, principal_id: 1, name: "Test Team".into(), ..Default::default() })
+            .await.expect("the actor should run").expect("the team should be stored");
+        assert_eq!(team.etag, Some("\"1\"".into()));
+
+        let updated = store.send(StoreTeam { team_id: 7, principal_id: 1, name: "Renamed Team".into(), etag: team.etag.clone(), ..Default::default() })
+            .await.expect("the actor should run").expect("the team should be updated");
+        assert_eq!(updated.etag, Some("\"2\"".into()));
+
+        let err = store.send(StoreTeam { team_id: 7, principal_id: 1, name: "Conflicting Team".into(), etag: team.etag.clone(), ..Default::default() })
+            .await.expect("the actor should run").expect_err("the stale etag should be rejected");
+        assert_eq!(err.code, 412);
+
+        store.send(StoreTeamAssignment { team_id: 7, principal_id: 1, role: Role::Manager, ..Default::default() })
+            .await.expect("the actor should run").expect("the assignment should be stored");
+
+        let team = store.send(GetTeam { id: 7, principal_id: 1 }).await.expect("the actor should run").expect("the team should exist");
+        assert_eq!(team.name, "Renamed Team");
+        assert_eq!(team.etag, updated.etag);
+
+        let teams = store.send(GetAllTeams {}).await.expect("the actor should run").expect("the teams should be listed");
+        assert_eq!(teams.iter().map(|t| (t.team_id, t.name.as_str())).collect::<Vec<_>>(), vec![(7, "Renamed Team")]);
+    }
+
+    #[actix_rt::test]
+    async fn spend_privacy_budget() {
+        let store = start(SqliteStore::open(":memory:").expect("an in-memory store"));
+
+        let budget = store.send(SpendPrivacyBudget { team_id: 7, epsilon: 1.0, budget: 2.0, since: None })
+            .await.expect("the actor should run").expect("the budget should be spent");
+        assert_eq!(budget, PrivacyBudget { spent: 1.0, remaining: 1.0 });
+
+        store.send(SpendPrivacyBudget { team_id: 7, epsilon: 1.0, budget: 2.0, since: None })
+            .await.expect("the actor should run").expect("the rest of the budget should be spent");
+        store.send(SpendPrivacyBudget { team_id: 7, epsilon: 1.0, budget: 2.0, since: None })
+            .await.expect("the actor should run").expect_err("the budget should be exhausted");
+
+        let budget = store.send(SpendPrivacyBudget { team_id: 7, epsilon: 1.0, budget: 2.0, since: Some(Utc::now()) })
+            .await.expect("the actor should run").expect("spends outside of the window should not count");
+        assert_eq!(budget.spent, 1.0);
+
+        let spends = store.send(GetPrivacySpends { team_id: 7 }).await.expect("the actor should run").expect("the spends should be listed");
+        assert_eq!(spends.len(), 1);
+
+        store.send(StorePrivacySpends { team_id: 7, spends: vec![spends[0].clone(), PrivacySpend { epsilon: 0.5, ..spends[0].clone() }] })
+            .await.expect("the actor should run").expect("the spends should be replaced");
+        let replaced = store.send(GetPrivacySpends { team_id: 7 }).await.expect("the actor should run").expect("the spends should be listed");
+        assert_eq!(replaced.iter().map(|s| s.epsilon).sum::<f64>(), 1.5);
+    }
+
+    #[actix_rt::test]
+    async fn reject_malformed_rows() {
+        let store = SqliteStore::open(":memory:").expect("an in-memory store");
+
+        store.connection.execute_batch("
+            INSERT INTO reports (team_id, id, timestamp, metric, value) VALUES
+                ('00000000000000000000000000000007', 'not-an-id', '2020-01-01T09:00:00Z', 'happy_sad', 1.0),
+                ('00000000000000000000000000000008', '00000000000000000000000000000001', 'yesterday', 'happy_sad', 1.0);
+        ").expect("the malformed reports should be inserted");
+
+        let store = start(store);
+
+        let err = store.send(GetReports { team: 7, ..Default::default() }).await.expect("the actor should run").expect_err("a malformed id should not be read as 0");
+        assert_eq!(err.code, 500);
+
+        let err = store.send(GetReport { team: 8, id: 1 }).await.expect("the actor should run").expect_err("a malformed timestamp should not be read as the current time");
+        assert_eq!(err.code, 500);
+    }
+
+    #[actix_rt::test]
+    async fn reconcile_legacy_teams() {
+        let store = SqliteStore::open(":memory:").expect("an in-memory store");
+
+        store.connection.execute_batch("
+            INSERT INTO legacy_teams (principal_id, team_id, name, version) VALUES
+                ('00000000000000000000000000000001', '00000000000000000000000000000007', 'Renamed Team', 1),
+                ('00000000000000000000000000000002', '00000000000000000000000000000007', 'Test Team', 1),
+                ('00000000000000000000000000000003', '00000000000000000000000000000007', 'Test Team', 1);
+            INSERT INTO team_assignments (team_id, principal_id, role, version) VALUES
+                ('00000000000000000000000000000007', '00000000000000000000000000000001', 'Manager', 1),
+                ('00000000000000000000000000000007', '00000000000000000000000000000002', 'Member', 1);
+        ").expect("the legacy teams should be inserted");
+
+        let store = start(store);
+
+        let reconciliation = store.send(ReconcileTeams {}).await.expect("the actor should run").expect("the teams should be reconciled");
+        assert_eq!(reconciliation, TeamReconciliation { teams: 1, diverged: 1, memberships: 0 });
+
+        let teams = store.send(GetTeams { principal_id: 2 }).await.expect("the actor should run").expect("the teams should be listed");
+        assert_eq!(teams.len(), 1);
+        assert_eq!(teams[0].name, "Renamed Team");
+
+        let teams = store.send(GetTeams { principal_id: 3 }).await.expect("the actor should run").expect("the teams should be listed");
+        assert!(teams.is_empty(), "copies held by principals without an assignment should not be kept");
+
+        let reconciliation = store.send(ReconcileTeams {}).await.expect("the actor should run").expect("the teams should be reconciled");
+        assert_eq!(reconciliation, TeamReconciliation::default());
+    }
+}
@@ -1,47 +1,74 @@
 use crate::models::*;
 use crate::api::APIError;
-use std::{fmt::Debug, sync::{Arc}};
+use super::odata::{Filter, Operator, Query, format_timestamp};
+use super::reconcile::{canonical_team_name, has_diverged};
+use std::{collections::{BTreeMap, BTreeSet}, fmt::Debug, sync::{Arc}};
 use chrono::prelude::*;
 use actix::prelude::*;
-use azure_sdk_storage_table::{CloudTable, Continuation, TableClient, TableEntity};
+use azure_sdk_core::errors::AzureError;
+use azure_sdk_storage_table::{Batch, CloudTable, Continuation, NoData, TableClient, TableEntity};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 
+/// The most operations which Table Storage accepts in a single entity group transaction.
+const MAX_BATCH_SIZE: usize = 100;
+
 pub struct TableStorage {
     started_at: chrono::DateTime<chrono::Utc>,
 
     reports: Arc<CloudTable>,
+    /// The daily rollups of each team's reports, partitioned by the team's ID.
+    report_rollups: Arc<CloudTable>,
+    /// The queries which have spent each team's privacy budget, partitioned by the team's ID.
+    privacy_spends: Arc<CloudTable>,
+    /// The alerts raised for each team, partitioned by the team's ID.
+    alerts: Arc<CloudTable>,
     team_assignments: Arc<CloudTable>,
-    teams: Arc<CloudTable>,
+    /// The canonical record for each team, partitioned by the team's ID.
+    team_records: Arc<CloudTable>,
+    /// The teams each principal is a member of, derived from their team assignments.
+    team_memberships: Arc<CloudTable>,
+    /// The per-member copies of each team written by earlier versions, which are
+    /// emptied by [ReconcileTeams].
+    legacy_teams: Arc<CloudTable>,
     users: Arc<CloudTable>,
+    /// The reports held for each team until they are published, partitioned in the same way as the reports table.
+    staged_reports: Arc<CloudTable>,
+    /// The requests made with an `Idempotency-Key`, with both keys set to the key's hash.
+    idempotency_keys: Arc<CloudTable>,
 }
 
-const URI_CHARACTERS: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
-    .add(b' ')
-    .add(b'"')
-    .add(b'<')
-    .add(b'>')
-    .add(b'%')
-    .add(b'#')
-    .add(b'&');
-
 impl TableStorage {
     pub fn new() -> Self {
         let connection_string = std::env::var("TABLE_STORAGE_CONNECTION_STRING").expect("Set the TABLE_STORAGE_CONNECTION_STRING environment variable before starting the server.");
 
         let client = TableClient::from_connection_string(&connection_string).expect("a valid connection string");
         let reports_table = CloudTable::new(client.clone(), "reports");
+        let report_rollups_table = CloudTable::new(client.clone(), "reportrollups");
+        let privacy_spends_table = CloudTable::new(client.clone(), "privacyspends");
+        let alerts_table = CloudTable::new(client.clone(), "alerts");
         let team_assignments_table = CloudTable::new(client.clone(), "teamassignments");
-        let teams_table = CloudTable::new(client.clone(), "teams");
-        let users_table = CloudTable::new(client, "users");
+        let team_records_table = CloudTable::new(client.clone(), "teamrecords");
+        let team_memberships_table = CloudTable::new(client.clone(), "teammemberships");
+        let legacy_teams_table = CloudTable::new(client.clone(), "teams");
+        let users_table = CloudTable::new(client.clone(), "users");
+        let staged_reports_table = CloudTable::new(client.clone(), "stagedreports");
+        let idempotency_keys_table = CloudTable::new(client, "idempotencykeys");
 
         Self {
             started_at: chrono::Utc::now(),
 
             reports: Arc::new(reports_table),
-            teams: Arc::new(teams_table),
+            report_rollups: Arc::new(report_rollups_table),
+            privacy_spends: Arc::new(privacy_spends_table),
+            alerts: Arc::new(alerts_table),
             team_assignments: Arc::new(team_assignments_table),
+            team_records: Arc::new(team_records_table),
+            team_memberships: Arc::new(team_memberships_table),
+            legacy_teams: Arc::new(legacy_teams_table),
             users: Arc::new(users_table),
+            staged_reports: Arc::new(staged_reports_table),
+            idempotency_keys: Arc::new(idempotency_keys_table),
         }
     }
 
@@ -60,7 +87,7 @@ impl TableStorage {
             .map(|r| r.into())
     }
 
-    async fn get_all<ST, T, P>(table: Arc<CloudTable>, query: String, filter: P) -> Result<Vec<T>, APIError>
+    async fn get_all<ST, T, P>(table: Arc<CloudTable>, query: Query, filter: P) -> Result<Vec<T>, APIError>
     where
         ST: Serialize + DeserializeOwned + Clone,
         P: Fn(&TableEntity<ST>) -> bool,
@@ -69,7 +96,7 @@ impl TableStorage {
         let mut continuation = Continuation::start();
 
         let mut entries: Vec<TableEntity<ST>> = vec![];
-        let safe_query = TableStorage::escape_query(query);
+        let safe_query = query.to_string();
 
         while let Some(mut results) = table.execute_query::<ST>(if safe_query.is_empty() { None } else { Some(safe_query.as_str()) }, &mut continuation).await? {
             entries.append(&mut results);
@@ -78,13 +105,53 @@ impl TableStorage {
         Ok(entries.iter().filter(|&e| filter(e)).map(|e| e.clone().into()).collect())
     }
 
+    async fn get_page<ST, T, P>(table: Arc<CloudTable>, query: Query, limit: Option<usize>, filter: P) -> Result<Page<T>, APIError>
+    where
+        ST: Serialize + DeserializeOwned + Clone,
+        P: Fn(&TableEntity<ST>) -> bool,
+        T: From<TableEntity<ST>>
+    {
+        let mut continuation = Continuation::start();
+
+        let mut entries: Vec<TableEntity<ST>> = vec![];
+        let safe_query = query.to_string();
+
+        // Table storage may return fewer entries than were requested along with a continuation
+        // token, so we keep reading until we have enough to know whether there is another page.
+        while let Some(mut results) = table.execute_query::<ST>(if safe_query.is_empty() { None } else { Some(safe_query.as_str()) }, &mut continuation).await? {
+            results.retain(|e| filter(e));
+            entries.append(&mut results);
+
+            if limit.map(|limit| entries.len() > limit).unwrap_or_default() {
+                break;
+            }
+        }
+
+        let page = Page::from_items(entries, limit, |e| u128::from_str_radix(&e.row_key, 16).unwrap_or_default());
+
+        Ok(Page {
+            items: page.items.into_iter().map(|e| e.into()).collect(),
+            next_cursor: page.next_cursor,
+        })
+    }
+
     async fn store_single<ST, T>(table: Arc<CloudTable>, item: TableEntity<ST>) -> Result<T, APIError> 
     where
         ST: Serialize + DeserializeOwned + Clone + Debug,
         T: From<TableEntity<ST>> {
-        let result = table.insert_or_update_entity(item).await?;
+        // Entities with an ETag are only written if they match the version currently in the table
+        let conditional = item.etag.is_some();
+        let result = if conditional {
+            table.update_entity(item).await
+        } else {
+            table.insert_or_update_entity(item).await
+        };
 
-        Ok(result.into())
+        match result {
+            Ok(result) => Ok(result.into()),
+            Err(AzureError::UnexpectedHTTPResult(err)) if conditional && (err.status_code().as_u16() == 412 || err.status_code().as_u16() == 404) => Err(APIError::precondition_failed()),
+            Err(err) => Err(err.into())
+        }
     }
 
     async fn remove_single(table: Arc<CloudTable>, partition_key: u128, row_key: u128) -> Result<(), APIError> {
@@ -96,28 +163,250 @@ impl TableStorage {
         Ok(())
     }
 
-    fn build_report_filter_query(partition_key: u128, metric: Option<String>, after: Option<DateTime<Utc>>) -> String {
-        let mut query = format!("$filter=PartitionKey eq '{:0>32x}'", partition_key);
-        
-        match metric {
-            Some(metric) => {
-                query = query + format!(" and Metric eq {}", metric).as_str()
-            },
-            None => {}
+    /// Removes an entity, treating one which has already been removed as a success so that
+    /// multi-step removals can safely be retried.
+    async fn remove_if_exists(table: Arc<CloudTable>, partition_key: u128, row_key: u128) -> Result<(), APIError> {
+        TableStorage::remove_row_if_exists(table, partition_key, &format!("{:0>32x}", row_key)).await
+    }
+
+    async fn remove_row_if_exists(table: Arc<CloudTable>, partition_key: u128, row_key: &str) -> Result<(), APIError> {
+        match table.delete(&format!("{:0>32x}", partition_key), row_key, None).await {
+            Ok(_) => Ok(()),
+            Err(AzureError::UnexpectedHTTPResult(err)) if err.status_code().as_u16() == 404 => Ok(()),
+            Err(err) => Err(err.into())
+        }
+    }
+
+    /// Writes a team's reports in a single entity group transaction, so that either all of them
+    /// are stored or none are.
+    async fn store_reports(table: Arc<CloudTable>, team_id: u128, reports: &[Report]) -> Result<(), APIError> {
+        if reports.len() >= MAX_BATCH_SIZE {
+            return Err(APIError::new(413, "Payload Too Large", "There are too many reports to store at once. Please submit fewer reports and try again."));
         }
 
-        match after {
-            Some(after) => {
-                query = query + format!(" and Timestamp gt {}", after.to_rfc3339()).as_str()
+        let mut batch = Batch::new(format!("{:0>32x}", team_id));
+        for report in reports {
+            batch.add_insert(format!("{:0>32x}", report.id), &TableStorageReport {
+                metric: report.metric.clone(),
+                reported_at: Some(format_timestamp(report.timestamp)),
+                value: report.value,
+                response_id: report.response_id.map(|id| format!("{:0>32x}", id)),
+                receipt_hash: report.receipt_hash.clone(),
+                options: TableStorageReport::encode_options(&report.options),
+                staged_at: report.staged_at.map(format_timestamp),
+            }).map_err(|err| {
+                error!("Unable to add a report to a batch: {}", err);
+                APIError::new(500, "Internal Server Error", "We ran into a problem, this has been reported and will be looked at.")
+            })?;
+        }
+
+        table.execute_batch(batch).await?;
+
+        // The client doesn't surface the failure of an accepted batch, but since batches are
+        // applied all-or-nothing, finding any one of its reports confirms that it was written.
+        if let Some(report) = reports.first() {
+            TableStorage::get_single::<TableStorageReport, Report>(table, team_id, report.id, APIError::new(500, "Internal Server Error", "We ran into a problem, this has been reported and will be looked at.")).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes reports for any number of teams to a table. Reports for different teams live in
+    /// different partitions, which cannot be written in a single transaction, so each team's
+    /// reports are written in their own entity group transaction and the reports of any teams
+    /// which could not be written are reported.
+    async fn store_report_batch(table: Arc<CloudTable>, reports: Vec<Report>) -> Result<ReportBatch, APIError> {
+        let mut teams: BTreeMap<u128, Vec<Report>> = BTreeMap::new();
+        for report in reports {
+            teams.entry(report.team_id).or_default().push(report);
+        }
+
+        let results = futures::future::join_all(teams.into_iter().map(|(team_id, reports)| {
+            let table = table.clone();
+            async move {
+                let result = TableStorage::store_reports(table, team_id, &reports).await;
+                (reports, result)
+            }
+        })).await;
+
+        let mut batch = ReportBatch::default();
+        for (reports, result) in results {
+            match result {
+                Ok(()) => batch.stored.extend(reports),
+                Err(error) => batch.failed.extend(reports.into_iter().map(|report| ReportFailure {
+                    report,
+                    error: APIError::new(error.code, &error.error, &error.message),
+                })),
+            }
+        }
+
+        Ok(batch)
+    }
+
+    /// Gets the reports in a team's partition of a table which were submitted with a receipt.
+    async fn receipt_reports(table: Arc<CloudTable>, team_id: u128, receipt_hash: &str) -> Result<Vec<Report>, APIError> {
+        TableStorage::get_all::<TableStorageReport, Report, _>(
+            table,
+            Query::new().filter(Filter::eq("PartitionKey", team_id).and(Filter::eq("ReceiptHash", receipt_hash))),
+            |_| true).await
+    }
+
+    /// Stores a daily rollup, replacing any existing rollup for the same team, metric and day.
+    async fn store_rollup(table: Arc<CloudTable>, rollup: ReportRollup) -> Result<ReportRollup, APIError> {
+        TableStorage::store_single::<TableStorageReportRollup, ReportRollup>(table, TableEntity {
+            partition_key: format!("{:0>32x}", rollup.team_id),
+            row_key: TableStorage::rollup_row_key(&rollup),
+            payload: TableStorageReportRollup {
+                metric: rollup.metric.clone(),
+                day: format_timestamp(rollup.day),
+                count: rollup.count,
+                sum: rollup.sum,
+                min: rollup.min,
+                max: rollup.max,
+                sum_squares: rollup.sum_squares,
+                option_counts: if rollup.option_counts.is_empty() { None } else { serde_json::to_string(&rollup.option_counts).ok() },
             },
-            None => {}
+            etag: None,
+            timestamp: None
+        }).await
+    }
+
+    /// Removes entities from a partition using entity group transactions, rather than sending a
+    /// separate request for each of them.
+    ///
+    /// A whole batch fails if any one of its entities was removed after being read (by a
+    /// retraction, or by another instance purging the same team), in which case that batch's
+    /// entities are removed one at a time instead, skipping any which have already gone.
+    async fn remove_batched(table: Arc<CloudTable>, partition_key: u128, row_keys: &[String]) -> Result<(), APIError> {
+        // The client rejects a batch as soon as it reaches the maximum size, so each stays below it
+        for chunk in row_keys.chunks(MAX_BATCH_SIZE - 1) {
+            let mut batch = Batch::new(format!("{:0>32x}", partition_key));
+            for row_key in chunk {
+                batch.add_delete(row_key.clone(), None).map_err(|err| {
+                    error!("Unable to add a deletion to a batch: {}", err);
+                    APIError::new(500, "Internal Server Error", "We ran into a problem, this has been reported and will be looked at.")
+                })?;
+            }
+
+            // The client doesn't surface the failure of an accepted batch, but since batches are
+            // applied all-or-nothing, finding any one of its entities shows that it failed.
+            let failed = match table.execute_batch(batch).await {
+                Ok(_) => table.get::<NoData>(&format!("{:0>32x}", partition_key), &chunk[0], None).await?.is_some(),
+                Err(err) => {
+                    warn!("Unable to remove a batch of {} entities, removing them one at a time instead: {}", chunk.len(), err);
+                    true
+                },
+            };
+
+            if failed {
+                futures::future::join_all(chunk.iter().map(|row_key| TableStorage::remove_row_if_exists(table.clone(), partition_key, row_key)))
+                    .await.into_iter().collect::<Result<Vec<()>, APIError>>()?;
+            }
         }
 
-        query
+        Ok(())
     }
 
-    fn escape_query(query: String) -> String {
-        percent_encoding::percent_encode(query.as_bytes(), URI_CHARACTERS).to_string()
+    /// Removes every entity in a partition, reading only their keys a page at a time and removing
+    /// each page before reading the next. Returns the number of entities found, which are left in
+    /// place for a `dry_run`.
+    async fn remove_partition(table: Arc<CloudTable>, partition_key: u128, dry_run: bool) -> Result<usize, APIError> {
+        let mut continuation = Continuation::start();
+        let query = TableStorage::build_partition_keys_query(partition_key).to_string();
+
+        let mut count = 0;
+        while let Some(results) = table.execute_query::<NoData>(Some(query.as_str()), &mut continuation).await? {
+            let row_keys: Vec<String> = results.into_iter().map(|e| e.row_key).collect();
+            count += row_keys.len();
+
+            if !dry_run {
+                TableStorage::remove_batched(table.clone(), partition_key, &row_keys).await?;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Rollups are identified by their day and metric, with the metric escaped since it may
+    /// contain characters which are not permitted in a row key.
+    fn rollup_row_key(rollup: &ReportRollup) -> String {
+        format!("{}-{}", rollup.day.format("%Y-%m-%d"), percent_encoding::utf8_percent_encode(&rollup.metric, percent_encoding::NON_ALPHANUMERIC))
+    }
+
+    async fn store_alert(table: Arc<CloudTable>, alert: Alert) -> Result<Alert, APIError> {
+        TableStorage::store_single::<TableStorageAlert, Alert>(table, TableEntity {
+            partition_key: format!("{:0>32x}", alert.team_id),
+            row_key: format!("{:0>32x}", alert.id),
+            payload: TableStorageAlert {
+                metric: alert.metric.clone(),
+                kind: alert.kind.as_str().into(),
+                raised_at: format_timestamp(alert.raised_at),
+                window_mean: alert.window_mean,
+                baseline_mean: alert.baseline_mean,
+                threshold: alert.threshold,
+                acknowledged_by: alert.acknowledged_by.map(|id| format!("{:0>32x}", id)),
+                acknowledged_at: alert.acknowledged_at.map(format_timestamp),
+            },
+            etag: None,
+            timestamp: None
+        }).await
+    }
+
+    /// Records that a principal is a member of a team in the membership index.
+    async fn add_membership(table: Arc<CloudTable>, principal_id: u128, team_id: u128) -> Result<(), APIError> {
+        TableStorage::store_single::<TableStorageTeamMembership, TableEntity<TableStorageTeamMembership>>(table, TableEntity {
+            partition_key: format!("{:0>32x}", principal_id),
+            row_key: format!("{:0>32x}", team_id),
+            payload: TableStorageTeamMembership {},
+            etag: None,
+            timestamp: None
+        }).await.map(|_| ())
+    }
+
+    /// Gets the canonical record for a team, as seen by the given principal.
+    async fn get_team_record(table: Arc<CloudTable>, team_id: u128, principal_id: u128) -> Result<Team, APIError> {
+        TableStorage::get_single::<TableStorageTeam, TableEntity<TableStorageTeam>>(
+            table,
+            team_id,
+            team_id,
+            APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again.")
+        ).await.map(|entity| Team::from(entity).for_principal(principal_id))
+    }
+
+    fn build_report_filter_query(partition_key: u128, metric: Option<String>, after: Option<DateTime<Utc>>, cursor: Option<u128>, limit: Option<usize>) -> Query {
+        let filter = Filter::eq("PartitionKey", partition_key)
+            .and_maybe(cursor.map(|cursor| Filter::gt("RowKey", cursor)))
+            .and_maybe(metric.map(|metric| Filter::eq("Metric", metric)))
+            .and_maybe(after.map(|after| Filter::ge("ReportedAt", after).or(Filter::written(Operator::Ge, after))));
+
+        Query::new()
+            .filter(filter)
+            .top(limit.map(|limit| limit + 1))
+    }
+
+    /// Reports written by earlier versions don't have a ReportedAt property, so report queries
+    /// also select anything written since `after`. This narrows those results down to the reports
+    /// which were actually made since then.
+    fn reported_since(entity: &TableEntity<TableStorageReport>, after: Option<DateTime<Utc>>) -> bool {
+        after.map(|after| Report::from(entity.clone()).timestamp >= after).unwrap_or(true)
+    }
+
+    fn build_expired_report_query(partition_key: u128, before: DateTime<Utc>) -> Query {
+        Query::new().filter(Filter::eq("PartitionKey", partition_key)
+            .and(Filter::lt("ReportedAt", before).or(Filter::written(Operator::Lt, before))))
+    }
+
+    /// Lists the keys of the entities in a partition, without reading any of their other properties.
+    fn build_partition_keys_query(partition_key: u128) -> Query {
+        Query::new()
+            .filter(Filter::eq("PartitionKey", partition_key))
+            .select(&["PartitionKey", "RowKey"])
+    }
+
+    fn build_rollup_filter_query(partition_key: u128, metric: Option<String>, after: Option<DateTime<Utc>>) -> Query {
+        Query::new().filter(Filter::eq("PartitionKey", partition_key)
+            .and_maybe(metric.map(|metric| Filter::eq("Metric", metric)))
+            .and_maybe(after.map(|after| Filter::ge("Day", ReportRollup::day_of(after)))))
     }
 }
 
@@ -125,10 +414,33 @@ impl TableStorage {
 struct TableStorageReport {
     #[serde(rename="Metric")]
     pub metric: String,
-    #[serde(rename="Timestamp")]
-    pub timestamp: String,
+    /// The time at which the report was made, which is kept separately from the system
+    /// `Timestamp` property since that is updated whenever the entity is written.
+    #[serde(rename="ReportedAt", default)]
+    pub reported_at: Option<String>,
     #[serde(rename="Value")]
     pub value: f32,
+    #[serde(rename="ResponseId", default, skip_serializing_if="Option::is_none")]
+    pub response_id: Option<String>,
+    #[serde(rename="ReceiptHash", default, skip_serializing_if="Option::is_none")]
+    pub receipt_hash: Option<String>,
+    /// The options chosen for a categorical or multi-select metric, encoded as a JSON array
+    /// since table storage has no list type.
+    #[serde(rename="Options", default, skip_serializing_if="Option::is_none")]
+    pub options: Option<String>,
+    /// When the report was staged, which is only kept in the staging table.
+    #[serde(rename="StagedAt", default, skip_serializing_if="Option::is_none")]
+    pub staged_at: Option<String>,
+}
+
+impl TableStorageReport {
+    fn encode_options(options: &[String]) -> Option<String> {
+        if options.is_empty() {
+            None
+        } else {
+            serde_json::to_string(options).ok()
+        }
+    }
 }
 
 impl From<TableEntity<TableStorageReport>> for Report {
@@ -137,8 +449,137 @@ impl From<TableEntity<TableStorageReport>> for Report {
             id: u128::from_str_radix(&entity.row_key, 16).unwrap_or_default(),
             team_id: u128::from_str_radix(&entity.partition_key, 16).unwrap_or_default(),
             metric: entity.payload.metric.clone(),
-            timestamp: DateTime::parse_from_rfc3339(entity.payload.timestamp.as_str()).map(|dt| dt.with_timezone(&Utc)).unwrap_or_else(|_| Utc::now()),
+            timestamp: entity.payload.reported_at.as_ref()
+                .and_then(|ts| DateTime::parse_from_rfc3339(ts.as_str()).ok())
+                .map(|dt| dt.with_timezone(&Utc))
+                .or(entity.timestamp)
+                .unwrap_or_else(Utc::now),
             value: entity.payload.value,
+            response_id: entity.payload.response_id.as_ref().and_then(|id| u128::from_str_radix(id, 16).ok()),
+            receipt_hash: entity.payload.receipt_hash.clone(),
+            options: entity.payload.options.as_ref()
+                .and_then(|options| serde_json::from_str(options).ok())
+                .unwrap_or_default(),
+            staged_at: entity.payload.staged_at.as_ref()
+                .and_then(|ts| DateTime::parse_from_rfc3339(ts.as_str()).ok())
+                .map(|dt| dt.with_timezone(&Utc)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TableStorageReportRollup {
+    #[serde(rename="Metric")]
+    pub metric: String,
+    #[serde(rename="Day")]
+    pub day: String,
+    #[serde(rename="Count")]
+    pub count: u64,
+    #[serde(rename="Sum")]
+    pub sum: f64,
+    #[serde(rename="Min")]
+    pub min: f32,
+    #[serde(rename="Max")]
+    pub max: f32,
+    #[serde(rename="SumSquares")]
+    pub sum_squares: f64,
+    /// The number of times each option was chosen, encoded as a JSON object.
+    #[serde(rename="OptionCounts", default, skip_serializing_if="Option::is_none")]
+    pub option_counts: Option<String>,
+}
+
+impl From<TableEntity<TableStorageReportRollup>> for ReportRollup {
+    fn from(entity: TableEntity<TableStorageReportRollup>) -> Self {
+        Self {
+            team_id: u128::from_str_radix(&entity.partition_key, 16).unwrap_or_default(),
+            metric: entity.payload.metric.clone(),
+            day: DateTime::parse_from_rfc3339(&entity.payload.day).map(|dt| dt.with_timezone(&Utc)).unwrap_or_else(|_| Utc::now()),
+            count: entity.payload.count,
+            sum: entity.payload.sum,
+            min: entity.payload.min,
+            max: entity.payload.max,
+            sum_squares: entity.payload.sum_squares,
+            option_counts: entity.payload.option_counts.as_ref()
+                .and_then(|counts| serde_json::from_str(counts).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TableStorageAlert {
+    #[serde(rename="Metric")]
+    pub metric: String,
+    #[serde(rename="Kind")]
+    pub kind: String,
+    #[serde(rename="RaisedAt")]
+    pub raised_at: String,
+    #[serde(rename="WindowMean")]
+    pub window_mean: f64,
+    #[serde(rename="BaselineMean", default, skip_serializing_if="Option::is_none")]
+    pub baseline_mean: Option<f64>,
+    #[serde(rename="Threshold", default, skip_serializing_if="Option::is_none")]
+    pub threshold: Option<f64>,
+    #[serde(rename="AcknowledgedBy", default, skip_serializing_if="Option::is_none")]
+    pub acknowledged_by: Option<String>,
+    #[serde(rename="AcknowledgedAt", default, skip_serializing_if="Option::is_none")]
+    pub acknowledged_at: Option<String>,
+}
+
+impl From<TableEntity<TableStorageAlert>> for Alert {
+    fn from(entity: TableEntity<TableStorageAlert>) -> Self {
+        let parse_timestamp = |ts: &String| DateTime::parse_from_rfc3339(ts).map(|dt| dt.with_timezone(&Utc)).unwrap_or_else(|_| Utc::now());
+
+        Self {
+            id: u128::from_str_radix(&entity.row_key, 16).unwrap_or_default(),
+            team_id: u128::from_str_radix(&entity.partition_key, 16).unwrap_or_default(),
+            metric: entity.payload.metric.clone(),
+            kind: AlertKind::parse(&entity.payload.kind),
+            raised_at: parse_timestamp(&entity.payload.raised_at),
+            window_mean: entity.payload.window_mean,
+            baseline_mean: entity.payload.baseline_mean,
+            threshold: entity.payload.threshold,
+            acknowledged_by: entity.payload.acknowledged_by.as_ref().map(|id| u128::from_str_radix(id, 16).unwrap_or_default()),
+            acknowledged_at: entity.payload.acknowledged_at.as_ref().map(parse_timestamp),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TableStorageIdempotencyKey {
+    #[serde(rename="RequestHash")]
+    pub request_hash: String,
+    #[serde(rename="CreatedAt")]
+    pub created_at: String,
+    #[serde(rename="Response", default, skip_serializing_if="Option::is_none")]
+    pub response: Option<String>,
+}
+
+impl From<TableEntity<TableStorageIdempotencyKey>> for IdempotencyRecord {
+    fn from(entity: TableEntity<TableStorageIdempotencyKey>) -> Self {
+        Self {
+            key_hash: entity.row_key.clone(),
+            request_hash: entity.payload.request_hash.clone(),
+            created_at: DateTime::parse_from_rfc3339(&entity.payload.created_at).map(|dt| dt.with_timezone(&Utc)).unwrap_or_else(|_| Utc::now()),
+            response: entity.payload.response.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TableStoragePrivacySpend {
+    #[serde(rename="SpentAt")]
+    pub spent_at: String,
+    #[serde(rename="Epsilon")]
+    pub epsilon: f64,
+}
+
+impl From<TableEntity<TableStoragePrivacySpend>> for PrivacySpend {
+    fn from(entity: TableEntity<TableStoragePrivacySpend>) -> Self {
+        Self {
+            team_id: u128::from_str_radix(&entity.partition_key, 16).unwrap_or_default(),
+            spent_at: DateTime::parse_from_rfc3339(&entity.payload.spent_at).map(|dt| dt.with_timezone(&Utc)).unwrap_or_else(|_| Utc::now()),
+            epsilon: entity.payload.epsilon,
         }
     }
 }
@@ -147,6 +588,17 @@ impl From<TableEntity<TableStorageReport>> for Report {
 struct TableStorageTeam {
     #[serde(rename="Name")]
     pub name: String,
+    #[serde(rename="RetentionDays", default, skip_serializing_if="Option::is_none")]
+    pub retention_days: Option<u32>,
+    /// The team's [TeamPrivacy] settings, stored as JSON.
+    #[serde(rename="Privacy", default, skip_serializing_if="Option::is_none")]
+    pub privacy: Option<String>,
+    /// The team's [AlertRule]s, stored as JSON.
+    #[serde(rename="AlertRules", default, skip_serializing_if="Option::is_none")]
+    pub alert_rules: Option<String>,
+    /// The team's [MetricDefinition]s, stored as JSON.
+    #[serde(rename="Metrics", default, skip_serializing_if="Option::is_none")]
+    pub metrics: Option<String>,
 }
 
 impl From<TableEntity<TableStorageTeam>> for Team {
@@ -155,10 +607,25 @@ impl From<TableEntity<TableStorageTeam>> for Team {
             team_id: u128::from_str_radix(&entity.row_key, 16).unwrap_or_default(),
             user_id: u128::from_str_radix(&entity.partition_key, 16).unwrap_or_default(),
             name: entity.payload.name.clone(),
+            retention_days: entity.payload.retention_days,
+            privacy: entity.payload.privacy.as_ref()
+                .and_then(|privacy| serde_json::from_str(privacy).ok())
+                .unwrap_or_default(),
+            alert_rules: entity.payload.alert_rules.as_ref()
+                .and_then(|rules| serde_json::from_str(rules).ok())
+                .unwrap_or_default(),
+            metrics: entity.payload.metrics.as_ref()
+                .and_then(|metrics| serde_json::from_str(metrics).ok())
+                .unwrap_or_default(),
+            etag: entity.etag.clone(),
         }
     }
 }
 
+/// An entry in the membership index, partitioned by principal with the team ID as its row key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TableStorageTeamMembership {}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct TableStorageTeamAssignment {
     #[serde(rename="Role")]
@@ -171,6 +638,7 @@ impl From<TableEntity<TableStorageTeamAssignment>> for TeamAssignment {
             team_id: u128::from_str_radix(&entity.partition_key, 16).unwrap_or_default(),
             user_id: u128::from_str_radix(&entity.row_key, 16).unwrap_or_default(),
             role: entity.payload.role.as_str().into(),
+            etag: entity.etag.clone(),
         }
     }
 }
@@ -277,6 +745,57 @@ macro_rules! actor_handler {
 
 impl Actor for TableStorage {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let report_rollups = self.report_rollups.clone();
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = report_rollups.create_if_not_exists().await {
+                warn!("Unable to create the report rollups table: {}", err);
+            }
+        }));
+
+        let privacy_spends = self.privacy_spends.clone();
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = privacy_spends.create_if_not_exists().await {
+                warn!("Unable to create the privacy spends table: {}", err);
+            }
+        }));
+
+        let alerts = self.alerts.clone();
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = alerts.create_if_not_exists().await {
+                warn!("Unable to create the alerts table: {}", err);
+            }
+        }));
+
+        let staged_reports = self.staged_reports.clone();
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = staged_reports.create_if_not_exists().await {
+                warn!("Unable to create the staged reports table: {}", err);
+            }
+        }));
+
+        let idempotency_keys = self.idempotency_keys.clone();
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = idempotency_keys.create_if_not_exists().await {
+                warn!("Unable to create the idempotency keys table: {}", err);
+            }
+        }));
+
+        let team_records = self.team_records.clone();
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = team_records.create_if_not_exists().await {
+                warn!("Unable to create the team records table: {}", err);
+            }
+        }));
+
+        let team_memberships = self.team_memberships.clone();
+        ctx.spawn(fut::wrap_future(async move {
+            if let Err(err) = team_memberships.create_if_not_exists().await {
+                warn!("Unable to create the team memberships table: {}", err);
+            }
+        }));
+    }
 }
 
 impl Handler<GetHealth> for TableStorage {
@@ -292,10 +811,18 @@ impl Handler<GetHealth> for TableStorage {
 
 actor_handler!(GetReport|msg => Report: get_single from reports(TableStorageReport) where pk=msg.team, rk=msg.id; not found = "The combination of team and record ID you provided could not be found. Please check them and try again.");
 
-actor_handler!(GetReports|msg => Report: get_all from reports(TableStorageReport) where
-    query=TableStorage::build_report_filter_query(msg.team, msg.metric.clone(), msg.after.clone()),
-    context = [],
-    filter=_i -> true);
+actor_handler!(GetReports => Page<Report>: handler = fn handle(&mut self, msg: GetReports, _: &mut Self::Context) -> Self::Result {
+    let table = self.reports.clone();
+
+    let work = async move {
+        let cursor = Page::<Report>::parse_cursor(&msg.cursor)?;
+        let query = TableStorage::build_report_filter_query(msg.team, msg.metric.clone(), msg.after, cursor, msg.limit);
+
+        TableStorage::get_page::<TableStorageReport, Report, _>(table, query, msg.limit, |e| TableStorage::reported_since(e, msg.after)).await
+    };
+
+    Box::new(fut::wrap_future(work))
+});
 
 
 actor_handler!(StoreReport|msg => Report: store_single in reports(TableStorageReport) TableEntity {
@@ -303,55 +830,781 @@ actor_handler!(StoreReport|msg => Report: store_single in reports(TableStorageRe
     row_key: format!("{:0>32x}", msg.id),
     payload: TableStorageReport {
         metric: msg.metric.clone(),
-        timestamp: msg.timestamp.clone().unwrap_or_else(|| Utc::now()).to_rfc3339(),
+        reported_at: Some(format_timestamp(msg.timestamp.unwrap_or_else(Utc::now))),
         value: msg.value,
+        response_id: None,
+        receipt_hash: None,
+        options: TableStorageReport::encode_options(&msg.options),
+        staged_at: None,
     },
     etag: None,
     timestamp: None
 });
 
+actor_handler!(StoreReports => ReportBatch: handler = fn handle(&mut self, msg: StoreReports, _: &mut Self::Context) -> Self::Result {
+    let work = TableStorage::store_report_batch(self.reports.clone(), msg.reports);
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(StageReports => ReportBatch: handler = fn handle(&mut self, msg: StageReports, _: &mut Self::Context) -> Self::Result {
+    let work = TableStorage::store_report_batch(self.staged_reports.clone(), msg.reports);
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(GetStagedReports => Vec<Report>: handler = fn handle(&mut self, msg: GetStagedReports, _: &mut Self::Context) -> Self::Result {
+    let work = TableStorage::get_all::<TableStorageReport, Report, _>(
+        self.staged_reports.clone(),
+        Query::new().filter(Filter::eq("PartitionKey", msg.team_id)),
+        |_| true);
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(PublishStagedReports => Vec<Report>: handler = fn handle(&mut self, msg: PublishStagedReports, _: &mut Self::Context) -> Self::Result {
+    let staged_reports = self.staged_reports.clone();
+    let reports_table = self.reports.clone();
+
+    let work = async move {
+        let ready: Vec<Report> = TableStorage::get_all::<TableStorageReport, Report, _>(
+            staged_reports.clone(),
+            Query::new().filter(Filter::eq("PartitionKey", msg.team_id)),
+            |_| true).await?
+            .into_iter().filter(|r| r.staged_before(msg.before)).map(|r| Report { staged_at: None, ..r }).collect();
+
+        if ready.is_empty() || ready.len() < msg.min_reports {
+            return Ok(vec![]);
+        }
+
+        // The reports can't be moved between tables atomically, so they are written with upserts
+        // before being removed from the staging area, letting a failed release be retried safely.
+        futures::future::join_all(ready.iter().map(|report| TableStorage::store_single::<TableStorageReport, Report>(reports_table.clone(), TableEntity {
+            partition_key: format!("{:0>32x}", report.team_id),
+            row_key: format!("{:0>32x}", report.id),
+            payload: TableStorageReport {
+                metric: report.metric.clone(),
+                reported_at: Some(format_timestamp(report.timestamp)),
+                value: report.value,
+                response_id: report.response_id.map(|id| format!("{:0>32x}", id)),
+                receipt_hash: report.receipt_hash.clone(),
+                options: TableStorageReport::encode_options(&report.options),
+                staged_at: None,
+            },
+            etag: None,
+            timestamp: None,
+        }))).await.into_iter().collect::<Result<Vec<Report>, APIError>>()?;
+
+        futures::future::join_all(ready.iter().map(|report| TableStorage::remove_single(staged_reports.clone(), report.team_id, report.id)))
+            .await.into_iter().collect::<Result<Vec<()>, APIError>>()?;
+
+        Ok(ready)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(GetReceiptReports => Vec<Report>: handler = fn handle(&mut self, msg: GetReceiptReports, _: &mut Self::Context) -> Self::Result {
+    let tables = [self.reports.clone(), self.staged_reports.clone()];
+
+    let work = async move {
+        let mut reports = vec![];
+        for table in tables {
+            reports.extend(TableStorage::receipt_reports(table, msg.team_id, &msg.receipt_hash).await?);
+        }
+
+        Ok(reports)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(RetractReports => Vec<Report>: handler = fn handle(&mut self, msg: RetractReports, _: &mut Self::Context) -> Self::Result {
+    let tables = [self.reports.clone(), self.staged_reports.clone()];
+
+    let work = async move {
+        let mut retracted = vec![];
+        for table in tables {
+            let reports = TableStorage::receipt_reports(table.clone(), msg.team_id, &msg.receipt_hash).await?;
+            futures::future::join_all(reports.iter().map(|report| TableStorage::remove_single(table.clone(), report.team_id, report.id)))
+                .await.into_iter().collect::<Result<Vec<()>, APIError>>()?;
+
+            retracted.extend(reports);
+        }
+
+        Ok(retracted)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(AmendReports => Vec<Report>: handler = fn handle(&mut self, msg: AmendReports, _: &mut Self::Context) -> Self::Result {
+    let tables = [self.reports.clone(), self.staged_reports.clone()];
+
+    let work = async move {
+        let mut amended = vec![];
+        for table in tables {
+            let reports: Vec<Report> = TableStorage::receipt_reports(table.clone(), msg.team_id, &msg.receipt_hash).await?
+                .into_iter().filter(|r| msg.metric.as_ref().map(|m| &r.metric == m).unwrap_or(true)).collect();
+
+            amended.extend(futures::future::join_all(reports.iter().map(|report| TableStorage::store_single::<TableStorageReport, Report>(table.clone(), TableEntity {
+                partition_key: format!("{:0>32x}", report.team_id),
+                row_key: format!("{:0>32x}", report.id),
+                payload: TableStorageReport {
+                    metric: report.metric.clone(),
+                    reported_at: Some(format_timestamp(report.timestamp)),
+                    value: msg.value,
+                    response_id: report.response_id.map(|id| format!("{:0>32x}", id)),
+                    receipt_hash: report.receipt_hash.clone(),
+                    options: TableStorageReport::encode_options(&msg.options),
+                    staged_at: report.staged_at.map(format_timestamp),
+                },
+                etag: None,
+                timestamp: None,
+            }))).await.into_iter().collect::<Result<Vec<Report>, APIError>>()?);
+        }
+
+        Ok(amended)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
 actor_handler!(RemoveReport|msg: remove_single from reports where pk=msg.team, rk=msg.id);
 
-actor_handler!(GetTeam|msg => Team: get_single from teams(TableStorageTeam) where pk=msg.principal_id, rk=msg.id; not found = "The team ID you provided could not be found. Please check them and try again.");
+actor_handler!(ClaimIdempotencyKey => Option<IdempotencyRecord>: handler = fn handle(&mut self, msg: ClaimIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+    let table = self.idempotency_keys.clone();
 
-actor_handler!(GetTeams|msg => Team: get_all from teams(TableStorageTeam) where
-    query = format!("$filter=PartitionKey eq '{:0>32x}'", msg.principal_id),
-    context = [],
-    filter = _i -> true);
+    let work = async move {
+        let now = msg.created_at.unwrap_or_else(Utc::now);
+        let claim = TableEntity {
+            partition_key: msg.key_hash.clone(),
+            row_key: msg.key_hash.clone(),
+            payload: TableStorageIdempotencyKey {
+                request_hash: msg.request_hash.clone(),
+                created_at: format_timestamp(now),
+                response: None,
+            },
+            etag: None,
+            timestamp: None,
+        };
 
-actor_handler!(StoreTeam|msg => Team: store_single in teams(TableStorageTeam) TableEntity {
-    partition_key: format!("{:0>32x}", msg.principal_id),
-    row_key: format!("{:0>32x}", msg.team_id),
-    payload: TableStorageTeam {
-        name: msg.name.clone(),
-    },
-    etag: None,
-    timestamp: None
+        // Claims are inserted, or replace an expired record only if it hasn't changed since it was
+        // read, so that two requests racing to claim the same key can't both go ahead.
+        let result = match table.get::<TableStorageIdempotencyKey>(&msg.key_hash, &msg.key_hash, None).await? {
+            Some(existing) => {
+                let etag = existing.etag.clone();
+                let record = IdempotencyRecord::from(existing);
+                if !record.expired(now) {
+                    return Ok(Some(record));
+                }
+
+                table.update_entity(TableEntity { etag, ..claim }).await
+            },
+            None => table.insert_entity(claim).await,
+        };
+
+        match result {
+            Ok(_) => Ok(None),
+            Err(AzureError::UnexpectedHTTPResult(err)) if err.status_code().as_u16() == 409 || err.status_code().as_u16() == 412 =>
+                Err(APIError::new(409, "Conflict", "Another request with the same Idempotency-Key is still being processed. Please wait for it to complete and try again.")),
+            Err(err) => Err(err.into()),
+        }
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(CompleteIdempotencyKey => (): handler = fn handle(&mut self, msg: CompleteIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+    let table = self.idempotency_keys.clone();
+
+    let work = async move {
+        let existing = table.get::<TableStorageIdempotencyKey>(&msg.key_hash, &msg.key_hash, None).await?
+            .ok_or_else(|| APIError::new(404, "Not Found", "The Idempotency-Key you provided could not be found. It may have expired."))?;
+
+        table.update_entity(TableEntity {
+            payload: TableStorageIdempotencyKey { response: Some(msg.response), ..existing.payload },
+            ..existing
+        }).await?;
+
+        Ok(())
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(ReleaseIdempotencyKey => (): handler = fn handle(&mut self, msg: ReleaseIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+    let table = self.idempotency_keys.clone();
+
+    let work = async move {
+        table.delete(&msg.key_hash, &msg.key_hash, None).await?;
+
+        Ok(())
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(PurgeIdempotencyKeys => usize: handler = fn handle(&mut self, msg: PurgeIdempotencyKeys, _: &mut Self::Context) -> Self::Result {
+    let table = self.idempotency_keys.clone();
+
+    let work = async move {
+        let before = msg.before.unwrap_or_else(|| Utc::now() - chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS));
+        let expired: Vec<IdempotencyRecord> = TableStorage::get_all::<TableStorageIdempotencyKey, IdempotencyRecord, _>(table.clone(), Query::new(), |_| true).await?
+            .into_iter().filter(|record| record.created_at < before).collect();
+
+        futures::future::join_all(expired.iter().map(|record| table.delete(&record.key_hash, &record.key_hash, None)))
+            .await.into_iter().collect::<Result<Vec<()>, AzureError>>()?;
+
+        Ok(expired.len())
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(PurgeReports => Vec<ReportPurge>: handler = fn handle(&mut self, _: PurgeReports, _: &mut Self::Context) -> Self::Result {
+    let team_records = self.team_records.clone();
+    let reports_table = self.reports.clone();
+    let report_rollups = self.report_rollups.clone();
+
+    let work = async move {
+        let now = Utc::now();
+        let teams: Vec<Team> = TableStorage::get_all::<TableStorageTeam, Team, _>(team_records, Query::new(), |t| t.payload.retention_days.is_some()).await?;
+
+        let mut purges = vec![];
+        for team in teams {
+            let before = match team.retention_cutoff(now) {
+                Some(before) => before,
+                None => continue
+            };
+
+            // Reports written by earlier versions don't have a ReportedAt property, but were last
+            // written no earlier than they were made, so their write time selects a superset of
+            // them which is narrowed down using each report's effective timestamp.
+            let expired: Vec<String> = TableStorage::get_all::<TableStorageReport, Report, _>(
+                reports_table.clone(),
+                TableStorage::build_expired_report_query(team.team_id, before),
+                |_| true).await?
+                .into_iter()
+                .filter(|r| r.timestamp < before)
+                .map(|r| format!("{:0>32x}", r.id))
+                .collect();
+            TableStorage::remove_batched(reports_table.clone(), team.team_id, &expired).await?;
+
+            let expired_rollups: Vec<String> = TableStorage::get_all::<TableStorageReportRollup, ReportRollup, _>(
+                report_rollups.clone(),
+                Query::new().filter(Filter::eq("PartitionKey", team.team_id).and(Filter::lt("Day", ReportRollup::day_of(before)))),
+                |_| true).await?
+                .iter()
+                .filter(|r| r.ends_before(before))
+                .map(TableStorage::rollup_row_key)
+                .collect();
+            TableStorage::remove_batched(report_rollups.clone(), team.team_id, &expired_rollups).await?;
+
+            purges.push(ReportPurge { team_id: team.team_id, before, reports: expired.len(), rollups: expired_rollups.len() });
+        }
+
+        Ok(purges)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(RollupReports => ReportCompaction: handler = fn handle(&mut self, msg: RollupReports, _: &mut Self::Context) -> Self::Result {
+    let team_records = self.team_records.clone();
+    let reports_table = self.reports.clone();
+    let report_rollups = self.report_rollups.clone();
+
+    let work = async move {
+        let before = ReportRollup::day_of(msg.before.unwrap_or_else(Utc::now));
+        let teams: Vec<Team> = TableStorage::get_all::<TableStorageTeam, Team, _>(team_records, Query::new(), |_| true).await?;
+
+        let mut compaction = ReportCompaction::default();
+        for team in teams {
+            let reports: Vec<Report> = TableStorage::get_all::<TableStorageReport, Report, _>(
+                reports_table.clone(),
+                TableStorage::build_expired_report_query(team.team_id, before),
+                |_| true).await?;
+
+            let expired: Vec<Report> = reports.into_iter().filter(|r| r.timestamp < before).collect();
+            if expired.is_empty() {
+                continue;
+            }
+
+            let existing: Vec<ReportRollup> = TableStorage::get_all::<TableStorageReportRollup, ReportRollup, _>(
+                report_rollups.clone(),
+                Query::new().filter(Filter::eq("PartitionKey", team.team_id)),
+                |_| true).await?;
+
+            let rollups = ReportRollup::combine(&[], &expired, |r| team.metric_bound(&r.metric));
+            compaction.reports += expired.len();
+            compaction.rollups += rollups.len();
+
+            // The rollups are written before the reports are removed so that a failure part way
+            // through can only ever count a report twice, rather than losing it.
+            let updated = rollups.iter().map(|rollup| existing.iter()
+                .filter(|e| e.metric == rollup.metric && e.day == rollup.day)
+                .fold(rollup.clone(), |mut combined, e| { combined.merge(e); combined }));
+            futures::future::join_all(updated.map(|rollup| TableStorage::store_rollup(report_rollups.clone(), rollup)))
+                .await.into_iter().collect::<Result<Vec<ReportRollup>, APIError>>()?;
+
+            let row_keys: Vec<String> = expired.iter().map(|report| format!("{:0>32x}", report.id)).collect();
+            TableStorage::remove_batched(reports_table.clone(), team.team_id, &row_keys).await?;
+        }
+
+        Ok(compaction)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(GetReportRollups => Vec<ReportRollup>: handler = fn handle(&mut self, msg: GetReportRollups, _: &mut Self::Context) -> Self::Result {
+    let table = self.report_rollups.clone();
+    let query = TableStorage::build_rollup_filter_query(msg.team, msg.metric.clone(), msg.after);
+
+    let work = TableStorage::get_all::<TableStorageReportRollup, ReportRollup, _>(table, query, |_| true);
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(StoreReportRollups => (): handler = fn handle(&mut self, msg: StoreReportRollups, _: &mut Self::Context) -> Self::Result {
+    let table = self.report_rollups.clone();
+
+    let work = async move {
+        futures::future::join_all(msg.rollups.into_iter().map(|rollup| TableStorage::store_rollup(table.clone(), rollup)))
+            .await.into_iter().collect::<Result<Vec<ReportRollup>, APIError>>()?;
+
+        Ok(())
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(SpendPrivacyBudget => PrivacyBudget: handler = fn handle(&mut self, msg: SpendPrivacyBudget, _: &mut Self::Context) -> Self::Result {
+    let table = self.privacy_spends.clone();
+
+    let work = async move {
+        // Each entity is only read for its row key and spend time, so the spends are kept as entities
+        let spends: Vec<TableEntity<TableStoragePrivacySpend>> = TableStorage::get_all::<TableStoragePrivacySpend, TableEntity<TableStoragePrivacySpend>, _>(
+            table.clone(),
+            Query::new().filter(Filter::eq("PartitionKey", msg.team_id)),
+            |_| true).await?;
+
+        let (current, expired): (Vec<_>, Vec<_>) = spends.into_iter()
+            .partition(|spend| msg.since.map(|since| PrivacySpend::from(spend.clone()).spent_at >= since).unwrap_or(true));
+
+        futures::future::join_all(expired.iter().map(|spend| table.delete(&spend.partition_key, &spend.row_key, None)))
+            .await.into_iter().collect::<Result<Vec<()>, AzureError>>()?;
+
+        let budget = PrivacyBudget::spend(current.iter().map(|spend| spend.payload.epsilon).sum(), msg.epsilon, msg.budget)?;
+
+        TableStorage::store_single::<TableStoragePrivacySpend, TableEntity<TableStoragePrivacySpend>>(table, TableEntity {
+            partition_key: format!("{:0>32x}", msg.team_id),
+            row_key: format!("{:0>32x}", new_id()),
+            payload: TableStoragePrivacySpend {
+                spent_at: format_timestamp(Utc::now()),
+                epsilon: msg.epsilon,
+            },
+            etag: None,
+            timestamp: None
+        }).await?;
+
+        Ok(budget)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(GetPrivacySpends => Vec<PrivacySpend>: handler = fn handle(&mut self, msg: GetPrivacySpends, _: &mut Self::Context) -> Self::Result {
+    let table = self.privacy_spends.clone();
+
+    let work = async move {
+        let mut spends: Vec<PrivacySpend> = TableStorage::get_all::<TableStoragePrivacySpend, PrivacySpend, _>(
+            table,
+            Query::new().filter(Filter::eq("PartitionKey", msg.team_id)),
+            |_| true).await?;
+
+        spends.sort_by_key(|spend| spend.spent_at);
+
+        Ok(spends)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(StorePrivacySpends => (): handler = fn handle(&mut self, msg: StorePrivacySpends, _: &mut Self::Context) -> Self::Result {
+    let table = self.privacy_spends.clone();
+
+    let work = async move {
+        TableStorage::remove_partition(table.clone(), msg.team_id, false).await?;
+
+        for spend in msg.spends {
+            TableStorage::store_single::<TableStoragePrivacySpend, TableEntity<TableStoragePrivacySpend>>(table.clone(), TableEntity {
+                partition_key: format!("{:0>32x}", msg.team_id),
+                row_key: format!("{:0>32x}", new_id()),
+                payload: TableStoragePrivacySpend {
+                    spent_at: format_timestamp(spend.spent_at),
+                    epsilon: spend.epsilon,
+                },
+                etag: None,
+                timestamp: None
+            }).await?;
+        }
+
+        Ok(())
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(GetAlerts => Vec<Alert>: handler = fn handle(&mut self, msg: GetAlerts, _: &mut Self::Context) -> Self::Result {
+    let table = self.alerts.clone();
+
+    let work = async move {
+        let mut alerts: Vec<Alert> = TableStorage::get_all::<TableStorageAlert, Alert, _>(
+            table,
+            Query::new().filter(Filter::eq("PartitionKey", msg.team_id)),
+            |_| true).await?;
+
+        alerts.sort_by(|a, b| b.raised_at.cmp(&a.raised_at).then(a.id.cmp(&b.id)));
+
+        Ok(alerts)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(StoreAlert => Alert: handler = fn handle(&mut self, msg: StoreAlert, _: &mut Self::Context) -> Self::Result {
+    let work = TableStorage::store_alert(self.alerts.clone(), msg.alert);
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(AcknowledgeAlert => Alert: handler = fn handle(&mut self, msg: AcknowledgeAlert, _: &mut Self::Context) -> Self::Result {
+    let table = self.alerts.clone();
+
+    let work = async move {
+        let mut alert: Alert = TableStorage::get_single::<TableStorageAlert, Alert>(
+            table.clone(),
+            msg.team_id,
+            msg.id,
+            APIError::new(404, "Not Found", "The alert ID you provided could not be found. Please check it and try again.")).await?;
+
+        if alert.acknowledged_at.is_none() {
+            alert.acknowledged_by = Some(msg.principal_id);
+            alert.acknowledged_at = Some(Utc::now());
+            alert = TableStorage::store_alert(table, alert).await?;
+        }
+
+        Ok(alert)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(GetTeam => Team: handler = fn handle(&mut self, msg: GetTeam, _: &mut Self::Context) -> Self::Result {
+    let team_assignments = self.team_assignments.clone();
+    let team_records = self.team_records.clone();
+
+    let work = async move {
+        TableStorage::get_single::<TableStorageTeamAssignment, TeamAssignment>(
+            team_assignments,
+            msg.id,
+            msg.principal_id,
+            APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again.")).await?;
+
+        TableStorage::get_team_record(team_records, msg.id, msg.principal_id).await
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(GetTeams => Vec<Team>: handler = fn handle(&mut self, msg: GetTeams, _: &mut Self::Context) -> Self::Result {
+    let team_memberships = self.team_memberships.clone();
+    let team_assignments = self.team_assignments.clone();
+    let team_records = self.team_records.clone();
+
+    let work = async move {
+        let principal_id = msg.principal_id;
+        let memberships: Vec<TableEntity<TableStorageTeamMembership>> = TableStorage::get_all::<TableStorageTeamMembership, TableEntity<TableStorageTeamMembership>, _>(
+            team_memberships,
+            Query::new().filter(Filter::eq("PartitionKey", principal_id)),
+            |_| true).await?;
+
+        let teams = futures::future::join_all(memberships.iter().map(|membership| {
+            let team_id = u128::from_str_radix(&membership.row_key, 16).unwrap_or_default();
+            let team_assignments = team_assignments.clone();
+            let team_records = team_records.clone();
+
+            async move {
+                // The index is only a lookup aid, the role assignment remains the source of truth
+                TableStorage::get_single::<TableStorageTeamAssignment, TeamAssignment>(
+                    team_assignments,
+                    team_id,
+                    principal_id,
+                    APIError::new(404, "Not Found", "The team ID you provided could not be found. Please check it and try again.")).await?;
+
+                TableStorage::get_team_record(team_records, team_id, principal_id).await
+            }
+        })).await;
+
+        // Index entries for teams which the principal has left, or which have been deleted, are skipped
+        teams.into_iter().filter(|team| !matches!(team, Err(err) if err.code == 404)).collect()
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(GetAllTeams => Vec<Team>: handler = fn handle(&mut self, _: GetAllTeams, _: &mut Self::Context) -> Self::Result {
+    let team_records = self.team_records.clone();
+
+    let work = async move {
+        TableStorage::get_all::<TableStorageTeam, Team, _>(team_records, Query::new(), |_| true).await
+    };
+
+    Box::new(fut::wrap_future(work))
 });
 
-actor_handler!(RemoveTeam|msg: remove_single from teams where pk=msg.principal_id, rk=msg.id);
+actor_handler!(StoreTeam => Team: handler = fn handle(&mut self, msg: StoreTeam, _: &mut Self::Context) -> Self::Result {
+    let table = self.team_records.clone();
+
+    let work = async move {
+        TableStorage::store_single::<TableStorageTeam, TableEntity<TableStorageTeam>>(table, TableEntity {
+            partition_key: format!("{:0>32x}", msg.team_id),
+            row_key: format!("{:0>32x}", msg.team_id),
+            payload: TableStorageTeam {
+                name: msg.name.clone(),
+                retention_days: msg.retention_days,
+                privacy: serde_json::to_string(&msg.privacy).ok(),
+                alert_rules: serde_json::to_string(&msg.alert_rules).ok(),
+                metrics: serde_json::to_string(&msg.metrics).ok(),
+            },
+            etag: msg.etag.clone(),
+            timestamp: None
+        }).await.map(|entity| Team::from(entity).for_principal(msg.principal_id))
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(RemoveTeam => (): handler = fn handle(&mut self, msg: RemoveTeam, _: &mut Self::Context) -> Self::Result {
+    let team_records = self.team_records.clone();
+    let team_assignments = self.team_assignments.clone();
+
+    let work = async move {
+        // Removing a team which has already been removed succeeds, so that retries are safe
+        match TableStorage::get_team_record(team_records.clone(), msg.id, 0).await {
+            Err(err) if err.code == 404 => return Ok(()),
+            other => other?,
+        };
+
+        let members: Vec<TeamAssignment> = TableStorage::get_all::<TableStorageTeamAssignment, TeamAssignment, _>(
+            team_assignments,
+            Query::new().filter(Filter::eq("PartitionKey", msg.id)),
+            |_| true).await?;
+
+        if members.is_empty() {
+            TableStorage::remove_if_exists(team_records, msg.id, msg.id).await?;
+        }
+
+        Ok(())
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(DeleteTeam => TeamDeletion: handler = fn handle(&mut self, msg: DeleteTeam, _: &mut Self::Context) -> Self::Result {
+    let team_records = self.team_records.clone();
+    let team_memberships = self.team_memberships.clone();
+    let team_assignments_table = self.team_assignments.clone();
+    let reports_table = self.reports.clone();
+    let report_rollups = self.report_rollups.clone();
+    let privacy_spends = self.privacy_spends.clone();
+    let alerts_table = self.alerts.clone();
+    let staged_reports = self.staged_reports.clone();
+
+    let work = async move {
+        let team = match TableStorage::get_team_record(team_records.clone(), msg.team_id, 0).await {
+            Ok(team) => Some(team),
+            Err(err) if err.code == 404 => None,
+            Err(err) => return Err(err),
+        };
+
+        let team_assignments: Vec<TeamAssignment> = TableStorage::get_all::<TableStorageTeamAssignment, TeamAssignment, _>(
+            team_assignments_table.clone(),
+            Query::new().filter(Filter::eq("PartitionKey", msg.team_id)),
+            |_| true).await?;
+
+        // Table storage cannot delete across partitions atomically, so the team assignments are
+        // removed last to allow a manager to retry the deletion if any of the earlier steps fail.
+        let reports = TableStorage::remove_partition(reports_table, msg.team_id, msg.dry_run).await?;
+        let rollups = TableStorage::remove_partition(report_rollups, msg.team_id, msg.dry_run).await?;
+        let alerts = TableStorage::remove_partition(alerts_table, msg.team_id, msg.dry_run).await?;
+        let staged_reports = TableStorage::remove_partition(staged_reports, msg.team_id, msg.dry_run).await?;
+
+        if !msg.dry_run {
+            TableStorage::remove_partition(privacy_spends, msg.team_id, false).await?;
+
+            // Each member's index entry is in their own partition, so these are removed one at a time
+            for assignment in team_assignments.iter() {
+                TableStorage::remove_if_exists(team_memberships.clone(), assignment.user_id, assignment.team_id).await?;
+            }
+
+            if team.is_some() {
+                TableStorage::remove_if_exists(team_records, msg.team_id, msg.team_id).await?;
+            }
+
+            TableStorage::remove_partition(team_assignments_table, msg.team_id, false).await?;
+        }
+
+        Ok(TeamDeletion {
+            teams: team.map(|team| team_assignments.iter().map(|a| team.clone().for_principal(a.user_id)).collect()).unwrap_or_default(),
+            team_assignments,
+            reports,
+            staged_reports,
+            rollups,
+            alerts,
+        })
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(ReconcileTeams => TeamReconciliation: handler = fn handle(&mut self, _: ReconcileTeams, _: &mut Self::Context) -> Self::Result {
+    let legacy_teams = self.legacy_teams.clone();
+    let team_records = self.team_records.clone();
+    let team_memberships = self.team_memberships.clone();
+    let team_assignments_table = self.team_assignments.clone();
+
+    let work = async move {
+        team_records.create_if_not_exists().await?;
+        team_memberships.create_if_not_exists().await?;
+
+        let mut reconciliation = TeamReconciliation::default();
+
+        let mut copies: BTreeMap<u128, Vec<Team>> = BTreeMap::new();
+        for team in TableStorage::get_all::<TableStorageTeam, Team, _>(legacy_teams.clone(), Query::new(), |_| true).await? {
+            copies.entry(team.team_id).or_default().push(team);
+        }
+
+        let team_assignments: Vec<TeamAssignment> = TableStorage::get_all::<TableStorageTeamAssignment, TeamAssignment, _>(
+            team_assignments_table,
+            Query::new(),
+            |_| true).await?;
+
+        for (team_id, copies) in copies {
+            match TableStorage::get_team_record(team_records.clone(), team_id, 0).await {
+                Err(err) if err.code == 404 => {
+                    let assignments: Vec<TeamAssignment> = team_assignments.iter().filter(|a| a.team_id == team_id).cloned().collect();
+
+                    TableStorage::store_single::<TableStorageTeam, TableEntity<TableStorageTeam>>(team_records.clone(), TableEntity {
+                        partition_key: format!("{:0>32x}", team_id),
+                        row_key: format!("{:0>32x}", team_id),
+                        payload: TableStorageTeam {
+                            name: canonical_team_name(&copies, &assignments).unwrap_or_default(),
+                            retention_days: None,
+                            privacy: None,
+                            alert_rules: None,
+                            metrics: None,
+                        },
+                        etag: None,
+                        timestamp: None
+                    }).await?;
+
+                    reconciliation.teams += 1;
+                    if has_diverged(&copies) {
+                        reconciliation.diverged += 1;
+                    }
+                },
+                Err(err) => return Err(err),
+                Ok(_) => {}
+            }
+
+            for copy in copies {
+                TableStorage::remove_single(legacy_teams.clone(), copy.user_id, copy.team_id).await?;
+            }
+        }
+
+        let expected: BTreeSet<(u128, u128)> = team_assignments.iter().map(|a| (a.user_id, a.team_id)).collect();
+        let actual: BTreeSet<(u128, u128)> = TableStorage::get_all::<TableStorageTeamMembership, TableEntity<TableStorageTeamMembership>, _>(team_memberships.clone(), Query::new(), |_| true).await?
+            .iter()
+            .map(|m| (u128::from_str_radix(&m.partition_key, 16).unwrap_or_default(), u128::from_str_radix(&m.row_key, 16).unwrap_or_default()))
+            .collect();
+
+        for (principal_id, team_id) in expected.difference(&actual) {
+            TableStorage::add_membership(team_memberships.clone(), *principal_id, *team_id).await?;
+            reconciliation.memberships += 1;
+        }
+
+        for (principal_id, team_id) in actual.difference(&expected) {
+            TableStorage::remove_single(team_memberships.clone(), *principal_id, *team_id).await?;
+            reconciliation.memberships += 1;
+        }
+
+        Ok(reconciliation)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
 
 actor_handler!(GetTeamAssignment|msg => TeamAssignment: get_single from team_assignments(TableStorageTeamAssignment) where pk=msg.team_id, rk=msg.principal_id; not found = "The team ID you provided could not be found. Please check them and try again.");
 
 actor_handler!(GetTeamAssignments|msg => TeamAssignment: get_all from team_assignments(TableStorageTeamAssignment) where
-    query = format!("$filter=PartitionKey eq '{:0>32x}'", msg.team_id),
+    query = Query::new().filter(Filter::eq("PartitionKey", msg.team_id)),
     context = [],
     filter = _i -> true);
 
-actor_handler!(StoreTeamAssignment|msg => TeamAssignment: store_single in team_assignments(TableStorageTeamAssignment) TableEntity {
-    partition_key: format!("{:0>32x}", msg.team_id),
-    row_key: format!("{:0>32x}", msg.principal_id),
-    payload: TableStorageTeamAssignment {
-        role: msg.role.into()
-    },
-    etag: None,
-    timestamp: None
-}); 
+actor_handler!(StoreTeamAssignment => TeamAssignment: handler = fn handle(&mut self, msg: StoreTeamAssignment, _: &mut Self::Context) -> Self::Result {
+    let team_assignments = self.team_assignments.clone();
+    let team_memberships = self.team_memberships.clone();
 
-actor_handler!(RemoveTeamAssignment|msg: remove_single from team_assignments where pk=msg.team_id, rk=msg.principal_id);
+    let work = async move {
+        let assignment: TeamAssignment = TableStorage::store_single::<TableStorageTeamAssignment, TeamAssignment>(team_assignments, TableEntity {
+            partition_key: format!("{:0>32x}", msg.team_id),
+            row_key: format!("{:0>32x}", msg.principal_id),
+            payload: TableStorageTeamAssignment {
+                role: msg.role.into()
+            },
+            etag: msg.etag.clone(),
+            timestamp: None
+        }).await?;
+
+        TableStorage::add_membership(team_memberships, msg.principal_id, msg.team_id).await?;
+
+        Ok(assignment)
+    };
+
+    Box::new(fut::wrap_future(work))
+});
+
+actor_handler!(RemoveTeamAssignment => (): handler = fn handle(&mut self, msg: RemoveTeamAssignment, _: &mut Self::Context) -> Self::Result {
+    let team_assignments = self.team_assignments.clone();
+    let team_memberships = self.team_memberships.clone();
+
+    let work = async move {
+        TableStorage::get_single::<TableStorageTeamAssignment, TeamAssignment>(
+            team_assignments.clone(),
+            msg.team_id,
+            msg.principal_id,
+            APIError::new(404, "Not Found", "The principal ID you provided could not be found. This likely means that you do not yet have any teams.")).await?;
+
+        // The index entry is removed first, so that a failure part way through never leaves an
+        // index entry behind without its assignment. GetTeam and GetTeams also check the
+        // assignment itself, so a retry is all that is needed to finish the removal.
+        TableStorage::remove_if_exists(team_memberships, msg.principal_id, msg.team_id).await?;
+        TableStorage::remove_if_exists(team_assignments, msg.team_id, msg.principal_id).await
+    };
+
+    Box::new(fut::wrap_future(work))
+});
 
 actor_handler!(GetUser|msg => User: get_single from users(TableStorageUser) where pk=msg.email_hash, rk=msg.email_hash; not found = "The user you are looking for could not be found. Please check that you have entered their email address correctly and try again.");
 
+actor_handler!(GetUsers|_msg => User: get_all from users(TableStorageUser) where
+    query = Query::new(),
+    context = [],
+    filter = _i -> true);
+
 actor_handler!(StoreUser|msg => User: store_single in users(TableStorageUser) TableEntity {
     partition_key: format!("{:0>32x}", msg.email_hash),
     row_key: format!("{:0>32x}", msg.email_hash),
@@ -361,4 +1614,190 @@ actor_handler!(StoreUser|msg => User: store_single in users(TableStorageUser) Ta
     },
     etag: None,
     timestamp: None
-});
\ No newline at end of file
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    /// Applies a report query to the entities which would be written to table storage for the
+    /// given reports, in the same order that table storage would return them.
+    fn query_entities(query: &Query, reports: &[StoreReport]) -> Vec<u128> {
+        let mut entities: Vec<serde_json::Value> = reports.iter().map(|msg| serde_json::to_value(TableEntity {
+            partition_key: format!("{:0>32x}", msg.team),
+            row_key: format!("{:0>32x}", msg.id),
+            payload: TableStorageReport {
+                metric: msg.metric.clone(),
+                reported_at: msg.timestamp.map(format_timestamp),
+                value: msg.value,
+                response_id: None,
+                receipt_hash: None,
+                options: TableStorageReport::encode_options(&msg.options),
+                staged_at: None,
+            },
+            etag: None,
+            timestamp: None
+        }).expect("the entity should serialize")).collect();
+
+        entities.sort_by_key(|e| e["RowKey"].as_str().unwrap_or_default().to_string());
+
+        entities.iter()
+            .filter(|e| query.filter.as_ref().map(|f| f.matches(e)).unwrap_or(true))
+            .take(query.top.unwrap_or(usize::MAX))
+            .map(|e| u128::from_str_radix(e["RowKey"].as_str().unwrap_or_default(), 16).unwrap_or_default())
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn report_filters_match_memory_store() {
+        let start = Utc.ymd(2020, 1, 1).and_hms(9, 0, 0);
+        let reports: Vec<StoreReport> = (1..=20u128).map(|id| StoreReport {
+            id,
+            team: if id % 5 == 0 { 2 } else { 1 },
+            metric: match id % 3 {
+                0 => "happy_sad".into(),
+                1 => "workload".into(),
+                _ => "it's complicated".into(),
+            },
+            timestamp: Some(start + chrono::Duration::milliseconds(1500 * (id as i64 % 7))),
+            value: id as f32,
+            options: vec![],
+        }).collect();
+
+        let store = MemoryStore::new().start();
+        for report in reports.iter() {
+            store.send(StoreReport {
+                id: report.id,
+                team: report.team,
+                metric: report.metric.clone(),
+                timestamp: report.timestamp,
+                value: report.value,
+                options: vec![],
+            }).await.expect("the actor should run").expect("the report should be stored");
+        }
+
+        let metrics = [None, Some("happy_sad".to_string()), Some("it's complicated".to_string()), Some("missing".to_string())];
+        let afters = [None, Some(start), Some(start + chrono::Duration::milliseconds(4500)), Some(start + chrono::Duration::hours(1))];
+        let cursors = [None, Some(3u128)];
+
+        for metric in metrics.iter() {
+            for after in afters.iter() {
+                for cursor in cursors.iter() {
+                    let expected: Vec<u128> = store.send(GetReports {
+                        team: 1,
+                        metric: metric.clone(),
+                        after: *after,
+                        cursor: cursor.map(|c| format!("{:0>32x}", c)),
+                        limit: Some(4),
+                    }).await.expect("the actor should run").expect("the reports should be listed")
+                        .items.iter().map(|r| r.id).collect();
+
+                    let query = TableStorage::build_report_filter_query(1, metric.clone(), *after, *cursor, Some(4));
+                    let mut actual = query_entities(&query, &reports);
+                    actual.truncate(4);
+
+                    assert_eq!(actual, expected, "metric = {:?}, after = {:?}, cursor = {:?}", metric, after, cursor);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn expired_report_query_includes_legacy_reports() {
+        let before = Utc.ymd(2020, 1, 1).and_hms(9, 0, 0);
+        let entity = |id: u128, reported_at: Option<DateTime<Utc>>, written: DateTime<Utc>| {
+            let mut entity = serde_json::json!({ "PartitionKey": format!("{:0>32x}", 1), "RowKey": format!("{:0>32x}", id), "Timestamp": written.to_rfc3339() });
+            if let Some(reported_at) = reported_at {
+                entity["ReportedAt"] = serde_json::Value::String(format_timestamp(reported_at));
+            }
+            entity
+        };
+
+        let entities = [
+            entity(1, Some(before - chrono::Duration::days(1)), before + chrono::Duration::days(1)),
+            entity(2, Some(before + chrono::Duration::hours(1)), before + chrono::Duration::days(1)),
+            entity(3, None, before - chrono::Duration::hours(1)),
+            entity(4, None, before + chrono::Duration::hours(1)),
+            entity(5, Some(before - chrono::Duration::days(1)), before - chrono::Duration::days(1)),
+        ];
+
+        let query = TableStorage::build_expired_report_query(1, before);
+        let filter = query.filter.expect("a filter");
+        let matched: Vec<&str> = entities.iter().filter(|e| filter.matches(e)).map(|e| e["RowKey"].as_str().unwrap_or_default()).collect();
+
+        assert_eq!(matched, vec![format!("{:0>32x}", 1), format!("{:0>32x}", 3), format!("{:0>32x}", 5)]);
+    }
+
+    #[test]
+    fn partition_keys_query_selects_only_keys() {
+        let query = TableStorage::build_partition_keys_query(7);
+
+        assert_eq!(query.filter, Some(Filter::eq("PartitionKey", 7u128)));
+        assert_eq!(query.select, vec!["PartitionKey", "RowKey"]);
+        assert_eq!(query.top, None);
+    }
+
+    #[test]
+    fn report_queries_keep_the_limit_outside_the_filter() {
+        let query = TableStorage::build_report_filter_query(1, Some("happy_sad".into()), None, Some(2), Some(10));
+
+        assert_eq!(
+            query.to_string(),
+            "$filter=%28%28PartitionKey%20eq%20%2700000000000000000000000000000001%27%29%20and%20%28RowKey%20gt%20%2700000000000000000000000000000002%27%29%29%20and%20%28Metric%20eq%20%27happy_sad%27%29&$top=11");
+    }
+
+    #[test]
+    fn report_filters_include_legacy_reports() {
+        let after = Utc.ymd(2020, 1, 1).and_hms(9, 0, 0);
+        let entity = |id: u128, reported_at: Option<DateTime<Utc>>, written: DateTime<Utc>| {
+            let mut entity = serde_json::json!({ "PartitionKey": format!("{:0>32x}", 1), "RowKey": format!("{:0>32x}", id), "Timestamp": written.to_rfc3339(), "Metric": "happy_sad", "Value": 1.0 });
+            if let Some(reported_at) = reported_at {
+                entity["ReportedAt"] = serde_json::Value::String(format_timestamp(reported_at));
+            }
+            entity
+        };
+
+        // Legacy reports only have the Timestamp of their last write, which is when they were made
+        let entities = [
+            entity(1, None, after + chrono::Duration::hours(1)),
+            entity(2, None, after - chrono::Duration::hours(1)),
+            entity(3, Some(after + chrono::Duration::hours(1)), after + chrono::Duration::hours(1)),
+            entity(4, Some(after - chrono::Duration::hours(1)), after + chrono::Duration::hours(1)),
+        ];
+
+        let query = TableStorage::build_report_filter_query(1, None, Some(after), None, None);
+        let filter = query.filter.expect("a filter");
+        let matched: Vec<u128> = entities.iter()
+            .filter(|e| filter.matches(e))
+            .map(|e| serde_json::from_value::<TableEntity<TableStorageReport>>(e.clone()).expect("the entity should deserialize"))
+            .filter(|e| TableStorage::reported_since(e, Some(after)))
+            .map(|e| u128::from_str_radix(&e.row_key, 16).unwrap_or_default())
+            .collect();
+
+        assert_eq!(matched, vec![1, 3]);
+    }
+
+    #[test]
+    fn reports_keep_their_own_timestamp() {
+        let reported_at = Utc.ymd(2020, 1, 1).and_hms(9, 0, 0);
+        let entity = TableEntity {
+            partition_key: format!("{:0>32x}", 1),
+            row_key: format!("{:0>32x}", 2),
+            payload: TableStorageReport {
+                metric: "happy_sad".into(),
+                reported_at: Some(format_timestamp(reported_at)),
+                value: 1.0,
+                response_id: None,
+                receipt_hash: None,
+                options: None,
+                staged_at: None,
+            },
+            etag: None,
+            timestamp: Some(Utc::now()),
+        };
+
+        let report: Report = entity.into();
+        assert_eq!(report.timestamp, reported_at);
+    }
+}